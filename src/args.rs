@@ -1,5 +1,5 @@
 use crate::lang::Result;
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 use std::{io::BufRead, sync::LazyLock};
 use tracing::Level;
 
@@ -17,6 +17,12 @@ pub struct Args {
     /// Minimum might be pulled higher.
     #[arg(global = true, short, long, action = clap::ArgAction::Count, verbatim_doc_comment)]
     pub verbosity: u8,
+    /// Record spans to a Chrome trace file (chrome://tracing, Perfetto)
+    /// for profiling.
+    #[arg(global = true, long)]
+    pub trace: bool,
+    #[arg(global = true, long, default_value = "data/trace.json")]
+    pub trace_output: String,
     #[command(subcommand)]
     pub command: Cmd,
 }
@@ -29,12 +35,29 @@ pub enum Cmd {
     /// Index / transform / migrate from src sqlite db to dest db
     #[command(name = "sindex")]
     SqliteIndex(crate::sqliteindex::SqliteIndexArgs),
+    /// Re-index the blocks of a legacy rocks db into a sqlite index
+    #[cfg(feature = "legacy-rocks")]
+    #[command(name = "migrate")]
+    Migrate(crate::migrate::MigrateArgs),
+    /// Re-index a height range of an existing index from the cli, in place
+    #[command(name = "reindex")]
+    Reindex(crate::reindex::ReindexArgs),
+    /// Cluster input addresses that are spent together in a tx
+    #[command(name = "cluster")]
+    Cluster(crate::clustering::ClusterArgs),
+    /// Store the input address pairs spent together in a tx, and list
+    /// the co-spend group of an address
+    #[command(name = "cospend")]
+    CoSpend(crate::cospend::CoSpendArgs),
     /// Reduce dot graph files
     #[command(name = "dotreduce")]
     DotReduce {
         #[arg(long = "in")]
         in_file: String,
     },
+    /// Stream the txs of a height range out as jsonl or csv
+    #[command(name = "export")]
+    Export(crate::export::ExportArgs),
     /// Analyze ICX claims and every address involved in the way
     /// up until the swap of the claims
     #[command(name = "icx1")]
@@ -42,6 +65,13 @@ pub enum Cmd {
     /// ICX analysis 2
     #[command(name = "icx2")]
     IcxAnalyze2(crate::icx2::IcxAnalyze2Args),
+    /// Claimed BTC, claim count, orders and counterparties per ICX
+    /// claiming address, top addresses by BTC claimed
+    #[command(name = "icxstats")]
+    IcxStats(crate::icxstats::IcxStatsArgs),
+    /// Open ICX orders at a given height
+    #[command(name = "icxorderbook")]
+    IcxOrderBook(crate::icxorderbook::IcxOrderBookArgs),
     /// Output the full ICX sequence chain
     #[command(name = "icxseq")]
     IcxSequence(crate::icxseq::IcxSequenceArgs),
@@ -49,12 +79,20 @@ pub enum Cmd {
     /// so the graph can loaded in memory and reused directly.
     #[command(name = "gbuild")]
     Graph(crate::graphbuild::GrapherArgs),
+    /// Construct a graph of only the addresses tagged through ICX txs
+    /// and the addresses they transact with
+    #[command(name = "gtagged")]
+    TaggedGraph(crate::graphtagged::TaggedGraphArgs),
     /// Load and explore full graph
     #[command(name = "gwalk")]
     GraphWalk(crate::graphwalk::GraphWalkArgs),
     /// Load the full graph, condense it and output dot files
     #[command(name = "gdot")]
     GraphDot(crate::graphdot::GraphDotArgs),
+    /// Rank the graph addresses with PageRank and list the top ones with
+    /// their degrees and ICX / swap volumes
+    #[command(name = "grank")]
+    GraphRank(crate::graphrank::GraphRankArgs),
     /// Find all paths with exclusions
     #[command(name = "gpath")]
     GraphPath(crate::gpath::GraphPathArgs),
@@ -65,6 +103,182 @@ pub enum Cmd {
     /// and check for errors
     #[command(name = "logparsecheck")]
     LogParseCheck(crate::logparse::LogParseArgs),
+    /// UTXO balance of an address at a given height
+    #[command(name = "balance")]
+    Balance(crate::balance::BalanceArgs),
+    /// Masternode creation, resignation and minting stats per owner address
+    #[command(name = "mnstats")]
+    MasternodeStats(crate::masternodes::MnStatsArgs),
+    /// Txs an address is in, with its role and amount, looked up through
+    /// the graph table without loading the graph
+    #[command(name = "search")]
+    Search(crate::search::SearchArgs),
+    /// Activity of an address over the chain: a density timeline, first
+    /// and last seen, tx types and amounts
+    #[command(name = "timeline")]
+    Timeline(crate::timeline::TimelineArgs),
+    /// Index stats: counts, height range and gaps, and sqlite page usage
+    #[command(name = "stats")]
+    Stats(crate::stats::StatsArgs),
+    /// List the known tx types as stored in the index
+    #[command(name = "txtypes")]
+    ListTxTypes,
+    /// Full history and net position of a vault
+    #[command(name = "vaulthistory")]
+    VaultHistory(crate::vaulthistory::VaultHistoryArgs),
+    /// Check the index for height gaps, missing txs and data that
+    /// doesn't parse, and optionally re-index the broken blocks
+    #[command(name = "verify")]
+    Verify(crate::verify::VerifyArgs),
+    /// Swap count and volume per token pair
+    #[command(name = "swapstats")]
+    SwapStats(crate::swapstats::SwapStatsArgs),
+    /// Swap volume of a token per day, week or month
+    #[command(name = "tokenvolume")]
+    TokenVolume(crate::tokenvolume::TokenVolumeArgs),
+    /// List the subcommands along with their args and defaults
+    #[command(name = "commands")]
+    ListCommands {
+        #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+        output_format: OutputFormat,
+    },
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CommandInfo {
+    pub name: String,
+    pub description: String,
+    pub args: Vec<ArgInfo>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ArgInfo {
+    pub name: String,
+    pub default_value: Option<String>,
+    pub required: bool,
+    pub description: String,
+}
+
+/// Subcommands and their args, as defined for clap. Global args are
+/// not included.
+pub fn list_commands() -> Vec<CommandInfo> {
+    let help_str =
+        |x: Option<&clap::builder::StyledStr>| x.map(|x| x.to_string()).unwrap_or_default();
+    Args::command()
+        .get_subcommands()
+        .map(|c| CommandInfo {
+            name: c.get_name().to_string(),
+            description: help_str(c.get_about()),
+            args: c
+                .get_arguments()
+                .map(|a| {
+                    let defaults = a.get_default_values();
+                    ArgInfo {
+                        name: a.get_id().to_string(),
+                        default_value: (!defaults.is_empty()).then(|| {
+                            defaults
+                                .iter()
+                                .map(|x| x.to_string_lossy())
+                                .collect::<Vec<_>>()
+                                .join(",")
+                        }),
+                        required: a.is_required_set(),
+                        description: help_str(a.get_help()),
+                    }
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+pub fn print_commands(output_format: OutputFormat) -> Result<()> {
+    let commands = list_commands();
+    match output_format {
+        OutputFormat::Text => {
+            for c in commands.iter() {
+                println!("{}: {}", c.name, c.description);
+                for a in c.args.iter() {
+                    println!(
+                        "    {:<28} {:<10} {}",
+                        a.name,
+                        if a.required { "required" } else { "" },
+                        a.default_value.as_deref().unwrap_or("-"),
+                    );
+                }
+            }
+        }
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&commands)?);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_list_commands() {
+    let commands = list_commands();
+    let names = commands.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+    let mut expected = vec!["cindex", "sindex"];
+    if cfg!(feature = "legacy-rocks") {
+        expected.push("migrate");
+    }
+    expected.extend([
+        "reindex",
+        "cluster",
+        "cospend",
+        "dotreduce",
+        "export",
+        "icx1",
+        "icx2",
+        "icxstats",
+        "icxorderbook",
+        "icxseq",
+        "gbuild",
+        "gtagged",
+        "gwalk",
+        "gdot",
+        "grank",
+        "gpath",
+        "spath",
+        "logparsecheck",
+        "balance",
+        "mnstats",
+        "search",
+        "timeline",
+        "stats",
+        "txtypes",
+        "vaulthistory",
+        "verify",
+        "swapstats",
+        "tokenvolume",
+        "commands",
+    ]);
+    assert_eq!(names, expected);
+
+    let cindex = commands.iter().find(|x| x.name == "cindex").unwrap();
+    let sqlite_path = cindex
+        .args
+        .iter()
+        .find(|x| x.name == "sqlite_path")
+        .unwrap();
+    assert_eq!(
+        sqlite_path.default_value.as_deref(),
+        Some("data/index.sqlite")
+    );
+    assert!(!sqlite_path.required);
+    let vault_id = commands
+        .iter()
+        .find(|x| x.name == "vaulthistory")
+        .and_then(|x| x.args.iter().find(|x| x.name == "vault_id"))
+        .unwrap();
+    assert!(vault_id.required);
+}
+
+/// Output format for the commands that print reports
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
 }
 
 pub fn verbosity_to_level(verbosity: u8, min: Option<u8>) -> Level {
@@ -85,6 +299,12 @@ pub fn get_args() -> &'static Args {
     &ARGS
 }
 
+// Addresses are at least 25 chars, anything shorter is likely a truncated
+// line.
+const MIN_ADDR_LEN: usize = 25;
+
+/// Each item is either an address, or a path to a file with one address
+/// per line. Blank lines and lines starting with `#` are skipped.
 pub fn process_list_args_with_file_paths(list: &[String]) -> Result<Vec<String>> {
     let mut r_list: Vec<String> = Vec::with_capacity(list.len());
     for x in list.iter() {
@@ -92,16 +312,54 @@ pub fn process_list_args_with_file_paths(list: &[String]) -> Result<Vec<String>>
             let mut r = std::io::BufReader::new(f);
             let mut buf = String::new();
             while r.read_line(&mut buf)? != 0 {
-                let line = buf.trim();
-                if line.is_empty() {
-                    continue;
+                // Also strips \r from CRLF files
+                let line = buf.trim_matches(|c: char| c.is_whitespace());
+                if !line.is_empty() && !line.starts_with('#') {
+                    r_list.push(line.to_string());
                 }
-                r_list.push(line.to_string());
                 buf.clear();
             }
         } else {
             r_list.push(x.clone());
         }
     }
+    if let Some(x) = r_list.iter().find(|x| x.len() < MIN_ADDR_LEN) {
+        return Err(format!("invalid address, too short: {}", x).into());
+    }
     Ok(r_list)
 }
+
+#[test]
+fn test_process_list_args_crlf() {
+    let path = std::env::temp_dir().join(format!("addrs-{}.txt", std::process::id()));
+    std::fs::write(
+        &path,
+        "# exchange addrs\r\n8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R\r\n\r\n  \tdf1qqvaqshw0hrjzakxms27xrk6npfef4sx6cqaejv\r\n",
+    )
+    .unwrap();
+    let list = [
+        path.to_string_lossy().into_owned(),
+        "dZcHjYhKtEM88TtZLjp314H2xZjkztXtRc".to_string(),
+    ];
+    let r = process_list_args_with_file_paths(&list);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        r.unwrap(),
+        [
+            "8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R",
+            "df1qqvaqshw0hrjzakxms27xrk6npfef4sx6cqaejv",
+            "dZcHjYhKtEM88TtZLjp314H2xZjkztXtRc",
+        ]
+    );
+
+    assert!(process_list_args_with_file_paths(&["8J6KKxHQ".to_string()]).is_err());
+
+    // 25 chars is accepted, 24 isn't
+    let addr = "8J6KKxHQAWDJDR1PQfC46ocgm";
+    assert_eq!(addr.len(), 25);
+    assert_eq!(
+        process_list_args_with_file_paths(&[addr.to_string()]).unwrap(),
+        [addr]
+    );
+    assert!(process_list_args_with_file_paths(&[addr[..24].to_string()]).is_err());
+}
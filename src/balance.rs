@@ -0,0 +1,303 @@
+use crate::args::OutputFormat;
+use crate::db::{self, SqliteBlockStore};
+use crate::lang::{OptionExt, Result};
+use clap::Parser;
+use std::collections::HashMap;
+use tracing::{debug, info, warn};
+
+const SATS_PER_COIN: i64 = 100_000_000;
+
+#[derive(Parser, Debug)]
+pub struct BalanceArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    #[arg(long, short = 'a')]
+    pub address: String,
+    /// Height to compute the balance at, inclusive
+    #[arg(long)]
+    pub height: u32,
+    /// Scan the tx_in and tx_out json of every tx instead of looking the
+    /// address up through the graph table and the coinbases. Slow, for
+    /// indexes built without the graph table.
+    #[arg(long, default_value_t = false)]
+    pub full_scan: bool,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct AddrBalance {
+    pub address: String,
+    pub height: i64,
+    pub balance: String,
+    pub balance_sats: i64,
+    pub credit_txs: u64,
+    pub debit_txs: u64,
+    /// Txs where the address is only seen in the DVM msg, which don't
+    /// move utxos
+    pub dvm_only_txs: u64,
+}
+
+/// UTXO balance of `addr` at `height`, from the folded tx ins and outs.
+/// Summed in sats, so there's no float error build up over many txs.
+/// The txs are looked up through `tx_addr_graph`, plus the coinbases
+/// which have no edges, unless `full_scan` is set. A negative balance
+/// means the index is missing txs of the address, and is an error.
+pub fn get_balance(
+    sql_store: &SqliteBlockStore,
+    addr: &str,
+    height: i64,
+    full_scan: bool,
+) -> Result<AddrBalance> {
+    let mut b = AddrBalance {
+        address: addr.to_string(),
+        height,
+        ..Default::default()
+    };
+    if full_scan {
+        sql_store.iter_txs_for_addr(addr, height, |tx| {
+            let tx = tx?;
+            b.add_tx(&tx.tx_in, &tx.tx_out);
+            Ok(())
+        })?;
+    } else {
+        let coinbases = sql_store.get_address_coinbase_txs(addr, 0, height)?;
+        for txid in coinbases
+            .into_iter()
+            .chain(sql_store.get_address_txs(addr, 0, height)?)
+        {
+            let tx = sql_store
+                .get_tx_addr_data_from_hash(&txid)?
+                .ok_or_with(|| format!("tx: {}", txid))?;
+            b.add_tx(&tx.tx_in, &tx.tx_out);
+        }
+    }
+    b.balance = sats_to_decimal_str(b.balance_sats);
+    if b.balance_sats < 0 {
+        return Err(format!(
+            "negative balance for {} at {}: {}, the index is missing txs of the address",
+            addr, height, b.balance
+        )
+        .into());
+    }
+    Ok(b)
+}
+
+impl AddrBalance {
+    fn add_tx(&mut self, tx_in: &HashMap<String, f64>, tx_out: &HashMap<String, f64>) {
+        let credit = tx_out.get(&self.address);
+        let debit = tx_in.get(&self.address);
+        if let Some(v) = credit {
+            self.balance_sats += to_sats(*v);
+            self.credit_txs += 1;
+        }
+        if let Some(v) = debit {
+            self.balance_sats -= to_sats(*v);
+            self.debit_txs += 1;
+        }
+        if credit.is_none() && debit.is_none() {
+            self.dvm_only_txs += 1;
+        }
+    }
+}
+
+fn to_sats(v: f64) -> i64 {
+    (v * SATS_PER_COIN as f64).round() as i64
+}
+
+fn sats_to_decimal_str(sats: i64) -> String {
+    let sign = if sats < 0 { "-" } else { "" };
+    let sats = sats.unsigned_abs();
+    let per_coin = SATS_PER_COIN as u64;
+    format!("{}{}.{:08}", sign, sats / per_coin, sats % per_coin)
+}
+
+pub fn run(args: &BalanceArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    let sql_store = SqliteBlockStore::open(Some(&args.sqlite_path))?;
+
+    info!("get balance of {} at {}..", args.address, args.height);
+    let b = get_balance(
+        &sql_store,
+        &args.address,
+        args.height.into(),
+        args.full_scan,
+    )?;
+    if b.credit_txs == 0 && b.debit_txs == 0 && b.dvm_only_txs > 0 {
+        warn!(
+            "{} is only a DVM participant in {} txs, no utxo balance",
+            b.address, b.dvm_only_txs
+        );
+    }
+
+    match args.output_format {
+        OutputFormat::Text => {
+            println!("address: {}", b.address);
+            println!("height: {}", b.height);
+            println!("balance: {} ({} sats)", b.balance, b.balance_sats);
+            println!("credit txs: {}, debit txs: {}", b.credit_txs, b.debit_txs);
+        }
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&b)?);
+        }
+    }
+
+    info!("complete");
+    Ok(())
+}
+
+#[test]
+fn test_get_balance() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    let addr = "8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R";
+    let other = "dZcHjYhKtEM88TtZLjp314H2xZjkztXtRc";
+    store
+        .with_stmts(|stmts| {
+            let txs = [
+                // coinbase
+                (
+                    "t1",
+                    1,
+                    "cb",
+                    String::new(),
+                    format!(r#"{{"{}":200.0}}"#, addr),
+                    String::new(),
+                ),
+                // self send, with change
+                (
+                    "t2",
+                    2,
+                    "utxo",
+                    format!(r#"{{"{}":200.0}}"#, addr),
+                    format!(r#"{{"{}":150.1,"{}":49.8999}}"#, addr, other),
+                    String::new(),
+                ),
+                // DVM only
+                (
+                    "t3",
+                    3,
+                    "ps",
+                    format!(r#"{{"{}":1.0}}"#, other),
+                    format!(r#"{{"{}":0.9999}}"#, other),
+                    format!(r#"["{}"]"#, addr),
+                ),
+                (
+                    "t4",
+                    4,
+                    "utxo",
+                    format!(r#"{{"{}":150.1}}"#, addr),
+                    format!(r#"{{"{}":150.0999}}"#, other),
+                    String::new(),
+                ),
+            ];
+            for (txid, height, tx_type, tx_in, tx_out, dvm) in txs {
+                crate::db::insert_test_tx(
+                    stmts,
+                    &crate::db::TestTx {
+                        txid,
+                        height,
+                        tx_type,
+                        tx_in: &tx_in,
+                        tx_out: &tx_out,
+                        dvm_in: &dvm,
+                        dvm_out: &dvm,
+                        ..Default::default()
+                    },
+                )?;
+                let mut tx_out: HashMap<String, f64> = serde_json::from_str(&tx_out).unwrap();
+                if tx_in.is_empty() {
+                    for o in tx_out.keys() {
+                        stmts[3].execute(rusqlite::params![o, height, txid])?;
+                    }
+                    continue;
+                }
+                let tx_in: HashMap<String, f64> = serde_json::from_str(&tx_in).unwrap();
+                if !dvm.is_empty() {
+                    tx_out.insert(addr.to_string(), 0.0);
+                }
+                for i in tx_in.keys() {
+                    for o in tx_out.keys() {
+                        stmts[2].execute(rusqlite::params![txid, i, o, 0])?;
+                    }
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let b = get_balance(&store, addr, 1, true).unwrap();
+    assert_eq!(
+        (b.balance.as_str(), b.balance_sats),
+        ("200.00000000", 20_000_000_000)
+    );
+    let b = get_balance(&store, addr, 3, true).unwrap();
+    assert_eq!(
+        (b.balance.as_str(), b.balance_sats),
+        ("150.10000000", 15_010_000_000)
+    );
+    assert_eq!((b.credit_txs, b.debit_txs, b.dvm_only_txs), (2, 1, 1));
+    let b = get_balance(&store, addr, 4, true).unwrap();
+    assert_eq!((b.balance.as_str(), b.balance_sats), ("0.00000000", 0));
+    let b = get_balance(&store, other, 4, true).unwrap();
+    assert_eq!(b.balance, "199.99970000");
+
+    let b = get_balance(
+        &store,
+        "df1qqvaqshw0hrjzakxms27xrk6npfef4sx6cqaejv",
+        4,
+        true,
+    )
+    .unwrap();
+    assert_eq!(
+        b,
+        AddrBalance {
+            address: "df1qqvaqshw0hrjzakxms27xrk6npfef4sx6cqaejv".to_string(),
+            height: 4,
+            balance: "0.00000000".to_string(),
+            ..Default::default()
+        }
+    );
+    assert!(get_balance(&store, "a%", 4, true).is_err());
+
+    // Through the graph, with the coinbase added, it's the same
+    for h in 1..=4 {
+        assert_eq!(
+            get_balance(&store, addr, h, false).unwrap(),
+            get_balance(&store, addr, h, true).unwrap()
+        );
+    }
+    let b = get_balance(&store, addr, 4, false).unwrap();
+    assert_eq!((b.credit_txs, b.debit_txs, b.dvm_only_txs), (2, 2, 1));
+    let b = get_balance(&store, other, 4, false).unwrap();
+    assert_eq!(b.balance, "199.99970000");
+
+    // Spends of outs that were never indexed
+    store
+        .with_stmts(|stmts| {
+            let tx_in = format!(r#"{{"{}":500.0}}"#, other);
+            let tx_out = format!(r#"{{"{}":499.9}}"#, addr);
+            crate::db::insert_test_tx(
+                stmts,
+                &crate::db::TestTx {
+                    txid: "t5",
+                    height: 5,
+                    tx_type: "utxo",
+                    tx_in: &tx_in,
+                    tx_out: &tx_out,
+                    ..Default::default()
+                },
+            )?;
+            stmts[2].execute(rusqlite::params!["t5", other, addr, 0])?;
+            Ok(())
+        })
+        .unwrap();
+    assert!(get_balance(&store, other, 5, false).is_err());
+    assert!(get_balance(&store, other, 5, true).is_err());
+    assert_eq!(
+        get_balance(&store, addr, 5, false).unwrap().balance,
+        "499.90000000"
+    );
+    assert_eq!(sats_to_decimal_str(-150), "-0.00000150");
+}
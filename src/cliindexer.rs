@@ -1,28 +1,39 @@
+use crate::clipool::CliDriverPool;
 use crate::db;
 use crate::dfiutils;
 use crate::lang;
 use crate::logparse::process_log_file;
+use crate::metrics::{self, ErrorKind, IndexerMetrics};
 use crate::models;
 use crate::models::LogEntryMap;
 use clap::Parser;
-use db::{
-    sqlite_begin_tx, sqlite_commit_and_begin_tx, sqlite_commit_tx, sqlite_create_index_factory_v2,
-    sqlite_get_stmts_v2, SqliteBlockStore,
-};
-use dfiutils::{extract_all_dfi_addresses, token_id_to_symbol_maybe, CliDriver};
+use db::{BatchCommit, BlockStore, LruBlockStore, SqliteBlockStore};
+use dfiutils::{extract_all_dfi_addresses, OutputExt, TokenResolver};
 use lang::OptionExt;
-use lang::Result;
-use models::{Block, IcxTxSet, TxType};
+use lang::{Error, Result, Secret};
+use models::{Block, IcxTxSet, Transaction, TxType};
+use rusqlite::CachedStatement;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::io::Write;
 use std::rc::Rc;
 use tracing::debug;
 use tracing::info;
+use tracing::info_span;
+use tracing::warn;
 
 #[derive(Parser, Debug)]
 pub struct CliIndexArgs {
     #[arg(long, default_value = "defi-cli")]
     pub defi_cli_path: String,
+    /// Talk to defid's json-rpc endpoint at this url (eg.
+    /// http://127.0.0.1:8554) instead of going through defi-cli
+    #[arg(long)]
+    pub rpc_url: Option<String>,
+    #[arg(long, requires = "rpc_url")]
+    pub rpc_user: Option<String>,
+    #[arg(long, requires = "rpc_url")]
+    pub rpc_pass: Option<Secret>,
     #[arg(long, default_value = "data/index.sqlite")]
     pub sqlite_path: String,
     // The path to the debug.log file from defid.
@@ -36,12 +47,215 @@ pub struct CliIndexArgs {
     pub log_icx_calc_matcher: String,
     #[arg(long, default_value = "SwapResult:")]
     pub log_swap_matcher: String,
-    #[arg(short = 's', long, default_value_t = 0)]
-    pub start_height: i64,
+    /// Defaults to 0
+    #[arg(short = 's', long)]
+    pub start_height: Option<u32>,
     #[arg(short = 'e', long, default_value_t = 2_000_000)]
-    pub end_height: i64,
+    pub end_height: u32,
     #[arg(long, default_value_t = true)]
     pub enable_graph_table: bool,
+    /// Start right after the last checkpoint in the index, for re-running
+    /// after a crash or to catch up with the chain
+    #[arg(long, default_value_t = false)]
+    pub resume: bool,
+    /// Timeout in seconds for each defi-cli or rpc call. No timeout if not set.
+    #[arg(long)]
+    pub rpc_timeout: Option<u64>,
+    /// Max number of defi-cli processes or rpc calls running at the same time
+    #[arg(long, default_value_t = 4)]
+    pub max_concurrent_cli: usize,
+    /// Number of blocks fetched from the cli ahead of the one being
+    /// indexed, each on its own thread. Capped by max_concurrent_cli.
+    #[arg(long, default_value_t = 4)]
+    pub fetch_ahead: usize,
+    /// Don't check the node is on the same chain as the existing index
+    #[arg(long, default_value_t = false)]
+    pub skip_network_check: bool,
+    /// Always drop the indexes before indexing. By default they're only
+    /// dropped when indexing a large range into an existing index.
+    #[arg(long, default_value_t = false)]
+    pub always_drop_indexes: bool,
+    /// Number of deserialized txs kept in memory for resolving tx ins.
+    /// 0 disables the cache.
+    #[arg(long, default_value_t = 1000)]
+    pub tx_cache_size: usize,
+    #[command(flatten)]
+    pub sqlite_pragmas: db::SqlitePragmaArgs,
+    /// Token id to symbol map, json or csv, for the swap tokens that
+    /// aren't in the built-in table
+    #[arg(long)]
+    pub token_map_path: Option<String>,
+    /// Fetch, parse and resolve the range as usual, but roll back all
+    /// writes at the end and print what would have been indexed
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+    /// Commit the write transaction every this many blocks
+    #[arg(long, default_value_t = 10000, value_parser = clap::value_parser!(u32).range(1..))]
+    pub batch_size: u32,
+    /// Also commit once the db has grown by this many bytes since the
+    /// last commit, for large blocks on limited memory
+    #[arg(long)]
+    pub batch_size_bytes: Option<u64>,
+    /// Serve Prometheus metrics of the run at `/metrics` on this port
+    #[arg(long)]
+    pub metrics_port: Option<u16>,
+    /// Address to serve the metrics on. Only local by default, set to
+    /// 0.0.0.0 to serve on all interfaces.
+    #[arg(long, default_value = "127.0.0.1", requires = "metrics_port")]
+    pub metrics_addr: std::net::IpAddr,
+    /// Skip txs that fail to index, like ones with an input that can't
+    /// be resolved, instead of stopping. The skipped txs are logged and
+    /// written out to the error log path.
+    #[arg(long, default_value_t = false)]
+    pub error_recovery: bool,
+    #[arg(long, default_value = "data/errors.jsonl")]
+    pub error_log_path: String,
+    /// Check the height range against the chain height, and the db for
+    /// write access and disk space, before indexing. Pass false to skip.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub height_range_validate: bool,
+    /// Only index txs of these types in full, eg. `ps,cs,icx-claim`. The
+    /// other txs keep a stub row with their outs, and their graph edges.
+    #[arg(long, use_value_delimiter = true, value_delimiter = ',')]
+    pub include_tx_types: Vec<String>,
+    /// Index txs of these types as stubs, as with `--include-tx-types`
+    #[arg(long, use_value_delimiter = true, value_delimiter = ',')]
+    pub exclude_tx_types: Vec<String>,
+}
+
+/// How `insert_block` indexes the txs
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InsertOpts<'a> {
+    pub enable_addr_graph: bool,
+    /// Skip the txs that fail, collecting their errors in the counts
+    pub error_recovery: bool,
+    /// Txs the filter doesn't allow are written as stubs. None is all.
+    pub tx_filter: Option<&'a TxTypeFilter>,
+}
+
+const TX_TYPE_FILTER_KEY: &str = "tx_type_filter";
+
+/// Tx types to index in full. Stored in the index on the first run, so
+/// later runs and re-indexing apply the same filter.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TxTypeFilter {
+    /// All types when empty
+    pub include: Vec<TxType>,
+    pub exclude: Vec<TxType>,
+}
+
+impl TxTypeFilter {
+    /// From tx type display strings, which must be known types
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        let parse = |types: &[String]| {
+            types
+                .iter()
+                .map(|x| match TxType::from_display(x) {
+                    TxType::Other(_) => Err(Error::from(format!("unknown tx type: {}", x))),
+                    t => Ok(t),
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Self {
+            include: parse(include)?,
+            exclude: parse(exclude)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    pub fn allows(&self, tx_type: &TxType) -> bool {
+        (self.include.is_empty() || self.include.contains(tx_type))
+            && !self.exclude.contains(tx_type)
+    }
+
+    /// The filter the index was built with, empty if it was built
+    /// without one
+    pub fn load(sql_store: &SqliteBlockStore) -> Result<Self> {
+        match sql_store.get_network_info(TX_TYPE_FILTER_KEY)? {
+            Some(x) => Ok(serde_json::from_str(&x)?),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Check against the filter stored in the index. An empty filter
+    /// takes the stored one, and a filter on a new index is stored.
+    pub fn resolve(self, sql_store: &SqliteBlockStore) -> Result<Self> {
+        let stored = Self::load(sql_store)?;
+        if self.is_empty() || self == stored {
+            return Ok(stored);
+        }
+        if !stored.is_empty() || sql_store.max_block_height()?.is_some() {
+            return Err(Error::from(format!(
+                "tx type filter mismatch: stored={}, current={}",
+                serde_json::to_string(&stored)?,
+                serde_json::to_string(&self)?
+            )));
+        }
+        sql_store.set_network_info(TX_TYPE_FILTER_KEY, &serde_json::to_string(&self)?)?;
+        Ok(self)
+    }
+}
+
+/// A tx skipped on error recovery
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct TxError {
+    pub height: i64,
+    pub txid: String,
+    pub error_msg: String,
+}
+
+/// What was indexed, or would have been on a dry run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexCounts {
+    pub blocks: u64,
+    pub txs: u64,
+    /// Tx ins resolved to their parent tx
+    pub tx_ins: u64,
+    pub tx_errors: Vec<TxError>,
+}
+
+impl std::ops::AddAssign for IndexCounts {
+    fn add_assign(&mut self, rhs: Self) {
+        self.blocks += rhs.blocks;
+        self.txs += rhs.txs;
+        self.tx_ins += rhs.tx_ins;
+        self.tx_errors.extend(rhs.tx_errors);
+    }
+}
+
+impl IndexCounts {
+    pub fn print_dry_run_summary(&self) {
+        println!("dry run, nothing written");
+        println!("blocks: {}", self.blocks);
+        println!("txs: {}", self.txs);
+        println!("tx ins resolved: {}", self.tx_ins);
+        if !self.tx_errors.is_empty() {
+            println!("txs skipped on error: {}", self.tx_errors.len());
+        }
+    }
+
+    /// Write the skipped txs to `path` as jsonl, if there are any
+    pub fn write_tx_errors(&self, path: &str) -> Result<()> {
+        if self.tx_errors.is_empty() {
+            return Ok(());
+        }
+        let f = std::fs::File::create(path)?;
+        let mut w = std::io::BufWriter::new(f);
+        for e in self.tx_errors.iter() {
+            serde_json::to_writer(&mut w, e)?;
+            writeln!(w)?;
+        }
+        w.flush()?;
+        warn!(
+            "{} txs skipped on error, written to {}",
+            self.tx_errors.len(),
+            path
+        );
+        Ok(())
+    }
 }
 
 pub fn run(args: &CliIndexArgs) -> Result<()> {
@@ -53,15 +267,32 @@ pub fn run(args: &CliIndexArgs) -> Result<()> {
         true => None,
         false => Some(args.defid_log_path.as_str()),
     };
-    let enable_addr_graph = args.enable_graph_table;
-    let start_height = args.start_height;
+    let tx_filter = TxTypeFilter::new(&args.include_tx_types, &args.exclude_tx_types)?;
+    if args.resume && args.start_height.is_some() {
+        return Err("cannot specify both --resume and --start-height".into());
+    }
     let end_height = args.end_height;
 
     info!("{:?}", args);
 
+    db::validate_sqlite_path(
+        db_path.unwrap_or(db::DEFAULT_SQLITE_PATH),
+        db::PathMode::Create,
+    )?;
+
     let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
 
+    let tokens = TokenResolver::from_path(args.token_map_path.as_deref())?;
+    if let Some(path) = &args.token_map_path {
+        info!("token map: {} ({} tokens)", path, tokens.len());
+    }
+
+    let metrics = std::sync::Arc::new(IndexerMetrics::default());
+    if let Some(port) = args.metrics_port {
+        metrics::serve(args.metrics_addr, port, std::sync::Arc::clone(&metrics))?;
+    }
+
     let mut log_entry_map = LogEntryMap::new();
 
     if let Some(defid_log_path) = defid_log_path {
@@ -72,6 +303,8 @@ pub fn run(args: &CliIndexArgs) -> Result<()> {
             args.log_icx_matcher.as_str(),
             args.log_icx_calc_matcher.as_str(),
             args.log_swap_matcher.as_str(),
+            None,
+            None,
             &mut log_entry_map,
         )?;
 
@@ -80,234 +313,245 @@ pub fn run(args: &CliIndexArgs) -> Result<()> {
             \tTotal transactions:     {}\n\
             \tTotal ICX entries:      {}\n\
             \tTotal ICX calc entries: {}\n\
-            \tTotal Swap entries:     {}",
+            \tTotal Swap entries:     {}\n\
+            \tTotal lines:            {}\n\
+            \tSkipped lines:          {}\n\
+            \tUpdated entries:        {}",
             log_entry_map.data.len(),
             log_entry_map.icx_count,
             log_entry_map.icx_calc_count,
             log_entry_map.swap_count,
+            log_entry_map.total_lines,
+            log_entry_map.skipped_lines,
+            log_entry_map.update_count,
         );
     }
 
-    let mut cli = CliDriver::with_cli_path(args.defi_cli_path.clone());
-    let sql_store = SqliteBlockStore::new_v2(db_path)?;
-
-    let chain_height = cli.get_block_count()?;
-    let iter_end_height = if chain_height < end_height {
-        chain_height
+    let rpc_timeout = args.rpc_timeout.map(std::time::Duration::from_secs);
+    let cli = match &args.rpc_url {
+        Some(url) => {
+            info!("using rpc: {}", url);
+            CliDriverPool::new_rpc(
+                url,
+                args.rpc_user.as_deref(),
+                args.rpc_pass.as_ref().map(|x| x.expose()),
+                args.max_concurrent_cli,
+                rpc_timeout,
+            )
+        }
+        None => CliDriverPool::new(&args.defi_cli_path, args.max_concurrent_cli, rpc_timeout),
+    };
+    let sql_store = if args.dry_run {
+        SqliteBlockStore::open_dry_run(
+            db_path.unwrap_or(db::DEFAULT_SQLITE_PATH),
+            &args.sqlite_pragmas,
+        )?
     } else {
-        end_height
+        SqliteBlockStore::open_or_create_with(
+            db_path.unwrap_or(db::DEFAULT_SQLITE_PATH),
+            &args.sqlite_pragmas,
+        )?
     };
+    info!("db: {} ({} bytes)", sql_store, sql_store.db_size_bytes()?);
+    debug!("{:?}", sql_store);
 
-    let sconn = &sql_store.conn;
-    for (name, _) in sqlite_create_index_factory_v2(sconn) {
-        if quit.load(std::sync::atomic::Ordering::Relaxed) {
-            info!("int: early exit indexes");
-            break;
-        }
-        info!("drop index: {}..", name);
-        let q = format!("DROP INDEX IF EXISTS {}", name);
-        sconn.execute(&q, [])?;
+    // Also covers the network info stored on the first run
+    if args.dry_run {
+        info!("dry run, all writes are rolled back");
+        sql_store.begin_tx()?;
     }
 
-    let mut stmts = sqlite_get_stmts_v2(sconn)?;
-    sqlite_begin_tx(sconn)?;
+    if args.skip_network_check {
+        info!("skipping network check");
+    } else {
+        check_network(&cli, &sql_store)?;
+    }
 
-    let mut err = Option::None;
-    for height in start_height..=iter_end_height {
-        if quit.load(std::sync::atomic::Ordering::Relaxed) {
-            info!("int: early exit");
-            break;
+    let tx_filter = tx_filter.resolve(&sql_store)?;
+    if !tx_filter.is_empty() {
+        info!("tx type filter: {:?}", tx_filter);
+    }
+    let opts = InsertOpts {
+        enable_addr_graph: args.enable_graph_table,
+        error_recovery: args.error_recovery,
+        tx_filter: Some(&tx_filter),
+    };
+
+    let start_height = if args.resume {
+        let h = sql_store.resume_height()?;
+        info!("resuming from height {}", h);
+        h
+    } else {
+        args.start_height.unwrap_or(0)
+    };
+
+    let chain_height = cli.get_block_count()?;
+    if args.resume && start_height > chain_height {
+        info!("index is up to date at height {}", chain_height);
+        return Ok(());
+    }
+    let iter_end_height = if args.height_range_validate {
+        let end = validate_height_range(start_height, end_height, chain_height)?;
+        if !args.dry_run {
+            let path = db_path.unwrap_or(db::DEFAULT_SQLITE_PATH);
+            db::validate_sqlite_path(path, db::PathMode::Write)?;
+            check_range_disk_space(path, &sql_store, start_height, end)?;
         }
+        end
+    } else {
+        chain_height.min(end_height)
+    };
 
-        // May be abstract this out to a fn so error control is better. For now, handle cli errors
-        // Reason: Ctrl + C will send SIGHUP to the child process and that'll exit with err
-        // returning upward instead of breaking on the loop and flushing. This is a workaround.
-        let hash = match cli.get_block_hash(height) {
-            Ok(hash) => hash,
-            Err(e) => {
-                err = Some(e);
+    // Dropping indexes speeds up bulk inserts, but rebuilding them at the
+    // end is expensive. On an empty db there's nothing to drop, and when
+    // appending close to the tip, keeping them is cheaper.
+    let drop_indexes = !args.dry_run
+        && (args.always_drop_indexes
+            || match sql_store.max_block_height()? {
+                None => false,
+                Some(last) => i64::from(start_height) <= last - 1000,
+            });
+    if drop_indexes {
+        for (name, _) in sql_store.index_factory() {
+            if quit.load(std::sync::atomic::Ordering::Relaxed) {
+                info!("int: early exit indexes");
                 break;
             }
-        };
-        let block_out = match cli.get_block(&hash, Some(4)) {
-            Ok(block) => block,
-            Err(e) => {
-                err = Some(e);
-                break;
-            }
-        };
-        let block_json_str = block_out.str()?;
-        let block: Block = block_out.json()?;
-
-        debug!("[{}] hash: {}", height, &hash);
-        {
-            stmts[0].execute(rusqlite::params![height, &hash, block_json_str])?;
+            info!("drop index: {}..", name);
+            sql_store.drop_index(name)?;
         }
+    } else {
+        info!("keeping existing indexes");
+    }
 
-        for tx in block.tx {
-            let tx_in_addrs = dfiutils::get_txin_addr_val_list(&tx.vin, &sql_store)?;
-            let tx_out_addrs = dfiutils::get_txout_addr_val_list(&tx, &tx.vout);
+    #[cfg(unix)]
+    serve_progress_signal(
+        std::sync::Arc::clone(&metrics),
+        db_path.unwrap_or(db::DEFAULT_SQLITE_PATH).to_string(),
+        start_height,
+        iter_end_height,
+    )?;
 
-            let tx_in_addrs = dfiutils::fold_addr_val_map(&tx_in_addrs);
-            let tx_out = dfiutils::fold_addr_val_map(&tx_out_addrs)
-                .into_iter()
-                .filter(|x| *x.0 != *"x") // strip coinbase out
-                .collect::<HashMap<_, _>>();
+    let tx_cache = LruBlockStore::new(&sql_store, args.tx_cache_size);
+    let mut batch = BatchCommit::new(&sql_store, args.batch_size, args.batch_size_bytes)?;
+    if !args.dry_run {
+        sql_store.begin_tx()?;
+    }
 
-            let mut tx_type = tx.vm.as_ref().map(|x| TxType::from(&*x.txtype));
-            let mut dvm_addrs = HashSet::new();
+    let mut counts = IndexCounts::default();
 
-            if tx_in_addrs.is_empty() {
-                tx_type = Some(TxType::Coinbase);
-            }
-
-            if !matches!(
-                &tx_type,
-                Some(TxType::Coinbase) | Some(TxType::Unknown) | Some(TxType::Utxo) | None
-            ) {
-                let dvm_data = tx.vm.as_ref().map(|x| x.msg.to_string()).unwrap();
-                dvm_addrs = extract_all_dfi_addresses(&dvm_data);
-            }
-            let mut icx_claim_data: Option<IcxTxSet> = None;
-            let mut icx_addr = empty();
-            let mut icx_amt = empty();
-            let mut swap_from = empty();
-            let mut swap_to = empty();
-            let mut swap_amt = empty();
-
-            match tx_type {
-                Some(TxType::PoolSwap) | Some(TxType::CompositeSwap) => {
-                    let swap_data = &tx.vm.as_ref().ok_or_err()?.msg;
-                    let swap_data: models::PoolSwapMsg = serde_json::from_value(swap_data.clone())?;
-                    swap_from = token_id_to_symbol_maybe(&swap_data.from_token).to_string();
-                    swap_to = token_id_to_symbol_maybe(&swap_data.to_token).to_string();
-                    swap_amt = format!("{:.9}", &swap_data.from_amount);
-                }
-                Some(TxType::ICXClaimDFCHTLC) => {
-                    if let Some(log_entry) = &log_entry_map.data.get(&tx.txid) {
-                        if let Some(icx_data) = &log_entry.icx_data {
-                            icx_claim_data = Some(IcxTxSet {
-                                order_tx: icx_data.order_tx.clone(),
-                                claim_tx: icx_data.claim_tx.clone(),
-                                offer_tx: icx_data.offer_tx.clone(),
-                                dfchtlc_tx: icx_data.dfchtlc_tx.clone(),
-                            });
-                            icx_addr = icx_data.address.to_string();
-                            icx_amt = icx_data.amount.to_string();
+    let mut err = Option::None;
+    let fetch_ahead = args.fetch_ahead.max(1);
+    std::thread::scope(|s| {
+        // Fetchers take every fetch_ahead-th height each, so the blocks can
+        // be received back in order by going round the receivers. Only the
+        // fetch runs on them, the db is only written from this thread.
+        let receivers = (0..fetch_ahead)
+            .map(|i| {
+                let (tx, rx) = std::sync::mpsc::sync_channel(1);
+                let (cli, quit) = (&cli, &quit);
+                s.spawn(move || {
+                    let heights = (start_height + i as u32..=iter_end_height).step_by(fetch_ahead);
+                    for height in heights {
+                        if quit.load(std::sync::atomic::Ordering::Relaxed) {
+                            break;
+                        }
+                        let r = fetch_block(cli, height);
+                        let failed = r.is_err();
+                        // Send only fails once the receiver is gone on early exit
+                        if tx.send(r).is_err() || failed {
+                            break;
                         }
                     }
-                }
-                _ => {}
-            }
+                });
+                rx
+            })
+            .collect::<Vec<_>>();
 
-            let (dvm_in_addrs, _): (Vec<_>, Vec<_>) = dvm_addrs
-                .iter()
-                .cloned()
-                .partition(|addr| tx_in_addrs.iter().any(|(in_addr, _)| *in_addr == *addr));
-
-            if enable_addr_graph {
-                // DVM addresses are parsed for all matching addresses inside the
-                // DVM data. There is no clean in and out: this requires specific
-                // knowledge of each message and there's no clear convention of this.
-                // So instead, we workaround this as we know that if tx in and dvm addr
-                // is the same, they were _likely_ source.
-                // We partition these out first. For out, we take the whole list
-                // to err on the side of caution to add more edges.
-
-                let mut changeset = HashMap::<[Rc<str>; 2], i64>::new();
-
-                for (out_addr, _) in tx_out.iter() {
-                    for (in_addr, _) in tx_in_addrs.iter() {
-                        let k = [in_addr.clone(), (*out_addr).clone()];
-                        changeset.insert(k, 0);
-                    }
+        sql_store.with_stmts(|stmts| {
+            for height in start_height..=iter_end_height {
+                if quit.load(std::sync::atomic::Ordering::Relaxed) {
+                    info!("int: early exit");
+                    break;
                 }
+                let _block_span = info_span!("block", height).entered();
 
-                for out_addr in dvm_addrs.iter() {
-                    for in_addr in dvm_in_addrs.iter() {
-                        let k = [in_addr.clone(), out_addr.clone()];
-                        let v = changeset.get_mut(&k);
-                        if let Some(v) = v {
-                            // we set to DVM + UTXO
-                            if *v == 0 {
-                                *v = 2;
-                            }
-                        } else {
-                            // we set this with DVM only
-                            changeset.insert(k, 1);
-                        }
+                // May be abstract this out to a fn so error control is better. For now, handle cli errors
+                // Reason: Ctrl + C will send SIGHUP to the child process and that'll exit with err
+                // returning upward instead of breaking on the loop and flushing. This is a workaround.
+                let rx = &receivers[(height - start_height) as usize % fetch_ahead];
+                let block_out = match info_span!("fetch_wait").in_scope(|| rx.recv()) {
+                    Ok(Ok(x)) => x,
+                    Ok(Err(e)) => {
+                        metrics.add_error(ErrorKind::Fetch);
+                        err = Some(e);
+                        break;
                     }
+                    // The fetcher stopped on the quit flag before sending
+                    Err(_) if quit.load(std::sync::atomic::Ordering::Relaxed) => {
+                        info!("int: early exit");
+                        break;
+                    }
+                    Err(_) => {
+                        err = Some("block fetcher stopped".into());
+                        break;
+                    }
+                };
+                let block_json_str = metrics.track(ErrorKind::Parse, block_out.str())?;
+                let block: Block = metrics.track(ErrorKind::Parse, block_out.json())?;
+
+                let block_counts = metrics.track(
+                    ErrorKind::Insert,
+                    insert_block(
+                        stmts,
+                        &tx_cache,
+                        &log_entry_map,
+                        &tokens,
+                        block_json_str,
+                        block,
+                        opts,
+                    ),
+                )?;
+                metrics.add_block(height, block_counts.txs);
+                for _ in block_counts.tx_errors.iter() {
+                    metrics.add_error(ErrorKind::Insert);
                 }
+                counts += block_counts;
 
-                for ([edge_in, edge_out], c_flags) in &changeset {
-                    stmts[2].execute(rusqlite::params![&tx.txid, &edge_in, &edge_out, c_flags])?;
+                if !args.dry_run && batch.is_due(&sql_store, height.into())? {
+                    metrics.time_commit(|| sql_store.commit_and_begin_tx())?;
+                }
+                if height % 10000 == 0 {
+                    info!("processed: [{}] / [{}]", height, end_height);
                 }
             }
+            Ok(())
+        })
+    })?;
 
-            // Transform to final strings. Mostly empty strings for non relevant fields
-
-            let tx_type_str = tx_type.clone().unwrap_or(TxType::Unknown).to_string();
-            let dvm_in_addrs_json = if dvm_in_addrs.is_empty() {
-                empty()
-            } else {
-                serde_json::to_string(&dvm_in_addrs)?
-            };
-            let dvm_addrs_json = if dvm_addrs.is_empty() {
-                empty()
-            } else {
-                serde_json::to_string(&dvm_addrs)?
-            };
-            let tx_in_json = if tx_in_addrs.is_empty() {
-                empty()
-            } else {
-                serde_json::to_string(&tx_in_addrs)?
-            };
-            let tx_out_json = if tx_out.is_empty() {
-                empty()
-            } else {
-                serde_json::to_string(&tx_out)?
-            };
-            let tx_json = serde_json::to_string(&tx)?;
-            let icx_claim_data = if icx_claim_data.is_none() {
-                empty()
-            } else {
-                serde_json::to_string(&icx_claim_data.unwrap())?
-            };
-
-            stmts[1].execute(rusqlite::params![
-                &tx.txid,
-                height,
-                &tx_type_str,
-                &tx_in_json,
-                &tx_out_json,
-                &dvm_in_addrs_json,
-                &dvm_addrs_json,
-                &tx_json,
-                &icx_claim_data,
-                &icx_addr,
-                &icx_amt,
-                &swap_from,
-                &swap_to,
-                &swap_amt,
-            ])?;
-        }
+    let (hits, misses) = tx_cache.stats();
+    info!("tx cache: {} hits, {} misses", hits, misses);
+    counts.write_tx_errors(&args.error_log_path)?;
 
-        if height % 10000 == 0 {
-            sqlite_commit_and_begin_tx(sconn)?;
-            info!("processed: [{}] / [{}]", height, end_height);
-        }
+    if args.dry_run {
+        sql_store.rollback_tx()?;
+        counts.print_dry_run_summary();
+        return match err {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
     }
 
     info!("flushing db");
-    sqlite_commit_tx(sconn)?;
+    metrics.time_commit(|| sql_store.commit_tx())?;
 
-    for (name, indexer) in sqlite_create_index_factory_v2(sconn) {
+    for (name, indexer) in sql_store.index_factory() {
         if quit.load(std::sync::atomic::Ordering::Relaxed) {
             info!("int: early exit indexes");
             break;
         }
         info!("creating index: {}..", name);
-        indexer()?;
+        info_span!("index_create", name).in_scope(&indexer)?;
     }
 
     if let Some(e) = err {
@@ -318,7 +562,670 @@ pub fn run(args: &CliIndexArgs) -> Result<()> {
     Ok(())
 }
 
+/// Log a progress snapshot on every SIGUSR1, from a thread that lives as
+/// long as the process. Reads the counters the indexing loop already keeps
+/// for the metrics, and the db size off the files, so it never touches the
+/// connection.
+#[cfg(unix)]
+fn serve_progress_signal(
+    metrics: std::sync::Arc<IndexerMetrics>,
+    db_path: String,
+    start_height: u32,
+    end_height: u32,
+) -> Result<()> {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGUSR1])?;
+    let started = std::time::Instant::now();
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            let db_bytes = [db_path.clone(), format!("{}-wal", db_path)]
+                .iter()
+                .filter_map(|x| std::fs::metadata(x).ok())
+                .map(|x| x.len())
+                .sum();
+            let blocks = metrics.blocks_processed();
+            let progress = Progress {
+                height: (blocks > 0).then(|| metrics.current_height()),
+                blocks,
+                total_blocks: u64::from(end_height.saturating_sub(start_height)) + 1,
+                txs: metrics.txs_processed(),
+                elapsed: started.elapsed(),
+                db_bytes,
+            };
+            info!("sig received: {}", progress);
+        }
+    });
+    Ok(())
+}
+
+/// Snapshot of an indexing run, logged on SIGUSR1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    /// Last indexed height, none before the first block
+    pub height: Option<u64>,
+    pub blocks: u64,
+    pub total_blocks: u64,
+    pub txs: u64,
+    pub elapsed: std::time::Duration,
+    pub db_bytes: u64,
+}
+
+impl Progress {
+    /// At the average rate so far, none until a block is in
+    pub fn remaining(&self) -> Option<std::time::Duration> {
+        if self.blocks == 0 {
+            return None;
+        }
+        let left = self.total_blocks.saturating_sub(self.blocks);
+        Some(self.elapsed.mul_f64(left as f64 / self.blocks as f64))
+    }
+}
+
+impl std::fmt::Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let secs = self.elapsed.as_secs_f64();
+        write!(
+            f,
+            "height: {}, {} / {} blocks ({:.2}%), {:.1} blocks/s, elapsed: {}s, remaining: {}, txs: {}, db: {} bytes",
+            self.height.map_or("-".to_string(), |x| x.to_string()),
+            self.blocks,
+            self.total_blocks,
+            self.blocks as f64 * 100. / self.total_blocks as f64,
+            if secs > 0. { self.blocks as f64 / secs } else { 0. },
+            self.elapsed.as_secs(),
+            self.remaining()
+                .map_or("-".to_string(), |x| format!("{}s", x.as_secs())),
+            self.txs,
+            self.db_bytes,
+        )
+    }
+}
+
+/// Insert `block` and its txs, resolving the tx ins through `block_store`.
+/// `block_json_str` is the raw block as stored. The caller owns the db
+/// transaction. On error recovery, a failed tx is skipped, though the
+/// graph edges it wrote before failing are kept.
+pub fn insert_block(
+    stmts: &mut [CachedStatement<'_>],
+    block_store: &impl BlockStore,
+    log_entry_map: &LogEntryMap,
+    tokens: &TokenResolver,
+    block_json_str: &str,
+    block: Block,
+    opts: InsertOpts<'_>,
+) -> Result<IndexCounts> {
+    let (height, hash) = (block.height, block.hash.clone());
+    let mut counts = IndexCounts {
+        blocks: 1,
+        ..Default::default()
+    };
+    debug!("[{}] hash: {}", height, &hash);
+    {
+        stmts[0].execute(rusqlite::params![
+            height,
+            &hash,
+            block_json_str,
+            block.minter.id.as_ref(),
+            block.minter.operator.as_deref(),
+        ])?;
+    }
+
+    for tx in block.tx {
+        let r = insert_tx(stmts, block_store, log_entry_map, tokens, height, &tx, opts)
+            .map_err(|e| Error::with_context(height.into(), &tx.txid, e));
+        let tx_ins = match r {
+            Ok(x) => x,
+            Err(e) if opts.error_recovery => {
+                warn!("skip tx: {}", e);
+                counts.tx_errors.push(TxError {
+                    height: height.into(),
+                    txid: tx.txid.to_string(),
+                    error_msg: e.to_string(),
+                });
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        counts.txs += 1;
+        counts.tx_ins += tx_ins;
+    }
+    Ok(counts)
+}
+
+/// Insert `tx` of the block at `height`. Returns the number of tx ins
+/// resolved.
+fn insert_tx(
+    stmts: &mut [CachedStatement<'_>],
+    block_store: &impl BlockStore,
+    log_entry_map: &LogEntryMap,
+    tokens: &TokenResolver,
+    height: u32,
+    tx: &Transaction,
+    opts: InsertOpts<'_>,
+) -> Result<u64> {
+    let tx_in_addrs = info_span!("txin_resolve")
+        .in_scope(|| dfiutils::get_txin_addr_val_list(&tx.vin, block_store))?;
+    let tx_ins = tx_in_addrs.len() as u64;
+    let tx_out_addrs = dfiutils::get_txout_addr_val_list(tx, &tx.vout);
+
+    let tx_in_addrs = dfiutils::fold_addr_val_map(&tx_in_addrs);
+    // Coinbases have no ins, so their fee clamps to 0. The outs without
+    // an address still count towards the total.
+    let fee = dfiutils::round_to_satoshi(
+        tx_in_addrs.values().sum::<f64>() - tx_out_addrs.iter().map(|x| x.1).sum::<f64>(),
+    )
+    .max(0.0);
+    let tx_out = dfiutils::fold_addr_val_map(&tx_out_addrs)
+        .into_iter()
+        .filter(|x| *x.0 != *"x") // strip coinbase out
+        .collect::<HashMap<_, _>>();
+
+    let mut tx_type = tx.vm.as_ref().map(TxType::from_vm);
+    let mut dvm_addrs = HashSet::new();
+
+    if tx_in_addrs.is_empty() {
+        tx_type = Some(TxType::Coinbase);
+    }
+
+    if !matches!(
+        &tx_type,
+        Some(TxType::Coinbase) | Some(TxType::Unknown) | Some(TxType::Utxo) | None
+    ) {
+        let dvm_data = tx.vm.as_ref().map(|x| x.msg.to_string()).unwrap();
+        dvm_addrs = extract_all_dfi_addresses(&dvm_data);
+    }
+    let mut icx_claim_data: Option<IcxTxSet> = None;
+    let mut icx_addr = empty();
+    let mut icx_btc_amt = empty();
+    let mut icx_dfc_amt = empty();
+    let mut swap_from = empty();
+    let mut swap_to = empty();
+    let mut swap_amt = empty();
+
+    match tx_type {
+        Some(TxType::PoolSwap) | Some(TxType::CompositeSwap) => {
+            let swap_data = &tx.vm.as_ref().ok_or_err()?.msg;
+            let swap_data: models::PoolSwapMsg = serde_json::from_value(swap_data.clone())?;
+            swap_from = tokens.symbol(&swap_data.from_token).to_string();
+            swap_to = tokens.symbol(&swap_data.to_token).to_string();
+            swap_amt = format!("{:.9}", swap_data.from_amount);
+        }
+        Some(TxType::FutureSwap) => {
+            let msg = &tx.vm.as_ref().ok_or_err()?.msg;
+            let msg: models::FutureSwapMsg = serde_json::from_value(msg.clone())?;
+            let (amount, token) = msg
+                .source_amount()
+                .ok_or_with(|| format!("future swap source: {}", msg.source))?;
+            swap_from = tokens.symbol(token).to_string();
+            swap_to = tokens.symbol(&msg.destination).to_string();
+            swap_amt = format!("{:.9}", amount);
+        }
+        Some(TxType::ICXClaimDFCHTLC) => {
+            if let Some(log_entry) = log_entry_map.get_entry(&tx.txid) {
+                if let Some(icx_data) = &log_entry.icx_data {
+                    icx_claim_data = Some(IcxTxSet {
+                        order_tx: icx_data.order_tx.clone(),
+                        claim_tx: icx_data.claim_tx.clone(),
+                        offer_tx: icx_data.offer_tx.clone(),
+                        dfchtlc_tx: icx_data.dfchtlc_tx.clone(),
+                    });
+                    icx_addr = icx_data.address.to_string();
+                    icx_btc_amt = icx_data.amount.to_string();
+                    if let Some(calc_data) = log_entry_map
+                        .get_entry(&icx_data.offer_tx)
+                        .and_then(|x| x.icx_calc_data.as_ref())
+                    {
+                        icx_dfc_amt = calc_data.dfc_amount().unwrap_or_default();
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+
+    let (dvm_in_addrs, _): (Vec<_>, Vec<_>) = dvm_addrs
+        .iter()
+        .cloned()
+        .partition(|addr| tx_in_addrs.iter().any(|(in_addr, _)| *in_addr == *addr));
+
+    if opts.enable_addr_graph {
+        // DVM addresses are parsed for all matching addresses inside the
+        // DVM data. There is no clean in and out: this requires specific
+        // knowledge of each message and there's no clear convention of this.
+        // So instead, we workaround this as we know that if tx in and dvm addr
+        // is the same, they were _likely_ source.
+        // We partition these out first. For out, we take the whole list
+        // to err on the side of caution to add more edges.
+
+        let mut changeset = HashMap::<[Rc<str>; 2], i64>::new();
+
+        for out_addr in tx_out.keys() {
+            for in_addr in tx_in_addrs.keys() {
+                let k = [in_addr.clone(), (*out_addr).clone()];
+                changeset.insert(k, 0);
+            }
+        }
+
+        for out_addr in dvm_addrs.iter() {
+            for in_addr in dvm_in_addrs.iter() {
+                let k = [in_addr.clone(), out_addr.clone()];
+                let v = changeset.get_mut(&k);
+                if let Some(v) = v {
+                    // we set to DVM + UTXO
+                    if *v == 0 {
+                        *v = 2;
+                    }
+                } else {
+                    // we set this with DVM only
+                    changeset.insert(k, 1);
+                }
+            }
+        }
+
+        for ([edge_in, edge_out], c_flags) in &changeset {
+            stmts[2].execute(rusqlite::params![&tx.txid, &edge_in, &edge_out, c_flags])?;
+        }
+    }
+
+    // Transform to final strings. Mostly empty strings for non relevant fields
+
+    let tx_type = tx_type.unwrap_or(TxType::Unknown);
+    let tx_type_str = tx_type.to_string();
+    let dvm_in_addrs_json = if dvm_in_addrs.is_empty() {
+        empty()
+    } else {
+        serde_json::to_string(&dvm_in_addrs)?
+    };
+    let dvm_addrs_json = if dvm_addrs.is_empty() {
+        empty()
+    } else {
+        serde_json::to_string(&dvm_addrs)?
+    };
+    let tx_in_json = if tx_in_addrs.is_empty() {
+        empty()
+    } else {
+        serde_json::to_string(&tx_in_addrs)?
+    };
+    let tx_out_json = if tx_out.is_empty() {
+        empty()
+    } else {
+        serde_json::to_string(&tx_out)?
+    };
+    let tx_json = serde_json::to_string(tx)?;
+    let icx_claim_data = match &icx_claim_data {
+        Some(icx) => serde_json::to_string(icx)?,
+        None => empty(),
+    };
+
+    let _db_write_span = info_span!("db_write").entered();
+    if opts.tx_filter.is_some_and(|f| !f.allows(&tx_type)) {
+        // Filtered out txs keep their outs, so the txs spending them can
+        // still be resolved.
+        let stub = Transaction {
+            txid: tx.txid.clone(),
+            vout: tx.vout.clone(),
+            ..Default::default()
+        };
+        stmts[1].execute(rusqlite::params![
+            &tx.txid,
+            height,
+            &tx_type_str,
+            "",
+            "",
+            "",
+            "",
+            serde_json::to_string(&stub)?,
+            "",
+            "",
+            "",
+            "",
+            "",
+            "",
+            "",
+            0.0,
+        ])?;
+        return Ok(tx_ins);
+    }
+    stmts[1].execute(rusqlite::params![
+        &tx.txid,
+        height,
+        &tx_type_str,
+        &tx_in_json,
+        &tx_out_json,
+        &dvm_in_addrs_json,
+        &dvm_addrs_json,
+        &tx_json,
+        &icx_claim_data,
+        &icx_addr,
+        &icx_btc_amt,
+        &swap_from,
+        &swap_to,
+        &swap_amt,
+        &icx_dfc_amt,
+        fee,
+    ])?;
+    // Coinbases have no graph edges, so their outs are kept on their own
+    if tx_type == TxType::Coinbase {
+        for out_addr in tx_out.keys() {
+            stmts[3].execute(rusqlite::params![out_addr, height, &tx.txid])?;
+        }
+    }
+    Ok(tx_ins)
+}
+
+/// Txs per block assumed for the disk space estimate, until the index has
+/// blocks of its own to average over
+const EST_TXS_PER_BLOCK: u64 = 5;
+
+/// Pre-flight check of the range to index against the chain height.
+/// Errors on a range that would index nothing, and returns the end height
+/// clamped to the chain height.
+pub fn validate_height_range(start: u32, end: u32, chain_height: u32) -> Result<u32> {
+    if end < start {
+        return Err(Error::from(format!(
+            "invalid height range: end height {} is below start height {}",
+            end, start
+        )));
+    }
+    if start > chain_height {
+        return Err(Error::from(format!(
+            "invalid height range: start height {} is past the chain height {}",
+            start, chain_height
+        )));
+    }
+    if end > chain_height {
+        warn!(
+            "end height {} is past the chain height, indexing up to {}",
+            end, chain_height
+        );
+        return Ok(chain_height);
+    }
+    Ok(end)
+}
+
+/// Check there's disk space at `db_path` for indexing `start..=end`, with
+/// the tx count estimated from the average of `avg_store`.
+pub fn check_range_disk_space(
+    db_path: &str,
+    avg_store: &SqliteBlockStore,
+    start: u32,
+    end: u32,
+) -> Result<()> {
+    let per_block = avg_store.avg_txs_per_block()?.unwrap_or(EST_TXS_PER_BLOCK);
+    db::check_disk_space(db_path, u64::from(end - start + 1) * per_block)
+}
+
+pub fn fetch_block(cli: &CliDriverPool, height: u32) -> Result<OutputExt> {
+    let hash = info_span!("rpc").in_scope(|| cli.get_block_hash(height.into()))?;
+    info_span!("rpc").in_scope(|| cli.get_block(&hash, Some(4)))
+}
+
+const MAINNET_GENESIS_HASH: &str =
+    "279b1a87aedc7b9471d4ad4e5f12967ab6259926cd097ade188dfcf22ebfe72a";
+const TESTNET_GENESIS_HASH: &str =
+    "034ac8c88a1a9b846750768c1ad6f295bc4d0dc4b9b418aee5c0ebd609be8f90";
+
+// Make sure the node is on the chain it says it is, and on the same chain
+// the index was built from. The chain is stored on the first run.
+pub fn check_network(cli: &CliDriverPool, sql_store: &SqliteBlockStore) -> Result<()> {
+    let info: models::BlockchainInfo = cli.get_blockchain_info()?.json()?;
+    if info.networkactive == Some(false) {
+        warn!("node network is not active");
+    }
+
+    let genesis_hash = cli.get_block_hash(0)?;
+    let expected_genesis = match info.chain.as_str() {
+        "main" => Some(MAINNET_GENESIS_HASH),
+        "test" => Some(TESTNET_GENESIS_HASH),
+        _ => None,
+    };
+    if let Some(expected) = expected_genesis {
+        if &*genesis_hash != expected {
+            return Err(lang::Error::from(format!(
+                "genesis mismatch for {}: expected={}, current={}",
+                info.chain, expected, genesis_hash
+            )));
+        }
+    }
+
+    match sql_store.get_network_info("chain")? {
+        Some(stored) if stored != info.chain => {
+            return Err(lang::Error::from(format!(
+                "network mismatch: stored={}, current={}",
+                stored, info.chain
+            )));
+        }
+        Some(_) => {}
+        None => {
+            sql_store.set_network_info("chain", &info.chain)?;
+            sql_store.set_network_info("genesis_hash", &genesis_hash)?;
+        }
+    }
+    info!("network: {}", info.chain);
+    Ok(())
+}
+
 // Just a short convenience alias for internal use.
 fn empty() -> String {
     String::new()
 }
+
+#[test]
+fn test_insert_block_error_recovery() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    let vout = r#"[{"value": 1.0, "n": 0, "scriptPubKey": {"asm": "", "hex": "", "type": "",
+        "addresses": ["a"]}}]"#;
+    let tx = |txid: &str, vin: &str| {
+        format!(
+            r#"{{"txid": "{}", "hash": "", "version": 1, "size": 1, "vsize": 1, "weight": 1,
+            "locktime": 0, "vin": {}, "vout": {}, "hex": ""}}"#,
+            txid, vin, vout
+        )
+    };
+    let missing_in = r#"[{"txid": "p0", "vout": 0, "scriptSig": {"asm": ""}, "sequence": 0}]"#;
+    let coinbase_in = r#"[{"coinbase": "", "sequence": 0}]"#;
+    let block_json = format!(
+        r#"{{"hash": "h1", "height": 1, "confirmations": 1, "strippedsize": 1, "size": 1,
+        "weight": 1, "minter": {{"id": "m", "stakeModifier": "s"}}, "version": 1,
+        "versionHex": "1", "merkleroot": "r", "time": 0, "mediantime": 0, "bits": "b",
+        "difficulty": 1.0, "chainwork": "c", "tx": [{}, {}], "nTx": 2}}"#,
+        tx("t1", missing_in),
+        tx("t2", coinbase_in)
+    );
+    let insert = |error_recovery| {
+        store.with_stmts(|stmts| {
+            insert_block(
+                stmts,
+                &store,
+                &LogEntryMap::new(),
+                &TokenResolver::default(),
+                &block_json,
+                serde_json::from_str(&block_json).unwrap(),
+                InsertOpts {
+                    enable_addr_graph: true,
+                    error_recovery,
+                    ..Default::default()
+                },
+            )
+        })
+    };
+
+    let e = insert(false).unwrap_err();
+    assert_eq!(e.to_string(), "at height 1, txid t1: tx hash not found: p0");
+
+    let counts = insert(true).unwrap();
+    assert_eq!(counts.txs, 1);
+    assert_eq!(
+        counts.tx_errors,
+        [TxError {
+            height: 1,
+            txid: "t1".to_string(),
+            error_msg: "at height 1, txid t1: tx hash not found: p0".to_string(),
+        }]
+    );
+    assert!(store.get_tx_height("t2").unwrap().is_some());
+    assert!(store.get_tx_height("t1").unwrap().is_none());
+}
+
+#[test]
+fn test_insert_block_fee_and_tx_filter() {
+    let vout = |outs: &[(&str, f64)]| {
+        outs.iter()
+            .enumerate()
+            .map(|(i, (addr, value))| {
+                format!(
+                    r#"{{"value": {}, "n": {}, "scriptPubKey": {{"asm": "", "hex": "",
+                    "type": "", "addresses": ["{}"]}}}}"#,
+                    value, i, addr
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let tx = |txid: &str, vin: &str, outs: &[(&str, f64)]| {
+        format!(
+            r#"{{"txid": "{}", "hash": "", "version": 1, "size": 1, "vsize": 1, "weight": 1,
+            "locktime": 0, "vin": {}, "vout": [{}], "hex": ""}}"#,
+            txid,
+            vin,
+            vout(outs)
+        )
+    };
+    let block = |height: u32, txs: &[String]| {
+        format!(
+            r#"{{"hash": "h{}", "height": {}, "confirmations": 1, "strippedsize": 1, "size": 1,
+            "weight": 1, "minter": {{"id": "m", "stakeModifier": "s"}}, "version": 1,
+            "versionHex": "1", "merkleroot": "r", "time": 0, "mediantime": 0, "bits": "b",
+            "difficulty": 1.0, "chainwork": "c", "tx": [{}], "nTx": {}}}"#,
+            height,
+            height,
+            txs.join(","),
+            txs.len()
+        )
+    };
+    let coinbase_in = r#"[{"coinbase": "", "sequence": 0}]"#;
+    let spend_in = r#"[{"txid": "t0", "vout": 0, "scriptSig": {"asm": ""}, "sequence": 0}]"#;
+    let blocks = [
+        block(1, &[tx("t0", coinbase_in, &[("a", 5.0)])]),
+        block(2, &[tx("t1", spend_in, &[("b", 3.0), ("c", 1.5)])]),
+        block(
+            3,
+            &[
+                tx("t2", &spend_in.replace("t0", "t1"), &[("b", 2.9)]).replace(
+                    r#""hex": """#,
+                    r#""hex": "", "vm": {"vmtype": "dvm", "txtype": "DFIP2203",
+                "msg": {"owner": "b", "source": "1.50000000@15", "destination": "2"}}"#,
+                ),
+            ],
+        ),
+    ];
+    let index = |tx_filter: Option<&TxTypeFilter>| {
+        let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+        store
+            .with_stmts(|stmts| {
+                for block_json in blocks.iter() {
+                    insert_block(
+                        stmts,
+                        &store,
+                        &LogEntryMap::new(),
+                        &TokenResolver::default(),
+                        block_json,
+                        serde_json::from_str(block_json).unwrap(),
+                        InsertOpts {
+                            tx_filter,
+                            ..Default::default()
+                        },
+                    )?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        store
+    };
+
+    let store = index(None);
+    assert_eq!(store.get_tx_data("t0").unwrap().unwrap().fee, 0.0);
+    assert_eq!(store.get_tx_data("t1").unwrap().unwrap().fee, 0.5);
+    assert_eq!(store.get_address_coinbase_txs("a", 0, 3).unwrap(), ["t0"]);
+    assert!(store
+        .get_address_coinbase_txs("b", 0, 3)
+        .unwrap()
+        .is_empty());
+    let t2 = store.get_tx_data("t2").unwrap().unwrap();
+    assert_eq!(t2.tx_type, "fs");
+    assert_eq!(
+        (
+            t2.swap_from.as_str(),
+            t2.swap_to.as_str(),
+            t2.swap_amt.as_str()
+        ),
+        ("dusd", "btc", "1.500000000")
+    );
+
+    // The coinbase is a stub, that t1 still resolves its in from
+    let filter = TxTypeFilter::new(&[], &["cb".to_string()]).unwrap();
+    let store = index(Some(&filter));
+    let t0 = store.get_tx_data("t0").unwrap().unwrap();
+    assert_eq!(t0.tx_type, "cb");
+    assert!(t0.tx_out.is_empty());
+    assert_eq!(t0.data.vout.len(), 1);
+    let t1 = store.get_tx_data("t1").unwrap().unwrap();
+    assert_eq!(t1.tx_in["a"], 5.0);
+    assert_eq!(t1.fee, 0.5);
+    assert!(store
+        .get_address_coinbase_txs("a", 0, 3)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_progress() {
+    let mut p = Progress {
+        height: None,
+        blocks: 0,
+        total_blocks: 400,
+        txs: 0,
+        elapsed: std::time::Duration::from_secs(10),
+        db_bytes: 4096,
+    };
+    assert_eq!(p.remaining(), None);
+    assert_eq!(
+        p.to_string(),
+        "height: -, 0 / 400 blocks (0.00%), 0.0 blocks/s, elapsed: 10s, remaining: -, txs: 0, db: 4096 bytes"
+    );
+
+    p.height = Some(1099);
+    p.blocks = 100;
+    p.txs = 250;
+    assert_eq!(p.remaining(), Some(std::time::Duration::from_secs(30)));
+    assert_eq!(
+        p.to_string(),
+        "height: 1099, 100 / 400 blocks (25.00%), 10.0 blocks/s, elapsed: 10s, remaining: 30s, txs: 250, db: 4096 bytes"
+    );
+}
+
+#[test]
+fn test_tx_type_filter() {
+    let types = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        TxTypeFilter::new(&types(&["ps", "nope"]), &[])
+            .unwrap_err()
+            .to_string(),
+        "unknown tx type: nope"
+    );
+    let f = TxTypeFilter::new(&types(&["ps", "cs"]), &types(&["cs"])).unwrap();
+    assert!(f.allows(&TxType::PoolSwap));
+    assert!(!f.allows(&TxType::CompositeSwap));
+    assert!(!f.allows(&TxType::Utxo));
+    let f = TxTypeFilter::new(&[], &types(&["u"])).unwrap();
+    assert!(f.allows(&TxType::PoolSwap));
+    assert!(!f.allows(&TxType::Utxo));
+
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    assert!(TxTypeFilter::default().resolve(&store).unwrap().is_empty());
+    assert_eq!(f.clone().resolve(&store).unwrap(), f);
+    // Stored, so later runs without a filter pick it up
+    assert_eq!(TxTypeFilter::default().resolve(&store).unwrap(), f);
+    assert_eq!(TxTypeFilter::load(&store).unwrap(), f);
+    let other = TxTypeFilter::new(&types(&["ps"]), &[]).unwrap();
+    assert!(other.resolve(&store).is_err());
+}
@@ -0,0 +1,172 @@
+use crate::dfiutils::{CliDriver, NodeDriver, OutputExt, RpcDriver};
+use crate::lang::{Error, Result};
+use crate::models::TStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+// Every cli call spawns a new defi-cli process. Spawn rate can be limited
+// on some systems, so when fetching from multiple threads, the drivers are
+// pooled and the number of concurrent calls is capped by a semaphore.
+
+/// Counting semaphore, std doesn't ship one.
+#[derive(Debug)]
+pub struct Semaphore {
+    state: Mutex<SemaphoreState>,
+    cv: Condvar,
+    max: usize,
+}
+
+#[derive(Debug)]
+struct SemaphoreState {
+    permits: usize,
+    closed: bool,
+}
+
+pub struct SemaphorePermit<'a> {
+    sem: &'a Semaphore,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Mutex::new(SemaphoreState {
+                permits,
+                closed: false,
+            }),
+            cv: Condvar::new(),
+            max: permits,
+        }
+    }
+
+    /// Block until a permit is free. Errors once the semaphore is closed.
+    pub fn acquire(&self) -> Result<SemaphorePermit<'_>> {
+        let mut state = self.state.lock().map_err(|_| "semaphore poisoned")?;
+        loop {
+            if state.closed {
+                return Err(Error::from("semaphore closed"));
+            }
+            if state.permits > 0 {
+                state.permits -= 1;
+                return Ok(SemaphorePermit { sem: self });
+            }
+            state = self.cv.wait(state).map_err(|_| "semaphore poisoned")?;
+        }
+    }
+
+    /// Stop handing out permits and wait for all in-flight ones to return.
+    pub fn close_and_drain(&self) {
+        let Ok(mut state) = self.state.lock() else {
+            return;
+        };
+        state.closed = true;
+        self.cv.notify_all();
+        while state.permits < self.max {
+            state = match self.cv.wait(state) {
+                Ok(s) => s,
+                Err(_) => return,
+            };
+        }
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.sem.state.lock() {
+            state.permits += 1;
+        }
+        self.sem.cv.notify_all();
+    }
+}
+
+/// Pool of cli or rpc drivers. Rpc drivers share the same http agent,
+/// and so its connection pool.
+#[derive(Debug)]
+pub struct CliDriverPool {
+    drivers: Vec<Mutex<Box<dyn NodeDriver>>>,
+    // Tick of the last use for each driver, to pick the least recently used.
+    last_used: Vec<AtomicU64>,
+    tick: AtomicU64,
+    semaphore: Arc<Semaphore>,
+}
+
+impl CliDriverPool {
+    pub fn new(cli_path: &str, max_concurrent: usize, timeout: Option<Duration>) -> Self {
+        let driver = CliDriver::with_cli_path(cli_path.to_owned()).with_timeout(timeout);
+        Self::with_drivers(max_concurrent, |_| Box::new(driver.clone()))
+    }
+
+    pub fn new_rpc(
+        url: &str,
+        user: Option<&str>,
+        pass: Option<&str>,
+        max_concurrent: usize,
+        timeout: Option<Duration>,
+    ) -> Self {
+        let driver = RpcDriver::new(url, user, pass, timeout);
+        Self::with_drivers(max_concurrent, |_| Box::new(driver.clone()))
+    }
+
+    fn with_drivers<F>(max_concurrent: usize, f: F) -> Self
+    where
+        F: FnMut(usize) -> Box<dyn NodeDriver>,
+    {
+        let max_concurrent = max_concurrent.max(1);
+        let drivers = (0..max_concurrent).map(f).map(Mutex::new).collect();
+        let last_used = (0..max_concurrent).map(|_| AtomicU64::new(0)).collect();
+        CliDriverPool {
+            drivers,
+            last_used,
+            tick: AtomicU64::new(0),
+            semaphore: Arc::new(Semaphore::new(max_concurrent)),
+        }
+    }
+
+    /// Run `f` with the least recently used free driver, while holding
+    /// a permit.
+    pub fn with_driver<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut dyn NodeDriver) -> Result<T>,
+    {
+        let _permit = self.semaphore.acquire()?;
+        let mut order = (0..self.drivers.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| self.last_used[i].load(Ordering::Relaxed));
+        // There are as many permits as drivers, so holding a permit
+        // guarantees at least one of them is free.
+        for i in order {
+            if let Ok(mut driver) = self.drivers[i].try_lock() {
+                let tick = self.tick.fetch_add(1, Ordering::Relaxed) + 1;
+                self.last_used[i].store(tick, Ordering::Relaxed);
+                return f(driver.as_mut());
+            }
+        }
+        Err(Error::from("no free cli driver in pool"))
+    }
+
+    pub fn get_block_count(&self) -> Result<u32> {
+        self.with_driver(|cli| cli.get_block_count())
+    }
+
+    pub fn get_block_hash(&self, height: i64) -> Result<TStr> {
+        self.with_driver(|cli| cli.get_block_hash(height))
+    }
+
+    pub fn get_block(&self, hash: &str, verbosity: Option<i32>) -> Result<OutputExt> {
+        self.with_driver(|cli| cli.get_block(hash, verbosity))
+    }
+
+    pub fn get_blockchain_info(&self) -> Result<OutputExt> {
+        self.with_driver(|cli| cli.get_blockchain_info())
+    }
+
+    /// Reject new requests and wait for in-flight ones to finish.
+    pub fn shutdown(&self) {
+        self.semaphore.close_and_drain();
+    }
+}
+
+impl Drop for CliDriverPool {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
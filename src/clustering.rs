@@ -0,0 +1,315 @@
+use crate::args::OutputFormat;
+use crate::db::{self, SqliteBlockStore};
+use crate::lang::Result;
+use clap::Parser;
+use std::collections::HashMap;
+use tracing::{debug, info};
+
+#[derive(Parser, Debug)]
+pub struct ClusterArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    /// Defaults to 0
+    #[arg(short = 's', long)]
+    pub start_height: Option<u32>,
+    #[arg(short = 'e', long, default_value_t = 2_000_000)]
+    pub end_height: u32,
+    /// List the addresses in the cluster of this address
+    #[arg(long, short = 'a')]
+    pub address: Option<String>,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+}
+
+/// Union-find over address indexes, grown as new addresses are seen.
+/// Union by size with path halving.
+#[derive(Debug, Default)]
+pub struct UnionFind {
+    parent: Vec<usize>,
+    size: Vec<usize>,
+}
+
+impl UnionFind {
+    pub fn push(&mut self) -> usize {
+        let i = self.parent.len();
+        self.parent.push(i);
+        self.size.push(1);
+        i
+    }
+
+    pub fn find(&mut self, mut x: usize) -> usize {
+        while self.parent[x] != x {
+            self.parent[x] = self.parent[self.parent[x]];
+            x = self.parent[x];
+        }
+        x
+    }
+
+    /// Returns false if `x` and `y` were already in the same set
+    pub fn union(&mut self, x: usize, y: usize) -> bool {
+        let (mut x, mut y) = (self.find(x), self.find(y));
+        if x == y {
+            return false;
+        }
+        if self.size[x] < self.size[y] {
+            std::mem::swap(&mut x, &mut y);
+        }
+        self.parent[y] = x;
+        self.size[x] += self.size[y];
+        true
+    }
+}
+
+/// Input addresses grouped by the common-input-ownership heuristic: all
+/// the inputs of a tx are taken to be controlled by the same entity.
+/// Addresses that only ever receive aren't clustered.
+#[derive(Debug, Default)]
+pub struct Clusters {
+    addrs: Vec<String>,
+    addr_index: HashMap<String, usize>,
+    sets: UnionFind,
+}
+
+impl Clusters {
+    fn index_of(&mut self, addr: &str) -> usize {
+        if let Some(i) = self.addr_index.get(addr) {
+            return *i;
+        }
+        let i = self.sets.push();
+        self.addrs.push(addr.to_string());
+        self.addr_index.insert(addr.to_string(), i);
+        i
+    }
+
+    /// Merge the clusters of all `tx_in` addresses of a tx
+    pub fn add_tx_inputs<'a, I>(&mut self, tx_in: I)
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut first = None;
+        for addr in tx_in {
+            let i = self.index_of(addr);
+            match first {
+                None => first = Some(i),
+                Some(f) => {
+                    self.sets.union(f, i);
+                }
+            }
+        }
+    }
+
+    /// `(address, cluster_id)` for every address. Ids are the index of
+    /// the first seen address of each cluster, so they're stable for the
+    /// same input.
+    pub fn assignments(&mut self) -> Vec<(&str, i64)> {
+        let mut ids = HashMap::new();
+        let mut v = Vec::with_capacity(self.addrs.len());
+        for i in 0..self.addrs.len() {
+            let root = self.sets.find(i);
+            let id = *ids.entry(root).or_insert(i as i64);
+            v.push((self.addrs[i].as_str(), id));
+        }
+        v
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct ClusterStats {
+    pub address_count: usize,
+    pub cluster_count: usize,
+    /// Clusters with more than one address
+    pub multi_address_clusters: usize,
+    pub largest_cluster_size: usize,
+    pub largest_cluster_id: Option<i64>,
+}
+
+pub fn cluster_stats(assignments: &[(&str, i64)]) -> ClusterStats {
+    let mut sizes = HashMap::<i64, usize>::new();
+    for (_, id) in assignments {
+        *sizes.entry(*id).or_default() += 1;
+    }
+    // Lowest id on ties
+    let largest = sizes
+        .iter()
+        .max_by_key(|(id, n)| (**n, std::cmp::Reverse(**id)))
+        .map(|(id, n)| (*id, *n));
+    ClusterStats {
+        address_count: assignments.len(),
+        cluster_count: sizes.len(),
+        multi_address_clusters: sizes.values().filter(|x| **x > 1).count(),
+        largest_cluster_size: largest.map(|x| x.1).unwrap_or_default(),
+        largest_cluster_id: largest.map(|x| x.0),
+    }
+}
+
+pub fn build_clusters<F>(
+    sql_store: &SqliteBlockStore,
+    start: i64,
+    end: i64,
+    interrupted: F,
+) -> Result<Clusters>
+where
+    F: Fn() -> bool,
+{
+    let mut clusters = Clusters::default();
+    let mut tx_count = 0;
+    sql_store.iter_txs_partial(
+        Some(&format!(
+            "where height between {} and {} order by height",
+            start, end
+        )),
+        |tx| {
+            if interrupted() {
+                info!("int: early exit");
+                return Err("interrupted".into());
+            }
+            let tx = tx?;
+            // Sorted, so the first seen order doesn't depend on the map
+            let mut tx_in = tx.tx_in.keys().map(|x| x.as_str()).collect::<Vec<_>>();
+            tx_in.sort();
+            clusters.add_tx_inputs(tx_in);
+            tx_count += 1;
+            Ok(())
+        },
+    )?;
+    info!("txs: {}", tx_count);
+    Ok(clusters)
+}
+
+pub fn run(args: &ClusterArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Write)?;
+    let sql_store = SqliteBlockStore::open_or_create(&args.sqlite_path)?;
+
+    let start_height = args.start_height.unwrap_or(0);
+    info!("cluster: {} - {}..", start_height, args.end_height);
+    let mut clusters = build_clusters(
+        &sql_store,
+        start_height.into(),
+        args.end_height.into(),
+        || quit.load(std::sync::atomic::Ordering::Relaxed),
+    )?;
+    let assignments = clusters.assignments();
+    let stats = cluster_stats(&assignments);
+
+    info!("store clusters..");
+    let n = sql_store.replace_address_clusters(assignments)?;
+    info!("stored: {}", n);
+
+    let members = match &args.address {
+        Some(addr) => match sql_store.get_cluster_id(addr)? {
+            Some(id) => Some(sql_store.get_cluster_addresses(id)?),
+            None => {
+                info!("{} never spends in the range, not clustered", addr);
+                Some(vec![])
+            }
+        },
+        None => None,
+    };
+
+    match args.format {
+        OutputFormat::Text => {
+            println!("addresses: {}", stats.address_count);
+            println!("clusters: {}", stats.cluster_count);
+            println!("multi address clusters: {}", stats.multi_address_clusters);
+            if let Some(id) = stats.largest_cluster_id {
+                println!(
+                    "largest cluster: {} ({} addresses)",
+                    id, stats.largest_cluster_size
+                );
+            }
+            if let (Some(addr), Some(members)) = (&args.address, &members) {
+                println!("cluster of {}: {} addresses", addr, members.len());
+                for x in members.iter() {
+                    println!("    {}", x);
+                }
+            }
+        }
+        OutputFormat::Json => {
+            #[derive(serde::Serialize)]
+            struct Out<'a> {
+                #[serde(flatten)]
+                stats: &'a ClusterStats,
+                cluster: Option<&'a Vec<String>>,
+            }
+            let out = Out {
+                stats: &stats,
+                cluster: members.as_ref(),
+            };
+            println!("{}", serde_json::to_string_pretty(&out)?);
+        }
+    }
+
+    info!("complete");
+    Ok(())
+}
+
+#[test]
+fn test_build_clusters() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            let txs = [
+                ("t1", 1, r#"{"a":1.0,"b":2.0}"#),
+                ("t2", 2, r#"{"c":1.0}"#),
+                ("t3", 3, r#"{"d":1.0,"e":1.0}"#),
+                ("t4", 4, r#"{"b":1.0,"e":1.0}"#),
+                ("t5", 5, ""),
+                ("t6", 9, r#"{"c":1.0,"f":1.0}"#),
+            ];
+            for (txid, height, tx_in) in txs {
+                crate::db::insert_test_tx(
+                    stmts,
+                    &crate::db::TestTx {
+                        txid,
+                        height,
+                        tx_type: "utxo",
+                        tx_in,
+                        tx_out: r#"{"z":1.0}"#,
+                        ..Default::default()
+                    },
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let mut clusters = build_clusters(&store, 0, 5, || false).unwrap();
+    let assignments = clusters.assignments();
+    assert_eq!(
+        assignments,
+        [("a", 0), ("b", 0), ("c", 2), ("d", 0), ("e", 0)]
+    );
+    assert_eq!(
+        cluster_stats(&assignments),
+        ClusterStats {
+            address_count: 5,
+            cluster_count: 2,
+            multi_address_clusters: 1,
+            largest_cluster_size: 4,
+            largest_cluster_id: Some(0),
+        }
+    );
+
+    assert_eq!(store.replace_address_clusters(assignments).unwrap(), 5);
+    let id = store.get_cluster_id("e").unwrap().unwrap();
+    assert_eq!(
+        store.get_cluster_addresses(id).unwrap(),
+        ["a", "b", "d", "e"]
+    );
+    assert_eq!(store.get_cluster_id("z").unwrap(), None);
+
+    // Replaced, not appended
+    let mut clusters = build_clusters(&store, 0, 9, || false).unwrap();
+    store
+        .replace_address_clusters(clusters.assignments())
+        .unwrap();
+    let id = store.get_cluster_id("f").unwrap().unwrap();
+    assert_eq!(store.get_cluster_addresses(id).unwrap(), ["c", "f"]);
+    assert_eq!(store.get_cluster_addresses(0).unwrap().len(), 4);
+}
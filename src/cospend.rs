@@ -0,0 +1,234 @@
+use crate::args::OutputFormat;
+use crate::db::{self, SqliteBlockStore};
+use crate::lang::Result;
+use clap::Parser;
+use std::collections::{BTreeMap, HashSet};
+use tracing::{debug, info};
+
+#[derive(Parser, Debug)]
+pub struct CoSpendArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    /// Defaults to 0
+    #[arg(short = 's', long)]
+    pub start_height: Option<u32>,
+    #[arg(short = 'e', long, default_value_t = 2_000_000)]
+    pub end_height: u32,
+    /// Txs with more input addresses than this are skipped, as the pairs
+    /// grow with the square of the inputs
+    #[arg(long, default_value_t = 100)]
+    pub max_tx_inputs: usize,
+    /// List the addresses co-spent with this address, transitively
+    #[arg(long, short = 'a')]
+    pub address: Option<String>,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+}
+
+/// Input address pairs that are spent together, with the number of txs
+/// each pair is in. Pairs are keyed with the lower address first.
+#[derive(Debug, Default)]
+pub struct CoSpendPairs {
+    pairs: BTreeMap<(String, String), i64>,
+    pub txs: u64,
+    pub skipped_txs: u64,
+}
+
+impl CoSpendPairs {
+    /// Count every pair of the `tx_in` addresses of a tx. Txs with less
+    /// than 2 addresses, or more than `max_inputs`, add nothing.
+    pub fn add_tx_inputs<'a, I>(&mut self, tx_in: I, max_inputs: usize)
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut addrs = tx_in.into_iter().collect::<Vec<_>>();
+        addrs.sort();
+        addrs.dedup();
+        if addrs.len() < 2 {
+            return;
+        }
+        if addrs.len() > max_inputs {
+            self.skipped_txs += 1;
+            return;
+        }
+        for (i, a) in addrs.iter().enumerate() {
+            for b in addrs[i + 1..].iter() {
+                *self
+                    .pairs
+                    .entry((a.to_string(), b.to_string()))
+                    .or_default() += 1;
+            }
+        }
+        self.txs += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn address_count(&self) -> usize {
+        self.pairs
+            .keys()
+            .flat_map(|(a, b)| [a, b])
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ((&str, &str), i64)> {
+        self.pairs
+            .iter()
+            .map(|((a, b), n)| ((a.as_str(), b.as_str()), *n))
+    }
+}
+
+pub fn build_cospend_pairs<F>(
+    sql_store: &SqliteBlockStore,
+    start: i64,
+    end: i64,
+    max_inputs: usize,
+    interrupted: F,
+) -> Result<CoSpendPairs>
+where
+    F: Fn() -> bool,
+{
+    let mut pairs = CoSpendPairs::default();
+    sql_store.iter_txs_partial(
+        Some(&format!(
+            "where height between {} and {} order by height",
+            start, end
+        )),
+        |tx| {
+            if interrupted() {
+                info!("int: early exit");
+                return Err("interrupted".into());
+            }
+            let tx = tx?;
+            pairs.add_tx_inputs(tx.tx_in.keys().map(|x| x.as_str()), max_inputs);
+            Ok(())
+        },
+    )?;
+    info!(
+        "co-spend txs: {}, skipped: {}",
+        pairs.txs, pairs.skipped_txs
+    );
+    Ok(pairs)
+}
+
+pub fn run(args: &CoSpendArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Write)?;
+    let sql_store = SqliteBlockStore::open_or_create(&args.sqlite_path)?;
+
+    let start_height = args.start_height.unwrap_or(0);
+    info!("cospend: {} - {}..", start_height, args.end_height);
+    let pairs = build_cospend_pairs(
+        &sql_store,
+        start_height.into(),
+        args.end_height.into(),
+        args.max_tx_inputs,
+        || quit.load(std::sync::atomic::Ordering::Relaxed),
+    )?;
+
+    info!("store pairs..");
+    let n = sql_store.replace_address_cospend(pairs.iter())?;
+    info!("stored: {}", n);
+
+    let group = match &args.address {
+        Some(addr) => Some(sql_store.get_cospend_group(addr)?),
+        None => None,
+    };
+
+    match args.format {
+        OutputFormat::Text => {
+            println!("txs: {}", pairs.txs);
+            println!("skipped txs: {}", pairs.skipped_txs);
+            println!("pairs: {}", pairs.len());
+            println!("addresses: {}", pairs.address_count());
+            if let (Some(addr), Some(group)) = (&args.address, &group) {
+                println!("co-spend group of {}: {} addresses", addr, group.len());
+                for x in group.iter() {
+                    println!("    {}", x);
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let out = serde_json::json!({
+                "txs": pairs.txs,
+                "skipped_txs": pairs.skipped_txs,
+                "pairs": pairs.len(),
+                "addresses": pairs.address_count(),
+                "group": group,
+            });
+            println!("{}", serde_json::to_string_pretty(&out)?);
+        }
+    }
+
+    info!("complete");
+    Ok(())
+}
+
+#[test]
+fn test_build_cospend_pairs() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            let txs = [
+                ("t1", 1, r#"{"b":1.0,"a":2.0}"#),
+                ("t2", 2, r#"{"c":1.0}"#),
+                ("t3", 3, r#"{"a":1.0,"b":1.0,"d":1.0}"#),
+                ("t4", 4, r#"{"e":1.0,"f":1.0}"#),
+                ("t5", 5, ""),
+                ("t6", 9, r#"{"d":1.0,"g":1.0}"#),
+            ];
+            for (txid, height, tx_in) in txs {
+                crate::db::insert_test_tx(
+                    stmts,
+                    &crate::db::TestTx {
+                        txid,
+                        height,
+                        tx_type: "utxo",
+                        tx_in,
+                        tx_out: r#"{"z":1.0}"#,
+                        ..Default::default()
+                    },
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let pairs = build_cospend_pairs(&store, 0, 5, 100, || false).unwrap();
+    assert_eq!(
+        pairs.iter().collect::<Vec<_>>(),
+        [
+            (("a", "b"), 2),
+            (("a", "d"), 1),
+            (("b", "d"), 1),
+            (("e", "f"), 1)
+        ]
+    );
+    assert_eq!((pairs.txs, pairs.skipped_txs), (3, 0));
+    assert_eq!(pairs.address_count(), 5);
+
+    let pairs = build_cospend_pairs(&store, 0, 5, 2, || false).unwrap();
+    assert_eq!((pairs.txs, pairs.skipped_txs), (2, 1));
+    assert_eq!(pairs.len(), 2);
+
+    let pairs = build_cospend_pairs(&store, 0, 9, 100, || false).unwrap();
+    assert_eq!(store.replace_address_cospend(pairs.iter()).unwrap(), 5);
+    assert_eq!(store.get_cospend_group("g").unwrap(), ["a", "b", "d", "g"]);
+    assert_eq!(store.get_cospend_group("f").unwrap(), ["e", "f"]);
+    // Only ever spent alone, or never spent
+    assert!(store.get_cospend_group("c").unwrap().is_empty());
+    assert!(store.get_cospend_group("z").unwrap().is_empty());
+
+    // Replaced, not appended
+    let pairs = build_cospend_pairs(&store, 4, 4, 100, || false).unwrap();
+    assert_eq!(store.replace_address_cospend(pairs.iter()).unwrap(), 1);
+    assert!(store.get_cospend_group("a").unwrap().is_empty());
+}
@@ -2,21 +2,337 @@
 
 #[cfg(feature = "legacy-sqlite-v1")]
 pub mod sqlite_v1;
+mod txcache;
 
-use crate::lang::{Result, ResultExt};
-use crate::models::{Block, IcxTxSet, Transaction};
-use rusqlite::{params, CachedStatement, Connection, OptionalExtension, Row};
-use std::collections::HashMap;
+pub use txcache::LruBlockStore;
 
-pub fn sqlite_init_db_v2(path: Option<&str>) -> Result<Connection> {
-    let path = path.unwrap_or("data/index.sqlite");
+use crate::lang::{Error, Result, ResultExt};
+use crate::models::{Block, IcxTxSet, Transaction, TxType};
+use rusqlite::{params, CachedStatement, Connection, OpenFlags, OptionalExtension, Row};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::RangeInclusive;
+use tracing::{error, info};
+
+pub const DEFAULT_SQLITE_PATH: &str = "data/index.sqlite";
+
+// Schema migrations applied in order on top of the base v2 tables.
+// `pragma user_version` tracks how many of these have been applied, so
+// entries must only ever be appended.
+const SQLITE_MIGRATIONS_V2: &[&str] = &[
+    // 1: icx_btc_exp_amt is the BTC side only, DFC side is derived from
+    // the ICXCalc log of the offer.
+    "ALTER TABLE txs ADD COLUMN icx_dfc_amt TEXT NOT NULL DEFAULT ''",
+    // 2: chain the index was built from, to catch mixing networks.
+    "CREATE TABLE IF NOT EXISTS network_info (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    )",
+    // 3: single row, written with every commit while indexing.
+    "CREATE TABLE IF NOT EXISTS checkpoints (
+        id INTEGER PRIMARY KEY CHECK (id = 0),
+        last_indexed_height INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    )",
+    // 4: input addresses grouped by the co-spend heuristic, see `cluster`.
+    "CREATE TABLE IF NOT EXISTS address_clusters (
+        address TEXT PRIMARY KEY,
+        cluster_id INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_address_clusters_cluster_id
+        ON address_clusters (cluster_id)",
+    // 5: utxo miner fee, ins minus outs. 0 for coinbases and txs indexed
+    // before this.
+    "ALTER TABLE txs ADD COLUMN fee REAL NOT NULL DEFAULT 0",
+    // 6: block minter, filled in for the blocks already indexed. The
+    // indexes are in the index factory.
+    "ALTER TABLE blocks ADD COLUMN minter_id TEXT;
+    ALTER TABLE blocks ADD COLUMN minter_operator TEXT;
+    UPDATE blocks SET
+        minter_id = json_extract(data, '$.minter.id'),
+        minter_operator = json_extract(data, '$.minter.operator')
+    WHERE json_valid(data)",
+    // 7: input address pairs spent together, with addr_a < addr_b, see
+    // `cospend`. addr_b has its own index for the reverse lookups.
+    "CREATE TABLE IF NOT EXISTS address_cospend (
+        addr_a TEXT NOT NULL,
+        addr_b TEXT NOT NULL,
+        tx_count INTEGER NOT NULL,
+        PRIMARY KEY (addr_a, addr_b)
+    );
+    CREATE INDEX IF NOT EXISTS idx_address_cospend_addr_b
+        ON address_cospend (addr_b)",
+    // 8: coinbase outs per address, as coinbases have no ins and so no
+    // tx_addr_graph edges. Filled in for the coinbases already indexed.
+    "CREATE TABLE IF NOT EXISTS coinbase_outs (
+        address TEXT NOT NULL,
+        height INTEGER NOT NULL,
+        txid TEXT NOT NULL,
+        PRIMARY KEY (address, height, txid)
+    );
+    INSERT OR IGNORE INTO coinbase_outs (address, height, txid)
+        SELECT j.key, txs.height, txs.txid FROM txs, json_each(txs.tx_out) AS j
+        WHERE txs.tx_type = 'cb' AND json_valid(txs.tx_out)",
+];
+
+const BLOCKS_IN_RANGE_QUERY: &str =
+    "SELECT data FROM blocks WHERE height BETWEEN ?1 AND ?2 ORDER BY height";
+
+const BLOCKS_BY_MINTER_QUERY: &str =
+    "SELECT height FROM blocks WHERE minter_id = ?1 ORDER BY height";
+
+const TXS_IN_RANGE_QUERY: &str =
+    "SELECT * FROM txs WHERE height >= ?1 AND height <= ?2 ORDER BY height";
+
+const TXS_BY_TYPE_QUERY: &str = "SELECT * FROM txs
+    WHERE tx_type = ?1 AND height >= ?2 AND height <= ?3 ORDER BY height";
+
+// The address is matched as a quoted json string, so only whole addresses
+// match. dvm_out holds all the DVM addresses, in and out.
+const TXS_FOR_ADDR_QUERY: &str = "SELECT * FROM txs
+    WHERE height <= ?2 AND (tx_in LIKE ?1 OR tx_out LIKE ?1 OR dvm_out LIKE ?1)
+    ORDER BY height";
+
+/// The txids come from the graph address indexes, so the txs table is only
+/// hit by txid.
+const TXS_FOR_ADDR_GRAPH_QUERY: &str = "SELECT * FROM txs
+    WHERE txid IN (
+        SELECT txid FROM tx_addr_graph WHERE in_addr = ?1
+        UNION SELECT txid FROM tx_addr_graph WHERE out_addr = ?1
+    ) AND height BETWEEN ?2 AND ?3
+    ORDER BY height, txid";
+
+// Txids off the graph address indexes, with the height filter from txs by
+// txid. The in and out variants are for the side of the edge `addr` is on.
+const ADDR_TXIDS_QUERY: &str = "SELECT txid FROM txs
+    WHERE txid IN (
+        SELECT txid FROM tx_addr_graph WHERE in_addr = ?1
+        UNION SELECT txid FROM tx_addr_graph WHERE out_addr = ?1
+    ) AND height BETWEEN ?2 AND ?3
+    ORDER BY height, txid";
+
+// Coinbases have no ins, so no graph edges, and are matched on the tx
+// out json instead. The tx type index narrows it to the coinbases.
+const ADDR_COINBASE_TXIDS_QUERY: &str = "SELECT txid FROM coinbase_outs
+    WHERE address = ?1 AND height BETWEEN ?2 AND ?3
+    ORDER BY height, txid";
+
+const ADDR_IN_TXIDS_QUERY: &str = "SELECT txid FROM txs
+    WHERE txid IN (SELECT txid FROM tx_addr_graph WHERE in_addr = ?1)
+    AND height BETWEEN ?2 AND ?3
+    ORDER BY height, txid";
+
+const ADDR_OUT_TXIDS_QUERY: &str = "SELECT txid FROM txs
+    WHERE txid IN (SELECT txid FROM tx_addr_graph WHERE out_addr = ?1)
+    AND height BETWEEN ?2 AND ?3
+    ORDER BY height, txid";
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct PageStats {
+    pub page_size: u64,
+    pub page_count: i64,
+    /// Unused pages, that a vacuum would free up
+    pub freelist_count: i64,
+    /// Negative when it's set in KiB instead of pages
+    pub cache_size: i64,
+}
+
+/// What an ICX tx refers to in `icx_links`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcxLink {
+    Order,
+    Offer,
+    DfcHtlc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathMode {
+    /// Existing index that's only read from
+    Read,
+    /// File is written to in an existing directory
+    Write,
+    /// File may not exist yet and will be created
+    Create,
+}
+
+/// Check the sqlite path up front, so commands fail with a clear message
+/// instead of creating an empty db or erroring deep inside a query.
+pub fn validate_sqlite_path(path: &str, mode: PathMode) -> Result<()> {
+    use std::io::Read;
+    let p = std::path::Path::new(path);
+    if p.is_dir() {
+        return Err(Error::from(format!("SQLite path is a directory: {}", path)));
+    }
+    match mode {
+        PathMode::Read => {
+            if !p.exists() {
+                return Err(Error::from(format!(
+                    "SQLite file not found at {}. Run 'cindex' first to build the index.",
+                    path
+                )));
+            }
+            let mut header = [0u8; 16];
+            let mut f = std::fs::File::open(p)
+                .map_err(|e| Error::from(format!("SQLite file not readable at {}: {}", path, e)))?;
+            if f.read_exact(&mut header).is_err() || &header != b"SQLite format 3\0" {
+                return Err(Error::from(format!("not a SQLite file: {}", path)));
+            }
+        }
+        PathMode::Write => {
+            let dir = match p.parent() {
+                Some(d) if !d.as_os_str().is_empty() => d,
+                _ => std::path::Path::new("."),
+            };
+            if !dir.is_dir() {
+                return Err(Error::from(format!(
+                    "SQLite directory not found: {}",
+                    dir.display()
+                )));
+            }
+            // The mode bits miss the owner and acls, so write a probe file
+            // instead. SQLite needs the directory for the wal and shm files
+            // as well, not just the db file.
+            let probe = dir.join(format!(".sqlite-write-probe-{}", std::process::id()));
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&probe)
+                .map_err(|e| {
+                    Error::from(format!(
+                        "SQLite directory not writable: {}: {}",
+                        dir.display(),
+                        e
+                    ))
+                })?;
+            std::fs::remove_file(&probe)?;
+            if p.exists() {
+                std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(p)
+                    .map_err(|e| {
+                        Error::from(format!("SQLite file not writable: {}: {}", path, e))
+                    })?;
+            }
+        }
+        PathMode::Create => {}
+    }
+    Ok(())
+}
+
+/// Rough size of an indexed tx on disk, with its graph edges and index
+/// entries
+pub const EST_BYTES_PER_TX: u64 = 2048;
+
+/// Check the filesystem of the db at `path` has room for `txs` more
+/// indexed txs, going by `EST_BYTES_PER_TX`.
+pub fn check_disk_space(path: &str, txs: u64) -> Result<()> {
+    let dir = match std::path::Path::new(path).parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => std::path::Path::new("."),
+    };
+    let available = fs2::available_space(dir)?;
+    let needed = txs.saturating_mul(EST_BYTES_PER_TX);
+    if available < needed {
+        return Err(Error::from(format!(
+            "not enough disk space at {}: {} bytes free, ~{} bytes needed for ~{} txs",
+            dir.display(),
+            available,
+            needed,
+            txs
+        )));
+    }
+    info!(
+        "disk space: {} bytes free, ~{} bytes needed for ~{} txs",
+        available, needed, txs
+    );
+    Ok(())
+}
+
+/// Write pragmas for the indexers, applied after the defaults in
+/// `sqlite_init_pragma_v1`. The defaults here keep those as they are.
+#[derive(clap::Args, Debug, Clone, PartialEq, Eq)]
+pub struct SqlitePragmaArgs {
+    /// Page cache size, in pages when positive or in KiB when negative.
+    /// A larger cache saves disk reads resolving tx ins, most of all on
+    /// HDDs, at the cost of memory. Defaults to sqlite's, 2000 KiB.
+    #[arg(long, allow_negative_numbers = true)]
+    pub sqlite_cache_size: Option<i64>,
+    /// WAL pages written before an automatic checkpoint, 0 to disable.
+    /// Higher means fewer, larger checkpoints and faster bulk writes,
+    /// for a larger WAL file and slower reads of the index meanwhile.
+    #[arg(long, default_value_t = 1000)]
+    pub sqlite_wal_autocheckpoint: u32,
+    #[arg(long, value_enum, default_value_t = SqliteSynchronous::Normal)]
+    pub sqlite_synchronous: SqliteSynchronous,
+    /// Bytes the WAL file is truncated to after a checkpoint, -1 for no
+    /// limit. No limit saves regrowing the file on every checkpoint, for
+    /// a WAL that stays at its largest size on disk.
+    #[arg(long, default_value_t = 67_108_864, allow_negative_numbers = true)]
+    pub sqlite_journal_size_limit: i64,
+}
+
+impl Default for SqlitePragmaArgs {
+    fn default() -> Self {
+        Self {
+            sqlite_cache_size: None,
+            sqlite_wal_autocheckpoint: 1000,
+            sqlite_synchronous: SqliteSynchronous::Normal,
+            sqlite_journal_size_limit: 67_108_864,
+        }
+    }
+}
+
+impl SqlitePragmaArgs {
+    pub fn pragmas(&self) -> Vec<String> {
+        let mut v = vec![
+            format!(
+                "pragma wal_autocheckpoint={}",
+                self.sqlite_wal_autocheckpoint
+            ),
+            format!("pragma synchronous={}", self.sqlite_synchronous.as_str()),
+            format!(
+                "pragma journal_size_limit={}",
+                self.sqlite_journal_size_limit
+            ),
+        ];
+        if let Some(n) = self.sqlite_cache_size {
+            v.push(format!("pragma cache_size={}", n));
+        }
+        v
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SqliteSynchronous {
+    /// No syncs, fastest. An OS crash or power loss can corrupt the db,
+    /// so only for indexes that can be rebuilt, or on a RAM disk.
+    Off,
+    /// Syncs on checkpoints. Safe from corruption in WAL mode, but the
+    /// last commits can be lost on power loss.
+    Normal,
+    /// Syncs on every commit. Slowest, most of all on HDDs.
+    Full,
+}
+
+impl SqliteSynchronous {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SqliteSynchronous::Off => "off",
+            SqliteSynchronous::Normal => "normal",
+            SqliteSynchronous::Full => "full",
+        }
+    }
+}
+
+pub fn sqlite_init_db_v2(path: Option<&str>, pragmas: &SqlitePragmaArgs) -> Result<Connection> {
+    let path = path.unwrap_or(DEFAULT_SQLITE_PATH);
     let conn = rusqlite::Connection::open(path)?;
-    sqlite_init_pragma_v1(&conn)?;
+    sqlite_init_pragma_v1(&conn, pragmas)?;
     sqlite_init_tables_v2(&conn)?;
     Ok(conn)
 }
 
-fn sqlite_init_pragma_v1(conn: &Connection) -> Result<()> {
+fn sqlite_init_pragma_v1(conn: &Connection, args: &SqlitePragmaArgs) -> Result<()> {
     let pragmas = [
         // "pragma locking_mode=exclusive",
         "pragma journal_mode=wal",
@@ -33,6 +349,9 @@ fn sqlite_init_pragma_v1(conn: &Connection) -> Result<()> {
     for pragma in &pragmas {
         conn.execute_batch(pragma).ext()?;
     }
+    for pragma in args.pragmas() {
+        conn.execute_batch(&pragma).ext()?;
+    }
     Ok(())
 }
 
@@ -90,7 +409,26 @@ fn sqlite_init_tables_v2(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
-#[derive(Debug, Clone, Default, serde::Deserialize)]
+fn sqlite_migrate_v2(conn: &Connection) -> Result<()> {
+    sqlite_apply_migrations(conn, SQLITE_MIGRATIONS_V2)
+}
+
+/// Each migration is committed together with its `user_version` bump, so
+/// an interrupted one is rolled back as a whole and re-run on the next
+/// open, instead of failing on the columns it already added.
+fn sqlite_apply_migrations(conn: &Connection, migrations: &[&str]) -> Result<()> {
+    let version: usize = conn.query_row("pragma user_version", [], |row| row.get(0))?;
+    for (i, migration) in migrations.iter().enumerate().skip(version) {
+        info!("applying schema migration: {}", i + 1);
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute_batch(&format!("pragma user_version = {}", i + 1))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
 pub struct TxRow {
     pub txid: String,
     pub height: i64,
@@ -100,15 +438,52 @@ pub struct TxRow {
     pub dvm_in: Vec<String>,
     pub dvm_out: Vec<String>,
     pub data: Transaction,
-    pub icx_data: IcxTxSet,
+    pub icx_data: Option<IcxTxSet>,
     pub icx_addr: String,
     pub icx_btc_exp_amt: String,
     pub swap_from: String,
     pub swap_to: String,
     pub swap_amt: String,
+    pub icx_dfc_amt: String,
+    /// Miner fee, the utxo ins minus the outs
+    #[serde(default)]
+    pub fee: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct SwapStats {
+    pub from_token: String,
+    pub to_token: String,
+    pub tx_count: i64,
+    pub total_amount: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeDirection {
+    Outgoing,
+    Incoming,
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IcxAmountType {
+    BtcExpected,
+    DfcExpected,
 }
 
 impl TxRow {
+    /// Which of the ICX amount columns is populated. BTC is checked
+    /// first, as it's always set from the ICX log when there's one.
+    pub fn icx_amount_type(&self) -> Option<IcxAmountType> {
+        if !self.icx_btc_exp_amt.is_empty() {
+            Some(IcxAmountType::BtcExpected)
+        } else if !self.icx_dfc_amt.is_empty() {
+            Some(IcxAmountType::DfcExpected)
+        } else {
+            None
+        }
+    }
+
     pub fn from_sqlite_row(row: &rusqlite::Row) -> Result<Self> {
         let mut v = TxRow::from_sqlite_row_partial(row)?;
         let data_str = row.get::<_, String>(7)?;
@@ -117,7 +492,7 @@ impl TxRow {
             v.data = serde_json::from_str(&data_str)?;
         }
         if !icx_data_str.is_empty() {
-            v.icx_data = serde_json::from_str(&icx_data_str)?;
+            v.icx_data = Some(serde_json::from_str(&icx_data_str)?);
         }
         Ok(v)
     }
@@ -163,12 +538,22 @@ impl TxRow {
             dvm_in,
             dvm_out,
             data: Transaction::default(), // Placeholder or default value
-            icx_data: IcxTxSet::default(), // Placeholder or default value
+            icx_data: None,               // Placeholder or default value
             icx_addr: row.get(9)?,
             icx_btc_exp_amt: row.get(10)?,
             swap_from: row.get(11)?,
             swap_to: row.get(12)?,
             swap_amt: row.get(13)?,
+            // Added by migration, so missing on stores opened read only
+            // that were never migrated.
+            icx_dfc_amt: match row.get(14) {
+                Err(rusqlite::Error::InvalidColumnIndex(_)) => String::new(),
+                r => r?,
+            },
+            fee: match row.get(15) {
+                Err(rusqlite::Error::InvalidColumnIndex(_)) => 0.0,
+                r => r?,
+            },
         })
     }
 }
@@ -185,6 +570,12 @@ pub fn sqlite_create_index_factory_v2(
             "CREATE INDEX IF NOT EXISTS idx_txs_tx_type ON txs (tx_type)",
             "idx_txs_tx_type",
         ),
+        // Type first, so the by type range queries seek straight to the
+        // type and read it in height order.
+        (
+            "CREATE INDEX IF NOT EXISTS idx_txs_type_height ON txs (tx_type, height)",
+            "idx_txs_type_height",
+        ),
         (
             "CREATE INDEX IF NOT EXISTS idx_txs_icx_addr ON txs (icx_addr)",
             "idx_txs_icx_addr",
@@ -197,6 +588,14 @@ pub fn sqlite_create_index_factory_v2(
             "CREATE INDEX IF NOT EXISTS idx_txs_swap_to ON txs (swap_to)",
             "idx_txs_swap_to",
         ),
+        (
+            "CREATE INDEX IF NOT EXISTS idx_blocks_minter_id ON blocks (minter_id)",
+            "idx_blocks_minter_id",
+        ),
+        (
+            "CREATE INDEX IF NOT EXISTS idx_blocks_minter_operator ON blocks (minter_operator)",
+            "idx_blocks_minter_operator",
+        ),
         (
             "CREATE INDEX IF NOT EXISTS idx_tx_addr_graph_txid ON tx_addr_graph (txid)",
             "idx_tx_addr_graph_txid",
@@ -222,20 +621,20 @@ pub fn sqlite_create_index_factory_v2(
     })
 }
 
-pub fn sqlite_get_stmts_v2(conn: &rusqlite::Connection) -> Result<[CachedStatement<'_>; 3]> {
+pub fn sqlite_get_stmts_v2(conn: &rusqlite::Connection) -> Result<[CachedStatement<'_>; 4]> {
     let insert_block_stmt = conn.prepare_cached(
         "
-        insert or replace into blocks (height, hash, data)
-        values (?1, ?2, ?3)
+        insert or replace into blocks (height, hash, data, minter_id, minter_operator)
+        values (?1, ?2, ?3, ?4, ?5)
     ",
     )?;
 
     let insert_tx_stmt = conn.prepare_cached(
         "
         insert or replace into txs (
-            txid, height, tx_type, tx_in, tx_out, dvm_in, dvm_out, data, icx_data, icx_addr, icx_btc_exp_amt, swap_from, swap_to, swap_amt
+            txid, height, tx_type, tx_in, tx_out, dvm_in, dvm_out, data, icx_data, icx_addr, icx_btc_exp_amt, swap_from, swap_to, swap_amt, icx_dfc_amt, fee
         )
-        values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)
+        values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)
     ",
     )?;
 
@@ -246,7 +645,65 @@ pub fn sqlite_get_stmts_v2(conn: &rusqlite::Connection) -> Result<[CachedStateme
     ",
     )?;
 
-    Ok([insert_block_stmt, insert_tx_stmt, insert_tx_addr_graph_stmt])
+    let insert_coinbase_out_stmt = conn.prepare_cached(
+        "
+        insert or replace into coinbase_outs (address, height, txid)
+        values (?1, ?2, ?3)
+    ",
+    )?;
+
+    Ok([
+        insert_block_stmt,
+        insert_tx_stmt,
+        insert_tx_addr_graph_stmt,
+        insert_coinbase_out_stmt,
+    ])
+}
+
+/// A `txs` row for the test fixtures. Every column defaults to empty, so
+/// tests only set what they check and new columns don't touch them.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct TestTx<'a> {
+    pub txid: &'a str,
+    pub height: i64,
+    pub tx_type: &'a str,
+    pub tx_in: &'a str,
+    pub tx_out: &'a str,
+    pub dvm_in: &'a str,
+    pub dvm_out: &'a str,
+    pub data: &'a str,
+    pub icx_data: &'a str,
+    pub icx_addr: &'a str,
+    pub icx_btc_exp_amt: &'a str,
+    pub swap_from: &'a str,
+    pub swap_to: &'a str,
+    pub swap_amt: &'a str,
+    pub icx_dfc_amt: &'a str,
+    pub fee: f64,
+}
+
+/// Insert `tx` with the txs statement of `SqliteBlockStore::with_stmts`
+#[cfg(test)]
+pub fn insert_test_tx(stmts: &mut [CachedStatement<'_>], tx: &TestTx) -> Result<usize> {
+    Ok(stmts[1].execute(params![
+        tx.txid,
+        tx.height,
+        tx.tx_type,
+        tx.tx_in,
+        tx.tx_out,
+        tx.dvm_in,
+        tx.dvm_out,
+        tx.data,
+        tx.icx_data,
+        tx.icx_addr,
+        tx.icx_btc_exp_amt,
+        tx.swap_from,
+        tx.swap_to,
+        tx.swap_amt,
+        tx.icx_dfc_amt,
+        tx.fee,
+    ])?)
 }
 
 // Raw tx to get around the borrow checker.
@@ -313,19 +770,569 @@ impl BlockStore for SqliteBlockStore {
 }
 
 pub struct SqliteBlockStore {
-    pub conn: Connection,
+    conn: Connection,
+    info: SqliteStoreInfo,
+    // Cleared on commit. Stores that are only read from never commit,
+    // so it's kept for their lifetime.
+    last_max_height: Cell<Option<i64>>,
+}
+
+// Queried once on open, for logging.
+#[derive(Debug)]
+struct SqliteStoreInfo {
+    path: String,
+    journal_mode: String,
+    page_count: u64,
+    page_size: u64,
+}
+
+impl SqliteStoreInfo {
+    fn query(conn: &Connection) -> Result<Self> {
+        let path = conn.query_row("pragma database_list", [], |row| row.get(2))?;
+        let journal_mode = conn.query_row("pragma journal_mode", [], |row| row.get(0))?;
+        let page_count = conn.query_row("pragma page_count", [], |row| row.get(0))?;
+        let page_size = conn.query_row("pragma page_size", [], |row| row.get(0))?;
+        Ok(Self {
+            path,
+            journal_mode,
+            page_count,
+            page_size,
+        })
+    }
+}
+
+impl std::fmt::Debug for SqliteBlockStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SqliteBlockStore")
+            .field("path", &self.info.path)
+            .field("journal_mode", &self.info.journal_mode)
+            .field("page_count", &self.info.page_count)
+            .field("page_size", &self.info.page_size)
+            .finish()
+    }
+}
+
+impl std::fmt::Display for SqliteBlockStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.info.path)
+    }
 }
 
 impl SqliteBlockStore {
+    fn from_conn(conn: Connection) -> Result<Self> {
+        let info = SqliteStoreInfo::query(&conn)?;
+        Ok(Self {
+            conn,
+            info,
+            last_max_height: Cell::new(None),
+        })
+    }
+
+    /// Current size of the db file, excluding the wal.
+    pub fn db_size_bytes(&self) -> Result<u64> {
+        let page_count: u64 = self
+            .conn
+            .query_row("pragma page_count", [], |row| row.get(0))?;
+        Ok(page_count * self.info.page_size)
+    }
+
+    /// Open the store with the legacy v1 schema (`dvm_addrs`, `tx_graph`).
     #[cfg(feature = "legacy-sqlite-v1")]
+    #[deprecated(since = "0.2.0", note = "Use SqliteBlockStore::open instead")]
     pub fn new_v1(path: Option<&str>) -> Result<Self> {
         let conn = crate::db::sqlite_v1::sqlite_init_db_v1(path)?;
-        Ok(Self { conn })
+        Self::from_conn(conn)
     }
 
+    #[deprecated(since = "0.2.0", note = "Use SqliteBlockStore::open instead")]
     pub fn new_v2(path: Option<&str>) -> Result<Self> {
-        let conn = sqlite_init_db_v2(path)?;
-        Ok(Self { conn })
+        Self::open(path)
+    }
+
+    /// Open the store with the current (v2) schema. Base tables are
+    /// created if missing, but schema migrations are not applied.
+    /// Defaults to [`DEFAULT_SQLITE_PATH`].
+    pub fn open(path: Option<&str>) -> Result<Self> {
+        let conn = sqlite_init_db_v2(path, &SqlitePragmaArgs::default())?;
+        Self::from_conn(conn)
+    }
+
+    /// Open an existing store for queries only. Skips the write pragmas
+    /// and table creation and sets `query_only`, so analysis commands can
+    /// read the index while another process is indexing into it.
+    pub fn open_readonly(path: &str) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        conn.execute_batch("pragma query_only=1; pragma locking_mode=normal")?;
+        Self::from_conn(conn)
+    }
+
+    /// Same as [`Self::open`], but also creates the parent directories
+    /// and brings the schema up to date. Use this for stores that are
+    /// written to.
+    pub fn open_or_create(path: &str) -> Result<Self> {
+        Self::open_or_create_with(path, &SqlitePragmaArgs::default())
+    }
+
+    /// [`Self::open_or_create`] with the write pragmas from the indexer
+    /// args.
+    pub fn open_or_create_with(path: &str, pragmas: &SqlitePragmaArgs) -> Result<Self> {
+        if let Some(dir) = std::path::Path::new(path).parent() {
+            if !dir.as_os_str().is_empty() {
+                std::fs::create_dir_all(dir)?;
+            }
+        }
+        let store = Self::from_conn(sqlite_init_db_v2(Some(path), pragmas)?)?;
+        sqlite_migrate_v2(&store.conn)?;
+        Ok(store)
+    }
+
+    /// Open the store for a dry run, without writing anything that the
+    /// rolled back transaction doesn't cover. No file or directories are
+    /// created, a missing store is dry run in memory instead, and
+    /// migrations aren't applied, so a store that needs them is an error.
+    /// An existing store is opened as is, without the init pragmas or
+    /// `pragmas`, as journal_mode and the wal checkpoint write to it.
+    pub fn open_dry_run(path: &str, pragmas: &SqlitePragmaArgs) -> Result<Self> {
+        if !std::path::Path::new(path).exists() {
+            info!("{} doesn't exist, dry run in memory", path);
+            return Self::open_or_create_with(":memory:", pragmas);
+        }
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        let version: usize = conn.query_row("pragma user_version", [], |row| row.get(0))?;
+        if version < SQLITE_MIGRATIONS_V2.len() {
+            return Err(Error::from(format!(
+                "schema of {} is at version {} of {}, run without --dry-run to migrate it first",
+                path,
+                version,
+                SQLITE_MIGRATIONS_V2.len()
+            )));
+        }
+        for table in ["blocks", "txs", "tx_addr_graph"] {
+            let exists: bool = conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1)",
+                params![table],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                return Err(Error::from(format!(
+                    "{} has no {} table, run without --dry-run to create it first",
+                    path, table
+                )));
+            }
+        }
+        Self::from_conn(conn)
+    }
+
+    /// Number of schema migrations applied
+    pub fn schema_version(&self) -> Result<usize> {
+        Ok(self
+            .conn
+            .query_row("pragma user_version", [], |row| row.get(0))?)
+    }
+
+    /// Run `f` with the cached insert statements for
+    /// `[blocks, txs, tx_addr_graph, coinbase_outs]`.
+    pub fn with_stmts<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut [CachedStatement<'_>]) -> Result<R>,
+    {
+        let mut stmts = sqlite_get_stmts_v2(&self.conn)?;
+        f(&mut stmts)
+    }
+
+    pub fn begin_tx(&self) -> Result<()> {
+        sqlite_begin_tx(&self.conn)?;
+        Ok(())
+    }
+
+    /// Commits along with a checkpoint of the highest block, so it's
+    /// always in sync with what's been committed.
+    pub fn commit_tx(&self) -> Result<()> {
+        self.last_max_height.set(None);
+        self.write_checkpoint()?;
+        sqlite_commit_tx(&self.conn)
+    }
+
+    /// Same as [`Self::commit_tx`], then begins a new transaction.
+    pub fn commit_and_begin_tx(&self) -> Result<()> {
+        self.last_max_height.set(None);
+        self.write_checkpoint()?;
+        sqlite_commit_and_begin_tx(&self.conn)?;
+        Ok(())
+    }
+
+    /// Drops everything since the last begin, for dry runs
+    pub fn rollback_tx(&self) -> Result<()> {
+        self.last_max_height.set(None);
+        self.conn.execute_batch("rollback")?;
+        Ok(())
+    }
+
+    fn write_checkpoint(&self) -> Result<()> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO checkpoints (id, last_indexed_height, updated_at)
+            SELECT 0, MAX(height), CAST(strftime('%s', 'now') AS INTEGER) FROM blocks
+            HAVING MAX(height) IS NOT NULL",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Last indexed height as of the last commit, None if nothing has
+    /// been committed yet.
+    pub fn get_checkpoint(&self) -> Result<Option<i64>> {
+        let v = self
+            .conn
+            .query_row(
+                "SELECT last_indexed_height FROM checkpoints WHERE id = 0",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(v)
+    }
+
+    /// Swap count and volume (in from token) per token pair, highest
+    /// volume first. Heights are inclusive, unbounded if not given.
+    pub fn get_pool_swap_stats(
+        &self,
+        start_height: Option<i64>,
+        end_height: Option<i64>,
+    ) -> Result<Vec<SwapStats>> {
+        // swap_amt is stored as formatted text
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT swap_from, swap_to, COUNT(*) as cnt, SUM(CAST(swap_amt AS REAL)) as vol
+            FROM txs
+            WHERE tx_type IN ('ps', 'cs')
+                AND (?1 IS NULL OR height >= ?1)
+                AND (?2 IS NULL OR height <= ?2)
+            GROUP BY swap_from, swap_to
+            ORDER BY vol DESC",
+        )?;
+        let rows = stmt.query_map(params![start_height, end_height], |row| {
+            Ok(SwapStats {
+                from_token: row.get(0)?,
+                to_token: row.get(1)?,
+                tx_count: row.get(2)?,
+                total_amount: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Calls `f` with `(block_time, swap_from, swap_to, swap_amt)` for
+    /// every pool swap from or to `token`, in height order. Times are
+    /// inclusive, unbounded if not given.
+    ///
+    /// Block time is read out of the block json, so this has to go
+    /// through every block with a matching swap.
+    pub fn iter_token_swaps<F>(
+        &self,
+        token: &str,
+        start_ts: Option<i64>,
+        end_ts: Option<i64>,
+        mut f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(i64, &str, &str, &str) -> Result<()>,
+    {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT block_time, swap_from, swap_to, swap_amt FROM (
+                SELECT json_extract(b.data, '$.time') as block_time,
+                    t.swap_from, t.swap_to, t.swap_amt, t.height
+                FROM txs t JOIN blocks b ON t.height = b.height
+                WHERE (t.swap_from = ?1 OR t.swap_to = ?1)
+                    AND t.tx_type IN ('ps', 'cs')
+            )
+            WHERE (?2 IS NULL OR block_time >= ?2)
+                AND (?3 IS NULL OR block_time <= ?3)
+            ORDER BY height",
+        )?;
+        let mut q = stmt.query(params![token, start_ts, end_ts])?;
+        while let Some(row) = q.next()? {
+            let swap_from = row.get_ref(1)?.as_str().map_err(|_| "swap_from error")?;
+            let swap_to = row.get_ref(2)?.as_str().map_err(|_| "swap_to error")?;
+            let swap_amt = row.get_ref(3)?.as_str().map_err(|_| "swap_amt error")?;
+            f(row.get(0)?, swap_from, swap_to, swap_amt)?;
+        }
+        Ok(())
+    }
+
+    /// Replace all data in `start..=end` in one transaction. Existing
+    /// blocks, txs, their graph edges and coinbase outs in the range are
+    /// deleted, then `f` inserts the new data. Rolls back if anything
+    /// fails, so the range is never left half replaced.
+    pub fn atomic_replace_range<F>(&self, start: i64, end: i64, f: F) -> Result<()>
+    where
+        F: FnOnce(&SqliteBlockStore) -> Result<()>,
+    {
+        self.begin_tx()?;
+        let r = (|| {
+            self.conn.execute(
+                "DELETE FROM tx_addr_graph WHERE txid IN
+                    (SELECT txid FROM txs WHERE height BETWEEN ?1 AND ?2)",
+                params![start, end],
+            )?;
+            self.conn.execute(
+                "DELETE FROM coinbase_outs WHERE height BETWEEN ?1 AND ?2",
+                params![start, end],
+            )?;
+            self.conn.execute(
+                "DELETE FROM txs WHERE height BETWEEN ?1 AND ?2",
+                params![start, end],
+            )?;
+            self.conn.execute(
+                "DELETE FROM blocks WHERE height BETWEEN ?1 AND ?2",
+                params![start, end],
+            )?;
+            f(self)
+        })();
+        match r {
+            Ok(()) => self.commit_tx(),
+            Err(e) => {
+                self.last_max_height.set(None);
+                if let Err(rollback_err) = self.conn.execute_batch("rollback") {
+                    error!("rollback failed: {:?}", rollback_err);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    pub fn index_factory(
+        &self,
+    ) -> impl Iterator<Item = (&str, impl Fn() -> rusqlite::Result<()> + '_)> {
+        sqlite_create_index_factory_v2(&self.conn)
+    }
+
+    pub fn drop_index(&self, name: &str) -> Result<()> {
+        let q = format!("DROP INDEX IF EXISTS {}", name);
+        self.conn.execute(&q, [])?;
+        Ok(())
+    }
+
+    /// `(txid, out_addr, c_flags)` of the edges going out of `addr`,
+    /// ordered by txid.
+    pub fn get_outgoing_addresses(
+        &self,
+        addr: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String, i64)>> {
+        self.query_adjacent_addresses(
+            "SELECT txid, out_addr, CAST(c_flags AS INTEGER) FROM tx_addr_graph
+            WHERE in_addr = ?1 ORDER BY txid LIMIT ?2",
+            addr,
+            limit,
+        )
+    }
+
+    /// `(txid, in_addr, c_flags)` of the edges coming into `addr`,
+    /// ordered by txid.
+    pub fn get_incoming_addresses(
+        &self,
+        addr: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String, i64)>> {
+        self.query_adjacent_addresses(
+            "SELECT txid, in_addr, CAST(c_flags AS INTEGER) FROM tx_addr_graph
+            WHERE out_addr = ?1 ORDER BY txid LIMIT ?2",
+            addr,
+            limit,
+        )
+    }
+
+    fn query_adjacent_addresses(
+        &self,
+        query: &str,
+        addr: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String, i64)>> {
+        // Negative limit is no limit in sqlite
+        let limit = limit.map(|x| x as i64).unwrap_or(-1);
+        let mut stmt = self.conn.prepare_cached(query)?;
+        let rows = stmt.query_map(params![addr, limit], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// All addresses within `hops` edges of `addr`, not including `addr`
+    /// itself. Same traversal as gwalk, but straight off the index
+    /// without loading the graph into memory.
+    pub fn get_neighborhood(
+        &self,
+        addr: &str,
+        direction: EdgeDirection,
+        hops: u8,
+    ) -> Result<HashSet<String>> {
+        let mut seen = HashSet::new();
+        let mut queue = VecDeque::new();
+        queue.push_back((addr.to_string(), 0));
+        seen.insert(addr.to_string());
+
+        while let Some((current, depth)) = queue.pop_front() {
+            if depth >= hops {
+                continue;
+            }
+            let mut next = Vec::new();
+            if matches!(direction, EdgeDirection::Outgoing | EdgeDirection::Both) {
+                next.extend(self.get_outgoing_addresses(&current, None)?);
+            }
+            if matches!(direction, EdgeDirection::Incoming | EdgeDirection::Both) {
+                next.extend(self.get_incoming_addresses(&current, None)?);
+            }
+            for (_, neighbor, _) in next {
+                if seen.insert(neighbor.clone()) {
+                    queue.push_back((neighbor, depth + 1));
+                }
+            }
+        }
+
+        seen.remove(addr);
+        Ok(seen)
+    }
+
+    pub fn get_network_info(&self, key: &str) -> Result<Option<String>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT value FROM network_info WHERE key = ?1")?;
+        let v = stmt.query_row(params![key], |row| row.get(0)).optional()?;
+        Ok(v)
+    }
+
+    pub fn set_network_info(&self, key: &str, value: &str) -> Result<()> {
+        self.conn.execute(
+            "insert or replace into network_info (key, value) values (?1, ?2)",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    pub fn count_blocks(&self) -> Result<i64> {
+        let v = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM blocks", [], |row| row.get(0))?;
+        Ok(v)
+    }
+
+    /// Highest indexed block height, None if there are no blocks.
+    /// Cached until the next commit.
+    pub fn max_block_height(&self) -> Result<Option<i64>> {
+        if let Some(h) = self.last_max_height.get() {
+            return Ok(Some(h));
+        }
+        // height is the rowid, so this is a single btree lookup
+        let v: Option<i64> = self
+            .conn
+            .query_row("SELECT MAX(height) FROM blocks", [], |row| row.get(0))?;
+        self.last_max_height.set(v);
+        Ok(v)
+    }
+
+    /// Highest rowid of `txs`, None if there are no txs. Rows are appended
+    /// in indexing order, so it marks how far the txs have been read.
+    pub fn max_tx_rowid(&self) -> Result<Option<i64>> {
+        Ok(self
+            .conn
+            .query_row("SELECT MAX(rowid) FROM txs", [], |row| row.get(0))?)
+    }
+
+    /// Average txs per block over the whole index, rounded up. None on
+    /// an empty store.
+    pub fn avg_txs_per_block(&self) -> Result<Option<u64>> {
+        let (Some(txs), Some(height)) = (self.max_tx_rowid()?, self.max_block_height()?) else {
+            return Ok(None);
+        };
+        Ok(Some((txs.max(0) as u64).div_ceil(height.max(0) as u64 + 1)))
+    }
+
+    /// Height to continue indexing from, right after the checkpoint.
+    /// Falls back to the highest block for indexes built before there
+    /// were checkpoints. 0 on an empty store.
+    pub fn resume_height(&self) -> Result<u32> {
+        let last = match self.get_checkpoint()? {
+            Some(h) => Some(h),
+            None => self.max_block_height()?,
+        };
+        match last {
+            Some(h) => Ok(u32::try_from(h + 1)?),
+            None => Ok(0),
+        }
+    }
+
+    pub fn min_block_height(&self) -> Result<Option<i64>> {
+        let v = self
+            .conn
+            .query_row("SELECT MIN(height) FROM blocks", [], |row| row.get(0))?;
+        Ok(v)
+    }
+
+    pub fn count_txs(&self) -> Result<i64> {
+        let v = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM txs", [], |row| row.get(0))?;
+        Ok(v)
+    }
+
+    /// `(tx_type, count)`, most common first
+    pub fn count_txs_by_type(&self) -> Result<Vec<(String, i64)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT tx_type, COUNT(*) AS n FROM txs GROUP BY tx_type ORDER BY n DESC, tx_type",
+        )?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Unique addresses on either side of the `tx_addr_graph` edges
+    pub fn count_graph_addresses(&self) -> Result<i64> {
+        let v = self.conn.query_row(
+            "SELECT COUNT(*) FROM (
+                SELECT in_addr FROM tx_addr_graph UNION SELECT out_addr FROM tx_addr_graph
+            )",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(v)
+    }
+
+    /// First `limit` runs of missing heights between the lowest and highest
+    /// block, as inclusive `(start, end)`.
+    pub fn get_height_gaps(&self, limit: usize) -> Result<Vec<(i64, i64)>> {
+        let mut stmt = self.conn.prepare(
+            "WITH gaps AS (
+                SELECT height + 1 AS start FROM blocks
+                WHERE height + 1 NOT IN (SELECT height FROM blocks)
+                    AND height < (SELECT MAX(height) FROM blocks)
+            )
+            SELECT start, (SELECT MIN(height) FROM blocks WHERE height > start) - 1
+            FROM gaps ORDER BY start LIMIT ?1",
+        )?;
+        let rows = stmt.query_map(params![limit as i64], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    pub fn get_page_stats(&self) -> Result<PageStats> {
+        let pragma = |name: &str| -> Result<i64> {
+            Ok(self
+                .conn
+                .query_row(&format!("pragma {}", name), [], |row| row.get(0))?)
+        };
+        Ok(PageStats {
+            page_size: self.info.page_size,
+            page_count: pragma("page_count")?,
+            freelist_count: pragma("freelist_count")?,
+            cache_size: pragma("cache_size")?,
+        })
     }
 
     // Note index for this might not be there in the beginning.
@@ -429,8 +1436,15 @@ impl SqliteBlockStore {
 
         match tx_data {
             Some((tx_in_data, tx_out_data, tx_type)) => {
-                let tx_in: HashMap<String, f64> = serde_json::from_str(&tx_in_data)?;
-                let tx_out: HashMap<String, f64> = serde_json::from_str(&tx_out_data)?;
+                // Empty for coinbases and txs without outs
+                let parse = |s: &str| -> Result<HashMap<String, f64>> {
+                    match s {
+                        "" => Ok(HashMap::new()),
+                        s => Ok(serde_json::from_str(s)?),
+                    }
+                };
+                let tx_in = parse(&tx_in_data)?;
+                let tx_out = parse(&tx_out_data)?;
 
                 let tx_addr_data = TxAddrData {
                     tx_type,
@@ -462,6 +1476,81 @@ impl SqliteBlockStore {
         Ok(())
     }
 
+    /// Blocks in `start..=end` in height order, off one range scan instead
+    /// of a lookup per height
+    pub fn iter_blocks_range<F>(&self, start: i64, end: i64, mut f: F) -> Result<()>
+    where
+        F: FnMut(Result<Block>) -> Result<()>,
+    {
+        let mut stmt = self.conn.prepare_cached(BLOCKS_IN_RANGE_QUERY)?;
+        let mut q = stmt.query([start, end])?;
+        while let Some(row) = q.next()? {
+            let data: &str = row.get_ref(0)?.as_str().map_err(|_| "ref error")?;
+            f(serde_json::from_str(data).map_err(|e| e.into()))?;
+        }
+        Ok(())
+    }
+
+    /// Lowest height of a block with a `time` at or after `ts`, None if
+    /// every block is older. The time is only in the block json, so this
+    /// binary searches the heights with a lookup per step rather than
+    /// scanning. Block times are only roughly ordered, so near `ts` the
+    /// height can be a few blocks off.
+    pub fn height_for_timestamp(&self, ts: i64) -> Result<Option<i64>> {
+        let (Some(mut lo), Some(max)) = (self.min_block_height()?, self.max_block_height()?) else {
+            return Ok(None);
+        };
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT height, json_extract(data, '$.time') FROM blocks
+            WHERE height >= ?1 ORDER BY height LIMIT 1",
+        )?;
+        let mut hi = max + 1;
+        let mut found = None;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            // The next block from mid, as the index can have gaps
+            let (h, time): (i64, i64) =
+                stmt.query_row([mid], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            if time >= ts {
+                found = Some(h);
+                hi = mid;
+            } else {
+                lo = h + 1;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Like `iter_blocks`, over the blocks with a `time` in
+    /// `start_timestamp..=end_timestamp`, in height order
+    pub fn iter_blocks_with_range<F>(
+        &self,
+        start_timestamp: i64,
+        end_timestamp: i64,
+        f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(Result<Block>) -> Result<()>,
+    {
+        let Some(start) = self.height_for_timestamp(start_timestamp)? else {
+            return Ok(());
+        };
+        let end = match self.height_for_timestamp(end_timestamp.saturating_add(1))? {
+            Some(h) => h - 1,
+            None => self.max_block_height()?.unwrap_or_default(),
+        };
+        self.iter_blocks_range(start, end, f)
+    }
+
+    /// Heights of the blocks minted by the masternode `minter_id`
+    pub fn get_blocks_by_minter(&self, minter_id: &str) -> Result<Vec<i64>> {
+        let mut stmt = self.conn.prepare_cached(BLOCKS_BY_MINTER_QUERY)?;
+        let heights = stmt
+            .query_map([minter_id], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<i64>>>()?;
+        Ok(heights)
+    }
+
     fn iter_table<F>(&self, table: &str, modifier: Option<&str>, mut f: F) -> Result<()>
     where
         F: FnMut(Result<&Row>) -> Result<()>,
@@ -513,12 +1602,198 @@ impl SqliteBlockStore {
         let mut stmt = self.conn.prepare(&query)?;
         let mut q = stmt.query([])?;
         while let Some(row) = q.next()? {
-            let tx_row = TxRow::from_sqlite_row(row)?;
-            f(Ok(tx_row))?;
+            // Row errors are passed on, so callers can choose to skip them
+            f(TxRow::from_sqlite_row(row))?;
         }
         Ok(())
     }
 
+    /// Iterate txs with `start <= height <= end` in height order. Uses
+    /// `idx_txs_height` when it's there.
+    pub fn iter_txs_in_range<F>(&self, start: i64, end: i64, mut f: F) -> Result<()>
+    where
+        F: FnMut(Result<TxRow>) -> Result<()>,
+    {
+        let mut stmt = self.conn.prepare_cached(TXS_IN_RANGE_QUERY)?;
+        let mut q = stmt.query(params![start, end])?;
+        while let Some(row) = q.next()? {
+            // Row errors are passed on, so callers can choose to skip them
+            f(TxRow::from_sqlite_row(row))?;
+        }
+        Ok(())
+    }
+
+    /// Txs of a single type with heights in `range`, in height order.
+    pub fn iter_txs_by_type<F>(
+        &self,
+        tx_type: &TxType,
+        range: RangeInclusive<i64>,
+        mut f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(Result<TxRow>) -> Result<()>,
+    {
+        let mut stmt = self.conn.prepare_cached(TXS_BY_TYPE_QUERY)?;
+        let mut q = stmt.query(params![tx_type.to_string(), range.start(), range.end()])?;
+        while let Some(row) = q.next()? {
+            // Row errors are passed on, so callers can choose to skip them
+            f(TxRow::from_sqlite_row(row))?;
+        }
+        Ok(())
+    }
+
+    pub fn get_txs_by_type(
+        &self,
+        tx_type: &TxType,
+        start_height: i64,
+        end_height: i64,
+    ) -> Result<Vec<TxRow>> {
+        let mut txs = Vec::new();
+        self.iter_txs_by_type(tx_type, start_height..=end_height, |tx| {
+            txs.push(tx?);
+            Ok(())
+        })?;
+        Ok(txs)
+    }
+
+    /// Txs up to `end_height` where `addr` is in the utxo ins or outs, or
+    /// is a DVM address. Rows are partial, without the tx data.
+    pub fn iter_txs_for_addr<F>(&self, addr: &str, end_height: i64, mut f: F) -> Result<()>
+    where
+        F: FnMut(Result<TxRow>) -> Result<()>,
+    {
+        if addr.is_empty() || !addr.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(Error::from(format!("invalid address: {}", addr)));
+        }
+        let pattern = format!("%\"{}\"%", addr);
+        let mut stmt = self.conn.prepare_cached(TXS_FOR_ADDR_QUERY)?;
+        let mut q = stmt.query(params![pattern, end_height])?;
+        while let Some(row) = q.next()? {
+            // Row errors are passed on, so callers can choose to skip them
+            f(TxRow::from_sqlite_row_partial(row))?;
+        }
+        Ok(())
+    }
+
+    /// Txs in `start..=end` with a `tx_addr_graph` edge from or to `addr`.
+    /// Txs without ins, like coinbases, have no edges and aren't found.
+    /// Rows are partial, without the tx data.
+    pub fn iter_txs_for_addr_graph<F>(
+        &self,
+        addr: &str,
+        start: i64,
+        end: i64,
+        mut f: F,
+    ) -> Result<()>
+    where
+        F: FnMut(Result<TxRow>) -> Result<()>,
+    {
+        let mut stmt = self.conn.prepare_cached(TXS_FOR_ADDR_GRAPH_QUERY)?;
+        let mut q = stmt.query(params![addr, start, end])?;
+        while let Some(row) = q.next()? {
+            f(TxRow::from_sqlite_row_partial(row))?;
+        }
+        Ok(())
+    }
+
+    /// Txids in `start..=end` with a `tx_addr_graph` edge from or to
+    /// `addr`, ordered by height. Same coverage as
+    /// `iter_txs_for_addr_graph`, so coinbases aren't found.
+    pub fn get_address_txs(&self, addr: &str, start: i64, end: i64) -> Result<Vec<String>> {
+        self.query_address_txids(ADDR_TXIDS_QUERY, addr, start, end)
+    }
+
+    /// The coinbases paying out to `addr`, which `get_address_txs` misses.
+    /// Looked up through `coinbase_outs`.
+    pub fn get_address_coinbase_txs(
+        &self,
+        addr: &str,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<String>> {
+        self.query_address_txids(ADDR_COINBASE_TXIDS_QUERY, addr, start, end)
+    }
+
+    /// Like `get_address_txs`, for the txs where `addr` is a tx in
+    pub fn get_address_in_txs(&self, addr: &str, start: i64, end: i64) -> Result<Vec<String>> {
+        self.query_address_txids(ADDR_IN_TXIDS_QUERY, addr, start, end)
+    }
+
+    /// Like `get_address_txs`, for the txs where `addr` is a tx out
+    pub fn get_address_out_txs(&self, addr: &str, start: i64, end: i64) -> Result<Vec<String>> {
+        self.query_address_txids(ADDR_OUT_TXIDS_QUERY, addr, start, end)
+    }
+
+    fn query_address_txids(
+        &self,
+        query: &str,
+        addr: &str,
+        start: i64,
+        end: i64,
+    ) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare_cached(query)?;
+        let rows = stmt.query_map(params![addr, start, end], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Stage how the ICX txs refer to each other in the temp table
+    /// `icx_links`, so chains can be followed with indexed lookups instead
+    /// of holding every ICX tx in memory. Offers refer to their order, HTLCs
+    /// to their offer and claims to their DFC HTLC, from the msg or else
+    /// the icx data from the logs. Returns the number of txs staged.
+    pub fn create_icx_links_table(&self) -> Result<usize> {
+        self.conn.execute_batch(
+            "pragma temp_store=file;
+            DROP TABLE IF EXISTS temp.icx_links;
+            CREATE TEMP TABLE icx_links (
+                txid TEXT PRIMARY KEY,
+                height INTEGER NOT NULL,
+                tx_type TEXT NOT NULL,
+                order_tx TEXT,
+                offer_tx TEXT,
+                dfchtlc_tx TEXT
+            );",
+        )?;
+        let n = self.conn.execute(
+            "INSERT INTO temp.icx_links
+            SELECT txid, height, tx_type,
+                json_extract(nullif(data, ''), '$.vm.msg.orderTx'),
+                json_extract(nullif(data, ''), '$.vm.msg.offerTx'),
+                coalesce(
+                    json_extract(nullif(data, ''), '$.vm.msg.dfchtlcTx'),
+                    json_extract(nullif(icx_data, ''), '$.dfchtlc_tx')
+                )
+            FROM txs WHERE tx_type IN (?1, ?2, ?3, ?4)",
+            params![
+                TxType::ICXMakeOffer.to_string(),
+                TxType::ICXSubmitDFCHTLC.to_string(),
+                TxType::ICXSubmitEXTHTLC.to_string(),
+                TxType::ICXClaimDFCHTLC.to_string(),
+            ],
+        )?;
+        self.conn.execute_batch(
+            "CREATE INDEX temp.idx_icx_links_order_tx ON icx_links (order_tx);
+            CREATE INDEX temp.idx_icx_links_offer_tx ON icx_links (offer_tx);
+            CREATE INDEX temp.idx_icx_links_dfchtlc_tx ON icx_links (dfchtlc_tx);",
+        )?;
+        Ok(n)
+    }
+
+    /// Txids in `icx_links` that refer to `txid` through `link`, in height
+    /// order.
+    pub fn get_icx_links(&self, link: IcxLink, txid: &str) -> Result<Vec<String>> {
+        let query = match link {
+            IcxLink::Order => "SELECT txid FROM temp.icx_links WHERE order_tx = ?1 ORDER BY height",
+            IcxLink::Offer => "SELECT txid FROM temp.icx_links WHERE offer_tx = ?1 ORDER BY height",
+            IcxLink::DfcHtlc => {
+                "SELECT txid FROM temp.icx_links WHERE dfchtlc_tx = ?1 ORDER BY height"
+            }
+        };
+        let mut stmt = self.conn.prepare_cached(query)?;
+        let rows = stmt.query_map(params![txid], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
     pub fn iter_txs_partial<F>(&self, modifier: Option<&str>, mut f: F) -> Result<()>
     where
         F: FnMut(Result<TxRow>) -> Result<()>,
@@ -531,9 +1806,7 @@ impl SqliteBlockStore {
         let mut q = stmt.query([])?;
         while let Some(row) = q.next()? {
             // debug!("{:?}", row);
-            let tx_row = TxRow::from_sqlite_row_partial(row)?;
-            // let tx_row = TxRow::default();
-            f(Ok(tx_row))?;
+            f(TxRow::from_sqlite_row_partial(row))?;
         }
         Ok(())
     }
@@ -550,4 +1823,1044 @@ impl SqliteBlockStore {
             .optional()?;
         Ok(tx_row)
     }
+
+    /// `get_tx_data` without the `data` and `icx_data` json, for the graph
+    /// walkers that look up a tx per edge. The two are selected as empty
+    /// so the columns stay where `from_sqlite_row_partial` reads them, and
+    /// the migration columns after them are left at their defaults.
+    pub fn get_tx_data_partial(&self, txid: &str) -> Result<Option<TxRow>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT txid, height, tx_type, tx_in, tx_out, dvm_in, dvm_out, '', '',
+                icx_addr, icx_btc_exp_amt, swap_from, swap_to, swap_amt
+            FROM txs WHERE txid = ?1",
+        )?;
+        let mut rows = stmt.query(params![txid])?;
+        match rows.next()? {
+            Some(row) => Ok(Some(TxRow::from_sqlite_row_partial(row)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_tx_height(&self, txid: &str) -> Result<Option<i64>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT height FROM txs WHERE txid = ?1")?;
+        Ok(stmt.query_row(params![txid], |row| row.get(0)).optional()?)
+    }
+
+    /// Replace all of `address_clusters` with the `(address, cluster_id)`
+    /// pairs, in one transaction.
+    pub fn replace_address_clusters<'a, I>(&self, clusters: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (&'a str, i64)>,
+    {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM address_clusters", [])?;
+        let mut n = 0;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO address_clusters (address, cluster_id) VALUES (?1, ?2)",
+            )?;
+            for (addr, id) in clusters {
+                n += stmt.execute(params![addr, id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(n)
+    }
+
+    pub fn get_cluster_id(&self, addr: &str) -> Result<Option<i64>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT cluster_id FROM address_clusters WHERE address = ?1")?;
+        Ok(stmt.query_row(params![addr], |row| row.get(0)).optional()?)
+    }
+
+    pub fn get_cluster_addresses(&self, cluster_id: i64) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT address FROM address_clusters WHERE cluster_id = ?1 ORDER BY address",
+        )?;
+        let rows = stmt.query_map(params![cluster_id], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+
+    /// Replace all of `address_cospend` with the `((addr_a, addr_b),
+    /// tx_count)` pairs, in one transaction.
+    pub fn replace_address_cospend<'a, I>(&self, pairs: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = ((&'a str, &'a str), i64)>,
+    {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM address_cospend", [])?;
+        let mut n = 0;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO address_cospend (addr_a, addr_b, tx_count) VALUES (?1, ?2, ?3)",
+            )?;
+            for ((a, b), count) in pairs {
+                n += stmt.execute(params![a, b, count])?;
+            }
+        }
+        tx.commit()?;
+        Ok(n)
+    }
+
+    /// All the addresses transitively linked to `addr` through co-spends,
+    /// `addr` included. Empty if it was never co-spent.
+    pub fn get_cospend_group(&self, addr: &str) -> Result<Vec<String>> {
+        let mut stmt = self.conn.prepare_cached(
+            "WITH RECURSIVE grp(addr) AS (
+                SELECT ?1 WHERE EXISTS (
+                    SELECT 1 FROM address_cospend WHERE addr_a = ?1 OR addr_b = ?1
+                )
+                UNION
+                SELECT CASE WHEN c.addr_a = grp.addr THEN c.addr_b ELSE c.addr_a END
+                FROM address_cospend c JOIN grp
+                    ON c.addr_a = grp.addr OR c.addr_b = grp.addr
+            )
+            SELECT addr FROM grp ORDER BY addr",
+        )?;
+        let rows = stmt.query_map(params![addr], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+    }
+}
+
+/// When the indexers commit their write transaction: on every `blocks`-th
+/// height, and with `bytes` set, also once the db has grown by that much
+/// since the last commit.
+#[derive(Debug)]
+pub struct BatchCommit {
+    blocks: u32,
+    bytes: Option<u64>,
+    last_size: u64,
+}
+
+impl BatchCommit {
+    pub fn new(store: &SqliteBlockStore, blocks: u32, bytes: Option<u64>) -> Result<Self> {
+        if blocks == 0 {
+            return Err("batch size must be at least 1".into());
+        }
+        Ok(Self {
+            blocks,
+            bytes,
+            last_size: store.db_size_bytes()?,
+        })
+    }
+
+    /// Whether to commit after `height`. The size baseline is reset when
+    /// it returns true, so the caller has to commit then.
+    pub fn is_due(&mut self, store: &SqliteBlockStore, height: i64) -> Result<bool> {
+        let due_blocks = height % i64::from(self.blocks) == 0;
+        let Some(bytes) = self.bytes else {
+            return Ok(due_blocks);
+        };
+        let size = store.db_size_bytes()?;
+        let due = due_blocks || size.saturating_sub(self.last_size) >= bytes;
+        if due {
+            self.last_size = size;
+        }
+        Ok(due)
+    }
+}
+
+#[test]
+fn test_tx_row_icx_amt_mapping() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            insert_test_tx(
+                stmts,
+                &TestTx {
+                    txid: "tx1",
+                    height: 1,
+                    tx_type: "icx-claim",
+                    icx_addr: "addr1",
+                    icx_btc_exp_amt: "0.5",
+                    icx_dfc_amt: "6000.00000000",
+                    ..Default::default()
+                },
+            )?;
+            insert_test_tx(
+                stmts,
+                &TestTx {
+                    txid: "tx2",
+                    height: 2,
+                    tx_type: "icx-claim",
+                    icx_dfc_amt: "1.0",
+                    ..Default::default()
+                },
+            )?;
+            Ok(())
+        })
+        .unwrap();
+
+    let mut rows = Vec::new();
+    store
+        .iter_txs(Some("order by height"), |tx| {
+            rows.push(tx?);
+            Ok(())
+        })
+        .unwrap();
+
+    assert_eq!(rows[0].icx_addr, "addr1");
+    assert_eq!(rows[0].icx_btc_exp_amt, "0.5");
+    assert_eq!(rows[0].icx_dfc_amt, "6000.00000000");
+    assert_eq!(rows[0].icx_amount_type(), Some(IcxAmountType::BtcExpected));
+    assert_eq!(rows[1].icx_amount_type(), Some(IcxAmountType::DfcExpected));
+}
+
+#[test]
+fn test_iter_txs_in_range_uses_height_index() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    for (_, indexer) in store.index_factory() {
+        indexer().unwrap();
+    }
+    let plan: String = store
+        .conn
+        .query_row(
+            &format!("EXPLAIN QUERY PLAN {}", TXS_IN_RANGE_QUERY),
+            params![0, 10],
+            |row| row.get(3),
+        )
+        .unwrap();
+    assert!(plan.contains("USING INDEX idx_txs_height"), "{}", plan);
+}
+
+#[test]
+fn test_iter_txs_by_type_uses_type_height_index() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    for (_, indexer) in store.index_factory() {
+        indexer().unwrap();
+    }
+    let plan: String = store
+        .conn
+        .query_row(
+            &format!("EXPLAIN QUERY PLAN {}", TXS_BY_TYPE_QUERY),
+            params!["icx-claim", 0, 10],
+            |row| row.get(3),
+        )
+        .unwrap();
+    assert!(
+        plan.contains("USING INDEX idx_txs_type_height (tx_type=? AND height>? AND height<?)"),
+        "{}",
+        plan
+    );
+}
+
+#[test]
+fn test_iter_txs_for_addr_graph_uses_addr_indexes() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    for (_, indexer) in store.index_factory() {
+        indexer().unwrap();
+    }
+    let mut stmt = store
+        .conn
+        .prepare(&format!("EXPLAIN QUERY PLAN {}", TXS_FOR_ADDR_GRAPH_QUERY))
+        .unwrap();
+    let plan = stmt
+        .query_map(params!["a", 0, 10], |row| row.get::<_, String>(3))
+        .unwrap()
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .unwrap()
+        .join("\n");
+    assert!(
+        plan.contains("USING INDEX idx_tx_addr_graph_in_addr"),
+        "{}",
+        plan
+    );
+    assert!(
+        plan.contains("USING INDEX idx_tx_addr_graph_out_addr"),
+        "{}",
+        plan
+    );
+}
+
+#[test]
+fn test_get_address_txs() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            for (txid, height) in [("t1", 1), ("t2", 2), ("t3", 3), ("t4", 9)] {
+                insert_test_tx(
+                    stmts,
+                    &TestTx {
+                        txid,
+                        height,
+                        tx_type: "u",
+                        ..Default::default()
+                    },
+                )?;
+            }
+            let edges = [
+                ("t1", "b", "a"),
+                ("t1", "b", "b"),
+                ("t2", "a", "c"),
+                ("t3", "c", "d"),
+                ("t4", "d", "a"),
+                ("t4", "a", "a"),
+            ];
+            for (txid, i, o) in edges {
+                stmts[2].execute(params![txid, i, o, 0])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+    for (_, indexer) in store.index_factory() {
+        indexer().unwrap();
+    }
+
+    assert_eq!(
+        store.get_address_txs("a", 0, 10).unwrap(),
+        ["t1", "t2", "t4"]
+    );
+    assert_eq!(store.get_address_txs("a", 2, 5).unwrap(), ["t2"]);
+    assert_eq!(store.get_address_in_txs("a", 0, 10).unwrap(), ["t2", "t4"]);
+    assert_eq!(store.get_address_out_txs("a", 0, 10).unwrap(), ["t1", "t4"]);
+    assert!(store.get_address_txs("z", 0, 10).unwrap().is_empty());
+
+    // txs is only hit by txid
+    for query in [ADDR_TXIDS_QUERY, ADDR_IN_TXIDS_QUERY, ADDR_OUT_TXIDS_QUERY] {
+        let mut stmt = store
+            .conn
+            .prepare(&format!("EXPLAIN QUERY PLAN {}", query))
+            .unwrap();
+        let plan = stmt
+            .query_map(params!["a", 0, 10], |row| row.get::<_, String>(3))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap()
+            .join("\n");
+        assert!(!plan.contains("SCAN txs"), "{}", plan);
+        assert!(!plan.contains("SCAN tx_addr_graph"), "{}", plan);
+    }
+}
+
+#[test]
+fn test_get_address_coinbase_txs() {
+    let conn = Connection::open_in_memory().unwrap();
+    sqlite_init_tables_v2(&conn).unwrap();
+    let migrations = SQLITE_MIGRATIONS_V2.len() - 1;
+    sqlite_apply_migrations(&conn, &SQLITE_MIGRATIONS_V2[..migrations]).unwrap();
+    let insert = |txid: &str, height: i64, tx_type: &str, tx_out: &str| {
+        conn.execute(
+            "INSERT INTO txs (txid, height, tx_type, tx_in, tx_out, dvm_in, dvm_out, data,
+                icx_data, icx_addr, icx_btc_exp_amt, swap_from, swap_to, swap_amt)
+            VALUES (?1, ?2, ?3, '', ?4, '', '', '', '', '', '', '', '', '')",
+            params![txid, height, tx_type, tx_out],
+        )
+        .unwrap();
+    };
+    insert("c1", 1, "cb", r#"{"a":50.0,"b":0.0}"#);
+    insert("u2", 2, "utxo", r#"{"a":1.0}"#);
+    insert("c3", 3, "cb", r#"{"a":50.0}"#);
+    // Filtered out coinbase stub
+    insert("c4", 4, "cb", "");
+
+    // Coinbases indexed before the table are filled in by the migration
+    let store = SqliteBlockStore::from_conn(conn).unwrap();
+    sqlite_apply_migrations(&store.conn, SQLITE_MIGRATIONS_V2).unwrap();
+    assert_eq!(
+        store.get_address_coinbase_txs("a", 0, 10).unwrap(),
+        ["c1", "c3"]
+    );
+    assert_eq!(store.get_address_coinbase_txs("a", 2, 10).unwrap(), ["c3"]);
+    assert_eq!(store.get_address_coinbase_txs("b", 0, 10).unwrap(), ["c1"]);
+    assert!(store
+        .get_address_coinbase_txs("a%", 0, 10)
+        .unwrap()
+        .is_empty());
+
+    store
+        .with_stmts(|stmts| Ok(stmts[3].execute(params!["b", 5, "c5"])?))
+        .unwrap();
+    assert_eq!(
+        store.get_address_coinbase_txs("b", 0, 10).unwrap(),
+        ["c1", "c5"]
+    );
+
+    let mut stmt = store
+        .conn
+        .prepare(&format!("EXPLAIN QUERY PLAN {}", ADDR_COINBASE_TXIDS_QUERY))
+        .unwrap();
+    let plan = stmt
+        .query_map(params!["a", 0, 10], |row| row.get::<_, String>(3))
+        .unwrap()
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .unwrap()
+        .join("\n");
+    assert!(!plan.contains("SCAN"), "{}", plan);
+}
+
+#[test]
+fn test_validate_sqlite_path_write() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("validate-write-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("index.sqlite");
+    let path_str = path.to_str().unwrap();
+    assert!(validate_sqlite_path(path_str, PathMode::Write).is_ok());
+    std::fs::write(&path, "").unwrap();
+    assert!(validate_sqlite_path(path_str, PathMode::Write).is_ok());
+    // No probe file left behind
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    let missing = dir.join("missing").join("index.sqlite");
+    assert!(validate_sqlite_path(missing.to_str().unwrap(), PathMode::Write).is_err());
+
+    // Read-only directory, with a writable file in it. Root can still
+    // write to it, so the check has to match what a write really does.
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o555)).unwrap();
+    let writable = std::fs::File::create(dir.join("probe")).is_ok();
+    let _ = std::fs::remove_file(dir.join("probe"));
+    assert_eq!(
+        validate_sqlite_path(path_str, PathMode::Write).is_ok(),
+        writable
+    );
+    std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o444)).unwrap();
+    let writable = std::fs::OpenOptions::new().write(true).open(&path).is_ok();
+    assert_eq!(
+        validate_sqlite_path(path_str, PathMode::Write).is_ok(),
+        writable
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_open_readonly() {
+    let dir = std::env::temp_dir().join(format!("open-readonly-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("index.sqlite").to_string_lossy().into_owned();
+    let writer = SqliteBlockStore::open_or_create(&path).unwrap();
+    writer
+        .with_stmts(|stmts| {
+            stmts[0].execute(params![
+                1,
+                "h1",
+                "{}",
+                rusqlite::types::Null,
+                rusqlite::types::Null
+            ])?;
+            Ok(())
+        })
+        .unwrap();
+
+    // Readable while the writer has a write transaction open
+    writer.begin_tx().unwrap();
+    writer
+        .with_stmts(|stmts| {
+            stmts[0].execute(params![
+                2,
+                "h2",
+                "{}",
+                rusqlite::types::Null,
+                rusqlite::types::Null
+            ])?;
+            Ok(())
+        })
+        .unwrap();
+    let reader = SqliteBlockStore::open_readonly(&path).unwrap();
+    assert_eq!(reader.get_block_hash(1).unwrap().as_deref(), Some("h1"));
+    assert_eq!(reader.get_block_hash(2).unwrap(), None);
+    assert!(reader
+        .with_stmts(|stmts| Ok(stmts[0].execute(params![
+            3,
+            "h3",
+            "{}",
+            rusqlite::types::Null,
+            rusqlite::types::Null
+        ])?))
+        .is_err());
+    writer.commit_tx().unwrap();
+    assert_eq!(reader.get_block_hash(2).unwrap().as_deref(), Some("h2"));
+
+    assert!(SqliteBlockStore::open_readonly(&dir.join("missing").to_string_lossy()).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_batch_commit() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    assert!(BatchCommit::new(&store, 0, None).is_err());
+
+    let mut batch = BatchCommit::new(&store, 10, None).unwrap();
+    let due = (1..=25)
+        .filter(|x| batch.is_due(&store, *x).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(due, [10, 20]);
+
+    let page_size = store.get_page_stats().unwrap().page_size;
+    let mut batch = BatchCommit::new(&store, 1000, Some(4 * page_size)).unwrap();
+    assert!(!batch.is_due(&store, 1).unwrap());
+    let data = "x".repeat(8 * page_size as usize);
+    store
+        .with_stmts(|stmts| {
+            stmts[0].execute(params![
+                2,
+                "h2",
+                data,
+                rusqlite::types::Null,
+                rusqlite::types::Null
+            ])?;
+            Ok(())
+        })
+        .unwrap();
+    assert!(batch.is_due(&store, 2).unwrap());
+    // Measured from the last commit on
+    assert!(!batch.is_due(&store, 3).unwrap());
+    assert!(batch.is_due(&store, 1000).unwrap());
+}
+
+#[test]
+fn test_atomic_replace_range_rollback() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    let insert_block = |store: &SqliteBlockStore, height: i64, hash: &str| {
+        store.with_stmts(|stmts| {
+            stmts[0].execute(params![
+                height,
+                hash,
+                "{}",
+                rusqlite::types::Null,
+                rusqlite::types::Null
+            ])?;
+            Ok(())
+        })
+    };
+    insert_block(&store, 1, "old1").unwrap();
+    insert_block(&store, 2, "old2").unwrap();
+
+    let r = store.atomic_replace_range(1, 2, |store| {
+        insert_block(store, 1, "new1")?;
+        Err("fail mid way".into())
+    });
+    assert!(r.is_err());
+    assert_eq!(store.get_block_hash(1).unwrap().as_deref(), Some("old1"));
+    assert_eq!(store.get_block_hash(2).unwrap().as_deref(), Some("old2"));
+
+    store
+        .atomic_replace_range(1, 2, |store| insert_block(store, 1, "new1"))
+        .unwrap();
+    assert_eq!(store.get_block_hash(1).unwrap().as_deref(), Some("new1"));
+    assert_eq!(store.get_block_hash(2).unwrap(), None);
+}
+
+#[test]
+fn test_get_pool_swap_stats() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            let swaps = [
+                ("s1", 1, "ps", "btc", "dfi", "0.5"),
+                ("s2", 2, "ps", "btc", "dfi", "0.25"),
+                ("s3", 3, "cs", "dfi", "dusd", "100.0"),
+                ("s4", 10, "ps", "btc", "dfi", "2.0"),
+                ("x1", 2, "aa", "", "", ""),
+            ];
+            for (txid, height, tx_type, from, to, amt) in swaps {
+                insert_test_tx(
+                    stmts,
+                    &TestTx {
+                        txid,
+                        height,
+                        tx_type,
+                        swap_from: from,
+                        swap_to: to,
+                        swap_amt: amt,
+                        ..Default::default()
+                    },
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let stats = store.get_pool_swap_stats(None, None).unwrap();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(
+        (stats[0].from_token.as_str(), stats[0].to_token.as_str()),
+        ("dfi", "dusd")
+    );
+    assert_eq!(stats[1].tx_count, 3);
+    assert!((stats[1].total_amount - 2.75).abs() < 1e-9);
+
+    let stats = store.get_pool_swap_stats(Some(2), Some(5)).unwrap();
+    let btc = stats.iter().find(|x| x.from_token == "btc").unwrap();
+    assert_eq!(btc.tx_count, 1);
+    assert!((btc.total_amount - 0.25).abs() < 1e-9);
+}
+
+#[test]
+fn test_get_neighborhood() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            let edges = [
+                ("t1", "a", "b", 0),
+                ("t2", "b", "c", 1),
+                ("t3", "d", "a", 2),
+            ];
+            for (txid, in_addr, out_addr, c_flags) in edges {
+                stmts[2].execute(params![txid, in_addr, out_addr, c_flags])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    assert_eq!(
+        store.get_outgoing_addresses("b", None).unwrap(),
+        [("t2".to_string(), "c".to_string(), 1)]
+    );
+    assert_eq!(store.get_incoming_addresses("a", Some(0)).unwrap(), []);
+
+    let set = |addrs: &[&str]| addrs.iter().map(|x| x.to_string()).collect::<HashSet<_>>();
+    let n = |direction, hops| store.get_neighborhood("a", direction, hops).unwrap();
+    assert_eq!(n(EdgeDirection::Outgoing, 1), set(&["b"]));
+    assert_eq!(n(EdgeDirection::Outgoing, 2), set(&["b", "c"]));
+    assert_eq!(n(EdgeDirection::Incoming, 2), set(&["d"]));
+    assert_eq!(n(EdgeDirection::Both, 1), set(&["b", "d"]));
+    assert_eq!(n(EdgeDirection::Both, 0), set(&[]));
+}
+
+#[test]
+fn test_block_height_bounds() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    let insert_block = |height: i64| {
+        store.with_stmts(|stmts| {
+            stmts[0].execute(params![
+                height,
+                format!("h{}", height),
+                "{}",
+                rusqlite::types::Null,
+                rusqlite::types::Null
+            ])?;
+            Ok(())
+        })
+    };
+    assert_eq!(store.max_block_height().unwrap(), None);
+    assert_eq!(store.min_block_height().unwrap(), None);
+    assert_eq!(store.resume_height().unwrap(), 0);
+
+    store.begin_tx().unwrap();
+    insert_block(5).unwrap();
+    insert_block(9).unwrap();
+    store.commit_tx().unwrap();
+    assert_eq!(store.max_block_height().unwrap(), Some(9));
+    assert_eq!(store.min_block_height().unwrap(), Some(5));
+
+    // Stays cached until the next commit
+    store.begin_tx().unwrap();
+    insert_block(12).unwrap();
+    assert_eq!(store.max_block_height().unwrap(), Some(9));
+    store.commit_tx().unwrap();
+    assert_eq!(store.max_block_height().unwrap(), Some(12));
+    assert_eq!(store.resume_height().unwrap(), 13);
+}
+
+#[test]
+fn test_iter_blocks_with_range() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    // A gap at 3, and 5 a bit older than 4 as block times can be
+    let blocks = [(1, 100), (2, 110), (4, 130), (5, 125), (6, 140)];
+    store
+        .with_stmts(|stmts| {
+            for (height, time) in blocks {
+                let json = serde_json::json!({
+                    "hash": format!("h{}", height), "height": height, "confirmations": 1,
+                    "strippedsize": 1, "size": 1, "weight": 1,
+                    "minter": {"id": "m", "totalMinted": 1, "stakeModifier": "s"},
+                    "version": 1, "versionHex": "1", "merkleroot": "r",
+                    "time": time, "mediantime": time, "bits": "b", "difficulty": 1.0,
+                    "chainwork": "c", "tx": [], "nTx": 0
+                });
+                stmts[0].execute(params![
+                    height,
+                    format!("h{}", height),
+                    json.to_string(),
+                    "m",
+                    rusqlite::types::Null
+                ])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    assert_eq!(store.height_for_timestamp(0).unwrap(), Some(1));
+    assert_eq!(store.height_for_timestamp(110).unwrap(), Some(2));
+    assert_eq!(store.height_for_timestamp(111).unwrap(), Some(4));
+    assert_eq!(store.height_for_timestamp(140).unwrap(), Some(6));
+    assert_eq!(store.height_for_timestamp(141).unwrap(), None);
+
+    let heights = |start, end| {
+        let mut v = vec![];
+        store
+            .iter_blocks_with_range(start, end, |b| {
+                v.push(b?.height);
+                Ok(())
+            })
+            .unwrap();
+        v
+    };
+    assert_eq!(heights(105, 130), [2, 4, 5]);
+    assert_eq!(heights(0, 1000), [1, 2, 4, 5, 6]);
+    assert_eq!(heights(131, 1000), [6]);
+    assert!(heights(141, 1000).is_empty());
+    assert!(heights(101, 105).is_empty());
+
+    let mut v = vec![];
+    store
+        .iter_blocks_range(2, 5, |b| {
+            v.push(b?.height);
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(v, [2, 4, 5]);
+}
+
+#[test]
+fn test_get_blocks_by_minter() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    for (_, indexer) in store.index_factory() {
+        indexer().unwrap();
+    }
+    store
+        .with_stmts(|stmts| {
+            for (height, minter) in [(3, "m1"), (1, "m1"), (2, "m2"), (4, "m1")] {
+                stmts[0].execute(params![height, format!("h{}", height), "{}", minter, "op"])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    assert_eq!(store.get_blocks_by_minter("m1").unwrap(), [1, 3, 4]);
+    assert_eq!(store.get_blocks_by_minter("m2").unwrap(), [2]);
+    assert!(store.get_blocks_by_minter("m3").unwrap().is_empty());
+    let plan: String = store
+        .conn
+        .query_row(
+            &format!("EXPLAIN QUERY PLAN {}", BLOCKS_BY_MINTER_QUERY),
+            ["m1"],
+            |row| row.get(3),
+        )
+        .unwrap();
+    assert!(plan.contains("idx_blocks_minter_id"), "{}", plan);
+}
+
+#[test]
+fn test_checkpoint() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    let insert_block = |height: i64| {
+        store.with_stmts(|stmts| {
+            stmts[0].execute(params![
+                height,
+                format!("h{}", height),
+                "{}",
+                rusqlite::types::Null,
+                rusqlite::types::Null
+            ])?;
+            Ok(())
+        })
+    };
+    store.begin_tx().unwrap();
+    store.commit_and_begin_tx().unwrap();
+    assert_eq!(store.get_checkpoint().unwrap(), None);
+
+    insert_block(1).unwrap();
+    insert_block(2).unwrap();
+    store.commit_and_begin_tx().unwrap();
+    assert_eq!(store.get_checkpoint().unwrap(), Some(2));
+
+    // Uncommitted blocks are not checkpointed
+    insert_block(3).unwrap();
+    store
+        .conn
+        .execute_batch("rollback; begin transaction")
+        .unwrap();
+    assert_eq!(store.get_checkpoint().unwrap(), Some(2));
+    assert_eq!(store.resume_height().unwrap(), 3);
+
+    insert_block(3).unwrap();
+    store.commit_tx().unwrap();
+    assert_eq!(store.get_checkpoint().unwrap(), Some(3));
+}
+
+#[test]
+fn test_get_txs_by_type() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            let txs = [
+                ("t1", 3, "icx-claim"),
+                ("t2", 1, "icx-claim"),
+                ("t3", 2, "ps"),
+                ("t4", 9, "icx-claim"),
+            ];
+            for (txid, height, tx_type) in txs {
+                insert_test_tx(
+                    stmts,
+                    &TestTx {
+                        txid,
+                        height,
+                        tx_type,
+                        ..Default::default()
+                    },
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let txids = |start, end| {
+        store
+            .get_txs_by_type(&TxType::ICXClaimDFCHTLC, start, end)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.txid)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(txids(0, 10), ["t2", "t1", "t4"]);
+    assert_eq!(txids(2, 5), ["t1"]);
+    assert!(store
+        .get_txs_by_type(&TxType::PoolSwap, 3, 10)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn test_rollback_tx() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store.begin_tx().unwrap();
+    store
+        .with_stmts(|stmts| {
+            stmts[0].execute(params![
+                1,
+                "h1",
+                "{}",
+                rusqlite::types::Null,
+                rusqlite::types::Null
+            ])?;
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(store.max_block_height().unwrap(), Some(1));
+    store.rollback_tx().unwrap();
+    assert_eq!(store.max_block_height().unwrap(), None);
+    assert_eq!(store.get_checkpoint().unwrap(), None);
+}
+
+#[test]
+fn test_get_tx_data_partial() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            insert_test_tx(
+                stmts,
+                &TestTx {
+                    txid: "t1",
+                    height: 7,
+                    tx_type: "ps",
+                    tx_in: r#"{"a":1.0}"#,
+                    tx_out: r#"{"b":0.9}"#,
+                    dvm_out: r#"["a","b"]"#,
+                    data: &serde_json::to_string(&Transaction {
+                        txid: "t1".into(),
+                        ..Default::default()
+                    })
+                    .unwrap(),
+                    icx_data: r#"{"order_tx":"o1","offer_tx":"","dfchtlc_tx":"","claim_tx":""}"#,
+                    icx_addr: "c",
+                    icx_btc_exp_amt: "0.5",
+                    swap_from: "btc",
+                    swap_to: "dfi",
+                    swap_amt: "0.1",
+                    icx_dfc_amt: "2.0",
+                    fee: 0.1,
+                    ..Default::default()
+                },
+            )?;
+            Ok(())
+        })
+        .unwrap();
+
+    let tx = store.get_tx_data_partial("t1").unwrap().unwrap();
+    assert_eq!(
+        (tx.txid.as_str(), tx.height, tx.tx_type.as_str()),
+        ("t1", 7, "ps")
+    );
+    assert_eq!(tx.tx_in["a"], 1.0);
+    assert_eq!(tx.dvm_out, ["a", "b"]);
+    assert_eq!(
+        (tx.icx_addr.as_str(), tx.icx_btc_exp_amt.as_str()),
+        ("c", "0.5")
+    );
+    assert_eq!(
+        (
+            tx.swap_from.as_str(),
+            tx.swap_to.as_str(),
+            tx.swap_amt.as_str()
+        ),
+        ("btc", "dfi", "0.1")
+    );
+    // The json blobs aren't read
+    assert!(tx.data.txid.is_empty());
+    assert!(tx.icx_data.is_none());
+    assert_eq!(&*store.get_tx_data("t1").unwrap().unwrap().data.txid, "t1");
+    assert!(store.get_tx_data_partial("t2").unwrap().is_none());
+}
+
+#[test]
+fn test_sqlite_pragma_args() {
+    let pragma = |store: &SqliteBlockStore, name: &str| -> i64 {
+        store
+            .conn
+            .query_row(&format!("pragma {}", name), [], |row| row.get(0))
+            .unwrap()
+    };
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    assert_eq!(pragma(&store, "synchronous"), 1);
+    assert_eq!(pragma(&store, "wal_autocheckpoint"), 1000);
+    assert_eq!(pragma(&store, "journal_size_limit"), 67_108_864);
+    assert_eq!(pragma(&store, "cache_size"), -2000);
+
+    let args = SqlitePragmaArgs {
+        sqlite_cache_size: Some(-64000),
+        sqlite_wal_autocheckpoint: 0,
+        sqlite_synchronous: SqliteSynchronous::Off,
+        sqlite_journal_size_limit: -1,
+    };
+    let store = SqliteBlockStore::open_or_create_with(":memory:", &args).unwrap();
+    assert_eq!(pragma(&store, "synchronous"), 0);
+    assert_eq!(pragma(&store, "wal_autocheckpoint"), 0);
+    assert_eq!(pragma(&store, "journal_size_limit"), -1);
+    assert_eq!(pragma(&store, "cache_size"), -64000);
+
+    #[derive(clap::Parser)]
+    struct Cli {
+        #[command(flatten)]
+        pragmas: SqlitePragmaArgs,
+    }
+    use clap::Parser;
+    assert_eq!(Cli::parse_from(["x"]).pragmas, SqlitePragmaArgs::default());
+    let cli = Cli::parse_from([
+        "x",
+        "--sqlite-cache-size",
+        "-64000",
+        "--sqlite-synchronous",
+        "full",
+    ]);
+    assert_eq!(cli.pragmas.sqlite_cache_size, Some(-64000));
+    assert_eq!(cli.pragmas.sqlite_synchronous, SqliteSynchronous::Full);
+}
+
+#[test]
+fn test_migration_rolled_back_on_failure() {
+    let dir = std::env::temp_dir().join(format!("migrate-rollback-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("index.sqlite");
+    let path = path.to_str().unwrap();
+    let _ = std::fs::remove_file(path);
+
+    let migrations = [
+        "CREATE TABLE t (a TEXT)",
+        // Fails after the column is added, like an interrupted migration
+        "ALTER TABLE t ADD COLUMN b TEXT; UPDATE missing SET x = 1",
+    ];
+    let version = |conn: &Connection| -> i64 {
+        conn.query_row("pragma user_version", [], |row| row.get(0))
+            .unwrap()
+    };
+    {
+        let conn = Connection::open(path).unwrap();
+        assert!(sqlite_apply_migrations(&conn, &migrations).is_err());
+        assert_eq!(version(&conn), 1);
+    }
+
+    // Reopened, the column wasn't left behind, so the fixed migration
+    // applies cleanly
+    let conn = Connection::open(path).unwrap();
+    assert_eq!(version(&conn), 1);
+    let fixed = [migrations[0], "ALTER TABLE t ADD COLUMN b TEXT"];
+    sqlite_apply_migrations(&conn, &fixed).unwrap();
+    assert_eq!(version(&conn), 2);
+    conn.execute("INSERT INTO t (a, b) VALUES ('x', 'y')", [])
+        .unwrap();
+    drop(conn);
+
+    // And a full store reopens after its migrations
+    let store_path = dir.join("store.sqlite");
+    let store_path = store_path.to_str().unwrap();
+    drop(SqliteBlockStore::open_or_create(store_path).unwrap());
+    let store = SqliteBlockStore::open_or_create(store_path).unwrap();
+    assert_eq!(version(&store.conn), SQLITE_MIGRATIONS_V2.len() as i64);
+    drop(store);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_open_dry_run() {
+    let dir = std::env::temp_dir().join(format!("open-dry-run-{}", std::process::id()));
+    let path = dir.join("sub").join("index.sqlite");
+    let path = path.to_str().unwrap();
+
+    // Missing, so in memory and nothing is created
+    let store = SqliteBlockStore::open_dry_run(path, &Default::default()).unwrap();
+    assert_eq!(store.schema_version().unwrap(), SQLITE_MIGRATIONS_V2.len());
+    drop(store);
+    assert!(!dir.exists());
+
+    drop(SqliteBlockStore::open_or_create(path).unwrap());
+    // Left in the rollback journal, so a switch to wal would show
+    let conn = Connection::open(path).unwrap();
+    conn.execute_batch("pragma journal_mode = delete").unwrap();
+    drop(conn);
+    let bytes = std::fs::read(path).unwrap();
+    let store = SqliteBlockStore::open_dry_run(path, &Default::default()).unwrap();
+    assert_eq!(store.schema_version().unwrap(), SQLITE_MIGRATIONS_V2.len());
+    store.begin_tx().unwrap();
+    store
+        .with_stmts(|stmts| {
+            insert_test_tx(
+                stmts,
+                &TestTx {
+                    txid: "t1",
+                    height: 1,
+                    tx_type: "utxo",
+                    ..Default::default()
+                },
+            )
+        })
+        .unwrap();
+    store.rollback_tx().unwrap();
+    drop(store);
+    assert_eq!(std::fs::read(path).unwrap(), bytes);
+    let conn = Connection::open(path).unwrap();
+    let journal_mode: String = conn
+        .query_row("pragma journal_mode", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(journal_mode, "delete");
+    drop(conn);
+
+    // Behind on migrations, left as is
+    let conn = Connection::open(path).unwrap();
+    conn.execute_batch("pragma user_version = 5").unwrap();
+    drop(conn);
+    assert!(SqliteBlockStore::open_dry_run(path, &Default::default()).is_err());
+    let conn = Connection::open(path).unwrap();
+    let version: i64 = conn
+        .query_row("pragma user_version", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(version, 5);
+
+    // Up to date, but without the tables
+    conn.execute_batch(&format!(
+        "drop table tx_addr_graph; pragma user_version = {}",
+        SQLITE_MIGRATIONS_V2.len()
+    ))
+    .unwrap();
+    drop(conn);
+    assert!(SqliteBlockStore::open_dry_run(path, &Default::default()).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
 }
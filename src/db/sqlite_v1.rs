@@ -1,13 +1,13 @@
 #![allow(dead_code)]
 
-use crate::db::sqlite_init_pragma_v1;
+use crate::db::{sqlite_init_pragma_v1, SqlitePragmaArgs, DEFAULT_SQLITE_PATH};
 use crate::lang::Result;
 use rusqlite::{CachedStatement, Connection};
 
 pub fn sqlite_init_db_v1(path: Option<&str>) -> Result<Connection> {
-    let path = path.unwrap_or("data/index.sqlite");
+    let path = path.unwrap_or(DEFAULT_SQLITE_PATH);
     let conn = rusqlite::Connection::open(path)?;
-    sqlite_init_pragma_v1(&conn)?;
+    sqlite_init_pragma_v1(&conn, &SqlitePragmaArgs::default())?;
     sqlite_init_tables_v1(&conn)?;
     Ok(conn)
 }
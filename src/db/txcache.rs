@@ -0,0 +1,120 @@
+use crate::db::{BlockStore, TxAddrData};
+use crate::lang::Result;
+use crate::models::{Block, Transaction};
+use lru::LruCache;
+use std::cell::{Cell, RefCell};
+use std::num::NonZeroUsize;
+
+/// Keeps the last `cap` txs from `get_tx_from_hash` around. Resolving tx
+/// ins looks up the parent tx of every input, and the same parents are
+/// often spent from many times within a few blocks. Misses are cached
+/// too, as the indexer errors on them anyway. Txs don't change once
+/// indexed, so nothing is invalidated.
+pub struct LruBlockStore<'a, S: BlockStore> {
+    inner: &'a S,
+    // None when the cache size is 0
+    cache: Option<RefCell<LruCache<String, Option<Transaction>>>>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+impl<'a, S: BlockStore> LruBlockStore<'a, S> {
+    pub fn new(inner: &'a S, cap: usize) -> Self {
+        LruBlockStore {
+            inner,
+            cache: NonZeroUsize::new(cap).map(|x| RefCell::new(LruCache::new(x))),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    }
+
+    /// `(hits, misses)` of `get_tx_from_hash` so far
+    pub fn stats(&self) -> (u64, u64) {
+        (self.hits.get(), self.misses.get())
+    }
+}
+
+impl<S: BlockStore> BlockStore for LruBlockStore<'_, S> {
+    fn get_block_from_hash(&self, hash: &str) -> Result<Option<Block>> {
+        self.inner.get_block_from_hash(hash)
+    }
+
+    fn get_block_hash(&self, height: i64) -> Result<Option<String>> {
+        self.inner.get_block_hash(height)
+    }
+
+    fn get_block_hash_for_tx(&self, tx_hash: &str) -> Result<Option<String>> {
+        self.inner.get_block_hash_for_tx(tx_hash)
+    }
+
+    fn get_block_for_tx(&self, tx_hash: &str) -> Result<Option<Block>> {
+        self.inner.get_block_for_tx(tx_hash)
+    }
+
+    fn get_block_from_height(&self, height: i64) -> Result<Option<Block>> {
+        self.inner.get_block_from_height(height)
+    }
+
+    fn get_tx_from_hash(&self, hash: &str) -> Result<Option<Transaction>> {
+        let Some(cache) = &self.cache else {
+            return self.inner.get_tx_from_hash(hash);
+        };
+        if let Some(tx) = cache.borrow_mut().get(hash) {
+            self.hits.set(self.hits.get() + 1);
+            return Ok(tx.clone());
+        }
+        self.misses.set(self.misses.get() + 1);
+        let tx = self.inner.get_tx_from_hash(hash)?;
+        cache.borrow_mut().put(hash.to_string(), tx.clone());
+        Ok(tx)
+    }
+
+    fn get_tx_addr_data_from_hash(&self, hash: &str) -> Result<Option<TxAddrData>> {
+        self.inner.get_tx_addr_data_from_hash(hash)
+    }
+}
+
+#[test]
+fn test_lru_block_store() {
+    use crate::db::SqliteBlockStore;
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            for txid in ["t1", "t2"] {
+                let data = serde_json::json!({
+                    "txid": txid, "hash": "", "version": 4, "size": 0, "vsize": 0,
+                    "weight": 0, "locktime": 0, "vin": [], "vout": [], "hex": "",
+                })
+                .to_string();
+                crate::db::insert_test_tx(
+                    stmts,
+                    &crate::db::TestTx {
+                        txid,
+                        height: 1,
+                        tx_type: "utxo",
+                        data: &data,
+                        ..Default::default()
+                    },
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let cache = LruBlockStore::new(&store, 1);
+    for txid in ["t1", "t1", "t2", "t1", "none", "none"] {
+        let tx = cache.get_tx_from_hash(txid).unwrap();
+        assert_eq!(
+            tx.as_ref().map(|x| &*x.txid),
+            (txid != "none").then_some(txid)
+        );
+    }
+    assert_eq!(cache.stats(), (2, 4));
+
+    let uncached = LruBlockStore::new(&store, 0);
+    assert_eq!(
+        uncached.get_tx_from_hash("t1").unwrap().unwrap().txid,
+        "t1".into()
+    );
+    assert_eq!(uncached.stats(), (0, 0));
+}
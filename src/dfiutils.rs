@@ -6,28 +6,38 @@ use crate::models::{TStr, Transaction, Vin, VinStandard, Vout};
 use crate::Result;
 use core::str;
 use std::collections::{HashMap, HashSet};
-use std::process::{Command, Output};
-use tracing::warn;
+use std::io::Read;
+use std::process::{Command, Output, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{trace, warn};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CliDriver {
     pub cli_path: String,
+    /// Kill the cli process if it takes longer than this.
+    pub timeout: Option<Duration>,
 }
 
+/// Stdout of a cli call. The raw output and the parsed json are both
+/// read from the same buffer, so e.g. a block can be stored as is and
+/// parsed without copying it or calling the cli again. Send, so outputs
+/// can be fetched on other threads.
+#[derive(Clone)]
 pub struct OutputExt {
-    output: Output,
+    stdout: Arc<[u8]>,
 }
 
 impl OutputExt {
-    pub fn str(&self) -> Result<TStr> {
-        Ok(TStr::from(std::str::from_utf8(&self.output.stdout)?))
+    pub fn str(&self) -> Result<&str> {
+        Ok(std::str::from_utf8(&self.stdout)?)
     }
 
     pub fn json<'a, T>(&'a self) -> Result<T>
     where
         T: serde::Deserialize<'a>,
     {
-        Ok(serde_json::from_slice(&self.output.stdout)?)
+        Ok(serde_json::from_slice(&self.stdout)?)
     }
 }
 
@@ -35,11 +45,20 @@ impl CliDriver {
     pub fn new() -> CliDriver {
         CliDriver {
             cli_path: "defi-cli".to_owned(),
+            timeout: None,
         }
     }
 
     pub fn with_cli_path(cli_path: String) -> CliDriver {
-        CliDriver { cli_path }
+        CliDriver {
+            cli_path,
+            timeout: None,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> CliDriver {
+        self.timeout = timeout;
+        self
     }
 
     pub fn run<I, S>(&mut self, args: I) -> Result<OutputExt>
@@ -47,18 +66,38 @@ impl CliDriver {
         I: IntoIterator<Item = S>,
         S: AsRef<std::ffi::OsStr>,
     {
-        let res = Command::new(&self.cli_path).args(args).output()?;
+        let args = args.into_iter().collect::<Vec<_>>();
+        trace!("cli: {} {}", self.cli_path, redact_cli_args(&args));
+
+        let mut cmd = Command::new(&self.cli_path);
+        cmd.args(&args);
+        let start = Instant::now();
+        let res = match self.timeout {
+            Some(timeout) => output_with_timeout(&mut cmd, timeout)?,
+            None => cmd.output()?,
+        };
+        trace!(
+            "cli exit: {:?} ({}ms)",
+            res.status.code(),
+            start.elapsed().as_millis()
+        );
         if !res.status.success() {
             let err = String::from_utf8_lossy(&res.stderr);
             return Err(err.into());
         }
-        Ok(OutputExt { output: res })
+        Ok(OutputExt {
+            stdout: Arc::from(res.stdout),
+        })
     }
 
-    pub fn get_block_count(&mut self) -> Result<i64> {
+    pub fn get_block_count(&mut self) -> Result<u32> {
         let out = self.run(["getblockcount"])?;
         let res = out.str()?;
-        Ok(res.trim().parse::<i64>()?)
+        let parsed = res.trim().parse::<i64>()?;
+        if parsed < 0 {
+            return Err("negative block count".into());
+        }
+        Ok(u32::try_from(parsed)?)
     }
 
     pub fn get_block_hash(&mut self, height: i64) -> Result<TStr> {
@@ -75,20 +114,263 @@ impl CliDriver {
         }
         self.run(args)
     }
+
+    pub fn get_blockchain_info(&mut self) -> Result<OutputExt> {
+        self.run(["getblockchaininfo"])
+    }
+}
+
+/// The calls the indexer makes to the node, either through defi-cli or
+/// straight to the json-rpc endpoint of defid.
+pub trait NodeDriver: Send + std::fmt::Debug {
+    fn get_block_count(&mut self) -> Result<u32>;
+    fn get_block_hash(&mut self, height: i64) -> Result<TStr>;
+    fn get_block(&mut self, hash: &str, verbosity: Option<i32>) -> Result<OutputExt>;
+    fn get_blockchain_info(&mut self) -> Result<OutputExt>;
+}
+
+impl NodeDriver for CliDriver {
+    fn get_block_count(&mut self) -> Result<u32> {
+        CliDriver::get_block_count(self)
+    }
+
+    fn get_block_hash(&mut self, height: i64) -> Result<TStr> {
+        CliDriver::get_block_hash(self, height)
+    }
+
+    fn get_block(&mut self, hash: &str, verbosity: Option<i32>) -> Result<OutputExt> {
+        CliDriver::get_block(self, hash, verbosity)
+    }
+
+    fn get_blockchain_info(&mut self) -> Result<OutputExt> {
+        CliDriver::get_blockchain_info(self)
+    }
+}
+
+/// Calls defid's json-rpc over http, which saves spawning a defi-cli
+/// process per call. Outputs hold the `result` of the response as is, so
+/// they parse the same as the cli output. Blocks are stored compact
+/// instead of pretty printed as the cli does.
+#[derive(Clone)]
+pub struct RpcDriver {
+    pub url: String,
+    // Basic auth header value, with the password in it
+    auth: Option<String>,
+    agent: ureq::Agent,
+}
+
+#[derive(serde::Deserialize)]
+struct RpcResponse<'a> {
+    #[serde(borrow)]
+    result: Option<&'a serde_json::value::RawValue>,
+    error: Option<serde_json::Value>,
+}
+
+impl RpcDriver {
+    pub fn new(
+        url: &str,
+        user: Option<&str>,
+        pass: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> RpcDriver {
+        use base64::Engine;
+
+        let auth = match (user, pass) {
+            (None, None) => None,
+            (user, pass) => {
+                let creds = format!("{}:{}", user.unwrap_or_default(), pass.unwrap_or_default());
+                let creds = base64::engine::general_purpose::STANDARD.encode(creds);
+                Some(format!("Basic {}", creds))
+            }
+        };
+        let mut agent = ureq::AgentBuilder::new();
+        if let Some(timeout) = timeout {
+            agent = agent.timeout(timeout);
+        }
+        RpcDriver {
+            url: url.to_owned(),
+            auth,
+            agent: agent.build(),
+        }
+    }
+
+    pub fn call(&mut self, method: &str, params: serde_json::Value) -> Result<OutputExt> {
+        trace!("rpc: {} {}", method, params);
+        let body = serde_json::json!({
+            "jsonrpc": "1.0",
+            "id": method,
+            "method": method,
+            "params": params,
+        });
+        let mut req = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/json");
+        if let Some(auth) = &self.auth {
+            req = req.set("Authorization", auth);
+        }
+        let start = Instant::now();
+        // The node replies with an error status along with the json error
+        // for failed calls, so the body is read for those too.
+        let res = match req.send_string(&body.to_string()) {
+            Ok(res) => res,
+            Err(ureq::Error::Status(_, res)) => res,
+            Err(e) => return Err(Error::from(format!("rpc: {}", e))),
+        };
+        let status = res.status();
+        let mut buf = Vec::new();
+        // into_string caps the body at 10mb, which verbose blocks can exceed
+        res.into_reader().read_to_end(&mut buf)?;
+        trace!("rpc status: {} ({}ms)", status, start.elapsed().as_millis());
+
+        let parsed = match serde_json::from_slice::<RpcResponse>(&buf) {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                let body = String::from_utf8_lossy(&buf);
+                return Err(Error::from(format!("rpc http {}: {}", status, body.trim())));
+            }
+        };
+        if let Some(err) = parsed.error.filter(|x| !x.is_null()) {
+            return Err(Error::from(format!("rpc {}: {}", method, err)));
+        }
+        let result = parsed
+            .result
+            .ok_or_else(|| Error::from(format!("rpc {}: no result", method)))?;
+        Ok(OutputExt {
+            stdout: Arc::from(result.get().as_bytes()),
+        })
+    }
+}
+
+impl NodeDriver for RpcDriver {
+    fn get_block_count(&mut self) -> Result<u32> {
+        let parsed: i64 = self.call("getblockcount", serde_json::json!([]))?.json()?;
+        if parsed < 0 {
+            return Err("negative block count".into());
+        }
+        Ok(u32::try_from(parsed)?)
+    }
+
+    fn get_block_hash(&mut self, height: i64) -> Result<TStr> {
+        let hash: String = self
+            .call("getblockhash", serde_json::json!([height]))?
+            .json()?;
+        Ok(TStr::from(hash.as_str()))
+    }
+
+    fn get_block(&mut self, hash: &str, verbosity: Option<i32>) -> Result<OutputExt> {
+        let params = match verbosity {
+            Some(v) => serde_json::json!([hash, v]),
+            None => serde_json::json!([hash]),
+        };
+        self.call("getblock", params)
+    }
+
+    fn get_blockchain_info(&mut self) -> Result<OutputExt> {
+        self.call("getblockchaininfo", serde_json::json!([]))
+    }
+}
+
+impl std::fmt::Debug for RpcDriver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RpcDriver")
+            .field("url", &self.url)
+            .field("auth", &self.auth.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+// Join args for logging, without leaking the rpc password.
+fn redact_cli_args<S: AsRef<std::ffi::OsStr>>(args: &[S]) -> String {
+    args.iter()
+        .map(|x| {
+            let arg = x.as_ref().to_string_lossy();
+            match arg.split_once('=') {
+                Some((k, _)) if k.trim_start_matches('-') == "rpcpassword" => {
+                    format!("{}=***", k)
+                }
+                _ => arg.into_owned(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[test]
+fn test_redact_cli_args() {
+    let args = [
+        "-rpcuser=u",
+        "--rpcpassword=secret",
+        "-rpcpassword=x=y",
+        "getblockcount",
+    ];
+    assert_eq!(
+        redact_cli_args(&args),
+        "-rpcuser=u --rpcpassword=*** -rpcpassword=*** getblockcount"
+    );
+}
+
+// std has no wait with timeout, so we poll the child instead. Pipes are
+// drained on their own threads so the child never blocks on a full pipe.
+fn output_with_timeout(cmd: &mut Command, timeout: Duration) -> Result<Output> {
+    fn drain<R: Read + Send + 'static>(
+        r: Option<R>,
+    ) -> std::thread::JoinHandle<std::io::Result<Vec<u8>>> {
+        std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut r) = r {
+                r.read_to_end(&mut buf)?;
+            }
+            Ok(buf)
+        })
+    }
+
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Err(Error::from(format!("cli timed out after {:?}", timeout)));
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    };
+
+    let join_err = |_| Error::from("cli output reader panicked");
+    Ok(Output {
+        status,
+        stdout: stdout.join().map_err(join_err)??,
+        stderr: stderr.join().map_err(join_err)??,
+    })
+}
+
+/// Bech32 addresses are case-insensitive, so they're lowercased to have
+/// one form per address. Legacy base58 addresses are left as they are.
+pub fn addr_normalize(addr: &str) -> String {
+    match addr.get(..3) {
+        Some(p) if p.eq_ignore_ascii_case("df1") => addr.to_ascii_lowercase(),
+        _ => addr.to_owned(),
+    }
 }
 
 pub fn extract_all_dfi_addresses(json_haystack: &str) -> HashSet<TStr> {
     use std::sync::LazyLock;
     static DFI_ADDRESS_RE: LazyLock<regex::Regex> = LazyLock::new(|| {
         let r1 = r#""(d|7|8)[1-9A-HJ-NP-Za-km-z]{25,34}""#; // legacy
-        let r2 = r#""df1[qpzry9x8gf2tvdw0s3jn54khce6mua7l]{38,87}""#; // bech32
+        let r2 = r#""(?i:df1[qpzry9x8gf2tvdw0s3jn54khce6mua7l]{38,87})""#; // bech32
         let s = [r1, r2].join("|");
         regex::Regex::new(&s).unwrap()
     });
 
     DFI_ADDRESS_RE
         .captures_iter(json_haystack)
-        .map(|x| TStr::from(x[0].trim_matches('\"'))) // remove quotes
+        .map(|x| TStr::from(addr_normalize(x[0].trim_matches('\"')))) // remove quotes
         .collect::<HashSet<_>>() // unique
 }
 
@@ -137,6 +419,34 @@ fn test_extract_dfi_addresses() {
     }
 }
 
+#[test]
+fn test_extract_dfi_addresses_large_payload() {
+    let addrs = [
+        "8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R",
+        "df1qqvaqshw0hrjzakxms27xrk6npfef4sx6cqaejv",
+        "dazewCkFnaw4o67RQrS5FATMKy9mAcohNA",
+        "7Q2nZCcKnxiRiHSNQtLB27RA5efxm2cE7w",
+    ];
+    let mut haystack = String::from("{");
+    for i in 0..50 {
+        haystack += &format!(
+            r#""k{}": "{}", "txid": "{}", "ds": "dd", "amount": {}, "#,
+            i,
+            addrs[i % addrs.len()],
+            "8842e454dcc8021cf2a74200a2154c795fc712fa4f6e035c7eaa5be744601b0a",
+            "1.2345".repeat(10),
+        );
+    }
+    // Adjacent, and a prefix with no closing quote
+    haystack += r#""a": ["8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R""dazewCkFnaw4o67RQrS5FATMKy9mAcohNA"], "b": "df1qqvaq"}"#;
+    assert!(haystack.len() > 10_000);
+
+    assert_eq!(
+        extract_all_dfi_addresses(&haystack),
+        addrs.map(TStr::from).into_iter().collect::<HashSet<_>>()
+    );
+}
+
 pub fn token_id_to_symbol_maybe(token_id: &str) -> &str {
     match token_id {
         "0" => "dfi",
@@ -152,6 +462,69 @@ pub fn token_id_to_symbol_maybe(token_id: &str) -> &str {
     }
 }
 
+/// Token id to symbol map from a json object (`{"16": "BTC-DFI"}`) or csv
+/// `id,symbol` lines, with an optional header. Json is picked by the
+/// `.json` extension.
+pub fn load_token_map(path: &str) -> Result<HashMap<String, String>> {
+    let data = std::fs::read_to_string(path)?;
+    if path.ends_with(".json") {
+        return Ok(serde_json::from_str(&data)?);
+    }
+    let mut map = HashMap::new();
+    for (i, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (id, symbol) = line
+            .split_once(',')
+            .ok_or_else(|| Error::from(format!("token map line {}: {}", i + 1, line)))?;
+        let (id, symbol) = (id.trim(), symbol.trim());
+        if i == 0 && id.parse::<u64>().is_err() {
+            continue;
+        }
+        map.insert(id.to_string(), symbol.to_string());
+    }
+    Ok(map)
+}
+
+/// Token symbols from a loaded token map, falling back to the built-in
+/// table of [`token_id_to_symbol_maybe`]. Symbols are lower cased to
+/// match the built-in ones.
+#[derive(Debug, Clone, Default)]
+pub struct TokenResolver {
+    map: HashMap<String, String>,
+}
+
+impl TokenResolver {
+    pub fn new(map: HashMap<String, String>) -> Self {
+        let map = map
+            .into_iter()
+            .map(|(k, v)| (k, v.to_lowercase()))
+            .collect();
+        TokenResolver { map }
+    }
+
+    /// Built-in table only if `path` is None
+    pub fn from_path(path: Option<&str>) -> Result<Self> {
+        match path {
+            Some(p) => Ok(Self::new(load_token_map(p)?)),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn symbol<'a>(&'a self, token_id: &'a str) -> &'a str {
+        match self.map.get(token_id) {
+            Some(s) => s,
+            None => token_id_to_symbol_maybe(token_id),
+        }
+    }
+}
+
 pub fn get_txin_addr_val_list(
     tx_ins: &[Vin],
     block_store: &impl BlockStore,
@@ -159,13 +532,13 @@ pub fn get_txin_addr_val_list(
     let map_fn = |x: VinStandard| {
         let tx_id = x.txid;
         let tx = block_store.get_tx_from_hash(&tx_id);
-        let tx = tx?.ok_or_else(|| Error::from(format!("tx hash not found: {}", &tx_id)))?;
+        let tx = tx?.ok_or_else(|| Error::from(format!("tx hash not found: {}", tx_id)))?;
         let utxo = tx
             .vout
             .iter()
             .find(|v| v.n == x.vout)
-            .ok_or_else(|| Error::from(format!("tx vout not found: {}", &tx_id)))?;
-        let val = utxo.value;
+            .ok_or_else(|| Error::from(format!("tx vout not found: {}", tx_id)))?;
+        let val = round_to_satoshi(utxo.value);
         if let Some(addrs) = &utxo.script_pub_key.addresses {
             if addrs.len() == 1 {
                 return Ok((addrs[0].clone(), val));
@@ -191,12 +564,13 @@ pub fn get_txout_addr_val_list(tx: &Transaction, tx_outs: &[Vout]) -> Vec<(TStr,
     tx_outs
         .iter()
         .map(|utxo| {
-            let val = utxo.value;
+            let val = round_to_satoshi(utxo.value);
             let addr = if let Some(addrs) = &utxo.script_pub_key.addresses {
                 if addrs.len() > 1 {
                     warn!("multiple addresses found: {}", tx.txid);
                 }
                 // Multi-sig, we just join it with a +
+                let addrs = addrs.iter().map(|x| addr_normalize(x)).collect::<Vec<_>>();
                 TStr::from(addrs.join("+"))
             } else {
                 // most dvm OP_RETURN txs without address will be these
@@ -207,13 +581,197 @@ pub fn get_txout_addr_val_list(tx: &Transaction, tx_outs: &[Vout]) -> Vec<(TStr,
         .collect::<Vec<_>>()
 }
 
+#[test]
+fn test_get_txout_addr_val_list_mixed_case() {
+    let vout = |addrs: Option<Vec<&str>>| Vout {
+        value: 1.0,
+        n: 0,
+        script_pub_key: crate::models::ScriptPubKey {
+            asm: "".into(),
+            hex: "".into(),
+            r#type: "".into(),
+            req_sigs: None,
+            addresses: addrs.map(|x| x.into_iter().map(TStr::from).collect()),
+        },
+    };
+    let tx_outs = [
+        vout(Some(vec!["DF1QQVAQSHW0HRJZAKXMS27XRK6NPFEF4SX6CQAEJV"])),
+        vout(Some(vec![
+            "df1qqvaqshw0hrjzakxms27xrk6npfef4sx6cqaejv",
+            "8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R",
+        ])),
+        vout(None),
+    ];
+    let list = get_txout_addr_val_list(&Transaction::default(), &tx_outs);
+    assert_eq!(
+        list.iter().map(|x| x.0.as_ref()).collect::<Vec<_>>(),
+        [
+            "df1qqvaqshw0hrjzakxms27xrk6npfef4sx6cqaejv",
+            "df1qqvaqshw0hrjzakxms27xrk6npfef4sx6cqaejv+8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R",
+            "x",
+        ]
+    );
+}
+
+/// Amounts have at most 8 decimals, so this drops the float error
+/// picked up when adding them up.
+pub fn round_to_satoshi(v: f64) -> f64 {
+    (v * 1e8).round() / 1e8
+}
+
 pub fn fold_addr_val_map(addr_val_list: &[(TStr, f64)]) -> HashMap<TStr, f64> {
-    addr_val_list
-        .iter()
-        .fold(HashMap::with_capacity(addr_val_list.len()), |mut m, v| {
-            m.entry(v.0.clone())
-                .and_modify(|x| *x += v.1)
-                .or_insert(v.1);
-            m
-        })
+    let mut m =
+        addr_val_list
+            .iter()
+            .fold(HashMap::with_capacity(addr_val_list.len()), |mut m, v| {
+                m.entry(v.0.clone())
+                    .and_modify(|x| *x += v.1)
+                    .or_insert(v.1);
+                m
+            });
+    m.values_mut().for_each(|v| *v = round_to_satoshi(*v));
+    m
+}
+
+#[test]
+fn test_fold_addr_val_map_rounding() {
+    let addr = TStr::from("addr1");
+    let list = vec![(addr.clone(), 0.1); 10];
+    assert_ne!(list.iter().map(|x| x.1).sum::<f64>(), 1.0);
+    let m = fold_addr_val_map(&list);
+    assert_eq!(m[&addr], 1.0);
+    assert_eq!(round_to_satoshi(0.1 + 0.2), 0.3);
+}
+
+#[test]
+fn test_rpc_driver() {
+    use std::io::{BufRead, BufReader, Write};
+
+    // Serves one canned reply per request, and hands back the requests.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let replies = [
+        (200, r#"{"result":"00ab","error":null,"id":"getblockhash"}"#),
+        (
+            200,
+            r#"{"result":{"hash":"00ab","tx":[]},"error":null,"id":"getblock"}"#,
+        ),
+        (
+            500,
+            r#"{"result":null,"error":{"code":-8,"message":"Block height out of range"},"id":"x"}"#,
+        ),
+        (401, ""),
+    ];
+    let server = std::thread::spawn(move || {
+        let mut requests = Vec::new();
+        for (status, reply) in replies {
+            let (stream, _) = listener.accept().unwrap();
+            let mut r = BufReader::new(stream);
+            let (mut line, mut auth, mut len) = (String::new(), String::new(), 0);
+            while r.read_line(&mut line).unwrap() > 2 {
+                let lower = line.to_lowercase();
+                if let Some(v) = lower.strip_prefix("content-length:") {
+                    len = v.trim().parse().unwrap();
+                }
+                if lower.starts_with("authorization:") {
+                    auth = line[14..].trim().to_string();
+                }
+                line.clear();
+            }
+            let mut body = vec![0; len];
+            r.read_exact(&mut body).unwrap();
+            requests.push((auth, String::from_utf8(body).unwrap()));
+            write!(
+                r.get_mut(),
+                "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                reply.len(),
+                reply
+            )
+            .unwrap();
+        }
+        requests
+    });
+
+    let mut rpc = RpcDriver::new(&url, Some("u"), Some("p"), Some(Duration::from_secs(5)));
+    assert_eq!(&*NodeDriver::get_block_hash(&mut rpc, 1).unwrap(), "00ab");
+    let block = NodeDriver::get_block(&mut rpc, "00ab", Some(4)).unwrap();
+    assert_eq!(block.str().unwrap(), r#"{"hash":"00ab","tx":[]}"#);
+    let err = NodeDriver::get_block_hash(&mut rpc, 9_999_999).unwrap_err();
+    assert!(
+        err.to_string().contains("Block height out of range"),
+        "{}",
+        err
+    );
+    let err = NodeDriver::get_block_count(&mut rpc).unwrap_err();
+    assert!(err.to_string().starts_with("rpc http 401"), "{}", err);
+    assert!(!format!("{:?}", rpc).contains("dTpw"));
+
+    let requests = server.join().unwrap();
+    // base64 of u:p
+    assert!(requests.iter().all(|x| x.0 == "Basic dTpw"));
+    let body: serde_json::Value = serde_json::from_str(&requests[1].1).unwrap();
+    assert_eq!(body["method"], "getblock");
+    assert_eq!(body["params"], serde_json::json!(["00ab", 4]));
+}
+
+#[test]
+fn test_token_resolver() {
+    let dir = std::env::temp_dir();
+    let json_path = dir.join(format!("tokens-{}.json", std::process::id()));
+    let csv_path = dir.join(format!("tokens-{}.csv", std::process::id()));
+    std::fs::write(&json_path, r#"{"16": "BTC-DFI", "2": "BTC.x"}"#).unwrap();
+    std::fs::write(&csv_path, "id,symbol\n16, BTC-DFI\n\n130,TSLA\n").unwrap();
+
+    let r = TokenResolver::from_path(json_path.to_str()).unwrap();
+    assert_eq!(
+        ["0", "2", "16", "999"].map(|x| r.symbol(x)),
+        ["dfi", "btc.x", "btc-dfi", "999"]
+    );
+    let r = TokenResolver::from_path(csv_path.to_str()).unwrap();
+    assert_eq!(r.len(), 2);
+    assert_eq!(
+        ["2", "16", "130"].map(|x| r.symbol(x)),
+        ["btc", "btc-dfi", "tsla"]
+    );
+    assert_eq!(TokenResolver::default().symbol("13"), "usdc");
+
+    std::fs::write(&csv_path, "16\n").unwrap();
+    assert!(load_token_map(csv_path.to_str().unwrap()).is_err());
+    std::fs::remove_file(json_path).unwrap();
+    std::fs::remove_file(csv_path).unwrap();
+}
+
+#[test]
+fn test_addr_normalize() {
+    let addr = "df1qqvaqshw0hrjzakxms27xrk6npfef4sx6cqaejv";
+    assert_eq!(addr_normalize(addr), addr);
+    assert_eq!(addr_normalize(&addr.to_uppercase()), addr);
+    assert_eq!(
+        addr_normalize("Df1QqvaqshW0hrjzakxms27xrk6npfef4sx6cqaejv"),
+        addr
+    );
+    // Legacy addresses are case-sensitive
+    assert_eq!(
+        addr_normalize("dZcuogFeLxy5NLFZnShYiX2sp9M6vv6UKj"),
+        "dZcuogFeLxy5NLFZnShYiX2sp9M6vv6UKj"
+    );
+    assert_eq!(addr_normalize("x"), "x");
+    assert_eq!(addr_normalize(""), "");
+}
+
+#[test]
+fn test_extract_dfi_addresses_mixed_case() {
+    let json_haystack = r#"{
+        "a": "DF1QQVAQSHW0HRJZAKXMS27XRK6NPFEF4SX6CQAEJV",
+        "b": "df1qqvaqshw0hrjzakxms27xrk6npfef4sx6cqaejv",
+        "c": "dZcuogFeLxy5NLFZnShYiX2sp9M6vv6UKj"
+    }"#;
+    assert_eq!(
+        extract_all_dfi_addresses(json_haystack),
+        HashSet::from([
+            TStr::from("df1qqvaqshw0hrjzakxms27xrk6npfef4sx6cqaejv"),
+            TStr::from("dZcuogFeLxy5NLFZnShYiX2sp9M6vv6UKj"),
+        ])
+    );
 }
@@ -1,9 +1,9 @@
-use crate::lang::Result;
+use crate::lang::{Error, Result};
 use std::collections::HashMap;
 use std::{
     collections::HashSet,
     fs::File,
-    io::{self, BufRead},
+    io::{self, BufRead, Write},
     path::Path,
 };
 
@@ -25,18 +25,17 @@ use std::{
 pub fn run(file_path: &str) -> Result<()> {
     // Read and parse the file
     let (nodes, edges) = parse_file(file_path)?;
+    let stdout = io::stdout();
+    write_reduced(nodes, edges, &mut stdout.lock())
+}
 
+// Nodes are renamed to sequential IDs starting from 1, in the order they
+// appear, and the new ID is used as the label as well.
+fn write_reduced<W: Write>(nodes: Vec<Node>, edges: Vec<Edge>, w: &mut W) -> Result<()> {
     // Create a mapping from old node IDs to new sequential IDs
     let mut id_mapping = HashMap::new();
-    let mut label_mapping = HashMap::new();
-    let mut label_reverse_mapping = HashMap::new();
-    let mut label_counter = 1;
-
     for (new_id, node) in nodes.iter().enumerate() {
         id_mapping.insert(node.id, new_id + 1); // new IDs start from 1
-        label_mapping.insert(label_counter, node.label.clone());
-        label_reverse_mapping.insert(node.label.clone(), label_counter);
-        label_counter += 1;
     }
 
     // Aggregate edges by (from, to, label) and count occurrences
@@ -47,36 +46,21 @@ pub fn run(file_path: &str) -> Result<()> {
         *edge_count.entry(key).or_insert(0) += 1;
     }
 
-    // Debug output to verify parsing
-    println!("digraph {{ ");
+    writeln!(w, "digraph {{ ")?;
 
     for node in nodes {
         let new_id = id_mapping[&node.id];
-        let l = "label = \"".to_string()
-            + label_reverse_mapping
-                .get(&node.label)
-                .unwrap()
-                .to_string()
-                .as_str()
-            + "\"";
-        println!("{} [{}]", new_id, l);
+        writeln!(w, "{} [label = \"{}\"]", new_id, new_id)?;
     }
 
     for ((from, to, label), count) in edge_count {
         let new_from = id_mapping[&from];
         let new_to = id_mapping[&to];
-        let edge_label = format!("{} ({})\"", label, count);
-        println!("{} -> {} [{}]", new_from, new_to, edge_label);
+        let edge_label = format!("{} ({})", label, count).replace('"', "\\\"");
+        writeln!(w, "{} -> {} [label = \"{}\"]", new_from, new_to, edge_label)?;
     }
 
-    println!("}}");
-
-    // Print out the label mappings
-    // println!("\nLabel Mappings:");
-    // for (id, label) in &label_mapping {
-    //  println!("{}: {}", id, label);
-    // }
-
+    writeln!(w, "}}")?;
     Ok(())
 }
 
@@ -84,7 +68,6 @@ pub fn run(file_path: &str) -> Result<()> {
 #[derive(Debug)]
 struct Node {
     id: usize,
-    label: String,
 }
 
 #[derive(Debug, Eq, PartialEq, Hash)]
@@ -97,30 +80,199 @@ struct Edge {
 fn parse_file(file_path: &str) -> Result<(Vec<Node>, Vec<Edge>)> {
     let path = Path::new(file_path);
     let file = File::open(path)?;
-    let reader = io::BufReader::new(file);
+    parse_lines(io::BufReader::new(file))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// Unquoted id or keyword
+    Id(String),
+    /// Quoted string, unescaped
+    Str(String),
+    Arrow,
+    LBracket,
+    RBracket,
+    LBrace,
+    RBrace,
+    Eq,
+    Comma,
+    Semi,
+}
+
+// Streams the tokens off the reader a byte at a time, so the file is never
+// held in memory.
+struct Lexer<R: BufRead> {
+    bytes: std::iter::Peekable<io::Bytes<R>>,
+    line: usize,
+    peeked: Option<Token>,
+}
+
+impl<R: BufRead> Lexer<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            bytes: reader.bytes().peekable(),
+            line: 1,
+            peeked: None,
+        }
+    }
+
+    fn err(&self, msg: &str) -> Error {
+        Error::from(format!("dot line {}: {}", self.line, msg))
+    }
+
+    fn next_byte(&mut self) -> Result<Option<u8>> {
+        let b = self.bytes.next().transpose()?;
+        if b == Some(b'\n') {
+            self.line += 1;
+        }
+        Ok(b)
+    }
+
+    fn peek_byte(&mut self) -> Option<u8> {
+        self.bytes.peek().and_then(|x| x.as_ref().ok()).copied()
+    }
+
+    fn peek(&mut self) -> Result<Option<&Token>> {
+        if self.peeked.is_none() {
+            self.peeked = self.lex()?;
+        }
+        Ok(self.peeked.as_ref())
+    }
+
+    fn next(&mut self) -> Result<Option<Token>> {
+        match self.peeked.take() {
+            Some(t) => Ok(Some(t)),
+            None => self.lex(),
+        }
+    }
+
+    fn expect(&mut self, want: Token) -> Result<()> {
+        match self.next()? {
+            Some(t) if t == want => Ok(()),
+            t => Err(self.err(&format!("expected {:?}, got {:?}", want, t))),
+        }
+    }
+
+    fn lex(&mut self) -> Result<Option<Token>> {
+        while self.peek_byte().is_some_and(|b| b.is_ascii_whitespace()) {
+            self.next_byte()?;
+        }
+        let Some(b) = self.next_byte()? else {
+            return Ok(None);
+        };
+        let t = match b {
+            b'[' => Token::LBracket,
+            b']' => Token::RBracket,
+            b'{' => Token::LBrace,
+            b'}' => Token::RBrace,
+            b'=' => Token::Eq,
+            b',' => Token::Comma,
+            b';' => Token::Semi,
+            b'-' if self.peek_byte() == Some(b'>') => {
+                self.next_byte()?;
+                Token::Arrow
+            }
+            b'"' => Token::Str(self.lex_str()?),
+            b if b.is_ascii_alphanumeric() || b == b'_' || b == b'.' || b == b'-' => {
+                let mut id = vec![b];
+                while let Some(b) = self.peek_byte() {
+                    if !(b.is_ascii_alphanumeric() || b == b'_' || b == b'.') {
+                        break;
+                    }
+                    id.push(b);
+                    self.next_byte()?;
+                }
+                Token::Id(String::from_utf8(id)?)
+            }
+            b => return Err(self.err(&format!("unexpected char: {}", b as char))),
+        };
+        Ok(Some(t))
+    }
+
+    // The opening quote is already consumed. Only escaped quotes are
+    // unescaped, other escapes like `\l` are kept as is, so they're written
+    // back out the same.
+    fn lex_str(&mut self) -> Result<String> {
+        let mut s = vec![];
+        loop {
+            match self.next_byte()? {
+                None => return Err(self.err("unterminated string")),
+                Some(b'"') => break,
+                Some(b'\\') => match self.next_byte()? {
+                    Some(b'"') => s.push(b'"'),
+                    Some(b) => s.extend([b'\\', b]),
+                    None => return Err(self.err("unterminated string")),
+                },
+                Some(b) => s.push(b),
+            }
+        }
+        Ok(String::from_utf8(s)?)
+    }
+}
 
+fn parse_lines<R: BufRead>(reader: R) -> Result<(Vec<Node>, Vec<Edge>)> {
+    let mut lexer = Lexer::new(reader);
     let mut nodes = Vec::new();
     let mut edges = Vec::new();
+    let mut node_ids = HashSet::new();
     let mut node_ids_with_edges = HashSet::new();
 
-    for line in reader.lines() {
-        let line = line?;
-        if line.contains("->") {
-            // Parse edge
-            let parts: Vec<&str> = line.split("->").collect();
-            let from: usize = parts[0].trim().parse().unwrap();
-            let rest: Vec<&str> = parts[1].split("[").collect();
-            let to: usize = rest[0].trim().parse().unwrap();
-            let label = rest[1].trim().trim_end_matches(']').trim().to_string();
+    // [strict] (di)graph [id] {
+    let mut t = lexer.next()?;
+    if t == Some(Token::Id("strict".into())) {
+        t = lexer.next()?;
+    }
+    if !matches!(&t, Some(Token::Id(x)) if x == "digraph" || x == "graph") {
+        return Err(lexer.err(&format!("expected digraph, got {:?}", t)));
+    }
+    if matches!(lexer.peek()?, Some(Token::Id(_) | Token::Str(_))) {
+        lexer.next()?;
+    }
+    lexer.expect(Token::LBrace)?;
+
+    loop {
+        let id = match lexer.next()? {
+            Some(Token::RBrace) => break,
+            Some(Token::Semi) => continue,
+            Some(Token::Id(x) | Token::Str(x)) => x,
+            t => return Err(lexer.err(&format!("expected a statement, got {:?}", t))),
+        };
+        match lexer.peek()? {
+            // Graph attribute, `id = value`
+            Some(Token::Eq) => {
+                lexer.next()?;
+                parse_value(&mut lexer)?;
+                continue;
+            }
+            // Defaults for all graph, node or edge statements
+            Some(Token::LBracket) if matches!(id.as_str(), "graph" | "node" | "edge") => {
+                parse_attrs(&mut lexer)?;
+                continue;
+            }
+            _ => {}
+        }
+        let from = parse_node_id(&lexer, &id)?;
+        if lexer.peek()? == Some(&Token::Arrow) {
+            lexer.next()?;
+            let to = match lexer.next()? {
+                Some(Token::Id(x) | Token::Str(x)) => parse_node_id(&lexer, &x)?,
+                t => return Err(lexer.err(&format!("expected a node id, got {:?}", t))),
+            };
+            let label = parse_attrs(&mut lexer)?.unwrap_or_default();
+            // Nodes can be declared by their edges alone
+            for id in [from, to] {
+                if node_ids.insert(id) {
+                    nodes.push(Node { id });
+                }
+            }
             edges.push(Edge { from, to, label });
             node_ids_with_edges.insert(from);
             node_ids_with_edges.insert(to);
-        } else if line.contains("[") {
-            // Parse node
-            let parts: Vec<&str> = line.split("[").collect();
-            let id: usize = parts[0].trim().parse().unwrap();
-            let label = parts[1].trim().trim_end_matches(']').trim().to_string();
-            nodes.push(Node { id, label });
+        } else {
+            parse_attrs(&mut lexer)?;
+            if node_ids.insert(from) {
+                nodes.push(Node { id: from });
+            }
         }
     }
 
@@ -131,3 +283,130 @@ fn parse_file(file_path: &str) -> Result<(Vec<Node>, Vec<Edge>)> {
 
     Ok((nodes, edges))
 }
+
+fn parse_node_id<R: BufRead>(lexer: &Lexer<R>, id: &str) -> Result<usize> {
+    id.parse()
+        .map_err(|_| lexer.err(&format!("node id is not a number: {}", id)))
+}
+
+fn parse_value<R: BufRead>(lexer: &mut Lexer<R>) -> Result<String> {
+    match lexer.next()? {
+        Some(Token::Id(x) | Token::Str(x)) => Ok(x),
+        t => Err(lexer.err(&format!("expected a value, got {:?}", t))),
+    }
+}
+
+// Optional `[k = v, ...]` list, returning the label if there is one
+fn parse_attrs<R: BufRead>(lexer: &mut Lexer<R>) -> Result<Option<String>> {
+    if lexer.peek()? != Some(&Token::LBracket) {
+        return Ok(None);
+    }
+    lexer.next()?;
+    let mut label = None;
+    loop {
+        let key = match lexer.next()? {
+            Some(Token::RBracket) => break,
+            Some(Token::Comma | Token::Semi) => continue,
+            Some(Token::Id(x) | Token::Str(x)) => x,
+            t => return Err(lexer.err(&format!("expected an attribute, got {:?}", t))),
+        };
+        lexer.expect(Token::Eq)?;
+        let value = parse_value(lexer)?;
+        if key == "label" {
+            label = Some(value);
+        }
+    }
+    Ok(label)
+}
+
+#[test]
+fn test_dotreduce_sequential_ids() {
+    let input = r#"digraph {
+    10 [ label = "addr1" ]
+    42 [ label = "addr2" ]
+    7 [ label = "addr3" ]
+    99 [ label = "no edges" ]
+    10 -> 42 [ label = "tx1 | 1" ]
+    42 -> 7 [ label = "tx2 | 2" ]
+}
+"#;
+    let (nodes, edges) = parse_lines(input.as_bytes()).unwrap();
+    let mut out = Vec::new();
+    write_reduced(nodes, edges, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines = out.lines().collect::<Vec<_>>();
+
+    assert_eq!(lines.first(), Some(&"digraph { "));
+    assert_eq!(lines.last(), Some(&"}"));
+    assert_eq!(
+        lines[1..4],
+        [
+            "1 [label = \"1\"]",
+            "2 [label = \"2\"]",
+            "3 [label = \"3\"]"
+        ]
+    );
+    let mut edge_lines = lines[4..lines.len() - 1].to_vec();
+    edge_lines.sort();
+    assert_eq!(
+        edge_lines,
+        [
+            "1 -> 2 [label = \"tx1  (1)\"]",
+            "2 -> 3 [label = \"tx2  (1)\"]"
+        ]
+    );
+}
+
+#[test]
+fn test_dotreduce_petgraph_dot() {
+    let mut g = petgraph::Graph::<String, String>::new();
+    let [a, b, c] = ["addr 1", "addr [2]", "addr \"3\""].map(|x| g.add_node(x.to_string()));
+    g.add_edge(a, b, "tx -> 1 | 1.5".to_string());
+    g.add_edge(b, c, "tx \"2\"; [x] | 2".to_string());
+    g.add_node("no edges".to_string());
+    let dot = format!("{}", petgraph::dot::Dot::new(&g));
+
+    let (nodes, edges) = parse_lines(dot.as_bytes()).unwrap();
+    assert_eq!(nodes.iter().map(|x| x.id).collect::<Vec<_>>(), [0, 1, 2]);
+    assert_eq!(
+        edges,
+        [
+            Edge {
+                from: 0,
+                to: 1,
+                label: "tx -> 1 | 1.5".to_string()
+            },
+            Edge {
+                from: 1,
+                to: 2,
+                label: "tx \"2\"; [x] | 2".to_string()
+            }
+        ]
+    );
+
+    let mut out = Vec::new();
+    write_reduced(nodes, edges, &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(
+        out.contains("2 -> 3 [label = \"tx \\\"2\\\"; [x]  (1)\"]"),
+        "{}",
+        out
+    );
+    // The output parses back
+    assert_eq!(parse_lines(out.as_bytes()).unwrap().1.len(), 2);
+
+    // Edges without a label, and nodes declared by their edges alone
+    let (nodes, edges) =
+        parse_lines("strict digraph g { 1 -> 2; 2 -> 3 [color = red] }".as_bytes()).unwrap();
+    assert_eq!(nodes.len(), 3);
+    assert_eq!(edges[1].label, "");
+
+    for bad in [
+        "digraph { 1 -> }",
+        "digraph { a -> 1 }",
+        "digraph { 1 [label = \"x ]",
+        "graph",
+    ] {
+        assert!(parse_lines(bad.as_bytes()).is_err(), "{}", bad);
+    }
+}
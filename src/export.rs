@@ -0,0 +1,205 @@
+use crate::db::{self, SqliteBlockStore, TxRow};
+use crate::lang::Result;
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
+use std::io::Write;
+use tracing::{debug, info};
+
+const WRITE_BUF_SIZE: usize = 64 * 1024 * 1024;
+
+const CSV_HEADER: &str = "txid,height,tx_type,tx_in,tx_out,dvm_in,dvm_out,data,icx_data,\
+    icx_addr,icx_btc_exp_amt,swap_from,swap_to,swap_amt,icx_dfc_amt,fee";
+
+#[derive(Parser, Debug)]
+pub struct ExportArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    /// File to write to, or `-` for stdout
+    #[arg(long, short = 'o', default_value = "-")]
+    pub output: String,
+    /// Defaults to 0
+    #[arg(short = 's', long)]
+    pub start_height: Option<u32>,
+    #[arg(short = 'e', long, default_value_t = 2_000_000)]
+    pub end_height: u32,
+    #[arg(long, value_enum, default_value_t = ExportFormat::Jsonl)]
+    pub format: ExportFormat,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Jsonl,
+    Csv,
+}
+
+pub fn run(args: &ExportArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    let sql_store = SqliteBlockStore::open(Some(&args.sqlite_path))?;
+
+    let w: Box<dyn Write> = match args.output.as_str() {
+        "-" => Box::new(std::io::stdout().lock()),
+        path => {
+            info!("writing to {}..", path);
+            let f = std::fs::File::create(path).with_context(|| format!("create: {}", path))?;
+            Box::new(f)
+        }
+    };
+    let mut w = std::io::BufWriter::with_capacity(WRITE_BUF_SIZE, w);
+
+    let start_height = args.start_height.unwrap_or(0);
+    let n = export_txs(
+        &sql_store,
+        start_height.into(),
+        args.end_height.into(),
+        args.format,
+        &mut w,
+        || quit.load(std::sync::atomic::Ordering::Relaxed),
+    )?;
+    w.flush()?;
+    info!("exported: {} txs", n);
+
+    info!("complete");
+    Ok(())
+}
+
+/// Stream the txs of `start..=end` to `w` in height order, one row at a
+/// time. Returns the number of txs written.
+pub fn export_txs<W, F>(
+    sql_store: &SqliteBlockStore,
+    start: i64,
+    end: i64,
+    format: ExportFormat,
+    w: &mut W,
+    interrupted: F,
+) -> Result<u64>
+where
+    W: Write,
+    F: Fn() -> bool,
+{
+    if format == ExportFormat::Csv {
+        writeln!(w, "{}", CSV_HEADER)?;
+    }
+    let mut n = 0u64;
+    sql_store.iter_txs(
+        Some(&format!(
+            "where height between {} and {} order by height",
+            start, end
+        )),
+        |tx| {
+            if interrupted() {
+                info!("int: early exit");
+                return Err("interrupted".into());
+            }
+            let tx = tx?;
+            match format {
+                ExportFormat::Jsonl => {
+                    serde_json::to_writer(&mut *w, &tx)?;
+                    writeln!(w)?;
+                }
+                ExportFormat::Csv => write_csv_row(w, &tx)?,
+            }
+            n += 1;
+            if n.is_multiple_of(100_000) {
+                info!("exported: {} (height {})", n, tx.height);
+            }
+            Ok(())
+        },
+    )?;
+    Ok(n)
+}
+
+/// Maps and structs are written as json in their field
+fn write_csv_row<W: Write>(w: &mut W, tx: &TxRow) -> Result<()> {
+    let icx_data = match &tx.icx_data {
+        Some(x) => serde_json::to_string(x)?,
+        None => String::new(),
+    };
+    let fields = [
+        tx.txid.clone(),
+        tx.height.to_string(),
+        tx.tx_type.clone(),
+        serde_json::to_string(&tx.tx_in)?,
+        serde_json::to_string(&tx.tx_out)?,
+        serde_json::to_string(&tx.dvm_in)?,
+        serde_json::to_string(&tx.dvm_out)?,
+        serde_json::to_string(&tx.data)?,
+        icx_data,
+        tx.icx_addr.clone(),
+        tx.icx_btc_exp_amt.clone(),
+        tx.swap_from.clone(),
+        tx.swap_to.clone(),
+        tx.swap_amt.clone(),
+        tx.icx_dfc_amt.clone(),
+        tx.fee.to_string(),
+    ];
+    for (i, x) in fields.iter().enumerate() {
+        if i > 0 {
+            w.write_all(b",")?;
+        }
+        w.write_all(csv_escape(x).as_bytes())?;
+    }
+    writeln!(w)?;
+    Ok(())
+}
+
+/// Quoted, with quotes doubled, only when the field needs it (RFC 4180)
+fn csv_escape(s: &str) -> std::borrow::Cow<'_, str> {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\"")).into()
+    } else {
+        s.into()
+    }
+}
+
+#[test]
+fn test_export_txs() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            for (txid, height, swap_from) in [("t2", 2, "dfi"), ("t1", 1, "a,\"b\""), ("t3", 3, "")]
+            {
+                crate::db::insert_test_tx(
+                    stmts,
+                    &crate::db::TestTx {
+                        txid,
+                        height,
+                        tx_type: "ps",
+                        tx_in: r#"{"a":1.5}"#,
+                        swap_from,
+                        ..Default::default()
+                    },
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let mut out = vec![];
+    let n = export_txs(&store, 1, 2, ExportFormat::Jsonl, &mut out, || false).unwrap();
+    assert_eq!(n, 2);
+    let rows = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|x| serde_json::from_str::<TxRow>(x).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rows.iter().map(|x| x.txid.as_str()).collect::<Vec<_>>(),
+        ["t1", "t2"]
+    );
+    assert_eq!(rows[0].tx_in["a"], 1.5);
+    assert_eq!(rows[0].swap_from, "a,\"b\"");
+
+    let mut out = vec![];
+    export_txs(&store, 1, 1, ExportFormat::Csv, &mut out, || false).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], CSV_HEADER);
+    assert!(lines[1].starts_with(r#"t1,1,ps,"{""a"":1.5}",{},[],[],"{""txid"":"#));
+    assert!(lines[1].ends_with(r#",,,,"a,""b""",,,,0"#));
+}
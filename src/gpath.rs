@@ -1,86 +1,98 @@
+use crate::args::process_list_args_with_file_paths;
+use crate::graphutils::PathFindingArgs;
 use crate::{
-    db::SqliteBlockStore,
+    db::{self, SqliteBlockStore},
     graphutils,
     lang::{self, OptionExt, Result},
     models::TxType,
 };
-use anyhow::Context;
 use clap::Parser;
 use tracing::{debug, info};
 
 #[derive(Parser, Debug)]
 pub struct GraphPathArgs {
-    #[arg(long, default_value = "data/index.sqlite")]
-    pub sqlite_path: String,
-    #[arg(long, default_value = "data/graph.bin")]
-    pub graph_data_path: String,
-    #[arg(long, default_value = "data/graph.meta.bin")]
-    pub graph_meta_path: String,
-    /// Source address
-    #[arg(long, short = 'a')]
-    pub src: String,
-    /// Dest address
-    #[arg(long, short = 'd')]
-    pub dest: String,
+    #[command(flatten)]
+    pub path: PathFindingArgs,
 }
 
 pub fn run(args: &GraphPathArgs) -> Result<()> {
     debug!("args: {:?}", args);
+    let args = &args.path;
 
     let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
 
-    let sql_store = SqliteBlockStore::new_v2(Some(&args.sqlite_path))?;
-    let (g, node_index_map) = graphutils::load_graph(&args.graph_meta_path, &args.graph_data_path)?;
+    let (src_addrs, dest_addrs) = (
+        process_list_args_with_file_paths(&args.src)?,
+        process_list_args_with_file_paths(&args.dest)?,
+    );
 
-    let src = &args.src;
-    let dest = &args.dest;
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
 
-    let src_index = node_index_map.get(src);
-    if src_index.is_none() {
-        return Err(lang::Error::from(format!("src not found: {}", src)));
-    }
-    let dest_index = node_index_map.get(dest);
-    if dest_index.is_none() {
-        return Err(lang::Error::from(format!("dest not found: {}", dest)));
-    }
+    let sql_store = SqliteBlockStore::open_readonly(&args.sqlite_path)?;
+    let (g, node_index_map) = match &args.graph_dir {
+        Some(dir) => graphutils::load_latest_graph(dir)?,
+        None => graphutils::load_graph(&args.graph_meta_path, &args.graph_data_path)?,
+    };
+
+    for src in src_addrs.iter() {
+        for dest in dest_addrs.iter() {
+            if quit.load(std::sync::atomic::Ordering::Relaxed) {
+                info!("int: early exit");
+                return Err("interrupted".into());
+            }
 
-    let src_index = src_index.unwrap();
-    let dest_index = dest_index.unwrap();
+            let src_index = node_index_map.get(src);
+            if src_index.is_none() {
+                return Err(lang::Error::from(format!("src not found: {}", src)));
+            }
+            let dest_index = node_index_map.get(dest);
+            if dest_index.is_none() {
+                return Err(lang::Error::from(format!("dest not found: {}", dest)));
+            }
 
-    info!("finding path..");
+            let src_index = src_index.unwrap();
+            let dest_index = dest_index.unwrap();
 
-    let paths = petgraph::algo::astar(
-        &g,
-        *src_index,
-        |node| node == *dest_index,
-        |_edge| 1,
-        |_node| 0,
-    );
+            info!("finding path: {} -> {}", src, dest);
 
-    debug!("{:?}", paths);
+            let paths = petgraph::algo::astar(
+                &g,
+                *src_index,
+                |node| node == *dest_index,
+                |_edge| 1,
+                |_node| 0,
+            );
 
-    match paths {
-        Some((_cost, path)) => {
-            for (i, node_idx) in path.windows(2).enumerate() {
-                let src_node = g.node_weight(node_idx[0]).context("node_weight")?;
-                let dest_node = g.node_weight(node_idx[1]).context("node_weight")?;
+            debug!("{:?}", paths);
 
-                let edge = g.find_edge(node_idx[0], node_idx[1]).context("find_edge")?;
-                let tx_id = g.edge_weight(edge).context("edge_weight")?;
+            match paths {
+                Some((_cost, path)) => {
+                    for (i, node_idx) in path.windows(2).enumerate() {
+                        let src_node = g.node_weight(node_idx[0]).ok_or_context("node_weight")?;
+                        let dest_node = g.node_weight(node_idx[1]).ok_or_context("node_weight")?;
 
-                let tx = sql_store.get_tx_data(tx_id)?.ok_or_err()?;
-                let tx_type = TxType::from_display(tx.tx_type.as_str());
+                        let edge = g
+                            .find_edge(node_idx[0], node_idx[1])
+                            .ok_or_context("find_edge")?;
+                        let tx_id = g.edge_weight(edge).ok_or_context("edge_weight")?;
 
-                info!(
-                    "[{}] {}: {} -> {} (tx: {})",
-                    i, tx_type, src_node, dest_node, tx_id,
-                );
+                        let tx = sql_store
+                            .get_tx_data_partial(tx_id)?
+                            .ok_or_with(|| format!("tx: {}", tx_id))?;
+                        let tx_type = TxType::from_display(tx.tx_type.as_str());
+
+                        info!(
+                            "[{}] {}: {} -> {} (tx: {})",
+                            i, tx_type, src_node, dest_node, tx_id,
+                        );
+                    }
+                }
+                None => {
+                    info!("no path found");
+                }
             }
         }
-        None => {
-            info!("no path found");
-        }
     }
 
     info!("complete");
@@ -1,9 +1,16 @@
-use crate::db::SqliteBlockStore;
+use crate::cliindexer::TxTypeFilter;
+use crate::db::{self, SqliteBlockStore, TxRow};
+use crate::dfiutils::addr_normalize;
+use crate::graphutils::{self, GraphEdge, TxEdge};
 use crate::lang::Result;
 use anyhow::Context;
-use clap::Parser;
-use std::collections::HashSet;
-use tracing::{debug, error, info};
+use clap::{Parser, ValueEnum};
+use petgraph::graph::{EdgeIndex, NodeIndex};
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use tracing::{debug, error, info, warn};
 
 #[derive(Parser, Debug)]
 pub struct GrapherArgs {
@@ -14,14 +21,124 @@ pub struct GrapherArgs {
     #[arg(long, default_value = "data/graph.meta.bin")]
     pub graph_meta_path: String,
     #[arg(short = 's', long, default_value_t = 0)]
-    pub start_height: i64,
+    pub start_height: u32,
     #[arg(short = 'e', long, default_value_t = 2_000_000)]
-    pub end_height: i64,
+    pub end_height: u32,
+    /// Output format of the graph. bincode can be loaded back by the
+    /// other graph commands, graphml is an export only format for
+    /// external tools.
+    #[arg(long, value_enum, default_value_t = GraphOutputFormat::Bincode)]
+    pub output_format: GraphOutputFormat,
+    #[arg(long, default_value = "data/graph.graphml")]
+    pub graphml_path: String,
+    /// Fail on the first tx row that can't be parsed, instead of
+    /// skipping it with a warning
+    #[arg(long, default_value_t = false)]
+    pub fail_on_parse_error: bool,
+    /// Skip txs where inputs * outputs is more than this, as each pair
+    /// is an edge
+    #[arg(long, default_value_t = 500)]
+    pub max_edges_per_tx: usize,
+    /// Max incoming edges per node. Edges past this are dropped.
+    /// No limit if not set.
+    #[arg(long)]
+    pub max_degree: Option<usize>,
+    /// Keep only one edge between each pair of addresses, from the
+    /// earliest tx, so the output isn't a multigraph
+    #[arg(long, default_value_t = false)]
+    pub dedup_edges: bool,
+    /// Weigh each edge with the amount sent to its target in the tx, and
+    /// the tx type. With dedup, the amounts of the skipped edges are added
+    /// to the kept one. Only the txid is written out to graphml.
+    #[arg(long, default_value_t = false)]
+    pub weighted: bool,
+    /// Load the graph from the data and meta paths and only add the txs
+    /// indexed since it was built, going by the rowid in the checkpoint
+    /// file. Builds from scratch if there's no graph yet. Txs that were
+    /// re-indexed since get new rowids and are added again, so rebuild
+    /// after a reindex.
+    #[arg(long, default_value_t = false)]
+    pub start_from_checkpoint: bool,
+    /// Last `txs` rowid in the graph, written along with the bincode output
+    #[arg(long, default_value = "data/graph.checkpoint")]
+    pub graph_checkpoint_path: String,
+    /// Only add edges for txs of these types, eg. `ps,cs` for a swap only
+    /// graph. All types if not set. The checkpoint doesn't keep these, so
+    /// pass the same types when resuming from it.
+    #[arg(long, use_value_delimiter = true, value_delimiter = ',')]
+    pub edge_tx_types: Vec<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphOutputFormat {
+    Bincode,
+    Graphml,
+}
+
+type BuiltGraph<E> = petgraph::Graph<Rc<str>, E>;
+
+/// The first edge between each pair of nodes, checked by the dedup instead
+/// of `find_edge`, which walks every edge of the node.
+type EdgeIndexMap = HashMap<(NodeIndex, NodeIndex), EdgeIndex>;
+
+/// Edge weights the graph can be built with
+pub(crate) trait BuildEdge: TxEdge + Clone + Sized {
+    fn new(tx: &TxRow, amount: f64) -> Self;
+    /// Fold in an edge between the same addresses that's skipped by dedup
+    fn merge(&mut self, amount: f64);
+    fn write_data<W: std::io::Write>(g: &petgraph::Graph<Rc<str>, Self>, w: W) -> Result<()>;
+    fn load(meta_path: &str, data_path: &str) -> Result<petgraph::Graph<String, Self>>;
+}
+
+impl BuildEdge for String {
+    fn new(tx: &TxRow, _amount: f64) -> Self {
+        tx.txid.clone()
+    }
+
+    fn merge(&mut self, _amount: f64) {}
+
+    fn write_data<W: std::io::Write>(g: &petgraph::Graph<Rc<str>, Self>, w: W) -> Result<()> {
+        bincode::serialize_into(w, g).context("g bincode ser err")?;
+        Ok(())
+    }
+
+    fn load(meta_path: &str, data_path: &str) -> Result<petgraph::Graph<String, Self>> {
+        Ok(graphutils::load_graph(meta_path, data_path)?.0)
+    }
+}
+
+impl BuildEdge for GraphEdge {
+    fn new(tx: &TxRow, amount: f64) -> Self {
+        GraphEdge {
+            txid: tx.txid.clone(),
+            amount,
+            tx_type: tx.tx_type.clone(),
+        }
+    }
+
+    fn merge(&mut self, amount: f64) {
+        self.amount += amount;
+    }
+
+    fn write_data<W: std::io::Write>(g: &petgraph::Graph<Rc<str>, Self>, w: W) -> Result<()> {
+        graphutils::write_weighted_graph_data(g, w)
+    }
+
+    fn load(meta_path: &str, data_path: &str) -> Result<petgraph::Graph<String, Self>> {
+        Ok(graphutils::load_weighted_graph(meta_path, data_path)?.0)
+    }
 }
 
 pub fn run(args: &GrapherArgs) -> Result<()> {
     debug!("args: {:?}", args);
+    if args.weighted {
+        build_graph::<GraphEdge>(args)
+    } else {
+        build_graph::<String>(args)
+    }
+}
 
+fn build_graph<E: BuildEdge>(args: &GrapherArgs) -> Result<()> {
     let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
     let user_sig = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
@@ -31,20 +148,73 @@ pub fn run(args: &GrapherArgs) -> Result<()> {
         std::sync::Arc::clone(&user_sig),
     )?;
 
-    let sql_store = SqliteBlockStore::new_v2(Some(&args.sqlite_path))?;
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    if args.start_from_checkpoint && args.output_format != GraphOutputFormat::Bincode {
+        return Err("--start-from-checkpoint needs the bincode output".into());
+    }
+
+    let sql_store = SqliteBlockStore::open(Some(&args.sqlite_path))?;
+    let tx_types_cond = tx_types_condition(&args.edge_tx_types)?;
+    if !args.edge_tx_types.is_empty() {
+        info!("edges only for tx types: {}", args.edge_tx_types.join(","));
+    }
     let mut txiter = 0;
+    let mut parse_errors = 0;
+    let mut skipped_high_fanout_txs: u32 = 0;
+    let mut skipped_high_degree_edges: u32 = 0;
+    let mut deduped_edges_skipped: u32 = 0;
 
-    let mut g = petgraph::Graph::new();
-    let mut node_index_map = std::collections::HashMap::<String, _>::new();
+    // Addresses are interned, so the graph node and the map key share
+    // the same allocation. Serialized output is the same as with String.
+    let mut interner = graphutils::AddressInterner::new();
+    let mut g = petgraph::Graph::<Rc<str>, E>::new();
+    let mut node_index_map = std::collections::HashMap::<Rc<str>, _>::new();
+    let mut edge_index_map = EdgeIndexMap::new();
 
-    let r = sql_store.iter_txs(None, |tx| {
+    let mut last_rowid = 0;
+    if args.start_from_checkpoint {
+        match load_checkpoint::<E>(args, &mut interner)? {
+            Some((rowid, loaded)) => {
+                info!("resuming graph from txs rowid {}", rowid);
+                for n in loaded.node_indices() {
+                    node_index_map.insert(loaded[n].clone(), n);
+                }
+                if args.dedup_edges {
+                    for e in loaded.edge_references() {
+                        edge_index_map
+                            .entry((e.source(), e.target()))
+                            .or_insert(e.id());
+                    }
+                }
+                (last_rowid, g) = (rowid, loaded);
+            }
+            None => info!("no graph checkpoint, building from scratch"),
+        }
+    }
+    // Bounded, so txs indexed while the graph builds are left for the next run
+    let end_rowid = sql_store.max_tx_rowid()?.unwrap_or_default();
+
+    let modifier = format!(
+        "where rowid > {} and rowid <= {}{} order by rowid",
+        last_rowid, end_rowid, tx_types_cond
+    );
+
+    let r = sql_store.iter_txs(Some(&modifier), |tx| {
         if quit.load(std::sync::atomic::Ordering::Relaxed) {
             info!("int: early exit");
             return Err("interrupted".into());
         }
 
         txiter += 1;
-        let tx = tx?;
+        let tx = match tx {
+            Ok(t) => t,
+            Err(e) if !args.fail_on_parse_error => {
+                parse_errors += 1;
+                warn!("skip tx parse err: {:?}", e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
 
         fn combine_addrs_with_multi_sig<'a, T1, T2>(
             addresses: T1,
@@ -59,14 +229,14 @@ pub fn run(args: &GrapherArgs) -> Result<()> {
                 if addr.contains('+') {
                     // Multi-sig, we include each of them for the graph
                     for part in addr.split('+') {
-                        set.insert(part.to_owned());
+                        set.insert(addr_normalize(part));
                     }
                 } else {
-                    set.insert(addr.to_owned());
+                    set.insert(addr_normalize(addr));
                 }
             }
             for dvm_addr in dvm_addresses {
-                set.insert(dvm_addr.to_owned());
+                set.insert(addr_normalize(dvm_addr));
             }
             set
         }
@@ -80,20 +250,55 @@ pub fn run(args: &GrapherArgs) -> Result<()> {
             tx.dvm_out.iter().map(|s| s.as_str()),
         );
 
+        if tx_ins.len() * tx_outs.len() > args.max_edges_per_tx {
+            warn!(
+                "skipping high-fan-out tx: {} ({} in × {} out)",
+                tx.txid,
+                tx_ins.len(),
+                tx_outs.len()
+            );
+            skipped_high_fanout_txs += 1;
+            return Ok(());
+        }
+
+        // Multi-sig outputs count in full for each of the addresses
+        let mut out_amounts = HashMap::<String, f64>::new();
+        if args.weighted {
+            for (addr, v) in tx.tx_out.iter() {
+                for part in addr.split('+') {
+                    *out_amounts.entry(addr_normalize(part)).or_default() += v;
+                }
+            }
+        }
+
         // Create nodes for any new addresses
         for addr in tx_ins.iter().chain(tx_outs.iter()) {
-            if !node_index_map.contains_key(addr) {
+            if !node_index_map.contains_key(addr.as_str()) {
+                let addr = interner.intern(addr);
                 let node_idx = g.add_node(addr.clone());
-                node_index_map.insert(addr.clone(), node_idx);
+                node_index_map.insert(addr, node_idx);
             }
         }
 
         // Add edges between inputs and outputs
         for to_addr in &tx_outs {
             for from_addr in &tx_ins {
-                let from_idx = node_index_map[from_addr];
-                let to_idx = node_index_map[to_addr];
-                g.add_edge(from_idx, to_idx, tx.txid.clone());
+                let from_idx = node_index_map[from_addr.as_str()];
+                let to_idx = node_index_map[to_addr.as_str()];
+                if let Some(max_degree) = args.max_degree {
+                    if g.edges_directed(to_idx, Direction::Incoming).count() >= max_degree {
+                        skipped_high_degree_edges += 1;
+                        continue;
+                    }
+                }
+                // Txs come in the order they were indexed, so the first edge is the
+                // earliest one.
+                let amount = out_amounts.get(to_addr.as_str()).copied();
+                let amount = amount.unwrap_or_default();
+                let dedup = args.dedup_edges.then_some(&mut edge_index_map);
+                if !add_edge(&mut g, from_idx, to_idx, &tx, amount, dedup) {
+                    deduped_edges_skipped += 1;
+                }
             }
         }
 
@@ -109,13 +314,7 @@ pub fn run(args: &GrapherArgs) -> Result<()> {
         if user_sig.load(std::sync::atomic::Ordering::Relaxed) {
             info!("sig received: dumping memory");
             user_sig.store(false, std::sync::atomic::Ordering::Release);
-            dump_graph_data(
-                txiter,
-                &g,
-                &node_index_map,
-                &args.graph_meta_path,
-                &args.graph_data_path,
-            )?;
+            dump_graph_data(txiter, &g, &node_index_map, args)?;
         }
 
         Ok(())
@@ -128,25 +327,100 @@ pub fn run(args: &GrapherArgs) -> Result<()> {
             error!("{:?}", e);
         }
     } else {
-        dump_graph_data(
-            txiter,
-            &g,
-            &node_index_map,
-            &args.graph_meta_path,
-            &args.graph_data_path,
-        )?;
+        dump_graph_data(txiter, &g, &node_index_map, args)?;
+        if args.output_format == GraphOutputFormat::Bincode {
+            info!(
+                "writing graph checkpoint to {}..",
+                args.graph_checkpoint_path
+            );
+            std::fs::write(&args.graph_checkpoint_path, end_rowid.to_string())?;
+        }
     }
 
-    info!("summary: scanned txs: {}", txiter);
+    info!(
+        "summary: scanned txs: {}, parse errors: {}",
+        txiter, parse_errors
+    );
+    info!(
+        "summary: skipped high fan-out txs: {}, skipped high degree edges: {}",
+        skipped_high_fanout_txs, skipped_high_degree_edges
+    );
+    if args.dedup_edges {
+        info!("summary: deduped edges skipped: {}", deduped_edges_skipped);
+    }
     Ok(())
 }
 
-fn dump_graph_data(
+/// `and tx_type in (..)` for the edge tx types, empty for all types.
+/// The types are checked to be known ones, so they're safe to inline.
+fn tx_types_condition(tx_types: &[String]) -> Result<String> {
+    if tx_types.is_empty() {
+        return Ok(String::new());
+    }
+    let filter = TxTypeFilter::new(tx_types, &[])?;
+    let types = filter
+        .include
+        .iter()
+        .map(|x| format!("'{}'", x))
+        .collect::<Vec<_>>();
+    Ok(format!(" and tx_type in ({})", types.join(", ")))
+}
+
+/// The graph at the data and meta paths and the last txs rowid in it, or
+/// None if there's no graph or checkpoint yet
+fn load_checkpoint<E: BuildEdge>(
+    args: &GrapherArgs,
+    interner: &mut graphutils::AddressInterner,
+) -> Result<Option<(i64, BuiltGraph<E>)>> {
+    let paths = [
+        &args.graph_data_path,
+        &args.graph_meta_path,
+        &args.graph_checkpoint_path,
+    ];
+    if let Some(missing) = paths.iter().find(|x| !std::path::Path::new(x).exists()) {
+        info!("not found: {}", missing);
+        return Ok(None);
+    }
+    let rowid = std::fs::read_to_string(&args.graph_checkpoint_path)?
+        .trim()
+        .parse::<i64>()
+        .with_context(|| format!("graph checkpoint: {}", args.graph_checkpoint_path))?;
+    let g = E::load(&args.graph_meta_path, &args.graph_data_path)?;
+    let g = g.map(|_, n| interner.intern(n), |_, e| e.clone());
+    Ok(Some((rowid, g)))
+}
+
+/// Returns false if the edge was skipped, as `dedup` is set and there's
+/// already an edge between the two in it.
+fn add_edge<E: BuildEdge>(
+    g: &mut petgraph::Graph<Rc<str>, E>,
+    from_idx: NodeIndex,
+    to_idx: NodeIndex,
+    tx: &TxRow,
+    amount: f64,
+    dedup: Option<&mut EdgeIndexMap>,
+) -> bool {
+    let Some(edges) = dedup else {
+        g.add_edge(from_idx, to_idx, E::new(tx, amount));
+        return true;
+    };
+    match edges.entry((from_idx, to_idx)) {
+        std::collections::hash_map::Entry::Occupied(e) => {
+            g[*e.get()].merge(amount);
+            false
+        }
+        std::collections::hash_map::Entry::Vacant(e) => {
+            e.insert(g.add_edge(from_idx, to_idx, E::new(tx, amount)));
+            true
+        }
+    }
+}
+
+fn dump_graph_data<E: BuildEdge>(
     txiter: i32,
-    g: &petgraph::Graph<String, String>,
-    node_index_map: &std::collections::HashMap<String, petgraph::graph::NodeIndex>,
-    meta_path: &str,
-    data_path: &str,
+    g: &petgraph::Graph<Rc<str>, E>,
+    node_index_map: &std::collections::HashMap<Rc<str>, petgraph::graph::NodeIndex>,
+    args: &GrapherArgs,
 ) -> crate::lang::Result<()> {
     info!(
         "txiter: {}, nodes: {}, edges: {}",
@@ -154,16 +428,123 @@ fn dump_graph_data(
         g.node_count(),
         g.edge_count()
     );
+    if args.output_format == GraphOutputFormat::Graphml {
+        info!("writing graphml to {}..", args.graphml_path);
+        let f = std::fs::File::create(&args.graphml_path)?;
+        let f = std::io::BufWriter::with_capacity(1 << 26, f); // 64mb
+        graphutils::write_graphml(g, f)?;
+        info!("done");
+        return Ok(());
+    }
+    write_graph_bincode(
+        g,
+        node_index_map,
+        &args.graph_meta_path,
+        &args.graph_data_path,
+    )
+}
+
+/// Writes the node index map to `meta_path` and the graph to `data_path`,
+/// as read back by `graphutils::load_graph`.
+pub(crate) fn write_graph_bincode<E: BuildEdge>(
+    g: &petgraph::Graph<Rc<str>, E>,
+    node_index_map: &HashMap<Rc<str>, NodeIndex>,
+    meta_path: &str,
+    data_path: &str,
+) -> Result<()> {
     info!("writing graph metadata to {}..", meta_path);
     let f = std::fs::File::create(meta_path)?;
     let f = std::io::BufWriter::with_capacity(1 << 26, f); // 64mb
-    bincode::serialize_into(f, &node_index_map).context("meta bincode ser err")?;
+    bincode::serialize_into(f, node_index_map).context("meta bincode ser err")?;
     // serde_json::to_writer(f, &node_index_map)?;
     info!("writing graph data to {}..", data_path);
     let f = std::fs::File::create(data_path)?;
     let f = std::io::BufWriter::with_capacity(1 << 26, f); // 64mb
-    bincode::serialize_into(f, &g).context("g bincode ser err")?;
+    E::write_data(g, f)?;
     // serde_json::to_writer(f, &g)?;
     info!("done");
     Ok(())
 }
+
+#[test]
+fn test_add_edge_dedup() {
+    let tx = |txid: &str| TxRow {
+        txid: txid.to_string(),
+        tx_type: "utxo".to_string(),
+        ..Default::default()
+    };
+    let mut g = petgraph::Graph::<Rc<str>, String>::new();
+    let mut edges = EdgeIndexMap::new();
+    let a = g.add_node(Rc::from("a"));
+    let b = g.add_node(Rc::from("b"));
+
+    assert!(add_edge(&mut g, a, b, &tx("tx1"), 1.0, Some(&mut edges)));
+    assert!(!add_edge(&mut g, a, b, &tx("tx2"), 1.0, Some(&mut edges)));
+    assert!(add_edge(&mut g, b, a, &tx("tx3"), 1.0, Some(&mut edges)));
+    assert_eq!(g.edge_count(), 2);
+    assert_eq!(edges.len(), 2);
+    assert_eq!(g[g.find_edge(a, b).unwrap()], "tx1");
+
+    assert!(add_edge(&mut g, a, b, &tx("tx4"), 1.0, None));
+    assert_eq!(g.edge_count(), 3);
+    // Still merged into the first edge
+    assert!(!add_edge(&mut g, a, b, &tx("tx5"), 1.0, Some(&mut edges)));
+    assert_eq!(g[edges[&(a, b)]], "tx1");
+
+    let mut g = petgraph::Graph::<Rc<str>, GraphEdge>::new();
+    let mut edges = EdgeIndexMap::new();
+    let a = g.add_node(Rc::from("a"));
+    let b = g.add_node(Rc::from("b"));
+    assert!(add_edge(&mut g, a, b, &tx("tx1"), 1.5, Some(&mut edges)));
+    assert!(!add_edge(&mut g, a, b, &tx("tx2"), 2.0, Some(&mut edges)));
+    let e = &g[g.find_edge(a, b).unwrap()];
+    assert_eq!(
+        (e.txid.as_str(), e.amount, e.tx_type.as_str()),
+        ("tx1", 3.5, "utxo")
+    );
+}
+
+#[test]
+fn test_load_checkpoint() {
+    let dir = std::env::temp_dir().join(format!("graph-checkpoint-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+    let args = GrapherArgs::parse_from([
+        "gbuild",
+        "--graph-data-path",
+        &path("graph.bin"),
+        "--graph-meta-path",
+        &path("graph.meta.bin"),
+        "--graph-checkpoint-path",
+        &path("graph.checkpoint"),
+    ]);
+
+    let mut interner = graphutils::AddressInterner::new();
+    let mut g = BuiltGraph::<String>::new();
+    let a = g.add_node(interner.intern("a"));
+    let b = g.add_node(interner.intern("b"));
+    g.add_edge(a, b, "tx1".to_string());
+    let node_index_map = [(g[a].clone(), a), (g[b].clone(), b)].into();
+    dump_graph_data(1, &g, &node_index_map, &args).unwrap();
+
+    let no_checkpoint = load_checkpoint::<String>(&args, &mut interner);
+    std::fs::write(path("graph.checkpoint"), "42\n").unwrap();
+    let loaded = load_checkpoint::<String>(&args, &mut interner);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(no_checkpoint.unwrap().is_none());
+    let (rowid, loaded) = loaded.unwrap().unwrap();
+    assert_eq!(rowid, 42);
+    assert_eq!(loaded[a], g[a]);
+    assert_eq!(loaded[loaded.find_edge(a, b).unwrap()], "tx1");
+}
+
+#[test]
+fn test_tx_types_condition() {
+    assert_eq!(tx_types_condition(&[]).unwrap(), "");
+    assert_eq!(
+        tx_types_condition(&["ps".to_string(), "cs".to_string()]).unwrap(),
+        " and tx_type in ('ps', 'cs')"
+    );
+    assert!(tx_types_condition(&["x' or 1=1 --".to_string()]).is_err());
+}
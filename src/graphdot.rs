@@ -1,6 +1,13 @@
 use crate::lang::Result;
-use crate::{db::SqliteBlockStore, graphutils};
-use clap::Parser;
+use crate::{
+    db::{self, SqliteBlockStore},
+    graphutils,
+};
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
+use petgraph::dot::{Config, Dot};
+use petgraph::Direction;
+use std::io::Write;
 use tracing::{debug, info};
 
 #[derive(Parser, Debug)]
@@ -11,10 +18,47 @@ pub struct GraphDotArgs {
     pub graph_data_path: String,
     #[arg(long, default_value = "data/graph.meta.bin")]
     pub graph_meta_path: String,
-    #[arg(long, default_value = "data/graph.dot")]
-    pub graph_out_path: String,
-    #[arg(long, default_value = "data/graph.acyc.dot")]
-    pub graph_out_acyclic_path: String,
+    #[arg(long, value_enum, default_value_t = GraphDotFormat::Dot)]
+    pub format: GraphDotFormat,
+    /// Full graph output. Defaults to data/graph.dot, or
+    /// data/graph.graphml for graphml.
+    #[arg(long)]
+    pub graph_out_path: Option<String>,
+    /// Condensed graph output. Defaults to data/graph.acyc.dot, or
+    /// data/graph.acyc.graphml for graphml.
+    #[arg(long)]
+    pub graph_out_acyclic_path: Option<String>,
+    #[arg(long, default_value = "data/graph.stats.json")]
+    pub graph_out_stats_path: String,
+    /// Drop nodes with less than this many edges (in + out) before
+    /// writing. 0 keeps all nodes.
+    #[arg(long, default_value_t = 0)]
+    pub filter_min_degree: usize,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphDotFormat {
+    Dot,
+    Graphml,
+}
+
+impl GraphDotFormat {
+    fn ext(&self) -> &'static str {
+        match self {
+            GraphDotFormat::Dot => "dot",
+            GraphDotFormat::Graphml => "graphml",
+        }
+    }
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+struct GraphDotStats {
+    nodes: usize,
+    edges: usize,
+    filtered_nodes: usize,
+    filtered_edges: usize,
+    condensed_nodes: usize,
+    condensed_edges: usize,
 }
 
 pub fn run(args: &GraphDotArgs) -> Result<()> {
@@ -29,10 +73,73 @@ pub fn run(args: &GraphDotArgs) -> Result<()> {
         std::sync::Arc::clone(&user_sig),
     )?;
 
-    let _sql_store = SqliteBlockStore::new_v2(Some(&args.sqlite_path))?;
-    let (g, _node_index_map) =
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+
+    let _sql_store = SqliteBlockStore::open(Some(&args.sqlite_path))?;
+    let (mut g, _node_index_map) =
         graphutils::load_graph(&args.graph_meta_path, &args.graph_data_path)?;
-    let gx = petgraph::algo::condensation(g, true);
+
+    let mut stats = GraphDotStats {
+        nodes: g.node_count(),
+        edges: g.edge_count(),
+        ..Default::default()
+    };
+
+    if args.filter_min_degree > 0 {
+        let degrees = node_degrees(&g);
+        g.retain_nodes(|_, n| degrees[n.index()] >= args.filter_min_degree);
+        info!(
+            "filtered min degree {}: {} nodes and {} edges",
+            args.filter_min_degree,
+            g.node_count(),
+            g.edge_count()
+        );
+    }
+    stats.filtered_nodes = g.node_count();
+    stats.filtered_edges = g.edge_count();
+
+    let ext = args.format.ext();
+    let out_path = match &args.graph_out_path {
+        Some(path) => path.clone(),
+        None => format!("data/graph.{}", ext),
+    };
+    let out_acyclic_path = match &args.graph_out_acyclic_path {
+        Some(path) => path.clone(),
+        None => format!("data/graph.acyc.{}", ext),
+    };
+
+    info!("writing {} graph to {}..", ext, out_path);
+    match args.format {
+        GraphDotFormat::Dot => {
+            let d = Dot::with_attr_getters(
+                &g,
+                &[Config::NodeNoLabel, Config::EdgeNoLabel],
+                &|_, e| format!("label = \"{}\"", e.weight()),
+                &|_, (_, addr)| format!("label = \"{}\"", addr),
+            );
+            write_dot(&out_path, &d)?;
+        }
+        GraphDotFormat::Graphml => write_graphml(&out_path, &g)?,
+    }
+
+    // Condensation consumes the graph and only keeps node weights, so we
+    // carry the degree along with the address to rank the addresses of
+    // each condensed node. The graph is rebuilt from parts to move the
+    // weights instead of cloning them.
+    let degrees = node_degrees(&g);
+    let (nodes, edges) = g.into_nodes_edges();
+    let mut gd =
+        petgraph::Graph::<(String, usize), String>::with_capacity(nodes.len(), edges.len());
+    for (n, degree) in nodes.into_iter().zip(degrees) {
+        gd.add_node((n.weight, degree));
+    }
+    for e in edges {
+        gd.add_edge(e.source(), e.target(), e.weight);
+    }
+
+    let gx = petgraph::algo::condensation(gd, true);
+    stats.condensed_nodes = gx.node_count();
+    stats.condensed_edges = gx.edge_count();
 
     info!(
         "condensed: {} nodes and {} edges",
@@ -40,8 +147,62 @@ pub fn run(args: &GraphDotArgs) -> Result<()> {
         gx.edge_count()
     );
 
-    // TODO: condense out
-    // TODO: condense acyclic out
+    info!("writing acyclic {} graph to {}..", ext, out_acyclic_path);
+    let gx = gx.map(
+        |_, addrs| condensed_node_label(addrs),
+        |_, txid| txid.clone(),
+    );
+    match args.format {
+        GraphDotFormat::Dot => write_dot(&out_acyclic_path, &Dot::new(&gx))?,
+        GraphDotFormat::Graphml => write_graphml(&out_acyclic_path, &gx)?,
+    }
+
+    info!("writing stats to {}..", args.graph_out_stats_path);
+    let f = std::fs::File::create(&args.graph_out_stats_path)
+        .with_context(|| format!("create: {}", args.graph_out_stats_path))?;
+    serde_json::to_writer_pretty(f, &stats)?;
+    info!("{:?}", stats);
+
+    Ok(())
+}
+
+fn node_degrees<N, E>(g: &petgraph::Graph<N, E>) -> Vec<usize> {
+    g.node_indices()
+        .map(|n| {
+            g.edges_directed(n, Direction::Outgoing).count()
+                + g.edges_directed(n, Direction::Incoming).count()
+        })
+        .collect()
+}
+
+// Top 3 addresses by degree, along with the count of the rest.
+fn condensed_node_label(addrs: &[(String, usize)]) -> String {
+    let mut top = addrs.iter().collect::<Vec<_>>();
+    top.sort_by_key(|x| std::cmp::Reverse(x.1));
+    let mut label = top
+        .iter()
+        .take(3)
+        .map(|(addr, _)| addr.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if addrs.len() > 3 {
+        label += &format!("\n(+{} more)", addrs.len() - 3);
+    }
+    label
+}
+
+fn write_dot<T: std::fmt::Display>(path: &str, dot: &T) -> Result<()> {
+    let f = std::fs::File::create(path).with_context(|| format!("create: {}", path))?;
+    let mut f = std::io::BufWriter::with_capacity(1 << 26, f); // 64mb
+    write!(f, "{}", dot).with_context(|| format!("write: {}", path))?;
+    f.flush().with_context(|| format!("flush: {}", path))?;
+    Ok(())
+}
 
+fn write_graphml(path: &str, g: &petgraph::Graph<String, String>) -> Result<()> {
+    let f = std::fs::File::create(path).with_context(|| format!("create: {}", path))?;
+    let mut f = std::io::BufWriter::with_capacity(1 << 26, f); // 64mb
+    graphutils::write_graphml(g, &mut f)?;
+    f.flush().with_context(|| format!("flush: {}", path))?;
     Ok(())
 }
@@ -0,0 +1,308 @@
+use crate::args::OutputFormat;
+use crate::db::{self, SqliteBlockStore};
+use crate::graphutils;
+use crate::lang::Result;
+use crate::models::TxType;
+use clap::Parser;
+use petgraph::graph::NodeIndex;
+use petgraph::Direction;
+use std::collections::{BTreeMap, HashMap};
+use tracing::{debug, info, warn};
+
+#[derive(Parser, Debug)]
+pub struct GraphRankArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    #[arg(long, default_value = "data/graph.bin")]
+    pub graph_data_path: String,
+    #[arg(long, default_value = "data/graph.meta.bin")]
+    pub graph_meta_path: String,
+    #[arg(long, default_value_t = 50)]
+    pub iterations: usize,
+    #[arg(long, default_value_t = 0.85)]
+    pub damping: f64,
+    #[arg(long, default_value_t = 100)]
+    pub top_n: usize,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct RankedAddr {
+    pub address: String,
+    pub score: f64,
+    pub in_degree: usize,
+    pub out_degree: usize,
+    /// Expected BTC of the ICX claims the address is part of
+    pub icx_btc_volume: f64,
+    /// Swapped from amount per token, for swaps sent by the address
+    pub swap_volume: BTreeMap<String, f64>,
+}
+
+/// Power iteration PageRank. Every edge counts as a link, so parallel
+/// edges weigh more. The rank of nodes with no out edges is spread evenly
+/// over all nodes. Runs over the raw edge list, as petgraph's `page_rank`
+/// visits every node pair and doesn't scale to the full graph.
+pub fn page_rank<N, E>(g: &petgraph::Graph<N, E>, damping: f64, iterations: usize) -> Vec<f64> {
+    let n = g.node_count();
+    if n == 0 {
+        return vec![];
+    }
+    let mut out_degree = vec![0usize; n];
+    for e in g.raw_edges() {
+        out_degree[e.source().index()] += 1;
+    }
+
+    let base = (1.0 - damping) / n as f64;
+    let mut rank = vec![1.0 / n as f64; n];
+    let mut next = vec![0.0; n];
+    for i in 0..iterations {
+        let dangling: f64 = (0..n)
+            .filter(|x| out_degree[*x] == 0)
+            .map(|x| rank[x])
+            .sum();
+        next.fill(base + damping * dangling / n as f64);
+        for e in g.raw_edges() {
+            let s = e.source().index();
+            next[e.target().index()] += damping * rank[s] / out_degree[s] as f64;
+        }
+        let delta: f64 = rank
+            .iter()
+            .zip(next.iter())
+            .map(|(a, b)| (a - b).abs())
+            .sum();
+        std::mem::swap(&mut rank, &mut next);
+        debug!("iteration {}: delta {:e}", i + 1, delta);
+    }
+    rank
+}
+
+/// Top `n` nodes by score, highest first
+pub fn top_ranked(scores: &[f64], n: usize) -> Vec<(NodeIndex, f64)> {
+    let mut v = scores
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (NodeIndex::new(i), *x))
+        .collect::<Vec<_>>();
+    v.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+    v.truncate(n);
+    v
+}
+
+/// Fill in the ICX and swap volumes of `addrs`, going through the swaps
+/// and ICX claims by type once instead of looking up each address.
+pub fn add_volumes(sql_store: &SqliteBlockStore, addrs: &mut [RankedAddr]) -> Result<()> {
+    let index = addrs
+        .iter()
+        .enumerate()
+        .map(|(i, x)| (x.address.clone(), i))
+        .collect::<HashMap<_, _>>();
+    let parse_amt = |txid: &str, s: &str| match s.parse::<f64>() {
+        Ok(v) => Some(v),
+        Err(_) => {
+            warn!("invalid amount: {} ({})", txid, s);
+            None
+        }
+    };
+
+    for tx_type in [TxType::PoolSwap, TxType::CompositeSwap] {
+        sql_store.iter_txs_by_type(&tx_type, 0..=i64::MAX, |tx| {
+            let tx = tx?;
+            let Some(amt) = parse_amt(&tx.txid, &tx.swap_amt) else {
+                return Ok(());
+            };
+            for addr in tx.tx_in.keys() {
+                if let Some(i) = index.get(addr) {
+                    *addrs[*i]
+                        .swap_volume
+                        .entry(tx.swap_from.clone())
+                        .or_default() += amt;
+                }
+            }
+            Ok(())
+        })?;
+    }
+
+    sql_store.iter_txs_by_type(&TxType::ICXClaimDFCHTLC, 0..=i64::MAX, |tx| {
+        let tx = tx?;
+        if tx.icx_btc_exp_amt.is_empty() {
+            return Ok(());
+        }
+        let Some(amt) = parse_amt(&tx.txid, &tx.icx_btc_exp_amt) else {
+            return Ok(());
+        };
+        let mut involved = tx.tx_in.keys().collect::<Vec<_>>();
+        involved.push(&tx.icx_addr);
+        involved.sort();
+        involved.dedup();
+        for addr in involved {
+            if let Some(i) = index.get(addr) {
+                addrs[*i].icx_btc_volume += amt;
+            }
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
+
+pub fn run(args: &GraphRankArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+    if !(0.0..1.0).contains(&args.damping) {
+        return Err("--damping must be in [0, 1)".into());
+    }
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    let sql_store = SqliteBlockStore::open(Some(&args.sqlite_path))?;
+    let (g, _) = graphutils::load_graph(&args.graph_meta_path, &args.graph_data_path)?;
+
+    info!("page rank: {} iterations..", args.iterations);
+    let scores = page_rank(&g, args.damping, args.iterations);
+
+    let mut top = top_ranked(&scores, args.top_n)
+        .into_iter()
+        .map(|(n, score)| RankedAddr {
+            address: g[n].clone(),
+            score,
+            in_degree: g.edges_directed(n, Direction::Incoming).count(),
+            out_degree: g.edges_directed(n, Direction::Outgoing).count(),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+
+    info!("get volumes..");
+    add_volumes(&sql_store, &mut top)?;
+
+    match args.format {
+        OutputFormat::Text => {
+            for (i, x) in top.iter().enumerate() {
+                let swaps = x
+                    .swap_volume
+                    .iter()
+                    .map(|(token, amt)| format!("{}@{}", amt, token))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                println!(
+                    "{:>4} {:.8} {:>8} {:>8} {:>14.8} {} {}",
+                    i + 1,
+                    x.score,
+                    x.in_degree,
+                    x.out_degree,
+                    x.icx_btc_volume,
+                    x.address,
+                    swaps
+                );
+            }
+        }
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&top)?);
+        }
+    }
+
+    info!("complete");
+    Ok(())
+}
+
+#[test]
+fn test_page_rank() {
+    let mut g = petgraph::Graph::<&str, ()>::new();
+    let [a, b, c, d] = ["a", "b", "c", "d"].map(|x| g.add_node(x));
+    // b, c and d all send to a, a only sends back to b. d is never sent to.
+    for (s, t) in [(b, a), (c, a), (d, a), (a, b)] {
+        g.add_edge(s, t, ());
+    }
+    let scores = page_rank(&g, 0.85, 100);
+    assert!((scores.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+    let top = top_ranked(&scores, 2);
+    assert_eq!(top.iter().map(|x| x.0).collect::<Vec<_>>(), [a, b]);
+    assert!((scores[c.index()] - scores[d.index()]).abs() < 1e-12);
+    assert!((scores[d.index()] - 0.15 / 4.0).abs() < 1e-9);
+
+    // Dangling nodes spread their rank, so all nodes end up equal
+    let mut g = petgraph::Graph::<&str, ()>::new();
+    g.add_node("a");
+    g.add_node("b");
+    assert_eq!(page_rank(&g, 0.85, 10), [0.5, 0.5]);
+    assert!(page_rank(&petgraph::Graph::<&str, ()>::new(), 0.85, 10).is_empty());
+}
+
+#[test]
+fn test_add_volumes() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            let txs = [
+                (
+                    "t1",
+                    TxType::PoolSwap,
+                    r#"{"a":1.0}"#,
+                    "",
+                    "",
+                    "DFI",
+                    "10.5",
+                ),
+                (
+                    "t2",
+                    TxType::CompositeSwap,
+                    r#"{"a":1.0,"b":1.0}"#,
+                    "",
+                    "",
+                    "BTC",
+                    "0.5",
+                ),
+                ("t3", TxType::PoolSwap, r#"{"c":1.0}"#, "", "", "DFI", "7"),
+                (
+                    "t4",
+                    TxType::ICXClaimDFCHTLC,
+                    r#"{"b":1.0}"#,
+                    "a",
+                    "0.25",
+                    "",
+                    "",
+                ),
+                (
+                    "t5",
+                    TxType::ICXClaimDFCHTLC,
+                    r#"{"b":1.0}"#,
+                    "b",
+                    "0.5",
+                    "",
+                    "",
+                ),
+                ("t6", TxType::Utxo, r#"{"a":1.0}"#, "", "", "DFI", "100"),
+            ];
+            for (txid, tx_type, tx_in, icx_addr, btc_amt, swap_from, swap_amt) in txs {
+                crate::db::insert_test_tx(
+                    stmts,
+                    &crate::db::TestTx {
+                        txid,
+                        height: 1,
+                        tx_type: &tx_type.to_string(),
+                        tx_in,
+                        icx_addr,
+                        icx_btc_exp_amt: btc_amt,
+                        swap_from,
+                        swap_amt,
+                        ..Default::default()
+                    },
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let mut addrs = ["a", "b"].map(|x| RankedAddr {
+        address: x.to_string(),
+        ..Default::default()
+    });
+    add_volumes(&store, &mut addrs).unwrap();
+    assert_eq!(
+        addrs[0].swap_volume,
+        BTreeMap::from([("BTC".to_string(), 0.5), ("DFI".to_string(), 10.5)])
+    );
+    assert_eq!(addrs[0].icx_btc_volume, 0.25);
+    assert_eq!(
+        addrs[1].swap_volume,
+        BTreeMap::from([("BTC".to_string(), 0.5)])
+    );
+    assert_eq!(addrs[1].icx_btc_volume, 0.75);
+}
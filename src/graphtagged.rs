@@ -0,0 +1,335 @@
+use crate::db::{self, SqliteBlockStore};
+use crate::dfiutils::extract_all_dfi_addresses;
+use crate::graphbuild;
+use crate::lang::Result;
+use crate::models::TxType;
+use clap::Parser;
+use petgraph::graph::NodeIndex;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use tracing::{debug, info, warn};
+
+// Port of the legacy rocks grapher (legacy/grapher.rs). Unlike gbuild which
+// adds an edge for every tx, only edges touching tagged addresses are added.
+// Addresses get tagged by taking part in ICX txs, and the tag propagates to
+// every address that transacts with a tagged one, which gives a much smaller
+// graph focused on the flow of ICX funds.
+
+#[derive(Parser, Debug)]
+pub struct TaggedGraphArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    #[arg(long, default_value = "data/graph-tagged.bin")]
+    pub graph_data_path: String,
+    #[arg(long, default_value = "data/graph-tagged.meta.bin")]
+    pub graph_meta_path: String,
+    #[arg(short = 's', long, default_value_t = 0)]
+    pub start_height: u32,
+    #[arg(short = 'e', long, default_value_t = 2_000_000)]
+    pub end_height: u32,
+    /// Seed the tagged set with every address that takes part in an ICX tx.
+    /// Without it, nothing is tagged and the graph stays empty.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub tag_icx_types: bool,
+    /// Fail on the first tx row that can't be parsed, instead of
+    /// skipping it with a warning
+    #[arg(long, default_value_t = false)]
+    pub fail_on_parse_error: bool,
+}
+
+pub fn run(args: &TaggedGraphArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+
+    let sql_store = SqliteBlockStore::open(Some(&args.sqlite_path))?;
+    let r = build_tagged_graph(&sql_store, args, || {
+        quit.load(std::sync::atomic::Ordering::Relaxed)
+    });
+    // As with gbuild, the graph is only written out when the scan finished
+    let tagged = match r {
+        Err(e) if e.to_string() == "interrupted" => {
+            info!("{:?}", e);
+            return Ok(());
+        }
+        r => r?,
+    };
+    let g = &tagged.graph;
+    info!(
+        "nodes: {}, edges: {}, tagged addrs: {}",
+        g.node_count(),
+        g.edge_count(),
+        tagged.tagged_addrs.len()
+    );
+    graphbuild::write_graph_bincode(
+        g,
+        &tagged.node_index_map,
+        &args.graph_meta_path,
+        &args.graph_data_path,
+    )
+}
+
+pub struct TaggedGraph {
+    pub graph: petgraph::Graph<Rc<str>, String>,
+    pub node_index_map: HashMap<Rc<str>, NodeIndex>,
+    pub tagged_addrs: HashSet<String>,
+}
+
+/// Scans the txs in the height range of `args`. An `interrupted` scan
+/// ends with an "interrupted" error.
+pub fn build_tagged_graph<F>(
+    sql_store: &SqliteBlockStore,
+    args: &TaggedGraphArgs,
+    interrupted: F,
+) -> Result<TaggedGraph>
+where
+    F: Fn() -> bool,
+{
+    let icx_tx_types: HashSet<_> = [
+        TxType::ICXCreateOrder,
+        TxType::ICXMakeOffer,
+        TxType::ICXSubmitDFCHTLC,
+        TxType::ICXSubmitEXTHTLC,
+        TxType::ICXClaimDFCHTLC,
+        TxType::ICXCloseOrder,
+        TxType::ICXCloseOffer,
+    ]
+    .iter()
+    .map(|x| x.to_string())
+    .collect();
+    let untagged_tx_types: HashSet<_> = [TxType::Coinbase, TxType::Unknown, TxType::Utxo]
+        .iter()
+        .map(|x| x.to_string())
+        .collect();
+
+    let mut g = petgraph::Graph::<Rc<str>, String>::new();
+    let mut node_index_map = HashMap::<Rc<str>, NodeIndex>::new();
+    let coinbase: Rc<str> = Rc::from("coinbase");
+    let coinbase_node = g.add_node(coinbase.clone());
+    node_index_map.insert(coinbase, coinbase_node);
+
+    let mut tagged_addrs = HashSet::<String>::new();
+    let mut txiter = 0;
+    let mut parse_errors = 0;
+
+    sql_store.iter_txs_in_range(args.start_height.into(), args.end_height.into(), |tx| {
+        if interrupted() {
+            info!("int: early exit");
+            return Err("interrupted".into());
+        }
+
+        txiter += 1;
+        if txiter % 100000 == 0 {
+            info!(
+                "txiter: {}, tagged: {}, nodes: {}, edges: {}",
+                txiter,
+                tagged_addrs.len(),
+                g.node_count(),
+                g.edge_count()
+            );
+        }
+        let tx = match tx {
+            Ok(t) => t,
+            Err(e) if !args.fail_on_parse_error => {
+                parse_errors += 1;
+                warn!("skip tx parse err: {:?}", e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        // We set dvm_addrs as well mark address to tag.
+        let mut tagged_dvm = false;
+        let mut dvm_addrs = vec![];
+        if !tx.tx_in.is_empty() && !untagged_tx_types.contains(&tx.tx_type) {
+            if let Some(vm) = tx.data.vm.as_ref() {
+                dvm_addrs = extract_all_dfi_addresses(&vm.msg.to_string())
+                    .into_iter()
+                    .map(|x| x.to_string())
+                    .collect();
+            }
+            tagged_dvm = (args.tag_icx_types && icx_tx_types.contains(&tx.tx_type))
+                || dvm_addrs.iter().any(|x| tagged_addrs.contains(x));
+        }
+
+        for addr in tx.tx_in.keys().chain(tx.tx_out.keys()).chain(&dvm_addrs) {
+            // contains over entry, as the alloc is more expensive and most
+            // addresses are already known.
+            if !node_index_map.contains_key(addr.as_str()) {
+                let addr: Rc<str> = Rc::from(addr.as_str());
+                node_index_map.insert(addr.clone(), g.add_node(addr));
+            }
+        }
+
+        let mut change_list = HashSet::<(NodeIndex, NodeIndex)>::new();
+        // Outs paid by a tagged in get tagged once the tx is done, so that
+        // a change out doesn't tag its own in halfway through the tx.
+        let mut paid_outs = vec![];
+
+        for (out_addr, value) in tx.tx_out.iter() {
+            let out_node = node_index_map[out_addr.as_str()];
+            let tagged_out = tagged_dvm || tagged_addrs.contains(out_addr);
+            if tagged_out {
+                tagged_addrs.insert(out_addr.clone());
+            }
+
+            // As in the legacy grapher, only the zero value outs of a tx
+            // without inputs are linked to the coinbase node.
+            if tx.tx_in.is_empty() {
+                if tagged_out && *value == 0. {
+                    change_list.insert((coinbase_node, out_node));
+                }
+                continue;
+            }
+
+            for in_addr in tx.tx_in.keys() {
+                if !tagged_dvm && !tagged_addrs.contains(in_addr) {
+                    continue;
+                }
+                tagged_addrs.insert(in_addr.clone());
+                change_list.insert((node_index_map[in_addr.as_str()], out_node));
+                paid_outs.push(out_addr);
+            }
+        }
+        tagged_addrs.extend(paid_outs.into_iter().cloned());
+
+        for in_addr in tx.tx_in.keys() {
+            for x in dvm_addrs.iter() {
+                if tagged_dvm || tagged_addrs.contains(x) {
+                    change_list
+                        .insert((node_index_map[in_addr.as_str()], node_index_map[x.as_str()]));
+                }
+            }
+        }
+
+        for (in_node, out_node) in change_list {
+            g.add_edge(in_node, out_node, tx.txid.clone());
+        }
+
+        Ok(())
+    })?;
+
+    // Untagged addresses are still added as nodes above, as they may get
+    // tagged later on. Only the ones that made it into an edge are kept.
+    g.retain_nodes(|g, n| n == coinbase_node || g.neighbors_undirected(n).next().is_some());
+    let node_index_map = g.node_indices().map(|n| (g[n].clone(), n)).collect();
+
+    info!(
+        "summary: scanned txs: {}, parse errors: {}, tagged addrs: {}",
+        txiter,
+        parse_errors,
+        tagged_addrs.len()
+    );
+    Ok(TaggedGraph {
+        graph: g,
+        node_index_map,
+        tagged_addrs,
+    })
+}
+
+#[test]
+fn test_build_tagged_graph() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            let icx_type = TxType::ICXCreateOrder.to_string();
+            let coinbase_type = TxType::Coinbase.to_string();
+            let txs = [
+                // The icx tx tags a, which then spends on to b and c
+                ("t1", 1, icx_type.as_str(), r#"{"a":1.0}"#, r#"{"a":0.9}"#),
+                ("t2", 2, "utxo", r#"{"a":0.9}"#, r#"{"b":0.8}"#),
+                ("t3", 3, "utxo", r#"{"b":0.8}"#, r#"{"c":0.7}"#),
+                ("t4", 4, "utxo", r#"{"x":1.0}"#, r#"{"y":0.9}"#),
+                (
+                    "t5",
+                    5,
+                    coinbase_type.as_str(),
+                    "",
+                    r#"{"b":50.0,"c":0.0,"d":0.0}"#,
+                ),
+            ];
+            for (txid, height, tx_type, tx_in, tx_out) in txs {
+                crate::db::insert_test_tx(
+                    stmts,
+                    &crate::db::TestTx {
+                        txid,
+                        height,
+                        tx_type,
+                        tx_in,
+                        tx_out,
+                        ..Default::default()
+                    },
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let args = TaggedGraphArgs::parse_from(["gtagged"]);
+    let tagged = build_tagged_graph(&store, &args, || false).unwrap();
+    let mut addrs = tagged.tagged_addrs.iter().collect::<Vec<_>>();
+    addrs.sort();
+    assert_eq!(addrs, ["a", "b", "c"]);
+
+    let g = &tagged.graph;
+    let mut edges = g
+        .edge_indices()
+        .map(|e| {
+            let (from, to) = g.edge_endpoints(e).unwrap();
+            (&*g[from], &*g[to], g[e].as_str())
+        })
+        .collect::<Vec<_>>();
+    edges.sort();
+    // Only the zero value coinbase out gets a coinbase edge
+    assert_eq!(
+        edges,
+        [
+            ("a", "a", "t1"),
+            ("a", "b", "t2"),
+            ("b", "c", "t3"),
+            ("coinbase", "c", "t5")
+        ]
+    );
+    let mut nodes = tagged
+        .node_index_map
+        .keys()
+        .map(|x| &**x)
+        .collect::<Vec<_>>();
+    nodes.sort();
+    assert_eq!(nodes, ["a", "b", "c", "coinbase"]);
+
+    let args = TaggedGraphArgs::parse_from(["gtagged", "--tag-icx-types", "false"]);
+    let tagged = build_tagged_graph(&store, &args, || false).unwrap();
+    assert!(tagged.tagged_addrs.is_empty());
+    assert_eq!(
+        (tagged.graph.node_count(), tagged.graph.edge_count()),
+        (1, 0)
+    );
+
+    let r = build_tagged_graph(&store, &args, || true);
+    assert_eq!(r.err().unwrap().to_string(), "interrupted");
+
+    // Unparseable rows are skipped, unless told to fail on them
+    store
+        .with_stmts(|stmts| {
+            crate::db::insert_test_tx(
+                stmts,
+                &crate::db::TestTx {
+                    txid: "t6",
+                    height: 6,
+                    tx_type: "utxo",
+                    tx_in: "{",
+                    ..Default::default()
+                },
+            )
+        })
+        .unwrap();
+    let args = TaggedGraphArgs::parse_from(["gtagged"]);
+    let tagged = build_tagged_graph(&store, &args, || false).unwrap();
+    assert_eq!(tagged.graph.edge_count(), 4);
+    let args = TaggedGraphArgs::parse_from(["gtagged", "--fail-on-parse-error"]);
+    assert!(build_tagged_graph(&store, &args, || false).is_err());
+}
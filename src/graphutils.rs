@@ -1,25 +1,138 @@
 use crate::lang::Result;
 use anyhow::Context;
+use clap::Args;
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use tracing::info;
 
-pub fn load_graph(
-    meta_path: &str,
-    data_path: &str,
-) -> Result<(
-    petgraph::Graph<String, String>,
-    std::collections::HashMap<String, petgraph::graph::NodeIndex>,
-)> {
+pub type NodeIndexMap = HashMap<String, petgraph::graph::NodeIndex>;
+
+/// Common args for the path finding commands.
+#[derive(Args, Debug)]
+pub struct PathFindingArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    #[arg(long, default_value = "data/graph.bin")]
+    pub graph_data_path: String,
+    #[arg(long, default_value = "data/graph.meta.bin")]
+    pub graph_meta_path: String,
+    /// Load the latest graph snapshot from this dir instead of the
+    /// graph data and meta paths
+    #[arg(long, conflicts_with_all = ["graph_data_path", "graph_meta_path"])]
+    pub graph_dir: Option<String>,
+    /// Source addresses. Can also be paths to files with one address per line.
+    #[arg(
+        long,
+        short = 'a',
+        required = true,
+        use_value_delimiter = true,
+        value_delimiter = ','
+    )]
+    pub src: Vec<String>,
+    /// Dest addresses. Can also be paths to files with one address per line.
+    #[arg(
+        long,
+        short = 'd',
+        required = true,
+        use_value_delimiter = true,
+        value_delimiter = ','
+    )]
+    pub dest: Vec<String>,
+}
+
+/// Hands out a shared `Rc<str>` per unique address, so that the same
+/// address seen across many txs is only allocated once.
+#[derive(Debug, Default)]
+pub struct AddressInterner {
+    set: HashSet<Rc<str>>,
+}
+
+impl AddressInterner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn intern(&mut self, s: &str) -> Rc<str> {
+        if let Some(v) = self.set.get(s) {
+            return v.clone();
+        }
+        let v: Rc<str> = Rc::from(s);
+        self.set.insert(v.clone());
+        v
+    }
+}
+
+/// Edge weight of the graphs built with `gbuild --weighted`. `amount` is
+/// the sum of the tx outputs to the edge target.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct GraphEdge {
+    pub txid: String,
+    pub amount: f64,
+    pub tx_type: String,
+}
+
+/// Edge weights that carry the txid of the edge. Either the txid itself,
+/// or a `GraphEdge`.
+pub trait TxEdge {
+    fn txid(&self) -> &str;
+}
+
+impl TxEdge for String {
+    fn txid(&self) -> &str {
+        self
+    }
+}
+
+impl TxEdge for GraphEdge {
+    fn txid(&self) -> &str {
+        &self.txid
+    }
+}
+
+// Prefix of the weighted graph data files. bincode isn't self describing, so
+// this is what tells the formats apart. Unweighted files start with the node
+// count, which can't realistically collide.
+const WEIGHTED_GRAPH_MAGIC: &[u8; 8] = b"WGRAPH01";
+
+enum GraphData {
+    Unweighted(petgraph::Graph<String, String>),
+    Weighted(petgraph::Graph<String, GraphEdge>),
+}
+
+fn load_node_index_map(meta_path: &str) -> Result<NodeIndexMap> {
     info!("loading graph metadata from {}..", meta_path);
     let f = std::fs::File::open(meta_path)?;
     let f = std::io::BufReader::with_capacity(1 << 26, f); // 64mb
-    let node_index_map: std::collections::HashMap<String, petgraph::graph::NodeIndex> =
-        bincode::deserialize_from(f).context("meta bincode err")?;
+    Ok(bincode::deserialize_from(f).context("meta bincode err")?)
+}
 
+fn load_graph_data(data_path: &str) -> Result<GraphData> {
     info!("loading graph data from {}..", data_path);
     let f = std::fs::File::open(data_path)?;
-    let f = std::io::BufReader::with_capacity(1 << 31, f); // 2gb
-    let g: petgraph::Graph<String, String> =
-        bincode::deserialize_from(f).context("g bincode err")?;
+    let mut f = std::io::BufReader::with_capacity(1 << 31, f); // 2gb
+    let weighted = f.fill_buf()?.starts_with(WEIGHTED_GRAPH_MAGIC);
+    let data = if weighted {
+        f.consume(WEIGHTED_GRAPH_MAGIC.len());
+        GraphData::Weighted(bincode::deserialize_from(f).context("g bincode err")?)
+    } else {
+        GraphData::Unweighted(bincode::deserialize_from(f).context("g bincode err")?)
+    };
+    Ok(data)
+}
+
+/// Loads both weighted and unweighted graphs. For weighted graphs only the
+/// txid of the edges is kept.
+pub fn load_graph(
+    meta_path: &str,
+    data_path: &str,
+) -> Result<(petgraph::Graph<String, String>, NodeIndexMap)> {
+    let node_index_map = load_node_index_map(meta_path)?;
+    let g = match load_graph_data(data_path)? {
+        GraphData::Unweighted(g) => g,
+        GraphData::Weighted(g) => g.map(|_, n| n.clone(), |_, e| e.txid.clone()),
+    };
 
     info!(
         "loaded graph with {} nodes and {} edges",
@@ -28,3 +141,290 @@ pub fn load_graph(
     );
     Ok((g, node_index_map))
 }
+
+/// Errors if the graph wasn't built with `gbuild --weighted`.
+pub fn load_weighted_graph(
+    meta_path: &str,
+    data_path: &str,
+) -> Result<(petgraph::Graph<String, GraphEdge>, NodeIndexMap)> {
+    let node_index_map = load_node_index_map(meta_path)?;
+    let g = match load_graph_data(data_path)? {
+        GraphData::Weighted(g) => g,
+        GraphData::Unweighted(_) => {
+            return Err(format!("not a weighted graph: {}", data_path).into());
+        }
+    };
+
+    info!(
+        "loaded weighted graph with {} nodes and {} edges",
+        g.node_count(),
+        g.edge_count()
+    );
+    Ok((g, node_index_map))
+}
+
+pub fn write_weighted_graph_data<N, W>(g: &petgraph::Graph<N, GraphEdge>, mut w: W) -> Result<()>
+where
+    N: serde::Serialize,
+    W: std::io::Write,
+{
+    w.write_all(WEIGHTED_GRAPH_MAGIC)?;
+    bincode::serialize_into(w, g).context("g bincode ser err")?;
+    Ok(())
+}
+
+/// Graph snapshots in `dir`, as `(snapshot_id, data_path, meta_path)`
+/// sorted newest first. A snapshot is a `graph-<id>.bin` along with its
+/// `graph-<id>.meta.bin`.
+pub fn list_graph_snapshots(dir: &str) -> Result<Vec<(u64, PathBuf, PathBuf)>> {
+    let mut snapshots = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("read dir: {}", dir))? {
+        let data_path = entry?.path();
+        let id = data_path
+            .file_name()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.strip_prefix("graph-"))
+            .and_then(|x| x.strip_suffix(".bin"))
+            .and_then(|x| x.parse::<u64>().ok());
+        let Some(id) = id else {
+            continue;
+        };
+        let meta_path = data_path.with_file_name(format!("graph-{}.meta.bin", id));
+        if meta_path.is_file() {
+            snapshots.push((id, data_path, meta_path));
+        }
+    }
+    snapshots.sort_by_key(|x| std::cmp::Reverse(x.0));
+    Ok(snapshots)
+}
+
+/// Data and meta paths of the newest snapshot in `dir`, falling back to
+/// `graph.bin` and `graph.meta.bin` if there are no snapshots.
+pub fn latest_graph_paths(dir: &str) -> Result<(String, String)> {
+    let (data_path, meta_path) = match list_graph_snapshots(dir)?.into_iter().next() {
+        Some((id, data_path, meta_path)) => {
+            info!("latest graph snapshot: {}", id);
+            (data_path, meta_path)
+        }
+        None => (
+            Path::new(dir).join("graph.bin"),
+            Path::new(dir).join("graph.meta.bin"),
+        ),
+    };
+    Ok((
+        data_path.to_string_lossy().into_owned(),
+        meta_path.to_string_lossy().into_owned(),
+    ))
+}
+
+/// Load the newest snapshot in `dir`, see `latest_graph_paths`.
+pub fn load_latest_graph(dir: &str) -> Result<(petgraph::Graph<String, String>, NodeIndexMap)> {
+    let (data_path, meta_path) = latest_graph_paths(dir)?;
+    load_graph(&meta_path, &data_path)
+}
+
+/// Write the graph out as GraphML, so it can be loaded by Gephi, yEd, etc.
+/// Nodes carry their weight as `label` and each edge carries its txid.
+pub fn write_graphml<N, E, W>(g: &petgraph::Graph<N, E>, w: W) -> Result<()>
+where
+    N: AsRef<str>,
+    E: TxEdge,
+    W: std::io::Write,
+{
+    use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+
+    let mut xw = quick_xml::Writer::new(w);
+    xw.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    xw.write_event(Event::Start(
+        BytesStart::new("graphml")
+            .with_attributes([("xmlns", "http://graphml.graphdrawing.org/xmlns")]),
+    ))?;
+    for (id, target) in [("label", "node"), ("txid", "edge")] {
+        xw.write_event(Event::Empty(BytesStart::new("key").with_attributes([
+            ("id", id),
+            ("for", target),
+            ("attr.name", id),
+            ("attr.type", "string"),
+        ])))?;
+    }
+    xw.write_event(Event::Start(
+        BytesStart::new("graph").with_attributes([("id", "G"), ("edgedefault", "directed")]),
+    ))?;
+
+    // Attribute values and text are escaped by quick-xml on write. Labels
+    // aren't necessarily unique, so nodes are identified by their index.
+    fn write_data<W: std::io::Write>(
+        xw: &mut quick_xml::Writer<W>,
+        key: &str,
+        value: &str,
+    ) -> Result<()> {
+        xw.write_event(Event::Start(
+            BytesStart::new("data").with_attributes([("key", key)]),
+        ))?;
+        xw.write_event(Event::Text(BytesText::new(value)))?;
+        xw.write_event(Event::End(BytesEnd::new("data")))?;
+        Ok(())
+    }
+    for n in g.node_indices() {
+        let id = format!("n{}", n.index());
+        xw.write_event(Event::Start(
+            BytesStart::new("node").with_attributes([("id", id.as_str())]),
+        ))?;
+        write_data(&mut xw, "label", g[n].as_ref())?;
+        xw.write_event(Event::End(BytesEnd::new("node")))?;
+    }
+    for e in g.raw_edges() {
+        let src = format!("n{}", e.source().index());
+        let dst = format!("n{}", e.target().index());
+        xw.write_event(Event::Start(
+            BytesStart::new("edge")
+                .with_attributes([("source", src.as_str()), ("target", dst.as_str())]),
+        ))?;
+        write_data(&mut xw, "txid", e.weight.txid())?;
+        xw.write_event(Event::End(BytesEnd::new("edge")))?;
+    }
+
+    xw.write_event(Event::End(BytesEnd::new("graph")))?;
+    xw.write_event(Event::End(BytesEnd::new("graphml")))?;
+    Ok(())
+}
+
+#[test]
+fn test_write_graphml() {
+    use quick_xml::events::Event;
+
+    let mut g = petgraph::Graph::<String, String>::new();
+    let a = g.add_node("8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R".to_string());
+    let b = g.add_node("df1qqvaqshw0hrjzakxms27xrk6npfef4sx6cqaejv".to_string());
+    let c = g.add_node("a+b<c>".to_string());
+    g.add_edge(a, b, "tx1".to_string());
+    g.add_edge(b, c, "tx2".to_string());
+
+    let mut buf = Vec::new();
+    write_graphml(&g, &mut buf).unwrap();
+    let xml = String::from_utf8(buf).unwrap();
+    assert!(xml.contains("a+b&lt;c&gt;"));
+
+    let mut reader = quick_xml::Reader::from_str(&xml);
+    let mut path = Vec::new();
+    let (mut nodes, mut edges, mut keys) = (0, 0, Vec::new());
+    let (mut labels, mut txids) = (Vec::new(), Vec::new());
+    loop {
+        match reader.read_event().unwrap() {
+            Event::Start(e) => {
+                match e.name().as_ref() {
+                    b"node" => nodes += 1,
+                    b"edge" => edges += 1,
+                    _ => {}
+                }
+                path.push(String::from_utf8(e.name().as_ref().to_vec()).unwrap());
+            }
+            Event::Empty(e) => {
+                if e.name().as_ref() == b"key" {
+                    assert_eq!(path, ["graphml"]);
+                    let id = e.try_get_attribute("id").unwrap().unwrap();
+                    keys.push(String::from_utf8(id.value.to_vec()).unwrap());
+                }
+            }
+            Event::Text(t) => {
+                let text = t.unescape().unwrap().into_owned();
+                match path.iter().map(|x| x.as_str()).collect::<Vec<_>>()[..] {
+                    ["graphml", "graph", "node", "data"] => labels.push(text),
+                    ["graphml", "graph", "edge", "data"] => txids.push(text),
+                    _ => {}
+                }
+            }
+            Event::End(_) => {
+                path.pop();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    assert!(path.is_empty());
+    assert_eq!(keys, ["label", "txid"]);
+    assert_eq!(nodes, 3);
+    assert_eq!(edges, 2);
+    assert_eq!(labels[2], "a+b<c>");
+    assert_eq!(txids, ["tx1", "tx2"]);
+    assert!(xml.contains(r#"<edge source="n1" target="n2">"#));
+}
+
+#[test]
+fn test_list_graph_snapshots() {
+    let dir = std::env::temp_dir().join(format!("graph-snapshots-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for name in [
+        "graph.bin",
+        "graph.meta.bin",
+        "graph-1.bin",
+        "graph-1.meta.bin",
+        "graph-10.bin",
+        "graph-10.meta.bin",
+        "graph-3.bin", // no meta, skipped
+    ] {
+        std::fs::write(dir.join(name), b"").unwrap();
+    }
+
+    let snapshots = list_graph_snapshots(dir.to_str().unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let ids = snapshots.iter().map(|x| x.0).collect::<Vec<_>>();
+    assert_eq!(ids, [10, 1]);
+    assert!(snapshots[0].2.ends_with("graph-10.meta.bin"));
+}
+
+#[test]
+fn test_load_weighted_graph() {
+    let dir = std::env::temp_dir().join(format!("graph-weighted-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+    let mut g = petgraph::Graph::<String, GraphEdge>::new();
+    let a = g.add_node("a".to_string());
+    let b = g.add_node("b".to_string());
+    g.add_edge(
+        a,
+        b,
+        GraphEdge {
+            txid: "tx1".to_string(),
+            amount: 1.5,
+            tx_type: "utxo".to_string(),
+        },
+    );
+    let node_index_map: NodeIndexMap = [("a".to_string(), a), ("b".to_string(), b)].into();
+    let meta = std::fs::File::create(path("graph.meta.bin")).unwrap();
+    bincode::serialize_into(meta, &node_index_map).unwrap();
+    let data = std::fs::File::create(path("graph.bin")).unwrap();
+    write_weighted_graph_data(&g, data).unwrap();
+    let mut unweighted = petgraph::Graph::<String, String>::new();
+    unweighted.add_node("a".to_string());
+    let data = std::fs::File::create(path("graph-unweighted.bin")).unwrap();
+    bincode::serialize_into(data, &unweighted).unwrap();
+
+    let weighted = load_weighted_graph(&path("graph.meta.bin"), &path("graph.bin"));
+    let txids = load_graph(&path("graph.meta.bin"), &path("graph.bin"));
+    let not_weighted = load_weighted_graph(&path("graph.meta.bin"), &path("graph-unweighted.bin"));
+    let loaded_unweighted = load_graph(&path("graph.meta.bin"), &path("graph-unweighted.bin"));
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let (wg, map) = weighted.unwrap();
+    assert_eq!(map, node_index_map);
+    assert_eq!(wg.raw_edges()[0].weight, g.raw_edges()[0].weight);
+    let (tg, _) = txids.unwrap();
+    assert_eq!(tg[tg.find_edge(a, b).unwrap()], "tx1");
+    assert!(not_weighted.is_err());
+    assert_eq!(loaded_unweighted.unwrap().0.node_count(), 1);
+}
+
+#[test]
+fn test_address_interner() {
+    let mut interner = AddressInterner::new();
+    let a = interner.intern("a");
+    let b = interner.intern("b");
+    assert!(Rc::ptr_eq(&a, &interner.intern(&String::from("a"))));
+    assert!(!Rc::ptr_eq(&a, &b));
+    assert_eq!((&*a, &*b), ("a", "b"));
+    // The set and the returned value
+    assert_eq!(Rc::strong_count(&a), 2);
+}
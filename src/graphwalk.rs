@@ -1,14 +1,15 @@
 use crate::{
-    db::SqliteBlockStore,
+    db::{self, SqliteBlockStore},
     graphutils,
     lang::{OptionExt, Result},
     models::TxType,
 };
 use anyhow::Context;
+use bigdecimal::BigDecimal;
 use clap::Parser;
 use petgraph::visit::EdgeRef;
 use std::str::FromStr;
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, trace, warn};
 
 #[derive(Parser, Debug)]
 pub struct GraphWalkArgs {
@@ -18,6 +19,10 @@ pub struct GraphWalkArgs {
     pub graph_data_path: String,
     #[arg(long, default_value = "data/graph.meta.bin")]
     pub graph_meta_path: String,
+    /// Load the latest graph snapshot from this dir instead of the
+    /// graph data and meta paths
+    #[arg(long, conflicts_with_all = ["graph_data_path", "graph_meta_path"])]
+    pub graph_dir: Option<String>,
     /// Address that's the origin (center point) of the graph exploration
     #[arg(long, short = 'a')]
     pub addr: String,
@@ -45,6 +50,69 @@ pub struct GraphWalkArgs {
         default_value = ""
     )]
     pub graph_mark_addr: Vec<String>,
+    /// Fail on the first tx row that can't be parsed, instead of
+    /// skipping it with a warning
+    #[arg(long, default_value_t = false)]
+    pub fail_on_parse_error: bool,
+    /// Write a json summary of the found ICX and BTC swaps to this path,
+    /// or `-` for stdout
+    #[arg(long)]
+    pub summary_output: Option<String>,
+    /// Nodes with more outgoing edges than this are reported as hubs
+    /// (exchanges, pools, etc)
+    #[arg(long, default_value_t = 1000)]
+    pub max_hub_degree: usize,
+    /// Don't walk past hub nodes
+    #[arg(long, default_value_t = false)]
+    pub skip_hub_addrs: bool,
+    /// Write the hub addresses found to this file, one per line
+    #[arg(long)]
+    pub hub_addrs_output: Option<String>,
+    /// Don't walk edges that moved less than this. Needs a graph built
+    /// with `gbuild --weighted`.
+    #[arg(long)]
+    pub min_amount: Option<f64>,
+    /// Max number of hops to walk from the origin address
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_depth: u32,
+    /// Walk but don't report the marks, ICX and BTC swaps found within
+    /// this many hops of the origin address
+    #[arg(long, default_value_t = 0)]
+    pub min_depth: u32,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct GraphWalkSummary {
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub total_icx_btc: BigDecimal,
+    #[serde(serialize_with = "serialize_decimal_str")]
+    pub total_btc_swaps: BigDecimal,
+    pub levels_traversed: usize,
+    pub nodes_visited: usize,
+    /// The walk stopped at the max depth with nodes left to visit
+    pub max_depth_reached: bool,
+    /// Unique hub nodes not walked past, not the edges into them
+    pub hub_nodes_skipped: usize,
+    pub source_addr: String,
+    pub icx_events: Vec<GraphWalkEvent>,
+    pub btc_swap_events: Vec<GraphWalkEvent>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct GraphWalkEvent {
+    pub txid: String,
+    pub height: i64,
+    pub amount: String,
+    pub src: String,
+    pub dst: String,
+}
+
+// Serialized as strings, to keep the full precision
+fn serialize_decimal_str<S: serde::Serializer>(
+    v: &BigDecimal,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    s.collect_str(v)
 }
 
 pub fn run(args: &GraphWalkArgs) -> Result<()> {
@@ -59,8 +127,35 @@ pub fn run(args: &GraphWalkArgs) -> Result<()> {
         std::sync::Arc::clone(&user_sig),
     )?;
 
-    let sql_store = SqliteBlockStore::new_v2(Some(&args.sqlite_path))?;
-    let (g, node_index_map) = graphutils::load_graph(&args.graph_meta_path, &args.graph_data_path)?;
+    if args.min_depth >= args.max_depth {
+        return Err(format!(
+            "--min-depth ({}) has to be less than --max-depth ({})",
+            args.min_depth, args.max_depth
+        )
+        .into());
+    }
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+
+    let sql_store = SqliteBlockStore::open_readonly(&args.sqlite_path)?;
+    let (g, node_index_map) = match (args.min_amount, &args.graph_dir) {
+        (None, Some(dir)) => graphutils::load_latest_graph(dir)?,
+        (None, None) => graphutils::load_graph(&args.graph_meta_path, &args.graph_data_path)?,
+        (Some(min_amount), dir) => {
+            let (data_path, meta_path) = match dir {
+                Some(dir) => graphutils::latest_graph_paths(dir)?,
+                None => (args.graph_data_path.clone(), args.graph_meta_path.clone()),
+            };
+            let (g, node_index_map) = graphutils::load_weighted_graph(&meta_path, &data_path)?;
+            // All nodes are kept, so the node indices stay the same
+            let g = g.filter_map(
+                |_, n| Some(n.clone()),
+                |_, e| (e.amount >= min_amount).then(|| e.txid.clone()),
+            );
+            info!("edges with min amount {}: {}", min_amount, g.edge_count());
+            (g, node_index_map)
+        }
+    };
 
     let mut graph_ignore_addr_list = args.graph_ignore_addr.clone();
     graph_ignore_addr_list.sort();
@@ -75,17 +170,24 @@ pub fn run(args: &GraphWalkArgs) -> Result<()> {
         .icx_ignore_addr
         .iter()
         .collect::<std::collections::HashSet<_>>();
-    let r = sql_store.iter_txs_partial(Some("where tx_type = \"icx-claim\""), |tx| {
+    let mut parse_errors = 0;
+    let r = sql_store.iter_txs_by_type(&TxType::ICXClaimDFCHTLC, 0..=i64::MAX, |tx| {
         if quit.load(std::sync::atomic::Ordering::Relaxed) {
             info!("int: early exit");
             return Err("interrupted".into());
         }
-        let tx = tx?;
-        if tx.tx_type == TxType::ICXClaimDFCHTLC.to_string() {
-            let icx_addr = tx.icx_addr;
-            if !icx_addr.is_empty() && !icx_ignore_list.contains(&icx_addr) {
-                icx_txs.insert(tx.txid.clone());
+        let tx = match tx {
+            Ok(t) => t,
+            Err(e) if !args.fail_on_parse_error => {
+                parse_errors += 1;
+                warn!("skip tx parse err: {:?}", e);
+                return Ok(());
             }
+            Err(e) => return Err(e),
+        };
+        let icx_addr = tx.icx_addr;
+        if !icx_addr.is_empty() && !icx_ignore_list.contains(&icx_addr) {
+            icx_txs.insert(tx.txid.clone());
         }
         Ok(())
     });
@@ -96,7 +198,11 @@ pub fn run(args: &GraphWalkArgs) -> Result<()> {
             error!("{:?}", e);
         }
     } else {
-        info!("done. icx_txs: {}", icx_txs.len());
+        info!(
+            "done. icx_txs: {}, parse errors: {}",
+            icx_txs.len(),
+            parse_errors
+        );
         trace!("icx_txs: {:?}", icx_txs);
     }
 
@@ -114,19 +220,28 @@ pub fn run(args: &GraphWalkArgs) -> Result<()> {
 
     let src_addr1 = &args.addr;
 
-    let addr1_index = node_index_map.get(src_addr1).context("node_index_map")?;
+    let addr1_index = node_index_map
+        .get(src_addr1)
+        .ok_or_context("node_index_map")?;
     info!("iter edges..");
 
-    let mut total_icx = bigdecimal::BigDecimal::from(0);
-    let mut total_btc_swaps = bigdecimal::BigDecimal::from(0);
+    let mut summary = GraphWalkSummary {
+        source_addr: src_addr1.clone(),
+        ..Default::default()
+    };
+
+    let mut hub_nodes = std::collections::BTreeSet::new();
+    let mut skipped_hub_nodes = std::collections::HashSet::new();
 
-    let max_levels = 20;
     let mut visited = std::collections::HashSet::new();
     let mut current_level = vec![*addr1_index];
+    let mut exhausted = false;
 
-    for level in 0..max_levels {
+    for level in 0..args.max_depth as usize {
         info!("running level: {}", level);
+        summary.levels_traversed = level + 1;
         let mut next_level = Vec::new();
+        let report = level >= args.min_depth as usize;
 
         if quit.load(std::sync::atomic::Ordering::Relaxed) {
             info!("int: early exit");
@@ -146,15 +261,17 @@ pub fn run(args: &GraphWalkArgs) -> Result<()> {
 
             let edges = g.edges(current_node);
             for x in edges {
-                let txid = g.edge_weight(x.id()).context("edge_weight")?;
-                let src = g.node_weight(x.source()).context("node_weight")?;
-                let dst = g.node_weight(x.target()).context("node_weight")?;
+                let txid = g.edge_weight(x.id()).ok_or_context("edge_weight")?;
+                let src = g.node_weight(x.source()).ok_or_context("node_weight")?;
+                let dst = g.node_weight(x.target()).ok_or_context("node_weight")?;
 
                 // info!("edge: {:?} -> {:?} ({})", src, dst, txid);
-                let tx = sql_store.get_tx_data(txid)?.ok_or_err()?;
+                let tx = sql_store
+                    .get_tx_data_partial(txid)?
+                    .ok_or_with(|| format!("tx: {}", txid))?;
                 let tx_type = TxType::from_display(tx.tx_type.as_str());
 
-                if graph_mark_addr_list.binary_search(dst).is_ok() {
+                if report && graph_mark_addr_list.binary_search(dst).is_ok() {
                     info!(
                         "MARK: found: lvl:{}, height: {}, tx: {}, src: {}, dst: {}, txtype: {}",
                         level, tx.height, txid, src, dst, tx.tx_type
@@ -166,21 +283,29 @@ pub fn run(args: &GraphWalkArgs) -> Result<()> {
                 }
 
                 match tx_type {
+                    _ if !report => {}
                     TxType::PoolSwap => {
                         if tx.swap_from == "btc" {
-                            let v = bigdecimal::BigDecimal::from_str(&tx.swap_amt).unwrap();
-                            total_btc_swaps += v;
+                            let v = BigDecimal::from_str(&tx.swap_amt).unwrap();
+                            summary.total_btc_swaps += v;
                             info!(
                                 "btc-swap: lvl: {}, height: {}, from: {}, to: {} / {}, amt: {} // btc_sum: {}",
-                                level, tx.height, src, dst, tx.swap_to, tx.swap_amt, total_btc_swaps
+                                level, tx.height, src, dst, tx.swap_to, tx.swap_amt, summary.total_btc_swaps
                             );
+                            summary.btc_swap_events.push(GraphWalkEvent {
+                                txid: tx.txid.clone(),
+                                height: tx.height,
+                                amount: tx.swap_amt.clone(),
+                                src: src.clone(),
+                                dst: dst.clone(),
+                            });
                         }
                     }
                     TxType::ICXClaimDFCHTLC => {
-                        let v = bigdecimal::BigDecimal::from_str(&tx.icx_btc_exp_amt);
+                        let v = BigDecimal::from_str(&tx.icx_btc_exp_amt);
                         match v {
                             Ok(v) => {
-                                total_icx += v;
+                                summary.total_icx_btc += v;
                             }
                             Err(e) => {
                                 error!(
@@ -191,20 +316,71 @@ pub fn run(args: &GraphWalkArgs) -> Result<()> {
                         }
                         info!(
                             "icx: lvl: {}, height: {}, from: {}, to: {}, tx: {}, icx_to: {}, amt: {} // icx_sum: {}",
-                            level, tx.height, src, dst, tx.txid, tx.icx_addr, tx.icx_btc_exp_amt, total_icx
+                            level, tx.height, src, dst, tx.txid, tx.icx_addr, tx.icx_btc_exp_amt, summary.total_icx_btc
                         );
+                        summary.icx_events.push(GraphWalkEvent {
+                            txid: tx.txid.clone(),
+                            height: tx.height,
+                            amount: tx.icx_btc_exp_amt.clone(),
+                            src: src.clone(),
+                            dst: dst.clone(),
+                        });
                     }
                     _ => {}
                 }
+
+                let degree = g.edges(x.target()).count();
+                if degree > args.max_hub_degree {
+                    if hub_nodes.insert(dst.clone()) {
+                        if args.skip_hub_addrs {
+                            warn!("hub node detected: {}, degree: {}, skipping", dst, degree);
+                        } else {
+                            warn!("hub node detected: {}, degree: {}", dst, degree);
+                        }
+                    }
+                    if args.skip_hub_addrs {
+                        skipped_hub_nodes.insert(x.target());
+                        continue;
+                    }
+                }
                 next_level.push(x.target());
             }
         }
 
         if next_level.is_empty() {
+            exhausted = true;
             break;
         }
         current_level = next_level;
     }
+    let visited_count = visited.len();
+    summary.nodes_visited = visited_count;
+    summary.hub_nodes_skipped = skipped_hub_nodes.len();
+    if !exhausted && current_level.iter().any(|x| !visited.contains(x)) {
+        summary.max_depth_reached = true;
+        warn!(
+            "walk cut short at max depth {} with {} nodes visited, the graph may have more hops",
+            args.max_depth, visited_count
+        );
+    }
+
+    if let Some(path) = &args.hub_addrs_output {
+        info!("writing {} hub addrs to {}..", hub_nodes.len(), path);
+        let mut out = hub_nodes.into_iter().collect::<Vec<_>>().join("\n");
+        out.push('\n');
+        std::fs::write(path, out).with_context(|| format!("write: {}", path))?;
+    }
+
+    match args.summary_output.as_deref() {
+        Some("-") => println!("{}", serde_json::to_string_pretty(&summary)?),
+        Some(path) => {
+            info!("writing summary to {}..", path);
+            let f = std::fs::File::create(path).with_context(|| format!("create: {}", path))?;
+            serde_json::to_writer_pretty(f, &summary)?;
+        }
+        None => {}
+    }
+
     info!("complete");
     Ok(())
 }
@@ -1,20 +1,47 @@
-use crate::db::{SqliteBlockStore, TxRow};
-use crate::lang::Result;
+use crate::db::{self, SqliteBlockStore, TxRow};
+use crate::lang::{Error, Result};
 use crate::models::TxType;
 use clap::Parser;
-use std::collections::HashSet;
-use tracing::{debug, error, info};
+use std::collections::{BTreeMap, HashSet};
+use tracing::{debug, error, info, warn};
 
 #[derive(Parser, Debug)]
 pub struct IcxAnalyzeArgs {
     #[arg(long, default_value = "data/index.sqlite")]
     pub sqlite_path: String,
     #[arg(short = 's', long, default_value_t = 0)]
-    pub start_height: i64,
+    pub start_height: u32,
     #[arg(short = 'e', long, default_value_t = 2_000_000)]
-    pub end_height: i64,
+    pub end_height: u32,
     #[arg(long, default_value_t = 1)]
     pub icx_addr: i64,
+    /// Also print the height, ins, outs, DVM addresses and icx data of
+    /// each tracked tx
+    #[arg(long, default_value_t = false)]
+    pub verbose_tx: bool,
+}
+
+/// Height, ins, outs, DVM addresses and icx data of `tx`, indented to go
+/// under its summary line. Addresses are sorted.
+pub fn print_tx_details(tx: &TxRow) {
+    println!("    height: {}", tx.height);
+    for (addr, v) in tx.tx_in.iter().collect::<BTreeMap<_, _>>() {
+        println!("    in: {} {:.8}", addr, v);
+    }
+    for (addr, v) in tx.tx_out.iter().collect::<BTreeMap<_, _>>() {
+        println!("    out: {} {:.8}", addr, v);
+    }
+    let mut dvm = tx.dvm_out.iter().collect::<Vec<_>>();
+    dvm.sort();
+    for addr in dvm {
+        println!("    dvm: {}", addr);
+    }
+    if let Some(icx) = &tx.icx_data {
+        match serde_json::to_string(icx) {
+            Ok(s) => println!("    icx_data: {}", s),
+            Err(e) => warn!("icx_data: {}", e),
+        }
+    }
 }
 
 pub fn run(args: &IcxAnalyzeArgs) -> Result<()> {
@@ -23,7 +50,9 @@ pub fn run(args: &IcxAnalyzeArgs) -> Result<()> {
     let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
 
-    let sql_store = SqliteBlockStore::new_v2(Some(&args.sqlite_path))?;
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+
+    let sql_store = SqliteBlockStore::open_readonly(&args.sqlite_path)?;
     let tracked_tx_types: HashSet<_> = [
         TxType::Unknown,
         // TxType::Coinbase,
@@ -92,7 +121,7 @@ pub fn run(args: &IcxAnalyzeArgs) -> Result<()> {
     let mut tracked = HashSet::new();
     let mut tracked_info: Option<TrackedInfo> = Option::None;
 
-    let r = sql_store.iter_txs(None, |tx| {
+    let r = sql_store.iter_txs_in_range(args.start_height.into(), args.end_height.into(), |tx| {
         if quit.load(std::sync::atomic::Ordering::Relaxed) {
             info!("int: early exit");
             return Err("interrupted".into());
@@ -159,24 +188,27 @@ pub fn run(args: &IcxAnalyzeArgs) -> Result<()> {
             "ps" => {
                 println!(
                     "{}: {} ({} -> {}: {})",
-                    &tx.tx_type, tx.txid, tx.swap_from, tx.swap_to, tx.swap_amt
+                    tx.tx_type, tx.txid, tx.swap_from, tx.swap_to, tx.swap_amt
                 );
             }
             "icx-claim" => {
                 println!(
                     "{}: {} ({} / {})",
-                    &tx.tx_type, tx.txid, tx.icx_btc_exp_amt, tx.icx_addr
+                    tx.tx_type, tx.txid, tx.icx_btc_exp_amt, tx.icx_addr
                 );
             }
             _ => {
-                println!("{}: {}", &tx.tx_type, tx.txid);
+                println!("{}: {}", tx.tx_type, tx.txid);
             }
         };
+        if args.verbose_tx {
+            print_tx_details(&tx);
+        }
 
         if let Some(t) = tracked_info.as_mut() {
             update_tracking_info(&tx, t)?;
             if stop_tracking_predicate(&tx, t) {
-                return Err("stop track crieteria hit".into());
+                return Err(Error::StopCriteria);
             }
         }
 
@@ -188,7 +220,7 @@ pub fn run(args: &IcxAnalyzeArgs) -> Result<()> {
     });
 
     if let Err(e) = r {
-        if e.to_string() == "stop track crieteria hit" {
+        if matches!(e, Error::StopCriteria) {
             info!("{:?}", e);
         } else {
             error!("{:?}", e);
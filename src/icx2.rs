@@ -1,23 +1,42 @@
 // WIP file. Remove on finish
 #![allow(dead_code)]
 
-use crate::db::{SqliteBlockStore, TxRow};
-use crate::lang::Result;
+use crate::db::{self, SqliteBlockStore, TxRow};
+use crate::icx1::print_tx_details;
+use crate::lang::{Error, Result};
 use crate::models::TxType;
+use bigdecimal::BigDecimal;
 use clap::Parser;
 use std::collections::HashSet;
-use tracing::{debug, error, info};
+use std::str::FromStr;
+use tracing::{debug, error, info, warn};
 
 #[derive(Parser, Debug)]
 pub struct IcxAnalyze2Args {
     #[arg(long, default_value = "data/index.sqlite")]
     pub sqlite_path: String,
     #[arg(short = 's', long, default_value_t = 0)]
-    pub start_height: i64,
+    pub start_height: u32,
     #[arg(short = 'e', long, default_value_t = 2_000_000)]
-    pub end_height: i64,
+    pub end_height: u32,
     #[arg(long, default_value_t = 1)]
     pub icx_addr: i64,
+    /// Number of satoshis below expected BTC minted to tolerate before
+    /// declaring tracking complete. Default 1 handles floating-point imprecision.
+    #[arg(long, default_value_t = 1)]
+    pub stop_threshold_sat: u64,
+    /// Fail on the first tx row that can't be parsed, instead of
+    /// skipping it with a warning
+    #[arg(long, default_value_t = false)]
+    pub fail_on_parse_error: bool,
+    /// Also follow funds through EVM txs and transfers between the
+    /// DVM and EVM
+    #[arg(long, default_value_t = false)]
+    pub include_evm_txs: bool,
+    /// Also print the height, ins, outs, DVM addresses and icx data of
+    /// each tracked tx
+    #[arg(long, default_value_t = false)]
+    pub verbose_tx: bool,
 }
 
 pub fn run(args: &IcxAnalyze2Args) -> Result<()> {
@@ -26,8 +45,10 @@ pub fn run(args: &IcxAnalyze2Args) -> Result<()> {
     let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
 
-    let sql_store = SqliteBlockStore::new_v2(Some(&args.sqlite_path))?;
-    let tracked_tx_types: HashSet<_> = [
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+
+    let sql_store = SqliteBlockStore::open_readonly(&args.sqlite_path)?;
+    let mut tracked_tx_types: HashSet<_> = [
         TxType::Unknown,
         // TxType::Coinbase,
         TxType::Utxo,
@@ -60,11 +81,24 @@ pub fn run(args: &IcxAnalyze2Args) -> Result<()> {
     .iter()
     .map(|x| x.to_string())
     .collect();
+    if args.include_evm_txs {
+        tracked_tx_types.extend(
+            [TxType::EvmTx, TxType::EvmDeploy, TxType::TransferDomain]
+                .iter()
+                .map(|x| x.to_string()),
+        );
+    }
+
+    // Swap amounts are stored with a fixed precision, so the sum of swaps
+    // can fall marginally short of the minted amount. Tolerate the configured
+    // undershoot (in sats) when deciding the minted BTC is fully swapped.
+    let stop_threshold =
+        BigDecimal::from(args.stop_threshold_sat) / BigDecimal::from(100_000_000u64);
 
     let stop_tracking_predicate = |tx: &TxRow, tracked_info: &TrackedInfo| -> bool {
         if tx.tx_type == TxType::PoolSwap.to_string()
             && tx.swap_from == "btc"
-            && tracked_info.current_swapped >= (tracked_info.btc_minted - 0.00000001)
+            && tracked_info.current_swapped >= (&tracked_info.btc_minted - &stop_threshold)
         {
             debug!("{:?}", tracked_info);
             return true;
@@ -74,7 +108,7 @@ pub fn run(args: &IcxAnalyze2Args) -> Result<()> {
 
     let update_tracking_info = |tx: &TxRow, tracked_info: &mut TrackedInfo| -> Result<()> {
         if tx.tx_type == TxType::PoolSwap.to_string() && tx.swap_from == "btc" {
-            tracked_info.current_swapped += str::parse::<f64>(&tx.swap_amt)?;
+            tracked_info.current_swapped += BigDecimal::from_str(&tx.swap_amt)?;
         }
         Ok(())
     };
@@ -84,18 +118,19 @@ pub fn run(args: &IcxAnalyze2Args) -> Result<()> {
     struct TrackedInfo {
         origin_txid: String,
         addr: String,
-        btc_minted: f64,
+        btc_minted: BigDecimal,
         // state
-        current_swapped: f64,
+        current_swapped: BigDecimal,
     }
 
     let mut count = 0;
     let mut this_addr_icx_claims = 0;
     let mut txiter = 0;
+    let mut parse_errors = 0;
     let mut tracked = HashSet::new();
     let mut tracked_info: Option<TrackedInfo> = Option::None;
 
-    let r = sql_store.iter_txs(None, |tx| {
+    let r = sql_store.iter_txs_in_range(args.start_height.into(), args.end_height.into(), |tx| {
         if quit.load(std::sync::atomic::Ordering::Relaxed) {
             info!("int: early exit");
             return Err("interrupted".into());
@@ -110,7 +145,15 @@ pub fn run(args: &IcxAnalyze2Args) -> Result<()> {
                 tracked.len()
             );
         }
-        let tx = tx?;
+        let tx = match tx {
+            Ok(t) => t,
+            Err(e) if !args.fail_on_parse_error => {
+                parse_errors += 1;
+                warn!("skip tx parse err: {:?}", e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
         let mut is_tracked_tx = false;
 
         // println!("{:?}", tx);
@@ -119,7 +162,7 @@ pub fn run(args: &IcxAnalyze2Args) -> Result<()> {
             if let Some(tr) = tracked_info.as_mut() {
                 if tr.addr == tx.icx_addr {
                     debug!("icx tx: {} // {}", &tx.txid, &tx.icx_addr);
-                    tr.btc_minted += str::parse::<f64>(&tx.icx_btc_exp_amt)?;
+                    tr.btc_minted += BigDecimal::from_str(&tx.icx_btc_exp_amt)?;
                     this_addr_icx_claims += 1;
                 }
             }
@@ -129,8 +172,8 @@ pub fn run(args: &IcxAnalyze2Args) -> Result<()> {
                 tracked_info = Some(TrackedInfo {
                     origin_txid: tx.txid.clone(),
                     addr: tx.icx_addr.clone(),
-                    btc_minted: str::parse(&tx.icx_btc_exp_amt)?,
-                    current_swapped: 0.,
+                    btc_minted: BigDecimal::from_str(&tx.icx_btc_exp_amt)?,
+                    current_swapped: BigDecimal::from(0),
                 });
                 this_addr_icx_claims += 1;
             }
@@ -162,24 +205,27 @@ pub fn run(args: &IcxAnalyze2Args) -> Result<()> {
             "ps" => {
                 println!(
                     "{}: {} ({} -> {}: {})",
-                    &tx.tx_type, tx.txid, tx.swap_from, tx.swap_to, tx.swap_amt
+                    tx.tx_type, tx.txid, tx.swap_from, tx.swap_to, tx.swap_amt
                 );
             }
             "icx-claim" => {
                 println!(
                     "{}: {} ({} / {})",
-                    &tx.tx_type, tx.txid, tx.icx_btc_exp_amt, tx.icx_addr
+                    tx.tx_type, tx.txid, tx.icx_btc_exp_amt, tx.icx_addr
                 );
             }
             _ => {
-                println!("{}: {}", &tx.tx_type, tx.txid);
+                println!("{}: {}", tx.tx_type, tx.txid);
             }
         };
+        if args.verbose_tx {
+            print_tx_details(&tx);
+        }
 
         if let Some(t) = tracked_info.as_mut() {
             update_tracking_info(&tx, t)?;
             if stop_tracking_predicate(&tx, t) {
-                return Err("stop track crieteria hit".into());
+                return Err(Error::StopCriteria);
             }
         }
 
@@ -191,7 +237,7 @@ pub fn run(args: &IcxAnalyze2Args) -> Result<()> {
     });
 
     if let Err(e) = r {
-        if e.to_string() == "stop track crieteria hit" {
+        if matches!(e, Error::StopCriteria) {
             info!("{:?}", e);
         } else {
             error!("{:?}", e);
@@ -208,5 +254,6 @@ pub fn run(args: &IcxAnalyze2Args) -> Result<()> {
         this_addr_icx_claims
     );
     info!("summary: scanned icx-claims: {}", count);
+    info!("summary: tx parse errors: {}", parse_errors);
     Ok(())
 }
@@ -0,0 +1,244 @@
+use crate::args::OutputFormat;
+use crate::db::{self, SqliteBlockStore, TxRow};
+use crate::lang::Result;
+use crate::models::TxType;
+use clap::Parser;
+use std::collections::HashMap;
+use tracing::{debug, error, info, warn};
+
+#[derive(Parser, Debug)]
+pub struct IcxOrderBookArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    /// Height to reconstruct the order book at, inclusive
+    #[arg(long)]
+    pub height: u32,
+    /// Only show orders created by this address
+    #[arg(long)]
+    pub addr: Option<String>,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct IcxOrder {
+    pub order_txid: String,
+    pub created_height: i64,
+    pub owner_addr: String,
+    /// Txids of the offers made on the order
+    pub offers: Vec<String>,
+}
+
+/// Orders are opened by ICXCreateOrder, and closed by either an
+/// ICXCloseOrder or an ICXClaimDFCHTLC on them. Offers and closes refer
+/// to the order with `orderTx` in the msg, claims only through the
+/// `IcxTxSet` from the logs.
+#[derive(Debug, Default)]
+pub struct IcxOrderBook {
+    open: HashMap<String, IcxOrder>,
+}
+
+impl IcxOrderBook {
+    pub fn apply(&mut self, tx: &TxRow) {
+        let msg = tx.data.vm.as_ref().map(|x| &x.msg);
+        match TxType::from_display(&tx.tx_type) {
+            TxType::ICXCreateOrder => {
+                let owner_addr = order_owner(tx);
+                self.open.insert(
+                    tx.txid.clone(),
+                    IcxOrder {
+                        order_txid: tx.txid.clone(),
+                        created_height: tx.height,
+                        owner_addr: owner_addr.to_string(),
+                        offers: vec![],
+                    },
+                );
+            }
+            TxType::ICXMakeOffer => {
+                let order_tx = msg.and_then(|x| x["orderTx"].as_str());
+                if let Some(order) = order_tx.and_then(|x| self.open.get_mut(x)) {
+                    order.offers.push(tx.txid.clone());
+                }
+            }
+            TxType::ICXCloseOrder => {
+                if let Some(order_tx) = msg.and_then(|x| x["orderTx"].as_str()) {
+                    self.open.remove(order_tx);
+                }
+            }
+            TxType::ICXClaimDFCHTLC => match &tx.icx_data {
+                Some(icx_data) => {
+                    self.open.remove(&*icx_data.order_tx);
+                }
+                None => warn!("icx claim without icx data: {}", tx.txid),
+            },
+            _ => {}
+        }
+    }
+
+    /// Open orders, oldest first
+    pub fn open_orders(self, addr: Option<&str>) -> Vec<IcxOrder> {
+        let mut orders = self
+            .open
+            .into_values()
+            .filter(|x| addr.is_none_or(|a| x.owner_addr == a))
+            .collect::<Vec<_>>();
+        orders.sort_by(|a, b| {
+            (a.created_height, &a.order_txid).cmp(&(b.created_height, &b.order_txid))
+        });
+        orders
+    }
+}
+
+/// Owner of an ICXCreateOrder tx. ownerAddress is optional in the msg,
+/// the order is then owned by the funding address.
+pub fn order_owner(tx: &TxRow) -> &str {
+    tx.data
+        .vm
+        .as_ref()
+        .and_then(|x| x.msg["ownerAddress"].as_str())
+        .or_else(|| tx.tx_in.keys().next().map(|x| x.as_str()))
+        .unwrap_or_default()
+}
+
+pub fn run(args: &IcxOrderBookArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    let sql_store = SqliteBlockStore::open(Some(&args.sqlite_path))?;
+
+    let tx_types = [
+        TxType::ICXCreateOrder,
+        TxType::ICXMakeOffer,
+        TxType::ICXCloseOrder,
+        TxType::ICXClaimDFCHTLC,
+    ]
+    .iter()
+    .map(|x| format!("'{}'", x))
+    .collect::<Vec<_>>()
+    .join(", ");
+    let modifier = format!(
+        "where tx_type in ({}) and height <= {} order by height",
+        tx_types, args.height
+    );
+
+    let mut book = IcxOrderBook::default();
+    info!("get icx txs..");
+    let r = sql_store.iter_txs(Some(&modifier), |tx| {
+        if quit.load(std::sync::atomic::Ordering::Relaxed) {
+            info!("int: early exit");
+            return Err("interrupted".into());
+        }
+        book.apply(&tx?);
+        Ok(())
+    });
+    if let Err(e) = r {
+        error!("{:?}", e);
+        return Err(e);
+    }
+
+    let orders = book.open_orders(args.addr.as_deref());
+    match args.output_format {
+        OutputFormat::Text => {
+            println!(
+                "{:<64} {:>10} {:<42} {:>6}",
+                "order", "height", "owner", "offers"
+            );
+            for o in orders.iter() {
+                println!(
+                    "{:<64} {:>10} {:<42} {:>6}",
+                    o.order_txid,
+                    o.created_height,
+                    o.owner_addr,
+                    o.offers.len()
+                );
+            }
+            println!("total open: {}", orders.len());
+        }
+        OutputFormat::Json => {
+            let out = serde_json::json!({
+                "height": args.height,
+                "open_orders": orders,
+                "total_open": orders.len(),
+            });
+            println!("{}", serde_json::to_string_pretty(&out)?);
+        }
+    }
+
+    info!("complete");
+    Ok(())
+}
+
+#[test]
+fn test_icx_order_book() {
+    use crate::models::{IcxTxSet, Transaction, VMInfo};
+
+    let tx = |txid: &str, height: i64, tx_type: TxType, msg: serde_json::Value| TxRow {
+        txid: txid.to_string(),
+        height,
+        tx_type: tx_type.to_string(),
+        data: Transaction {
+            vm: Some(VMInfo {
+                vmtype: "dvm".into(),
+                txtype: "".into(),
+                msg,
+            }),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let create = |txid: &str, height: i64, owner: &str| {
+        tx(
+            txid,
+            height,
+            TxType::ICXCreateOrder,
+            serde_json::json!({ "ownerAddress": owner }),
+        )
+    };
+
+    let mut claim = tx("c1", 5, TxType::ICXClaimDFCHTLC, serde_json::json!({}));
+    claim.icx_data = Some(IcxTxSet {
+        order_tx: "o2".into(),
+        offer_tx: "of2".into(),
+        dfchtlc_tx: "d2".into(),
+        claim_tx: "c1".into(),
+    });
+    let txs = [
+        create("o1", 1, "addr1"),
+        create("o2", 2, "addr2"),
+        create("o3", 3, "addr1"),
+        tx(
+            "of1",
+            3,
+            TxType::ICXMakeOffer,
+            serde_json::json!({ "orderTx": "o1" }),
+        ),
+        tx(
+            "e3",
+            4,
+            TxType::ICXCloseOrder,
+            serde_json::json!({ "orderTx": "o3" }),
+        ),
+        claim,
+    ];
+
+    let mut book = IcxOrderBook::default();
+    txs.iter().for_each(|x| book.apply(x));
+    let orders = book.open_orders(None);
+    assert_eq!(orders.len(), 1);
+    assert_eq!(orders[0].order_txid, "o1");
+    assert_eq!(orders[0].offers, ["of1"]);
+
+    let mut book = IcxOrderBook::default();
+    txs[..3].iter().for_each(|x| book.apply(x));
+    let orders = book.open_orders(Some("addr1"));
+    assert_eq!(
+        orders
+            .iter()
+            .map(|x| x.order_txid.as_str())
+            .collect::<Vec<_>>(),
+        ["o1", "o3"]
+    );
+}
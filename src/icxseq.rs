@@ -1,103 +1,289 @@
-// WIP file. Remove on finish
-#![allow(unused_variables)]
-
-use crate::{db::SqliteBlockStore, graphutils, lang::Result, models::TxType};
-use clap::Parser;
-use tracing::{debug, error, info, trace};
+use crate::{
+    db::{self, IcxLink, SqliteBlockStore, TxRow},
+    icxorderbook::order_owner,
+    lang::{OptionExt, Result},
+    models::TxType,
+};
+use clap::{Parser, ValueEnum};
+use tracing::{debug, info, warn};
 
 #[derive(Parser, Debug)]
 pub struct IcxSequenceArgs {
     #[arg(long, default_value = "data/index.sqlite")]
     pub sqlite_path: String,
-    #[arg(long, default_value = "data/graph.bin")]
-    pub graph_data_path: String,
-    #[arg(long, default_value = "data/graph.meta.bin")]
-    pub graph_meta_path: String,
-    /// Address that's the origin (center point) of the graph exploration
+    /// Address that created the orders
     #[arg(long, short = 'a')]
     pub addr: String,
-    /// ICX addresses to ignore for co-relation
-    #[arg(
-        long,
-        use_value_delimiter = true,
-        value_delimiter = ',',
-        default_value = ""
-    )]
-    pub icx_ignore_addr: Vec<String>,
-    /// Graph addresses to ignore for co-relation
-    #[arg(
-        long,
-        use_value_delimiter = true,
-        value_delimiter = ',',
-        default_value = ""
-    )]
-    pub graph_ignore_addr: Vec<String>,
-    /// Graph addresses to mark for co-relation
-    #[arg(
-        long,
-        use_value_delimiter = true,
-        value_delimiter = ',',
-        default_value = ""
-    )]
-    pub graph_mark_addr: Vec<String>,
+    #[arg(long, value_enum, default_value_t = IcxSeqFormat::Table)]
+    pub format: IcxSeqFormat,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcxSeqFormat {
+    Table,
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct IcxSeqEntry {
+    pub order_tx: String,
+    pub txid: String,
+    pub height: i64,
+    pub tx_type: String,
+    pub amount: String,
+    pub counterparty: String,
 }
 
-// WIP file. Remove on finish
-#[allow(unreachable_code)]
 pub fn run(args: &IcxSequenceArgs) -> Result<()> {
     debug!("args: {:?}", args);
 
     let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
-    let user_sig = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
-    #[cfg(unix)]
-    signal_hook::flag::register(
-        signal_hook::consts::SIGUSR1,
-        std::sync::Arc::clone(&user_sig),
-    )?;
 
-    let sql_store = SqliteBlockStore::new_v2(Some(&args.sqlite_path))?;
-    let (g, node_index_map) = graphutils::load_graph(&args.graph_meta_path, &args.graph_data_path)?;
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    let sql_store = SqliteBlockStore::open(Some(&args.sqlite_path))?;
 
-    let mut graph_ignore_addr_list = args.graph_ignore_addr.clone();
-    graph_ignore_addr_list.sort();
+    let seq = get_icx_sequence(&sql_store, &args.addr, || {
+        quit.load(std::sync::atomic::Ordering::Relaxed)
+    })?;
 
-    let mut graph_mark_addr_list = args.graph_mark_addr.clone();
-    graph_mark_addr_list.sort();
+    match args.format {
+        IcxSeqFormat::Table => {
+            let mut last_order = None;
+            for x in seq.iter() {
+                if last_order != Some(&x.order_tx) {
+                    println!("order: {}", x.order_tx);
+                    last_order = Some(&x.order_tx);
+                }
+                println!(
+                    "  {:<64} {:>8} {:<10} {:>18} {}",
+                    x.txid, x.height, x.tx_type, x.amount, x.counterparty
+                );
+            }
+        }
+        IcxSeqFormat::Csv => {
+            println!("order_tx,txid,height,tx_type,amount,counterparty");
+            for x in seq.iter() {
+                println!(
+                    "{},{},{},{},{},{}",
+                    x.order_tx, x.txid, x.height, x.tx_type, x.amount, x.counterparty
+                );
+            }
+        }
+        IcxSeqFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&seq)?);
+        }
+    }
 
-    todo!("impl pending");
+    info!("complete");
+    Ok(())
+}
 
-    // ICX txs
-    info!("get all icx txs..");
-    let mut icx_txs = std::collections::HashSet::<String>::new();
-    let icx_ignore_list = args
-        .icx_ignore_addr
-        .iter()
-        .collect::<std::collections::HashSet<_>>();
-    let r = sql_store.iter_txs_partial(Some("where tx_type glob \"icx-claim\""), |tx| {
-        if quit.load(std::sync::atomic::Ordering::Relaxed) {
+/// Every order created by `addr`, each followed by its offers, HTLCs and
+/// claims in height order. The links are staged in a temp table first, so
+/// only the orders of `addr` and the current chain are held in memory.
+pub fn get_icx_sequence<F>(
+    sql_store: &SqliteBlockStore,
+    addr: &str,
+    interrupted: F,
+) -> Result<Vec<IcxSeqEntry>>
+where
+    F: Fn() -> bool,
+{
+    info!("get orders..");
+    let mut orders = Vec::new();
+    sql_store.iter_txs_by_type(&TxType::ICXCreateOrder, 0..=i64::MAX, |tx| {
+        if interrupted() {
             info!("int: early exit");
             return Err("interrupted".into());
         }
         let tx = tx?;
-        if tx.tx_type == TxType::ICXClaimDFCHTLC.to_string() {
-            let icx_addr = tx.icx_addr;
-            if !icx_addr.is_empty() && !icx_ignore_list.contains(&icx_addr) {
-                icx_txs.insert(tx.txid.clone());
-            }
+        if order_owner(&tx) == addr {
+            orders.push(tx);
         }
         Ok(())
-    });
-    if let Err(e) = r {
-        if e.to_string() == "interrupted" {
-            info!("{:?}", e);
-        } else {
-            error!("{:?}", e);
+    })?;
+    info!("orders: {}", orders.len());
+
+    info!("stage icx links..");
+    let n = sql_store.create_icx_links_table()?;
+    info!("icx links: {}", n);
+
+    let mut seq = Vec::new();
+    for order in orders {
+        if interrupted() {
+            info!("int: early exit");
+            return Err("interrupted".into());
+        }
+        let mut chain = vec![];
+        for offer in sql_store.get_icx_links(IcxLink::Order, &order.txid)? {
+            let htlcs = sql_store.get_icx_links(IcxLink::Offer, &offer)?;
+            chain.push(offer);
+            for htlc in htlcs {
+                let claims = sql_store.get_icx_links(IcxLink::DfcHtlc, &htlc)?;
+                chain.push(htlc);
+                chain.extend(claims);
+            }
+        }
+
+        let mut entries = vec![to_entry(&order.txid, &order, addr)];
+        for txid in chain {
+            let tx = sql_store
+                .get_tx_data(&txid)?
+                .ok_or_with(|| format!("tx: {}", txid))?;
+            entries.push(to_entry(&order.txid, &tx, addr));
         }
-    } else {
-        info!("done. icx_txs: {}", icx_txs.len());
-        trace!("icx_txs: {:?}", icx_txs);
+        entries.sort_by_key(|x| x.height);
+        seq.extend(entries);
     }
-    info!("complete");
-    Ok(())
+    Ok(seq)
+}
+
+fn to_entry(order_tx: &str, tx: &TxRow, addr: &str) -> IcxSeqEntry {
+    let msg = tx.data.vm.as_ref().map(|x| &x.msg);
+    let msg_str = |k: &str| match msg.map(|x| &x[k]) {
+        Some(serde_json::Value::String(s)) => Some(s.clone()),
+        Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+        _ => None,
+    };
+
+    // Claims only have the expected BTC amount, from the logs.
+    let amount = msg_str("amount")
+        .or_else(|| msg_str("amountFrom"))
+        .or_else(|| (!tx.icx_btc_exp_amt.is_empty()).then(|| tx.icx_btc_exp_amt.clone()))
+        .unwrap_or_default();
+    if amount.is_empty() {
+        warn!("no amount: {} ({})", tx.txid, tx.tx_type);
+    }
+
+    // First address involved that isn't addr
+    let mut tx_in = tx.tx_in.keys().cloned().collect::<Vec<_>>();
+    tx_in.sort();
+    let counterparty = [msg_str("ownerAddress"), msg_str("htlcScriptAddress")]
+        .into_iter()
+        .flatten()
+        .chain(std::iter::once(tx.icx_addr.clone()))
+        .chain(tx_in)
+        .find(|x| !x.is_empty() && x != addr)
+        .unwrap_or_default();
+
+    IcxSeqEntry {
+        order_tx: order_tx.to_string(),
+        txid: tx.txid.clone(),
+        height: tx.height,
+        tx_type: tx.tx_type.clone(),
+        amount,
+        counterparty,
+    }
+}
+
+#[test]
+fn test_get_icx_sequence() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    let addr = "8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R";
+    let taker = "dZcHjYhKtEM88TtZLjp314H2xZjkztXtRc";
+    let data = |msg: serde_json::Value| {
+        serde_json::json!({
+            "txid": "", "hash": "", "version": 4, "size": 0, "vsize": 0,
+            "weight": 0, "locktime": 0, "vin": [], "vout": [], "hex": "",
+            "vm": { "vmtype": "dvm", "txtype": "", "msg": msg },
+        })
+        .to_string()
+    };
+    store
+        .with_stmts(|stmts| {
+            let txs = [
+                (
+                    "o1",
+                    1,
+                    TxType::ICXCreateOrder,
+                    data(serde_json::json!({ "ownerAddress": addr, "amountFrom": 10 })),
+                    "",
+                    "",
+                ),
+                (
+                    "o2",
+                    1,
+                    TxType::ICXCreateOrder,
+                    data(serde_json::json!({ "ownerAddress": taker, "amountFrom": 1 })),
+                    "",
+                    "",
+                ),
+                (
+                    "of1",
+                    2,
+                    TxType::ICXMakeOffer,
+                    data(serde_json::json!({ "orderTx": "o1", "amount": "0.1", "ownerAddress": taker })),
+                    "",
+                    "",
+                ),
+                (
+                    "d1",
+                    3,
+                    TxType::ICXSubmitDFCHTLC,
+                    data(serde_json::json!({ "offerTx": "of1", "amount": "10" })),
+                    "",
+                    "",
+                ),
+                (
+                    "e1",
+                    4,
+                    TxType::ICXSubmitEXTHTLC,
+                    data(serde_json::json!({ "offerTx": "of1", "amount": "0.1", "htlcScriptAddress": "3Mx" })),
+                    "",
+                    "",
+                ),
+                // Claim linked only through the icx data
+                (
+                    "c1",
+                    5,
+                    TxType::ICXClaimDFCHTLC,
+                    data(serde_json::json!({})),
+                    r#"{"order_tx":"o1","offer_tx":"of1","dfchtlc_tx":"d1","claim_tx":"c1"}"#,
+                    "0.1",
+                ),
+            ];
+            for (txid, height, tx_type, data, icx_data, btc_amt) in txs {
+                crate::db::insert_test_tx(
+                    stmts,
+                    &crate::db::TestTx {
+                        txid,
+                        height,
+                        tx_type: &tx_type.to_string(),
+                        data: &data,
+                        icx_data,
+                        icx_addr: taker,
+                        icx_btc_exp_amt: btc_amt,
+                        ..Default::default()
+                    },
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let seq = get_icx_sequence(&store, addr, || false).unwrap();
+    let rows = seq
+        .iter()
+        .map(|x| {
+            (
+                x.order_tx.as_str(),
+                x.txid.as_str(),
+                x.amount.as_str(),
+                x.counterparty.as_str(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rows,
+        [
+            ("o1", "o1", "10", taker),
+            ("o1", "of1", "0.1", taker),
+            ("o1", "d1", "10", taker),
+            ("o1", "e1", "0.1", "3Mx"),
+            ("o1", "c1", "0.1", taker),
+        ]
+    );
+    assert_eq!(seq[4].tx_type, "icx-claim");
 }
@@ -0,0 +1,216 @@
+use crate::args::OutputFormat;
+use crate::db::{self, SqliteBlockStore, TxRow};
+use crate::lang::Result;
+use crate::models::TxType;
+use clap::Parser;
+use std::collections::{HashMap, HashSet};
+use tracing::{debug, info};
+
+#[derive(Parser, Debug)]
+pub struct IcxStatsArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    #[arg(short = 's', long, default_value_t = 0)]
+    pub start_height: u32,
+    #[arg(short = 'e', long, default_value_t = 2_000_000)]
+    pub end_height: u32,
+    /// Number of addresses to list, by total BTC claimed
+    #[arg(long, default_value_t = 20)]
+    pub top_n: usize,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+}
+
+/// ICX claims of an address. The sets are output as their counts.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct IcxAddressStats {
+    pub address: String,
+    /// Sum of the BTC expected amounts of the claims
+    pub btc_claimed: f64,
+    pub claims: u64,
+    #[serde(rename = "unique_order_txs", serialize_with = "serialize_len")]
+    pub order_txs: HashSet<String>,
+    /// The other addresses in the claim txs
+    #[serde(rename = "unique_counterparties", serialize_with = "serialize_len")]
+    pub counterparties: HashSet<String>,
+}
+
+fn serialize_len<S: serde::Serializer>(
+    v: &HashSet<String>,
+    s: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    s.serialize_u64(v.len() as u64)
+}
+
+impl IcxAddressStats {
+    pub fn add_claim(&mut self, tx: &TxRow) -> Result<()> {
+        self.btc_claimed += tx.icx_btc_exp_amt.parse::<f64>()?;
+        self.claims += 1;
+        if let Some(icx) = &tx.icx_data {
+            self.order_txs.insert(icx.order_tx.to_string());
+        }
+        let addrs = tx
+            .tx_in
+            .keys()
+            .chain(tx.tx_out.keys())
+            .chain(tx.dvm_out.iter())
+            .filter(|x| **x != self.address);
+        self.counterparties.extend(addrs.cloned());
+        Ok(())
+    }
+}
+
+/// Claims in `start..=end` per claiming address, top `top_n` by BTC
+/// claimed. Claims without icx data from the log have no address and are
+/// left out.
+pub fn get_icx_address_stats<F>(
+    sql_store: &SqliteBlockStore,
+    start: i64,
+    end: i64,
+    top_n: usize,
+    interrupted: F,
+) -> Result<Vec<IcxAddressStats>>
+where
+    F: Fn() -> bool,
+{
+    let mut stats = HashMap::<String, IcxAddressStats>::new();
+    let mut no_addr = 0;
+    sql_store.iter_txs_by_type(&TxType::ICXClaimDFCHTLC, start..=end, |tx| {
+        if interrupted() {
+            info!("int: early exit");
+            return Err("interrupted".into());
+        }
+        let tx = tx?;
+        if tx.icx_addr.is_empty() {
+            no_addr += 1;
+            return Ok(());
+        }
+        stats
+            .entry(tx.icx_addr.clone())
+            .or_insert_with(|| IcxAddressStats {
+                address: tx.icx_addr.clone(),
+                ..Default::default()
+            })
+            .add_claim(&tx)
+    })?;
+    if no_addr > 0 {
+        info!("claims without an icx address: {}", no_addr);
+    }
+
+    let mut stats = stats.into_values().collect::<Vec<_>>();
+    stats.sort_by(|a, b| {
+        b.btc_claimed
+            .total_cmp(&a.btc_claimed)
+            .then_with(|| a.address.cmp(&b.address))
+    });
+    stats.truncate(top_n);
+    Ok(stats)
+}
+
+pub fn run(args: &IcxStatsArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    let sql_store = SqliteBlockStore::open_readonly(&args.sqlite_path)?;
+
+    info!("get icx claim stats..");
+    let stats = get_icx_address_stats(
+        &sql_store,
+        args.start_height.into(),
+        args.end_height.into(),
+        args.top_n,
+        || quit.load(std::sync::atomic::Ordering::Relaxed),
+    )?;
+
+    match args.output_format {
+        OutputFormat::Text => {
+            println!(
+                "{:<42} {:>18} {:>8} {:>8} {:>14}",
+                "address", "btc", "claims", "orders", "counterparties"
+            );
+            for s in stats.iter() {
+                println!(
+                    "{:<42} {:>18.8} {:>8} {:>8} {:>14}",
+                    s.address,
+                    s.btc_claimed,
+                    s.claims,
+                    s.order_txs.len(),
+                    s.counterparties.len()
+                );
+            }
+        }
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        }
+    }
+
+    info!("complete");
+    Ok(())
+}
+
+#[test]
+fn test_get_icx_address_stats() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            // The claim address, btc amount, order, and the in and out address
+            let claims = [
+                ("c1", 1, "a", "0.5", "o1", "a", "b"),
+                ("c2", 2, "a", "0.25", "o1", "a", "c"),
+                ("c3", 3, "a", "1.0", "o2", "d", "a"),
+                ("c4", 4, "e", "2.0", "o3", "e", "e"),
+                ("c5", 5, "", "", "", "f", "f"),
+                ("c6", 9, "g", "9.0", "o4", "g", "g"),
+            ];
+            for (txid, height, icx_addr, btc, order, in_addr, out_addr) in claims {
+                let icx_data = match order {
+                    "" => String::new(),
+                    x => serde_json::json!({
+                        "order_tx": x, "offer_tx": "", "dfchtlc_tx": "", "claim_tx": ""
+                    })
+                    .to_string(),
+                };
+                crate::db::insert_test_tx(
+                    stmts,
+                    &crate::db::TestTx {
+                        txid,
+                        height,
+                        tx_type: "icx-claim",
+                        tx_in: &format!(r#"{{"{}":1.0}}"#, in_addr),
+                        tx_out: &format!(r#"{{"{}":0.9}}"#, out_addr),
+                        icx_data: &icx_data,
+                        icx_addr,
+                        icx_btc_exp_amt: btc,
+                        ..Default::default()
+                    },
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let stats = get_icx_address_stats(&store, 0, 5, 10, || false).unwrap();
+    let rows = stats
+        .iter()
+        .map(|x| {
+            (
+                x.address.as_str(),
+                x.btc_claimed,
+                x.claims,
+                x.order_txs.len(),
+                x.counterparties.len(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(rows, [("e", 2.0, 1, 1, 0), ("a", 1.75, 3, 2, 3)]);
+
+    let stats = get_icx_address_stats(&store, 0, 9, 1, || false).unwrap();
+    assert_eq!(stats.len(), 1);
+    assert_eq!(stats[0].address, "g");
+    let json = serde_json::to_value(&stats[0]).unwrap();
+    assert_eq!(json["unique_order_txs"], 1);
+    assert_eq!(json["unique_counterparties"], 0);
+}
@@ -17,6 +17,11 @@ pub enum Error {
     StrUtf8(#[from] std::str::Utf8Error, std::backtrace::Backtrace),
     #[error("parse float: {0}")]
     ParseFloat(#[from] ParseFloatError, std::backtrace::Backtrace),
+    #[error("parse bigdecimal: {0}")]
+    ParseBigDecimal(
+        #[from] bigdecimal::ParseBigDecimalError,
+        std::backtrace::Backtrace,
+    ),
     #[error("string utf8: {0}")]
     StringUtf8(
         #[from] std::string::FromUtf8Error,
@@ -31,6 +36,19 @@ pub enum Error {
     #[cfg(feature = "legacy-rocks")]
     #[error("rocksdb: {0}")]
     RocksDB(#[from] rust_rocksdb::Error, std::backtrace::Backtrace),
+    /// Where in the chain an indexer error happened
+    #[error("at height {height}, txid {txid}: {source}")]
+    WithContext {
+        height: i64,
+        txid: String,
+        #[source]
+        #[backtrace]
+        source: Box<Error>,
+    },
+    /// Not a failure: raised by analyzers to break out of an iteration
+    /// once their tracking criteria is satisfied.
+    #[error("stop_track_criteria_hit")]
+    StopCriteria,
     #[error(transparent)]
     Anyhow(
         #[from]
@@ -43,6 +61,14 @@ impl Error {
     pub fn none_err() -> Self {
         Error::from("Some option expected, got none")
     }
+
+    pub fn with_context(height: i64, txid: &str, err: Error) -> Self {
+        Error::WithContext {
+            height,
+            txid: txid.to_string(),
+            source: Box::new(err),
+        }
+    }
 }
 
 impl std::convert::From<String> for Error {
@@ -84,6 +110,10 @@ impl<T, E: Into<Error>> ResultExt<T> for std::result::Result<T, E> {
 
 pub trait OptionExt<T> {
     fn ok_or_err(self) -> Result<T>;
+    /// Like `ok_or_err`, with `ctx` naming what was missing
+    fn ok_or_context(self, ctx: &str) -> Result<T>;
+    /// Like `ok_or_context`, for messages that are costly to build
+    fn ok_or_with<F: FnOnce() -> String>(self, f: F) -> Result<T>;
 }
 
 impl<T> OptionExt<T> for Option<T> {
@@ -93,4 +123,74 @@ impl<T> OptionExt<T> for Option<T> {
             None => Err(Error::none_err()),
         }
     }
+
+    fn ok_or_context(self, ctx: &str) -> Result<T> {
+        match self {
+            Some(v) => Ok(v),
+            None => Err(Error::from(format!("None: {}", ctx))),
+        }
+    }
+
+    fn ok_or_with<F: FnOnce() -> String>(self, f: F) -> Result<T> {
+        match self {
+            Some(v) => Ok(v),
+            None => Err(Error::from(format!("None: {}", f()))),
+        }
+    }
+}
+
+/// String kept out of the Debug output, for passwords passed as args.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::str::FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Secret(s.to_string()))
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("***")
+    }
+}
+
+#[test]
+fn test_option_ext_messages() {
+    let none: Option<u8> = None;
+    assert_eq!(
+        none.ok_or_err().unwrap_err().to_string(),
+        "Some option expected, got none"
+    );
+    assert_eq!(
+        none.ok_or_context("edge_weight").unwrap_err().to_string(),
+        "None: edge_weight"
+    );
+    assert_eq!(
+        none.ok_or_with(|| format!("tx: {}", "abc"))
+            .unwrap_err()
+            .to_string(),
+        "None: tx: abc"
+    );
+    assert_eq!(Some(1).ok_or_with(|| unreachable!()).unwrap(), 1);
+}
+
+#[test]
+fn test_error_with_context() {
+    let e = Error::with_context(42, "abc", Error::from("bad tx"));
+    assert_eq!(e.to_string(), "at height 42, txid abc: bad tx");
+    assert_eq!(
+        std::error::Error::source(&e).map(|x| x.to_string()),
+        Some("bad tx".to_string())
+    );
+    // The backtrace of the wrapped error is passed through
+    assert!(std::error::request_ref::<std::backtrace::Backtrace>(&e).is_some());
 }
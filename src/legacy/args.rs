@@ -5,6 +5,10 @@ use clap::Parser;
 #[derive(Parser, Debug)]
 #[command(version, about, long_about=None, propagate_version=true, next_line_help(true))]
 pub struct Args {
+    /// 0: check db index, 1: index from cli, 2: index tx data, 3: graph
+    /// (rocksdb, superseded by the sqlite based `gtagged` command)
+    #[arg(long, default_value_t = 3)]
+    pub mode: u8,
     #[arg(long, default_value = "data/logs/")]
     pub graph_logs_path: String,
 }
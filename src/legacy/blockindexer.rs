@@ -10,7 +10,7 @@ pub fn index_from_cli() -> Result<()> {
     let block_store = RocksBlockStore::new(&db)?;
 
     let mut i = 4_100_000;
-    let height = cli.get_block_count()?;
+    let height = i64::from(cli.get_block_count()?);
 
     let mut tx_batch = Some(WriteBatch::default());
 
@@ -11,10 +11,9 @@ mod grapher;
 mod lang;
 mod models;
 mod txindexer;
-use crate::lang::Result;
+use crate::lang::{Error, Result};
 use clap::Parser;
 use std::env;
-use tracing::info;
 
 fn main() -> Result<()> {
     env::set_var("RUST_BACKTRACE", "1");
@@ -22,13 +21,12 @@ fn main() -> Result<()> {
     tracing_subscriber::fmt().compact().with_ansi(false).init();
     let args = args::Args::parse();
 
-    let mode = 3;
-    match mode {
+    match args.mode {
         0 => blockindexer::check_db_index()?,
         1 => blockindexer::index_from_cli()?,
         2 => txindexer::index_tx_data()?,
         3 => grapher::graph_it(args)?,
-        _ => info!("error"),
+        m => return Err(Error::from(format!("unknown mode: {}", m))),
     };
 
     Ok(())
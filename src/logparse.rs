@@ -8,8 +8,10 @@ use clap::Parser;
 use lang::Result;
 use models::LogIcxData;
 use std::io::BufRead;
+use std::io::Write;
 use tracing::info;
 use tracing::trace;
+use tracing::warn;
 
 #[derive(Parser, Debug)]
 pub struct LogParseArgs {
@@ -24,6 +26,15 @@ pub struct LogParseArgs {
     pub log_icx_calc_matcher: String,
     #[arg(long, default_value = "SwapResult:")]
     pub log_swap_matcher: String,
+    /// Lines that match none of the matchers above but contain this
+    /// pattern are collected and printed, to discover new log types.
+    #[arg(long)]
+    pub log_unknown_pattern: Option<String>,
+    /// Write the parsed ICX, ICX calc and swap lines to this file, for
+    /// later runs to read instead of the full log. Gzipped if the path
+    /// ends with `.gz`.
+    #[arg(long)]
+    pub output_path: Option<String>,
 }
 
 pub fn run(args: &LogParseArgs) -> Result<()> {
@@ -41,26 +52,58 @@ pub fn run(args: &LogParseArgs) -> Result<()> {
 
     info!("ingesting log file: {}", defid_log_path);
 
-    process_log_file(
-        defid_log_path,
-        args.log_icx_matcher.as_str(),
-        args.log_icx_calc_matcher.as_str(),
-        args.log_swap_matcher.as_str(),
-        &mut log_entry_map,
-    )?;
+    let mut process = |output: Option<&mut dyn Write>| {
+        process_log_file(
+            defid_log_path,
+            args.log_icx_matcher.as_str(),
+            args.log_icx_calc_matcher.as_str(),
+            args.log_swap_matcher.as_str(),
+            args.log_unknown_pattern.as_deref(),
+            output,
+            &mut log_entry_map,
+        )
+    };
+    match args.output_path.as_deref() {
+        None => process(None)?,
+        Some(path) => {
+            info!("writing matched lines to: {}", path);
+            let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            if path.ends_with(".gz") {
+                let mut w = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+                process(Some(&mut w))?;
+                w.finish()?.flush()?;
+            } else {
+                let mut w = file;
+                process(Some(&mut w))?;
+                w.flush()?;
+            }
+        }
+    }
 
     info!(
         "log file ingested:\n\
         \tTotal transactions:     {}\n\
         \tTotal ICX entries:      {}\n\
         \tTotal ICX calc entries: {}\n\
-        \tTotal Swap entries:     {}",
+        \tTotal Swap entries:     {}\n\
+        \tTotal lines:            {}\n\
+        \tSkipped lines:          {}\n\
+        \tUpdated entries:        {}\n\
+        \tUnknown entries:        {}",
         log_entry_map.data.len(),
         log_entry_map.icx_count,
         log_entry_map.icx_calc_count,
         log_entry_map.swap_count,
+        log_entry_map.total_lines,
+        log_entry_map.skipped_lines,
+        log_entry_map.update_count,
+        log_entry_map.unknown_data.len(),
     );
 
+    for line in log_entry_map.unknown_data.iter() {
+        info!("unknown: {}", line);
+    }
+
     Ok(())
 }
 
@@ -69,6 +112,8 @@ pub fn process_log_file(
     log_icx_matcher: &str,
     log_icx_calc_matcher: &str,
     log_swap_matcher: &str,
+    log_unknown_pattern: Option<&str>,
+    mut output: Option<&mut dyn Write>,
     combined_data: &mut LogEntryMap,
 ) -> Result<()> {
     let file = std::fs::File::open(defid_log_path)?;
@@ -95,7 +140,18 @@ pub fn process_log_file(
             })
     }
 
+    // Parsed lines are written out as they were read, with a newline
+    // for a last line without one
+    let mut write_line = |line: &str| -> Result<()> {
+        if let Some(w) = output.as_mut() {
+            w.write_all(line.trim_end_matches('\n').as_bytes())?;
+            w.write_all(b"\n")?;
+        }
+        Ok(())
+    };
+
     while reader.read_line(&mut line_buffer)? != 0 {
+        combined_data.total_lines += 1;
         match () {
             _ if line_buffer.contains(log_icx_matcher) => {
                 if let Some(data) = parse_json_line::<LogIcxData>(&line_buffer) {
@@ -103,8 +159,17 @@ pub fn process_log_file(
                         .data
                         .entry(data.claim_tx.clone())
                         .or_insert_with(LogEntry::new);
-                    entry.icx_data = Some(data);
+                    if let Some(prev) = entry.set_icx_data(data) {
+                        combined_data.update_count += 1;
+                        if entry.icx_data.as_ref() != Some(&prev) {
+                            warn!(
+                                "icx data mismatch for claim tx: {}: {:?} / {:?}",
+                                prev.claim_tx, prev, entry.icx_data
+                            );
+                        }
+                    }
                     combined_data.icx_count += 1;
+                    write_line(&line_buffer)?;
                 }
             }
             _ if line_buffer.contains(log_icx_calc_matcher) => {
@@ -113,8 +178,11 @@ pub fn process_log_file(
                         .data
                         .entry(data.calc_tx.clone())
                         .or_insert_with(LogEntry::new);
-                    entry.icx_calc_data = Some(data);
+                    if entry.set_icx_calc_data(data).is_some() {
+                        combined_data.update_count += 1;
+                    }
                     combined_data.icx_calc_count += 1;
+                    write_line(&line_buffer)?;
                 }
             }
             _ if line_buffer.contains(log_swap_matcher) => {
@@ -123,8 +191,22 @@ pub fn process_log_file(
                         .data
                         .entry(data.txid.clone())
                         .or_insert_with(LogEntry::new);
-                    entry.swap_data = Some(data);
+                    if entry.set_swap_data(data).is_some() {
+                        combined_data.update_count += 1;
+                    }
                     combined_data.swap_count += 1;
+                    write_line(&line_buffer)?;
+                }
+            }
+            // Anything with a json body is likely an event we don't know about yet
+            _ if line_buffer.contains('{') => {
+                combined_data.skipped_lines += 1;
+                if let Some(pattern) = log_unknown_pattern {
+                    if line_buffer.contains(pattern) {
+                        combined_data
+                            .unknown_data
+                            .push(line_buffer.trim_end().to_owned());
+                    }
                 }
             }
             _ => {}
@@ -134,3 +216,59 @@ pub fn process_log_file(
 
     Ok(())
 }
+
+#[test]
+fn test_process_log_file_output() {
+    let dir = std::env::temp_dir().join(format!("logparse-output-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let log_path = dir.join("debug.log");
+    let lines = [
+        r#"2024-11-05T09:31:58Z ICX: {"order_tx":"o1","offer_tx":"o2","dfchtlc_tx":"h1","claim_tx":"c1","address":"bc1qxyz","amount":"0.00015000"}"#,
+        "2024-11-05T09:31:58Z UpdateTip: new best=00ab height=894004",
+        r#"2024-11-05T09:31:59Z ICXCalc: {"calc_type":"CICXMakeOfferMessage","calc_tx":"o2","calc_start_amount":"0.00015000","calc_fee_per_btc":"0.00300000","calc_pool_dfi_per_btc":"12294.82047387","calc_taker_fee_in_btc":"0.00000045","calc_taker_fee_in_dfi":"0.00553266"}"#,
+        r#"2024-11-05T09:31:59Z SwapResult: {"broken""#,
+        r#"2024-11-05T09:31:59Z Other: {"a":1}"#,
+        r#"2024-11-05T09:31:59Z SwapResult: {"height":894004,"txid":"s1","destination":"dbdztbUBcNhDzG2NCiCVKXBZgQXybi8xvj","result":"0.02239840@1"}"#,
+    ];
+    std::fs::write(&log_path, lines.join("\n")).unwrap();
+
+    let process = |path: &std::path::Path, output: Option<&mut dyn Write>| {
+        let mut m = LogEntryMap::new();
+        process_log_file(
+            path.to_str().unwrap(),
+            "ICX:",
+            "ICXCalc:",
+            "SwapResult:",
+            None,
+            output,
+            &mut m,
+        )
+        .unwrap();
+        m
+    };
+
+    let mut out = Vec::new();
+    let m = process(&log_path, Some(&mut out));
+    assert_eq!(m.total_lines, 6);
+    let expected = [lines[0], lines[2], lines[5], ""].join("\n");
+    assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+    // Read back gzipped, with the same entries as the full log
+    let gz_path = dir.join("debug.filtered.log.gz");
+    let mut w = flate2::write::GzEncoder::new(
+        std::fs::File::create(&gz_path).unwrap(),
+        flate2::Compression::default(),
+    );
+    w.write_all(expected.as_bytes()).unwrap();
+    w.finish().unwrap();
+    let m2 = process(&gz_path, None);
+    assert_eq!(m2.total_lines, 3);
+    assert_eq!(
+        (m2.icx_count, m2.icx_calc_count, m2.swap_count),
+        (m.icx_count, m.icx_calc_count, m.swap_count)
+    );
+    assert_eq!((m2.icx_count, m2.icx_calc_count, m2.swap_count), (1, 1, 1));
+    assert_eq!(m2.data.len(), m.data.len());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,23 +1,47 @@
 #![feature(error_generic_member_access)]
 
 mod args;
+mod balance;
 mod cliindexer;
+mod clipool;
+mod clustering;
+mod cospend;
 mod db;
 mod dfiutils;
 mod dotreducer;
+mod export;
 mod gpath;
 mod graphbuild;
 mod graphdot;
+mod graphrank;
+mod graphtagged;
 mod graphutils;
 mod graphwalk;
 mod icx1;
 mod icx2;
+mod icxorderbook;
 mod icxseq;
+mod icxstats;
 mod lang;
 mod logparse;
+mod masternodes;
+mod metrics;
+#[cfg(feature = "legacy-rocks")]
+mod migrate;
 mod models;
+mod reindex;
+#[cfg(feature = "legacy-rocks")]
+#[path = "legacy/db.rs"]
+mod rocks;
+mod search;
 mod spath;
 mod sqliteindex;
+mod stats;
+mod swapstats;
+mod timeline;
+mod tokenvolume;
+mod vaulthistory;
+mod verify;
 
 use crate::lang::Result;
 use args::{get_args, verbosity_to_level, Cmd};
@@ -29,27 +53,72 @@ fn main_fallible() -> Result<()> {
     let args = get_args();
     let emit_ansi = atty::is(atty::Stream::Stdout);
 
-    tracing_subscriber::fmt::fmt()
-        .with_max_level(verbosity_to_level(args.verbosity, Some(2)))
-        .with_ansi(emit_ansi)
-        .compact()
-        .init();
+    let level = verbosity_to_level(args.verbosity, Some(2));
+
+    // Guard flushes the trace file on drop, so it has to outlive the command.
+    let _trace_guard = if args.trace {
+        use tracing_subscriber::prelude::*;
+        let (chrome_layer, guard) = tracing_chrome::ChromeLayerBuilder::new()
+            .file(&args.trace_output)
+            .build();
+        let fmt_layer = tracing_subscriber::fmt::layer()
+            .with_ansi(emit_ansi)
+            .compact()
+            .with_filter(tracing_subscriber::filter::LevelFilter::from_level(level));
+        tracing_subscriber::registry()
+            .with(fmt_layer)
+            .with(chrome_layer)
+            .init();
+        Some(guard)
+    } else {
+        tracing_subscriber::fmt::fmt()
+            .with_max_level(level)
+            .with_ansi(emit_ansi)
+            .compact()
+            .init();
+        None
+    };
 
     match &args.command {
+        Cmd::Balance(a) => balance::run(a)?,
         Cmd::CliIndex(a) => cliindexer::run(a)?,
+        Cmd::Cluster(a) => clustering::run(a)?,
+        Cmd::CoSpend(a) => cospend::run(a)?,
         Cmd::DotReduce { in_file } => {
             dotreducer::run(in_file)?;
         }
+        Cmd::Export(a) => export::run(a)?,
         Cmd::Graph(a) => graphbuild::run(a)?,
         Cmd::GraphDot(a) => graphdot::run(a)?,
         Cmd::GraphPath(a) => gpath::run(a)?,
+        Cmd::GraphRank(a) => graphrank::run(a)?,
         Cmd::GraphWalk(a) => graphwalk::run(a)?,
+        Cmd::TaggedGraph(a) => graphtagged::run(a)?,
         Cmd::IcxAnalyze1(a) => icx1::run(a)?,
         Cmd::IcxAnalyze2(a) => icx2::run(a)?,
+        Cmd::IcxOrderBook(a) => icxorderbook::run(a)?,
         Cmd::IcxSequence(a) => icxseq::run(a)?,
+        Cmd::IcxStats(a) => icxstats::run(a)?,
+        Cmd::ListCommands { output_format } => args::print_commands(*output_format)?,
+        Cmd::ListTxTypes => {
+            for t in models::TxType::known_types() {
+                println!("{}", t);
+            }
+        }
         Cmd::LogParseCheck(a) => logparse::run(a)?,
+        Cmd::MasternodeStats(a) => masternodes::run(a)?,
+        #[cfg(feature = "legacy-rocks")]
+        Cmd::Migrate(a) => migrate::run(a)?,
+        Cmd::Reindex(a) => reindex::run(a)?,
+        Cmd::Search(a) => search::run(a)?,
+        Cmd::Timeline(a) => timeline::run(a)?,
         Cmd::ShortestPath(a) => spath::run(a)?,
         Cmd::SqliteIndex(a) => sqliteindex::run(a)?,
+        Cmd::Stats(a) => stats::run(a)?,
+        Cmd::SwapStats(a) => swapstats::run(a)?,
+        Cmd::TokenVolume(a) => tokenvolume::run(a)?,
+        Cmd::VaultHistory(a) => vaulthistory::run(a)?,
+        Cmd::Verify(a) => verify::run(a)?,
     }
     Ok(())
 }
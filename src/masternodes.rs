@@ -0,0 +1,162 @@
+use crate::args::OutputFormat;
+use crate::db::{self, SqliteBlockStore, TxRow};
+use crate::lang::Result;
+use crate::models::TxType;
+use clap::Parser;
+use std::collections::HashMap;
+use tracing::{debug, error, info};
+
+#[derive(Parser, Debug)]
+pub struct MnStatsArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    #[arg(short = 's', long, default_value_t = 0)]
+    pub start_height: u32,
+    #[arg(short = 'e', long, default_value_t = 2_000_000)]
+    pub end_height: u32,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct MnRecord {
+    pub created_height: Option<i64>,
+    pub resigned_height: Option<i64>,
+    pub operator_addr: Option<String>,
+    pub owner_addr: Option<String>,
+    pub blocks_minted: u64,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct MnSummary<'a> {
+    created: usize,
+    resigned: usize,
+    active: usize,
+    top_minter: Option<(&'a str, u64)>,
+}
+
+pub fn run(args: &MnStatsArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+
+    let sql_store = SqliteBlockStore::open(Some(&args.sqlite_path))?;
+    let height_filter = format!(
+        "height between {} and {}",
+        args.start_height, args.end_height
+    );
+
+    // Masternodes are tracked by owner address. The owner is the DVM
+    // address that's also an input, falling back to the utxo inputs.
+    fn owner_addr(tx: &TxRow) -> Option<String> {
+        tx.dvm_in
+            .first()
+            .or_else(|| tx.tx_in.keys().next())
+            .cloned()
+    }
+
+    let mut mns = HashMap::<String, MnRecord>::new();
+
+    info!("get all masternode txs..");
+    let r = sql_store.iter_txs_partial(
+        Some(&format!(
+            "where tx_type in ('{}', '{}') and {} order by height",
+            TxType::CreateMasternode,
+            TxType::ResignMasternode,
+            height_filter
+        )),
+        |tx| {
+            if quit.load(std::sync::atomic::Ordering::Relaxed) {
+                info!("int: early exit");
+                return Err("interrupted".into());
+            }
+            let tx = tx?;
+            let Some(owner) = owner_addr(&tx) else {
+                debug!("mn tx without owner: {}", tx.txid);
+                return Ok(());
+            };
+            let record = mns.entry(owner.clone()).or_default();
+            record.owner_addr = Some(owner.clone());
+            match TxType::from_display(&tx.tx_type) {
+                TxType::CreateMasternode => {
+                    record.created_height = Some(tx.height);
+                    record.resigned_height = None;
+                    record.operator_addr = tx.dvm_out.iter().find(|x| **x != owner).cloned();
+                }
+                TxType::ResignMasternode => {
+                    record.resigned_height = Some(tx.height);
+                }
+                _ => {}
+            }
+            Ok(())
+        },
+    );
+    if let Err(e) = r {
+        error!("{:?}", e);
+        return Err(e);
+    }
+    info!("done. masternodes: {}", mns.len());
+
+    info!("get coinbase rewards..");
+    let r = sql_store.iter_txs_partial(
+        Some(&format!(
+            "where tx_type = '{}' and {}",
+            TxType::Coinbase,
+            height_filter
+        )),
+        |tx| {
+            if quit.load(std::sync::atomic::Ordering::Relaxed) {
+                info!("int: early exit");
+                return Err("interrupted".into());
+            }
+            let tx = tx?;
+            for addr in tx.tx_out.keys() {
+                if let Some(record) = mns.get_mut(addr) {
+                    record.blocks_minted += 1;
+                }
+            }
+            Ok(())
+        },
+    );
+    if let Err(e) = r {
+        error!("{:?}", e);
+        return Err(e);
+    }
+
+    let summary = MnSummary {
+        created: mns.values().filter(|x| x.created_height.is_some()).count(),
+        resigned: mns.values().filter(|x| x.resigned_height.is_some()).count(),
+        active: mns
+            .values()
+            .filter(|x| x.created_height.is_some() && x.resigned_height.is_none())
+            .count(),
+        top_minter: mns
+            .iter()
+            .max_by_key(|(_, x)| x.blocks_minted)
+            .map(|(addr, x)| (addr.as_str(), x.blocks_minted)),
+    };
+
+    match args.output_format {
+        OutputFormat::Text => {
+            println!("created:  {}", summary.created);
+            println!("resigned: {}", summary.resigned);
+            println!("active:   {}", summary.active);
+            if let Some((addr, minted)) = summary.top_minter {
+                println!("top minter: {} ({} blocks)", addr, minted);
+            }
+        }
+        OutputFormat::Json => {
+            let out = serde_json::json!({
+                "summary": summary,
+                "masternodes": mns,
+            });
+            println!("{}", serde_json::to_string_pretty(&out)?);
+        }
+    }
+
+    info!("complete");
+    Ok(())
+}
@@ -0,0 +1,223 @@
+use crate::lang::Result;
+use std::fmt::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// What failed, for the `type` label of `indexer_errors_total`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Fetching the block from the cli or rpc
+    Fetch,
+    /// Parsing the fetched block
+    Parse,
+    /// Inserting the block and its txs
+    Insert,
+    /// Committing a batch
+    Commit,
+}
+
+impl ErrorKind {
+    const ALL: [ErrorKind; 4] = [
+        ErrorKind::Fetch,
+        ErrorKind::Parse,
+        ErrorKind::Insert,
+        ErrorKind::Commit,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            ErrorKind::Fetch => "fetch",
+            ErrorKind::Parse => "parse",
+            ErrorKind::Insert => "insert",
+            ErrorKind::Commit => "commit",
+        }
+    }
+}
+
+/// Indexer counters, updated from the indexing thread and read by the
+/// metrics server.
+#[derive(Debug, Default)]
+pub struct IndexerMetrics {
+    blocks_processed: AtomicU64,
+    txs_processed: AtomicU64,
+    current_height: AtomicU64,
+    errors: [AtomicU64; ErrorKind::ALL.len()],
+    commit_count: AtomicU64,
+    commit_nanos: AtomicU64,
+}
+
+impl IndexerMetrics {
+    pub fn add_block(&self, height: u32, txs: u64) {
+        self.blocks_processed.fetch_add(1, Ordering::Relaxed);
+        self.txs_processed.fetch_add(txs, Ordering::Relaxed);
+        self.current_height.store(height.into(), Ordering::Relaxed);
+    }
+
+    pub fn blocks_processed(&self) -> u64 {
+        self.blocks_processed.load(Ordering::Relaxed)
+    }
+
+    pub fn txs_processed(&self) -> u64 {
+        self.txs_processed.load(Ordering::Relaxed)
+    }
+
+    pub fn current_height(&self) -> u64 {
+        self.current_height.load(Ordering::Relaxed)
+    }
+
+    pub fn add_error(&self, kind: ErrorKind) {
+        self.errors[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count the error of `r`, if any, and pass it on
+    pub fn track<T>(&self, kind: ErrorKind, r: Result<T>) -> Result<T> {
+        if r.is_err() {
+            self.add_error(kind);
+        }
+        r
+    }
+
+    /// Run the commit `f`, timing it and counting its error
+    pub fn time_commit<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let start = std::time::Instant::now();
+        let r = self.track(ErrorKind::Commit, f());
+        let nanos = start.elapsed().as_nanos().try_into().unwrap_or(u64::MAX);
+        self.commit_count.fetch_add(1, Ordering::Relaxed);
+        self.commit_nanos.fetch_add(nanos, Ordering::Relaxed);
+        r
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let load = |x: &AtomicU64| x.load(Ordering::Relaxed);
+        let mut s = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(String, String)]| {
+            let _ = writeln!(s, "# HELP {} {}", name, help);
+            let _ = writeln!(s, "# TYPE {} {}", name, kind);
+            for (suffix, v) in values {
+                let _ = writeln!(s, "{}{} {}", name, suffix, v);
+            }
+        };
+        metric(
+            "indexer_blocks_processed_total",
+            "counter",
+            "Blocks indexed",
+            &[(String::new(), load(&self.blocks_processed).to_string())],
+        );
+        metric(
+            "indexer_txs_processed_total",
+            "counter",
+            "Txs indexed",
+            &[(String::new(), load(&self.txs_processed).to_string())],
+        );
+        metric(
+            "indexer_current_height",
+            "gauge",
+            "Height of the last indexed block",
+            &[(String::new(), load(&self.current_height).to_string())],
+        );
+        metric(
+            "indexer_errors_total",
+            "counter",
+            "Indexing errors by type",
+            &ErrorKind::ALL.map(|x| {
+                (
+                    format!("{{type=\"{}\"}}", x.label()),
+                    load(&self.errors[x as usize]).to_string(),
+                )
+            }),
+        );
+        metric(
+            "indexer_batch_commit_duration_seconds",
+            "summary",
+            "Time taken by the db batch commits",
+            &[
+                (
+                    "_sum".to_string(),
+                    (load(&self.commit_nanos) as f64 / 1e9).to_string(),
+                ),
+                ("_count".to_string(), load(&self.commit_count).to_string()),
+            ],
+        );
+        s
+    }
+}
+
+/// Serve `metrics` at `/metrics` on `addr:port`, from a background thread
+/// that lives as long as the process. Returns the address bound to, for
+/// when `port` is 0.
+pub fn serve(addr: IpAddr, port: u16, metrics: Arc<IndexerMetrics>) -> Result<SocketAddr> {
+    let server = tiny_http::Server::http((addr, port))
+        .map_err(|e| format!("metrics server on {}:{}: {}", addr, port, e))?;
+    let bound = server
+        .server_addr()
+        .to_ip()
+        .ok_or("metrics server not on an ip address")?;
+    info!("metrics: http://{}/metrics", bound);
+    let content_type =
+        tiny_http::Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+    std::thread::spawn(move || {
+        for req in server.incoming_requests() {
+            let res = if req.url() == "/metrics" {
+                tiny_http::Response::from_string(metrics.render()).with_header(content_type.clone())
+            } else {
+                tiny_http::Response::from_string("not found").with_status_code(404)
+            };
+            if let Err(e) = req.respond(res) {
+                warn!("metrics response: {}", e);
+            }
+        }
+    });
+    Ok(bound)
+}
+
+#[test]
+fn test_indexer_metrics_render() {
+    let m = IndexerMetrics::default();
+    m.add_block(10, 3);
+    m.add_block(11, 2);
+    m.add_error(ErrorKind::Fetch);
+    assert!(m
+        .track::<()>(ErrorKind::Insert, Err("fail".into()))
+        .is_err());
+    assert_eq!(m.time_commit(|| Ok(1)).unwrap(), 1);
+
+    let out = m.render();
+    let lines = out.lines().collect::<Vec<_>>();
+    for x in [
+        "# TYPE indexer_blocks_processed_total counter",
+        "indexer_blocks_processed_total 2",
+        "indexer_txs_processed_total 5",
+        "# TYPE indexer_current_height gauge",
+        "indexer_current_height 11",
+        "indexer_errors_total{type=\"fetch\"} 1",
+        "indexer_errors_total{type=\"parse\"} 0",
+        "indexer_errors_total{type=\"insert\"} 1",
+        "indexer_errors_total{type=\"commit\"} 0",
+        "# TYPE indexer_batch_commit_duration_seconds summary",
+        "indexer_batch_commit_duration_seconds_count 1",
+    ] {
+        assert!(lines.contains(&x), "{}\n{}", x, out);
+    }
+}
+
+#[test]
+fn test_serve() {
+    use std::io::{Read, Write};
+
+    let m = Arc::new(IndexerMetrics::default());
+    m.add_block(7, 1);
+    let addr = serve(IpAddr::from([127, 0, 0, 1]), 0, m).unwrap();
+    assert!(addr.ip().is_loopback());
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).unwrap();
+    assert!(res.starts_with("HTTP/1.1 200"), "{}", res);
+    assert!(res.contains("indexer_current_height 7"), "{}", res);
+}
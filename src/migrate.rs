@@ -0,0 +1,124 @@
+use crate::cliindexer::{self, IndexCounts, InsertOpts};
+use crate::db;
+use crate::dfiutils::TokenResolver;
+use crate::lang::Result;
+use crate::models::LogEntryMap;
+use crate::rocks::{rocks_open_db, RocksBlockStore};
+use clap::Parser;
+use db::{BatchCommit, LruBlockStore, SqliteBlockStore};
+use tracing::{debug, info};
+
+#[derive(Parser, Debug)]
+pub struct MigrateArgs {
+    #[arg(long, default_value = "data/db")]
+    pub src_rocks_path: String,
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub dest_sqlite_path: String,
+    /// Defaults to 0
+    #[arg(short = 's', long)]
+    pub start_height: Option<u32>,
+    /// Stops earlier at the first height missing from the rocks db
+    #[arg(short = 'e', long, default_value_t = 2_000_000)]
+    pub end_height: u32,
+    #[arg(long, default_value_t = true)]
+    pub enable_graph_table: bool,
+    /// Commit the write transaction every this many blocks
+    #[arg(long, default_value_t = 10000, value_parser = clap::value_parser!(u32).range(1..))]
+    pub batch_size: u32,
+    #[arg(long, default_value_t = 1000)]
+    pub tx_cache_size: usize,
+    #[command(flatten)]
+    pub sqlite_pragmas: db::SqlitePragmaArgs,
+}
+
+/// Re-index the blocks of a legacy rocks db into a v2 sqlite index. The
+/// tx ins are resolved from the dest, so the range has to start from 0
+/// or right after what the dest already has, same as for `cindex`.
+pub fn run(args: &MigrateArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    db::validate_sqlite_path(&args.dest_sqlite_path, db::PathMode::Create)?;
+
+    let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+
+    info!("rocks db: {}", args.src_rocks_path);
+    let rocks_db = rocks_open_db(Some(&args.src_rocks_path))?;
+    let rocks_store = RocksBlockStore::new(&rocks_db)?;
+
+    let sql_store =
+        SqliteBlockStore::open_or_create_with(&args.dest_sqlite_path, &args.sqlite_pragmas)?;
+    info!("db: {} ({} bytes)", sql_store, sql_store.db_size_bytes()?);
+
+    let start_height = args.start_height.unwrap_or(0);
+    let end_height = args.end_height;
+    let opts = InsertOpts {
+        enable_addr_graph: args.enable_graph_table,
+        ..Default::default()
+    };
+    let (log_entry_map, tokens) = (LogEntryMap::new(), TokenResolver::default());
+
+    for (name, _) in sql_store.index_factory() {
+        info!("drop index: {}..", name);
+        sql_store.drop_index(name)?;
+    }
+
+    let tx_cache = LruBlockStore::new(&sql_store, args.tx_cache_size);
+    let mut batch = BatchCommit::new(&sql_store, args.batch_size, None)?;
+    sql_store.begin_tx()?;
+
+    let mut counts = IndexCounts::default();
+    let res = sql_store.with_stmts(|stmts| {
+        for height in start_height..=end_height {
+            if quit.load(std::sync::atomic::Ordering::Relaxed) {
+                info!("int: early exit");
+                return Err("interrupted".into());
+            }
+            let Some(block) = rocks_store.get_block_from_height(height.into())? else {
+                info!("no block at height {} in the rocks db, stopping", height);
+                break;
+            };
+            // Stored as raw json in rocks, but only the parsed block is
+            // exposed, so this is the re-serialized form
+            let block_json = serde_json::to_string(&block)?;
+            counts += cliindexer::insert_block(
+                stmts,
+                &tx_cache,
+                &log_entry_map,
+                &tokens,
+                &block_json,
+                block,
+                opts,
+            )?;
+
+            if batch.is_due(&sql_store, height.into())? {
+                sql_store.commit_and_begin_tx()?;
+            }
+            if height % 10000 == 0 {
+                info!("processed: [{}] / [{}]", height, end_height);
+            }
+        }
+        Ok(())
+    });
+
+    info!("flushing db");
+    sql_store.commit_tx()?;
+    info!(
+        "migrated: {} blocks, {} txs, {} tx ins",
+        counts.blocks, counts.txs, counts.tx_ins
+    );
+
+    if res.is_ok() {
+        for (name, indexer) in sql_store.index_factory() {
+            if quit.load(std::sync::atomic::Ordering::Relaxed) {
+                info!("int: early exit indexes");
+                break;
+            }
+            info!("creating index: {}..", name);
+            indexer()?;
+        }
+        info!("complete");
+    }
+
+    res
+}
@@ -1,11 +1,21 @@
 #![allow(dead_code)]
 
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
+use std::sync::{LazyLock, Mutex};
+use tracing::warn;
 
 pub type TStr = Rc<str>;
 
+// Only the fields we check, from `getblockchaininfo`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlockchainInfo {
+    pub chain: String,
+    #[serde(default)]
+    pub networkactive: Option<bool>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Block {
@@ -37,10 +47,46 @@ pub struct MinterInfo {
     pub operator: Option<TStr>,
     pub owner: Option<TStr>,
     pub reward_address: Option<TStr>,
-    pub total_minted: u64,
+    // -1 for pruned blocks and some edge cases
+    #[serde(default, deserialize_with = "deserialize_total_minted")]
+    pub total_minted: Option<u64>,
     pub stake_modifier: TStr,
 }
 
+fn deserialize_total_minted<'de, D>(d: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<i64>::deserialize(d)? {
+        None | Some(-1) => Ok(None),
+        Some(v) => u64::try_from(v)
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("invalid totalMinted: {}", v))),
+    }
+}
+
+#[test]
+fn test_block_deser_negative_fields() {
+    let json = r#"{
+        "hash": "h", "height": 1, "confirmations": -1,
+        "strippedsize": 1, "size": 1, "weight": 1,
+        "minter": {"id": "m", "totalMinted": -1, "stakeModifier": "s"},
+        "version": 1, "versionHex": "1", "merkleroot": "r",
+        "time": 0, "mediantime": 0, "bits": "b", "difficulty": 1.0,
+        "chainwork": "c", "tx": [], "nTx": 0
+    }"#;
+    let block: Block = serde_json::from_str(json).unwrap();
+    assert_eq!(block.confirmations, -1);
+    assert_eq!(block.minter.total_minted, None);
+
+    let json = r#"{"id": "m", "totalMinted": 42, "stakeModifier": "s"}"#;
+    let minter: MinterInfo = serde_json::from_str(json).unwrap();
+    assert_eq!(minter.total_minted, Some(42));
+
+    let json = r#"{"id": "m", "totalMinted": -2, "stakeModifier": "s"}"#;
+    assert!(serde_json::from_str::<MinterInfo>(json).is_err());
+}
+
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Transaction {
@@ -131,7 +177,7 @@ pub struct ScriptPubKey {
     pub addresses: Option<Vec<TStr>>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum TxType {
     Unknown,
     Coinbase,
@@ -160,6 +206,11 @@ pub enum TxType {
     ICXClaimDFCHTLC,
     ICXCloseOrder,
     ICXCloseOffer,
+    EvmTx,
+    EvmDeploy,
+    TransferDomain,
+    FutureSwap,
+    UpdateLoanScheme,
     Other(String),
 }
 
@@ -194,7 +245,18 @@ impl From<&str> for TxType {
             "ICXClaimDFCHTLC" => ICXClaimDFCHTLC,
             "ICXCloseOrder" => ICXCloseOrder,
             "ICXCloseOffer" => ICXCloseOffer,
-            other => Other(other.to_owned()),
+            "EvmTx" => EvmTx,
+            // Not a tx type on chain, see `TxType::from_vm`
+            "EvmDeploy" => EvmDeploy,
+            "TransferDomain" => TransferDomain,
+            // Named after the DFIPs on chain, 2206F for the DUSD ones
+            "FutureSwap" | "DFIP2203" | "DFIP2206F" => FutureSwap,
+            // Creating and updating a scheme are the same message
+            "UpdateLoanScheme" | "LoanScheme" => UpdateLoanScheme,
+            other => {
+                warn_unknown_tx_type(other);
+                Other(other.to_owned())
+            }
         }
     }
 }
@@ -230,12 +292,31 @@ impl std::fmt::Display for TxType {
             ICXClaimDFCHTLC => "icx-claim",
             ICXCloseOrder => "icx-endor",
             ICXCloseOffer => "icx-endof",
+            EvmTx => "evm",
+            EvmDeploy => "evm+",
+            TransferDomain => "td",
+            FutureSwap => "fs",
+            UpdateLoanScheme => "lsc",
             Other(m) => m,
         };
         f.write_str(t)
     }
 }
 
+// Serialized as the display string, the same as in the txs table
+impl Serialize for TxType {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TxType {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        Ok(TxType::from_display(&s))
+    }
+}
+
 impl TxType {
     pub fn from_display(s: &str) -> Self {
         match s {
@@ -266,11 +347,85 @@ impl TxType {
             "icx-claim" => TxType::ICXClaimDFCHTLC,
             "icx-endor" => TxType::ICXCloseOrder,
             "icx-endof" => TxType::ICXCloseOffer,
-            other => TxType::Other(other.to_owned()),
+            "evm" => TxType::EvmTx,
+            "evm+" => TxType::EvmDeploy,
+            "td" => TxType::TransferDomain,
+            "fs" => TxType::FutureSwap,
+            "lsc" => TxType::UpdateLoanScheme,
+            other => {
+                warn_unknown_tx_type(other);
+                TxType::Other(other.to_owned())
+            }
+        }
+    }
+
+    /// Display strings of all known tx types, as stored in the index.
+    pub fn known_types() -> &'static [&'static str] {
+        &[
+            "_",
+            "cb",
+            "u",
+            "au",
+            "+a",
+            "-a",
+            "aa",
+            "ax",
+            "+m",
+            "-m",
+            "ps",
+            "cs",
+            "+p",
+            "-p",
+            "v-",
+            "v+",
+            "l-",
+            "l+",
+            "vn",
+            "+o",
+            "icx-start",
+            "icx-of",
+            "icx-sdfc",
+            "icx-sbtc",
+            "icx-claim",
+            "icx-endor",
+            "icx-endof",
+            "evm",
+            "evm+",
+            "td",
+            "fs",
+            "lsc",
+        ]
+    }
+
+    /// Tx type of a DVM or EVM tx. Contract deployments are EvmTx on
+    /// chain, they're told apart by the msg having no recipient.
+    pub fn from_vm(vm: &VMInfo) -> Self {
+        let t = TxType::from(&*vm.txtype);
+        let is_deploy = vm.msg["createTx"].as_bool() == Some(true)
+            || vm.msg.get("to").is_some_and(|x| x.is_null() || x == "");
+        if t == TxType::EvmTx && is_deploy {
+            TxType::EvmDeploy
+        } else {
+            t
         }
     }
 }
 
+// Unknown types are likely new tx types from a chain upgrade. Warn once per
+// type, not once per tx.
+static WARNED_TYPES: LazyLock<Mutex<HashSet<String>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+fn warn_unknown_tx_type(t: &str) {
+    let Ok(mut warned) = WARNED_TYPES.lock() else {
+        return;
+    };
+    if !warned.contains(t) {
+        warn!("unknown tx type: {}", t);
+        warned.insert(t.to_owned());
+    }
+}
+
 type TokenAmount = TStr;
 
 // vm":{"vmtype":"dvm","txtype":"UtxosToAccount","msg":{"8RbpgySS2qkXQG2UosQCqADtS7zRAr8bx5":"60000.00000000@0"}}}
@@ -306,9 +461,149 @@ pub struct PoolSwapMsg {
     pub from_amount: f64,
     pub from_token: TStr,
     pub to_token: TStr,
+    // Older txs may not carry these
+    #[serde(default)]
+    pub max_price: Option<f64>,
+    #[serde(default)]
+    pub max_price_high_precision: Option<String>,
+    // Not part of the tx msg. Effective execution price (to per from),
+    // filled in from the `SwapResult` log entry.
+    #[serde(default)]
+    pub actual_price: Option<f64>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl PoolSwapMsg {
+    /// Set `actual_price` from the amount received in the swap result log.
+    pub fn apply_swap_result(&mut self, swap: &LogSwapData) {
+        let to_amount = swap
+            .result
+            .split('@')
+            .next()
+            .and_then(|x| x.parse::<f64>().ok());
+        if let Some(to_amount) = to_amount {
+            if self.from_amount > 0. {
+                self.actual_price = Some(to_amount / self.from_amount);
+            }
+        }
+    }
+
+    pub fn slippage(&self) -> Option<f64> {
+        match (self.actual_price, self.max_price) {
+            (Some(actual), Some(max)) if max != 0. => Some((actual - max) / max),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_pool_swap_msg_deser() {
+    let json = r#"
+        {
+            "fromAddress": "8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R",
+            "fromAmount": 9.0,
+            "fromToken": "0",
+            "maxPrice": 2.531e-05,
+            "maxPriceHighPrecision": "0.00002531",
+            "toAddress": "8eG9Pe1wQnWZuXD5NRr3QaxDex9RJ99fd5",
+            "toToken": "2"
+        }
+    "#;
+    let mut msg: PoolSwapMsg = serde_json::from_str(json).unwrap();
+    assert_eq!(msg.from_amount, 9.0);
+    assert_eq!(&*msg.from_token, "0");
+    assert_eq!(msg.max_price, Some(2.531e-05));
+    assert_eq!(msg.max_price_high_precision.as_deref(), Some("0.00002531"));
+    assert_eq!(msg.actual_price, None);
+    assert_eq!(msg.slippage(), None);
+
+    msg.apply_swap_result(&LogSwapData {
+        txid: TStr::from("99c64a70b2632a30d4ce20d9bcce3962945c8a6afd8fa547bf26a290b268bcde"),
+        destination: TStr::from("8eG9Pe1wQnWZuXD5NRr3QaxDex9RJ99fd5"),
+        result: TStr::from("0.00022500@2"),
+    });
+    assert!((msg.actual_price.unwrap() - 0.000025).abs() < 1e-12);
+    assert!((msg.slippage().unwrap() - (0.000025 - 2.531e-05) / 2.531e-05).abs() < 1e-9);
+
+    // Older txs without max price
+    let json =
+        r#"{"fromAddress":"a","fromAmount":1.0,"fromToken":"0","toAddress":"b","toToken":"2"}"#;
+    let msg: PoolSwapMsg = serde_json::from_str(json).unwrap();
+    assert_eq!(msg.max_price, None);
+    assert_eq!(msg.max_price_high_precision, None);
+}
+
+/// Swap at the future swap settlement price, done at the next settlement
+/// block rather than in the tx's block
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FutureSwapMsg {
+    pub owner: TStr,
+    /// Amount and token, `amount@token`
+    pub source: TokenAmount,
+    /// Token id to swap to
+    #[serde(deserialize_with = "deserialize_str_or_num")]
+    pub destination: TStr,
+    #[serde(default)]
+    pub is_premiumlane: bool,
+}
+
+impl FutureSwapMsg {
+    /// Amount and token of the source. None if the amount doesn't parse.
+    pub fn source_amount(&self) -> Option<(f64, &str)> {
+        let (amount, token) = self.source.split_once('@')?;
+        Some((amount.parse().ok()?, token))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateLoanSchemeMsg {
+    pub id: TStr,
+    pub rate: u64,
+    pub min_col_ratio: u64,
+}
+
+// Token ids come as either strings or numbers
+fn deserialize_str_or_num<'de, D>(d: D) -> Result<TStr, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(d)? {
+        serde_json::Value::String(s) => Ok(TStr::from(s)),
+        serde_json::Value::Number(n) => Ok(TStr::from(n.to_string())),
+        v => Err(serde::de::Error::custom(format!("invalid token: {}", v))),
+    }
+}
+
+#[test]
+fn test_future_swap_and_loan_scheme_msg_deser() {
+    let json = r#"{"owner":"a","source":"10.50000000@15","destination":"0"}"#;
+    let msg: FutureSwapMsg = serde_json::from_str(json).unwrap();
+    assert_eq!(msg.source_amount(), Some((10.5, "15")));
+    assert_eq!(&*msg.destination, "0");
+    assert!(!msg.is_premiumlane);
+
+    let json = r#"{"owner":"a","source":"1@DUSD","destination":2,"is_premiumlane":true}"#;
+    let msg: FutureSwapMsg = serde_json::from_str(json).unwrap();
+    assert_eq!(msg.source_amount(), Some((1.0, "DUSD")));
+    assert_eq!(&*msg.destination, "2");
+    assert!(msg.is_premiumlane);
+
+    let json = r#"{"id":"MIN150","rate":500000000,"minColRatio":150}"#;
+    let msg: UpdateLoanSchemeMsg = serde_json::from_str(json).unwrap();
+    assert_eq!(msg.min_col_ratio, 150);
+
+    for (txtype, display) in [
+        ("DFIP2203", "fs"),
+        ("FutureSwap", "fs"),
+        ("LoanScheme", "lsc"),
+    ] {
+        let t = TxType::from(txtype);
+        assert_eq!(t.to_string(), display);
+        assert_eq!(TxType::from_display(display), t);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub struct LogIcxData {
     pub order_tx: TStr,
     pub offer_tx: TStr,
@@ -330,6 +625,17 @@ pub struct LogIcxCalcData {
     pub calc_taker_fee_in_dfi: TStr,
 }
 
+impl LogIcxCalcData {
+    /// DFC amount of the offer, from the BTC start amount at the pool
+    /// price at the time of the calc. None if either doesn't parse.
+    pub fn dfc_amount(&self) -> Option<String> {
+        use std::str::FromStr;
+        let start = bigdecimal::BigDecimal::from_str(&self.calc_start_amount).ok()?;
+        let price = bigdecimal::BigDecimal::from_str(&self.calc_pool_dfi_per_btc).ok()?;
+        Some((start * price).with_scale(8).to_string())
+    }
+}
+
 // 2024-11-05T09:31:59Z SwapResult: {"height":894004,"txid":"99c64a70b2632a30d4ce20d9bcce3962945c8a6afd8fa547bf26a290b268bcde","destination":"dbdztbUBcNhDzG2NCiCVKXBZgQXybi8xvj","result":"0.02239840@1"}
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LogSwapData {
@@ -338,7 +644,28 @@ pub struct LogSwapData {
     pub result: TStr,
 }
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[test]
+fn test_log_data_deser() {
+    let line = r#"{"order_tx":"o1","offer_tx":"o2","dfchtlc_tx":"h1","claim_tx":"c1","address":"bc1qxyz","amount":"0.00015000"}"#;
+    let d: LogIcxData = serde_json::from_str(line).unwrap();
+    assert_eq!(&*d.claim_tx, "c1");
+    assert_eq!(&*d.offer_tx, "o2");
+    assert_eq!(&*d.address, "bc1qxyz");
+    assert_eq!(&*d.amount, "0.00015000");
+
+    let line = r#"{"calc_type":"CICXMakeOfferMessage","calc_tx":"8f17836797c93e13b80c36dbade8ac0e8b4b7a4a390aa6769a64705bd3683f07","calc_start_amount":"0.00015000","calc_fee_per_btc":"0.00300000","calc_pool_dfi_per_btc":"12294.82047387","calc_taker_fee_in_btc":"0.00000045","calc_taker_fee_in_dfi":"0.00553266"}"#;
+    let d: LogIcxCalcData = serde_json::from_str(line).unwrap();
+    assert_eq!(&*d.calc_type, "CICXMakeOfferMessage");
+    assert_eq!(&*d.calc_pool_dfi_per_btc, "12294.82047387");
+    assert_eq!(d.dfc_amount().as_deref(), Some("1.84422307"));
+
+    let line = r#"{"height":894004,"txid":"99c64a70b2632a30d4ce20d9bcce3962945c8a6afd8fa547bf26a290b268bcde","destination":"dbdztbUBcNhDzG2NCiCVKXBZgQXybi8xvj","result":"0.02239840@1"}"#;
+    let d: LogSwapData = serde_json::from_str(line).unwrap();
+    assert_eq!(&*d.destination, "dbdztbUBcNhDzG2NCiCVKXBZgQXybi8xvj");
+    assert_eq!(&*d.result, "0.02239840@1");
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IcxTxSet {
     pub order_tx: TStr,
     pub offer_tx: TStr,
@@ -351,6 +678,12 @@ pub struct LogEntryMap {
     pub icx_count: usize,
     pub icx_calc_count: usize,
     pub swap_count: usize,
+    pub total_lines: usize,
+    // Lines with a `{` that didn't match any of the matchers
+    pub skipped_lines: usize,
+    pub unknown_data: Vec<String>,
+    // Entries where a field was already set by an earlier line
+    pub update_count: usize,
 }
 
 pub struct LogEntry {
@@ -366,8 +699,20 @@ impl LogEntryMap {
             icx_count: 0,
             icx_calc_count: 0,
             swap_count: 0,
+            total_lines: 0,
+            skipped_lines: 0,
+            unknown_data: Vec::new(),
+            update_count: 0,
         }
     }
+
+    pub fn get_entry(&self, txid: &str) -> Option<&LogEntry> {
+        self.data.get(txid)
+    }
+
+    pub fn contains(&self, txid: &str) -> bool {
+        self.data.contains_key(txid)
+    }
 }
 
 impl LogEntry {
@@ -378,4 +723,74 @@ impl LogEntry {
             swap_data: None,
         }
     }
+
+    // Setters return the previous value, if it was set.
+
+    pub fn set_icx_data(&mut self, data: LogIcxData) -> Option<LogIcxData> {
+        self.icx_data.replace(data)
+    }
+
+    pub fn set_icx_calc_data(&mut self, data: LogIcxCalcData) -> Option<LogIcxCalcData> {
+        self.icx_calc_data.replace(data)
+    }
+
+    pub fn set_swap_data(&mut self, data: LogSwapData) -> Option<LogSwapData> {
+        self.swap_data.replace(data)
+    }
+}
+
+#[test]
+fn test_known_tx_types_roundtrip() {
+    for t in TxType::known_types() {
+        let tx_type = TxType::from_display(t);
+        assert!(!matches!(tx_type, TxType::Other(_)), "{}", t);
+        assert_eq!(tx_type.to_string(), *t);
+    }
+}
+
+#[test]
+fn test_tx_type_serde_display() {
+    let types = TxType::known_types()
+        .iter()
+        .map(|x| TxType::from_display(x))
+        .chain([TxType::Other("CloseVault".into())]);
+    for t in types {
+        let json = serde_json::to_string(&t).unwrap();
+        assert_eq!(json, format!("\"{}\"", t));
+        assert_eq!(serde_json::from_str::<TxType>(&json).unwrap(), t);
+    }
+    assert_eq!(
+        serde_json::to_string(&TxType::ICXClaimDFCHTLC).unwrap(),
+        "\"icx-claim\""
+    );
+    assert_eq!(
+        serde_json::from_str::<TxType>("\"+a\"").unwrap(),
+        TxType::UtxosToAccount
+    );
+}
+
+#[test]
+fn test_tx_type_from_vm() {
+    let vm = |txtype: &str, msg: serde_json::Value| VMInfo {
+        vmtype: "evm".into(),
+        txtype: txtype.into(),
+        msg,
+    };
+    let to = "0x9b8a4af42140d8a4c153a822f02571a1dd037e89";
+    assert_eq!(
+        TxType::from_vm(&vm("EvmTx", serde_json::json!({ "to": to }))),
+        TxType::EvmTx
+    );
+    assert_eq!(
+        TxType::from_vm(&vm("EvmTx", serde_json::json!({ "to": null }))),
+        TxType::EvmDeploy
+    );
+    assert_eq!(
+        TxType::from_vm(&vm("EvmTx", serde_json::json!({ "createTx": true }))),
+        TxType::EvmDeploy
+    );
+    assert_eq!(
+        TxType::from_vm(&vm("TransferDomain", serde_json::json!({}))),
+        TxType::TransferDomain
+    );
 }
@@ -0,0 +1,169 @@
+use crate::cliindexer::{self, IndexCounts, InsertOpts, TxTypeFilter};
+use crate::clipool::CliDriverPool;
+use crate::db::{self, LruBlockStore, SqliteBlockStore};
+use crate::dfiutils::TokenResolver;
+use crate::lang::Result;
+use crate::logparse::process_log_file;
+use crate::models::{Block, LogEntryMap};
+use clap::Parser;
+use tracing::{debug, info, info_span};
+
+/// Used by the deletes of the range, so never dropped
+const KEEP_INDEXES: [&str; 2] = ["idx_txs_height", "idx_tx_addr_graph_txid"];
+
+/// Ranges at least this long drop the other indexes during the re-index
+const DROP_INDEXES_MIN_BLOCKS: u32 = 1000;
+
+#[derive(Parser, Debug)]
+pub struct ReindexArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    #[arg(long, default_value = "defi-cli")]
+    pub defi_cli_path: String,
+    #[arg(short = 's', long)]
+    pub start_height: u32,
+    #[arg(short = 'e', long)]
+    pub end_height: u32,
+    /// The path to the debug.log file from defid, gzipped or raw
+    #[arg(long, default_value = "data/debug.log.gz")]
+    pub defid_log_path: String,
+    #[arg(long, default_value = "ICX:")]
+    pub log_icx_matcher: String,
+    #[arg(long, default_value = "ICXCalc:")]
+    pub log_icx_calc_matcher: String,
+    #[arg(long, default_value = "SwapResult:")]
+    pub log_swap_matcher: String,
+    #[arg(long, default_value_t = true)]
+    pub enable_graph_table: bool,
+    /// Timeout in seconds for each defi-cli call. No timeout if not set.
+    #[arg(long)]
+    pub rpc_timeout: Option<u64>,
+    /// Don't check the node is on the same chain as the existing index
+    #[arg(long, default_value_t = false)]
+    pub skip_network_check: bool,
+    /// Number of deserialized txs kept in memory for resolving tx ins.
+    /// 0 disables the cache.
+    #[arg(long, default_value_t = 1000)]
+    pub tx_cache_size: usize,
+    #[command(flatten)]
+    pub sqlite_pragmas: db::SqlitePragmaArgs,
+    /// Token id to symbol map, json or csv, for the swap tokens that
+    /// aren't in the built-in table
+    #[arg(long)]
+    pub token_map_path: Option<String>,
+}
+
+/// Re-index `start..=end` in place. The blocks, txs and graph edges of the
+/// range are replaced in one transaction, so an error or an interrupt
+/// leaves the index as it was.
+pub fn run(args: &ReindexArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+    let (start_height, end_height) = (args.start_height, args.end_height);
+    if start_height > end_height {
+        return Err("--start-height is after --end-height".into());
+    }
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Write)?;
+
+    let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+
+    let tokens = TokenResolver::from_path(args.token_map_path.as_deref())?;
+    if let Some(path) = &args.token_map_path {
+        info!("token map: {} ({} tokens)", path, tokens.len());
+    }
+
+    let mut log_entry_map = LogEntryMap::new();
+    if !args.defid_log_path.is_empty() {
+        info!("ingesting log file: {}", args.defid_log_path);
+        process_log_file(
+            &args.defid_log_path,
+            &args.log_icx_matcher,
+            &args.log_icx_calc_matcher,
+            &args.log_swap_matcher,
+            None,
+            None,
+            &mut log_entry_map,
+        )?;
+        info!("log entries: {}", log_entry_map.data.len());
+    }
+
+    let rpc_timeout = args.rpc_timeout.map(std::time::Duration::from_secs);
+    let cli = CliDriverPool::new(&args.defi_cli_path, 1, rpc_timeout);
+    let sql_store = SqliteBlockStore::open_or_create_with(&args.sqlite_path, &args.sqlite_pragmas)?;
+    info!("db: {} ({} bytes)", sql_store, sql_store.db_size_bytes()?);
+
+    if args.skip_network_check {
+        info!("skipping network check");
+    } else {
+        cliindexer::check_network(&cli, &sql_store)?;
+    }
+    let tx_filter = TxTypeFilter::load(&sql_store)?;
+
+    let chain_height = cli.get_block_count()?;
+    if end_height > chain_height {
+        return Err(format!("--end-height is past the chain height {}", chain_height).into());
+    }
+
+    // The deletes go through the height and txid indexes, the others only
+    // slow down the inserts of a large range.
+    let mut dropped = vec![];
+    if end_height - start_height + 1 >= DROP_INDEXES_MIN_BLOCKS {
+        for (name, _) in sql_store.index_factory() {
+            if !KEEP_INDEXES.contains(&name) {
+                info!("drop index: {}..", name);
+                sql_store.drop_index(name)?;
+                dropped.push(name.to_string());
+            }
+        }
+    }
+
+    info!("reindex: {} - {}..", start_height, end_height);
+    let mut counts = IndexCounts::default();
+    let res = sql_store.atomic_replace_range(start_height.into(), end_height.into(), |store| {
+        let tx_cache = LruBlockStore::new(store, args.tx_cache_size);
+        store.with_stmts(|stmts| {
+            for height in start_height..=end_height {
+                if quit.load(std::sync::atomic::Ordering::Relaxed) {
+                    info!("int: early exit");
+                    return Err("interrupted".into());
+                }
+                let _block_span = info_span!("block", height).entered();
+                let block_out = cliindexer::fetch_block(&cli, height)?;
+                let block: Block = block_out.json()?;
+                counts += cliindexer::insert_block(
+                    stmts,
+                    &tx_cache,
+                    &log_entry_map,
+                    &tokens,
+                    block_out.str()?,
+                    block,
+                    InsertOpts {
+                        enable_addr_graph: args.enable_graph_table,
+                        tx_filter: Some(&tx_filter),
+                        ..Default::default()
+                    },
+                )?;
+                if height % 10000 == 0 {
+                    info!("processed: [{}] / [{}]", height, end_height);
+                }
+            }
+            Ok(())
+        })
+    });
+
+    // Rebuilt even when the re-index failed, as the dropped indexes are
+    // gone either way.
+    for (name, indexer) in sql_store.index_factory() {
+        if dropped.iter().any(|x| x == name) {
+            info!("creating index: {}..", name);
+            info_span!("index_create", name).in_scope(&indexer)?;
+        }
+    }
+    res?;
+
+    info!("reindexed: {} blocks, {} txs", counts.blocks, counts.txs);
+    info!("complete");
+    Ok(())
+}
@@ -0,0 +1,196 @@
+use crate::args::OutputFormat;
+use crate::db::{self, SqliteBlockStore};
+use crate::lang::Result;
+use clap::Parser;
+use tracing::{debug, info};
+
+#[derive(Parser, Debug)]
+pub struct SearchArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    #[arg(long, short = 'a')]
+    pub address: String,
+    /// Defaults to 0
+    #[arg(short = 's', long)]
+    pub start_height: Option<u32>,
+    #[arg(short = 'e', long, default_value_t = 2_000_000)]
+    pub end_height: u32,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddrRole {
+    In,
+    Out,
+    Dvm,
+}
+
+impl std::fmt::Display for AddrRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            AddrRole::In => "in",
+            AddrRole::Out => "out",
+            AddrRole::Dvm => "dvm",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct AddrTxMatch {
+    pub txid: String,
+    pub height: i64,
+    pub tx_type: String,
+    pub role: AddrRole,
+    /// Utxo amount for in and out, none for dvm
+    pub amount: Option<f64>,
+}
+
+/// The txs of `addr` in `start..=end`, with one match for each role the
+/// address has in the tx. Looked up through `tx_addr_graph`, so the
+/// index needs to be built with the graph table.
+pub fn search_addr<F>(
+    sql_store: &SqliteBlockStore,
+    addr: &str,
+    start: i64,
+    end: i64,
+    interrupted: F,
+) -> Result<Vec<AddrTxMatch>>
+where
+    F: Fn() -> bool,
+{
+    let mut matches = vec![];
+    sql_store.iter_txs_for_addr_graph(addr, start, end, |tx| {
+        if interrupted() {
+            info!("int: early exit");
+            return Err("interrupted".into());
+        }
+        let tx = tx?;
+        let mut roles = vec![];
+        if let Some(v) = tx.tx_in.get(addr) {
+            roles.push((AddrRole::In, Some(*v)));
+        }
+        if let Some(v) = tx.tx_out.get(addr) {
+            roles.push((AddrRole::Out, Some(*v)));
+        }
+        if tx.dvm_out.iter().any(|x| x == addr) {
+            roles.push((AddrRole::Dvm, None));
+        }
+        for (role, amount) in roles {
+            matches.push(AddrTxMatch {
+                txid: tx.txid.clone(),
+                height: tx.height,
+                tx_type: tx.tx_type.clone(),
+                role,
+                amount,
+            });
+        }
+        Ok(())
+    })?;
+    Ok(matches)
+}
+
+pub fn run(args: &SearchArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    let sql_store = SqliteBlockStore::open_readonly(&args.sqlite_path)?;
+
+    let start_height = args.start_height.unwrap_or(0);
+    info!(
+        "search: {} ({} - {})..",
+        args.address, start_height, args.end_height
+    );
+    let matches = search_addr(
+        &sql_store,
+        &args.address,
+        start_height.into(),
+        args.end_height.into(),
+        || quit.load(std::sync::atomic::Ordering::Relaxed),
+    )?;
+    info!("matches: {}", matches.len());
+
+    match args.format {
+        OutputFormat::Text => {
+            println!(
+                "{:<64} {:>8} {:<24} {:<4} {:>18}",
+                "txid", "height", "tx_type", "role", "amount"
+            );
+            for x in matches.iter() {
+                let amount = match x.amount {
+                    Some(v) => format!("{:.8}", v),
+                    None => "-".to_string(),
+                };
+                println!(
+                    "{:<64} {:>8} {:<24} {:<4} {:>18}",
+                    x.txid, x.height, x.tx_type, x.role, amount
+                );
+            }
+        }
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&matches)?);
+        }
+    }
+
+    info!("complete");
+    Ok(())
+}
+
+#[test]
+fn test_search_addr() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            let txs = [
+                ("t1", 1, r#"{"b":5.0}"#, r#"{"a":2.0,"b":2.9}"#, ""),
+                ("t2", 2, r#"{"a":2.0}"#, r#"{"c":1.9}"#, r#"["a","c"]"#),
+                ("t3", 3, r#"{"c":1.0}"#, r#"{"d":0.9}"#, ""),
+                ("t4", 9, r#"{"d":1.0}"#, r#"{"a":0.9}"#, ""),
+            ];
+            for (txid, height, tx_in, tx_out, dvm_out) in txs {
+                crate::db::insert_test_tx(
+                    stmts,
+                    &crate::db::TestTx {
+                        txid,
+                        height,
+                        tx_type: "utxo",
+                        tx_in,
+                        tx_out,
+                        dvm_out,
+                        ..Default::default()
+                    },
+                )?;
+                let tx_in: std::collections::HashMap<String, f64> =
+                    serde_json::from_str(tx_in).unwrap();
+                let tx_out: std::collections::HashMap<String, f64> =
+                    serde_json::from_str(tx_out).unwrap();
+                for i in tx_in.keys() {
+                    for o in tx_out.keys() {
+                        stmts[2].execute(rusqlite::params![txid, i, o, 0])?;
+                    }
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let matches = search_addr(&store, "a", 0, 5, || false).unwrap();
+    assert_eq!(
+        matches
+            .iter()
+            .map(|x| (x.txid.as_str(), x.height, x.role, x.amount))
+            .collect::<Vec<_>>(),
+        [
+            ("t1", 1, AddrRole::Out, Some(2.0)),
+            ("t2", 2, AddrRole::In, Some(2.0)),
+            ("t2", 2, AddrRole::Dvm, None),
+        ]
+    );
+    assert_eq!(search_addr(&store, "a", 0, 9, || false).unwrap().len(), 4);
+    assert!(search_addr(&store, "z", 0, 9, || false).unwrap().is_empty());
+}
@@ -1,48 +1,31 @@
-// WIP file. Remove on finish
-#![allow(unused_variables)]
-
-use std::collections::HashSet;
+use std::collections::{hash_map::Entry, HashMap, HashSet, VecDeque};
 
 use crate::args::process_list_args_with_file_paths;
 use crate::graphutils;
+use crate::graphutils::PathFindingArgs;
 use crate::{
-    db::SqliteBlockStore,
+    db::{self, SqliteBlockStore},
     lang::{OptionExt, Result},
     models::TxType,
 };
-use anyhow::Context;
 use clap::Parser;
+use petgraph::graph::NodeIndex;
+use petgraph::visit::NodeFiltered;
 use tracing::{debug, info};
 
 #[derive(Parser, Debug)]
 pub struct ShortestPathArgs {
-    #[arg(long, default_value = "data/index.sqlite")]
-    pub sqlite_path: String,
-    #[arg(long, default_value = "data/graph.bin")]
-    pub graph_data_path: String,
-    #[arg(long, default_value = "data/graph.meta.bin")]
-    pub graph_meta_path: String,
-    /// Source address
-    #[arg(
-        long,
-        short = 'a',
-        required = true,
-        use_value_delimiter = true,
-        value_delimiter = ','
-    )]
-    pub src: Vec<String>,
-    /// Dest address
-    #[arg(
-        long,
-        short = 'd',
-        required = true,
-        use_value_delimiter = true,
-        value_delimiter = ','
-    )]
-    pub dest: Vec<String>,
+    #[command(flatten)]
+    pub path: PathFindingArgs,
     /// Ignore list to ignore paths with given addresses
-    #[arg(long, short = 'd', use_value_delimiter = true, value_delimiter = ',')]
+    #[arg(long, short = 'i', use_value_delimiter = true, value_delimiter = ',')]
     pub ignore: Vec<String>,
+    /// Number of paths to find per src and dest, shortest first
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub k: u32,
+    /// Only find paths of up to this many hops
+    #[arg(long)]
+    pub max_path_length: Option<usize>,
 }
 
 pub fn run(args: &ShortestPathArgs) -> Result<()> {
@@ -52,23 +35,37 @@ pub fn run(args: &ShortestPathArgs) -> Result<()> {
     signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
 
     let (src_addrs, dest_addrs, ignore_addrs) = (
-        process_list_args_with_file_paths(&args.src)?,
-        process_list_args_with_file_paths(&args.dest)?,
+        process_list_args_with_file_paths(&args.path.src)?,
+        process_list_args_with_file_paths(&args.path.dest)?,
         process_list_args_with_file_paths(&args.ignore)?
             .into_iter()
             .collect::<HashSet<_>>(),
     );
 
-    let sql_store = SqliteBlockStore::new_v2(Some(&args.sqlite_path))?;
-    let (g, node_index_map) = graphutils::load_graph(&args.graph_meta_path, &args.graph_data_path)?;
+    db::validate_sqlite_path(&args.path.sqlite_path, db::PathMode::Read)?;
+    let sql_store = SqliteBlockStore::open_readonly(&args.path.sqlite_path)?;
+    let (g, node_index_map) = match &args.path.graph_dir {
+        Some(dir) => graphutils::load_latest_graph(dir)?,
+        None => graphutils::load_graph(&args.path.graph_meta_path, &args.path.graph_data_path)?,
+    };
 
-    if ignore_addrs.is_empty() {
+    if args.k > 1 || args.max_path_length.is_some() {
+        path_find_k_shortest(
+            src_addrs,
+            dest_addrs,
+            ignore_addrs,
+            args.k as usize,
+            args.max_path_length,
+            quit,
+            node_index_map,
+            g,
+            sql_store,
+        )?;
+    } else if ignore_addrs.is_empty() {
         // Without ignore list is much easier, since we can use A* to only go after the single path.
         path_find_astar_fixed_cost(src_addrs, dest_addrs, quit, node_index_map, g, sql_store)?;
     } else {
-        // This is going to be more work, as we have no choice but to evaluate more paths.
-        // Alternatively, implement a custom A* that attaches a high cost and skip nodes on seeing
-        // the ignore list, but still stops search at a certain level.
+        // Same A*, but over a view of the graph without the ignored nodes.
         path_find_with_ignore(
             src_addrs,
             dest_addrs,
@@ -93,6 +90,11 @@ fn path_find_with_ignore(
     g: petgraph::Graph<String, String>,
     sql_store: SqliteBlockStore,
 ) -> Result<()> {
+    let ignore_nodes = ignore_addrs
+        .iter()
+        .filter_map(|x| node_index_map.get(x).copied())
+        .collect::<HashSet<_>>();
+
     for src in src_addrs.iter() {
         for dest in dest_addrs.iter() {
             if quit.load(std::sync::atomic::Ordering::Relaxed) {
@@ -101,12 +103,210 @@ fn path_find_with_ignore(
             }
             info!("finding path: {} -> {}", src, dest);
 
-            let src_index = node_index_map.get(src).context("src_index")?;
-            let dest_index = node_index_map.get(dest).context("dest_index")?;
+            let Some(src_index) = node_index_map.get(src) else {
+                info!("src not found: {}", src);
+                continue;
+            };
+            let Some(dest_index) = node_index_map.get(dest) else {
+                info!("dest not found: {}", dest);
+                continue;
+            };
+            if ignore_nodes.contains(src_index) || ignore_nodes.contains(dest_index) {
+                info!("src or dest is in the ignore list");
+                continue;
+            }
+
+            match shortest_path_ignoring(&g, *src_index, *dest_index, &ignore_nodes) {
+                Some(path) => log_path(&g, &sql_store, &path)?,
+                None => info!("no path found"),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn path_find_k_shortest(
+    src_addrs: Vec<String>,
+    dest_addrs: Vec<String>,
+    ignore_addrs: HashSet<String>,
+    k: usize,
+    max_len: Option<usize>,
+    quit: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    node_index_map: std::collections::HashMap<String, petgraph::prelude::NodeIndex>,
+    g: petgraph::Graph<String, String>,
+    sql_store: SqliteBlockStore,
+) -> Result<()> {
+    let ignore_nodes = ignore_addrs
+        .iter()
+        .filter_map(|x| node_index_map.get(x).copied())
+        .collect::<HashSet<_>>();
+
+    for src in src_addrs.iter() {
+        for dest in dest_addrs.iter() {
+            if quit.load(std::sync::atomic::Ordering::Relaxed) {
+                info!("int: early exit");
+                return Err("interrupted".into());
+            }
+            info!("finding {} paths: {} -> {}", k, src, dest);
+
+            let Some(src_index) = node_index_map.get(src) else {
+                info!("src not found: {}", src);
+                continue;
+            };
+            let Some(dest_index) = node_index_map.get(dest) else {
+                info!("dest not found: {}", dest);
+                continue;
+            };
+
+            let paths = k_shortest_paths(&g, *src_index, *dest_index, k, &ignore_nodes, max_len);
+            if paths.is_empty() {
+                info!("no path found");
+            }
+            for (i, path) in paths.iter().enumerate() {
+                info!("path {}/{}: {} hops", i + 1, paths.len(), path.len() - 1);
+                log_path(&g, &sql_store, path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Up to `k` loopless paths from `src` to `dest`, shortest first, with
+/// Yen's algorithm. Each next path branches off a previous one at a spur
+/// node, with the edges the previous paths took from the same root
+/// blocked. Paths through `ignore` or longer than `max_len` hops aren't
+/// considered.
+fn k_shortest_paths(
+    g: &petgraph::Graph<String, String>,
+    src: NodeIndex,
+    dest: NodeIndex,
+    k: usize,
+    ignore: &HashSet<NodeIndex>,
+    max_len: Option<usize>,
+) -> Vec<Vec<NodeIndex>> {
+    let max_len = max_len.unwrap_or(usize::MAX);
+    if ignore.contains(&src) || ignore.contains(&dest) {
+        return vec![];
+    }
+    let Some(first) = bfs_path(g, src, dest, ignore, &HashSet::new(), max_len) else {
+        return vec![];
+    };
+    let mut paths = vec![first];
+    let mut candidates: Vec<Vec<NodeIndex>> = vec![];
+
+    while paths.len() < k {
+        let prev = &paths[paths.len() - 1];
+        for i in 0..prev.len() - 1 {
+            let (spur, root) = (prev[i], &prev[..=i]);
+            let blocked_edges = paths
+                .iter()
+                .filter(|p| p.len() > i + 1 && p[..=i] == *root)
+                .map(|p| (p[i], p[i + 1]))
+                .collect::<HashSet<_>>();
+            // The root nodes are blocked too, so the paths stay loopless
+            let mut blocked_nodes = ignore.clone();
+            blocked_nodes.extend(&root[..i]);
 
-            todo!("unimplemented");
+            let Some(spur_path) =
+                bfs_path(g, spur, dest, &blocked_nodes, &blocked_edges, max_len - i)
+            else {
+                continue;
+            };
+            let path = root[..i]
+                .iter()
+                .chain(&spur_path)
+                .copied()
+                .collect::<Vec<_>>();
+            if !paths.contains(&path) && !candidates.contains(&path) {
+                candidates.push(path);
+            }
+        }
+        // First found wins a tie, so the order is stable
+        let Some((min, _)) = candidates.iter().enumerate().min_by_key(|(_, p)| p.len()) else {
+            break;
+        };
+        paths.push(candidates.remove(min));
+    }
+    paths
+}
+
+// All edges cost the same, so a breadth first search finds a shortest
+// path, and stops at `max_len` hops.
+fn bfs_path(
+    g: &petgraph::Graph<String, String>,
+    src: NodeIndex,
+    dest: NodeIndex,
+    blocked_nodes: &HashSet<NodeIndex>,
+    blocked_edges: &HashSet<(NodeIndex, NodeIndex)>,
+    max_len: usize,
+) -> Option<Vec<NodeIndex>> {
+    let mut prev = HashMap::from([(src, src)]);
+    let mut queue = VecDeque::from([(src, 0)]);
+    while let Some((n, depth)) = queue.pop_front() {
+        if n == dest {
+            let mut path = vec![dest];
+            let mut n = dest;
+            while n != src {
+                n = prev[&n];
+                path.push(n);
+            }
+            path.reverse();
+            return Some(path);
+        }
+        if depth >= max_len {
+            continue;
+        }
+        for next in g.neighbors(n) {
+            if blocked_nodes.contains(&next) || blocked_edges.contains(&(n, next)) {
+                continue;
+            }
+            if let Entry::Vacant(e) = prev.entry(next) {
+                e.insert(n);
+                queue.push_back((next, depth + 1));
+            }
         }
     }
+    None
+}
+
+// Ignored nodes are filtered out of the graph the search sees, so they're
+// never expanded, rather than dropping the paths through them after.
+fn shortest_path_ignoring(
+    g: &petgraph::Graph<String, String>,
+    src: NodeIndex,
+    dest: NodeIndex,
+    ignore_nodes: &HashSet<NodeIndex>,
+) -> Option<Vec<NodeIndex>> {
+    let filtered = NodeFiltered::from_fn(g, |n| !ignore_nodes.contains(&n));
+    petgraph::algo::astar(&filtered, src, |n| n == dest, |_edge| 1, |_node| 0)
+        .map(|(_cost, path)| path)
+}
+
+fn log_path(
+    g: &petgraph::Graph<String, String>,
+    sql_store: &SqliteBlockStore,
+    path: &[NodeIndex],
+) -> Result<()> {
+    for (i, node_idx) in path.windows(2).enumerate() {
+        let src_node = g.node_weight(node_idx[0]).ok_or_context("node_weight")?;
+        let dest_node = g.node_weight(node_idx[1]).ok_or_context("node_weight")?;
+
+        let edge = g
+            .find_edge(node_idx[0], node_idx[1])
+            .ok_or_context("find_edge")?;
+        let tx_id = g.edge_weight(edge).ok_or_context("edge_weight")?;
+
+        let tx = sql_store
+            .get_tx_data_partial(tx_id)?
+            .ok_or_with(|| format!("tx: {}", tx_id))?;
+        let tx_type = TxType::from_display(tx.tx_type.as_str());
+
+        info!(
+            "[{}] {}: {} -> {} (tx: {}, height: {})",
+            i, tx_type, src_node, dest_node, tx_id, tx.height,
+        );
+    }
     Ok(())
 }
 
@@ -151,23 +351,7 @@ fn path_find_astar_fixed_cost(
             debug!("{:?}", paths);
 
             match paths {
-                Some((_cost, path)) => {
-                    for (i, node_idx) in path.windows(2).enumerate() {
-                        let src_node = g.node_weight(node_idx[0]).context("node_weight")?;
-                        let dest_node = g.node_weight(node_idx[1]).context("node_weight")?;
-
-                        let edge = g.find_edge(node_idx[0], node_idx[1]).context("find_edge")?;
-                        let tx_id = g.edge_weight(edge).context("edge_weight")?;
-
-                        let tx = sql_store.get_tx_data(tx_id)?.ok_or_err()?;
-                        let tx_type = TxType::from_display(tx.tx_type.as_str());
-
-                        info!(
-                            "[{}] {}: {} -> {} (tx: {})",
-                            i, tx_type, src_node, dest_node, tx_id,
-                        );
-                    }
-                }
+                Some((_cost, path)) => log_path(&g, &sql_store, &path)?,
                 None => {
                     info!("no path found");
                 }
@@ -176,3 +360,42 @@ fn path_find_astar_fixed_cost(
     }
     Ok(())
 }
+
+#[test]
+fn test_shortest_path_ignoring() {
+    let mut g = petgraph::Graph::<String, String>::new();
+    let [a, b, c, d, e] = ["a", "b", "c", "d", "e"].map(|x| g.add_node(x.to_string()));
+    // a -> b -> d is the shortest, a -> c -> e -> d the alternative
+    for (from, to) in [(a, b), (b, d), (a, c), (c, e), (e, d)] {
+        g.add_edge(from, to, String::new());
+    }
+
+    let path =
+        |ignore: &[NodeIndex]| shortest_path_ignoring(&g, a, d, &ignore.iter().copied().collect());
+    assert_eq!(path(&[]), Some(vec![a, b, d]));
+    assert_eq!(path(&[b]), Some(vec![a, c, e, d]));
+    assert_eq!(path(&[b, e]), None);
+}
+
+#[test]
+fn test_k_shortest_paths() {
+    let mut g = petgraph::Graph::<String, String>::new();
+    let [a, b, c, d, e, f] = ["a", "b", "c", "d", "e", "f"].map(|x| g.add_node(x.to_string()));
+    // a -> b -> d, a -> c -> e -> d and a -> b -> f -> e -> d
+    for (from, to) in [(a, b), (b, d), (a, c), (c, e), (e, d), (b, f), (f, e)] {
+        g.add_edge(from, to, String::new());
+    }
+
+    let paths = |k, ignore: &[NodeIndex], max_len| {
+        k_shortest_paths(&g, a, d, k, &ignore.iter().copied().collect(), max_len)
+    };
+    assert_eq!(paths(1, &[], None), [vec![a, b, d]]);
+    assert_eq!(
+        paths(10, &[], None),
+        [vec![a, b, d], vec![a, c, e, d], vec![a, b, f, e, d]]
+    );
+    assert_eq!(paths(2, &[b], None), [vec![a, c, e, d]]);
+    assert_eq!(paths(10, &[], Some(3)).len(), 2);
+    assert!(paths(10, &[], Some(1)).is_empty());
+    assert!(paths(10, &[d], None).is_empty());
+}
@@ -1,22 +1,16 @@
+use crate::cliindexer::{self, IndexCounts, InsertOpts, TxTypeFilter};
 use crate::db;
-use crate::dfiutils;
+use crate::dfiutils::TokenResolver;
 use crate::lang;
+use crate::lang::OptionExt;
 use crate::logparse::process_log_file;
 use crate::models;
 use crate::models::LogEntryMap;
 use anyhow::Context;
 use clap::Parser;
-use db::{
-    sqlite_begin_tx, sqlite_commit_and_begin_tx, sqlite_commit_tx, sqlite_create_index_factory_v2,
-    sqlite_get_stmts_v2, SqliteBlockStore,
-};
-use dfiutils::{extract_all_dfi_addresses, token_id_to_symbol_maybe};
-use lang::OptionExt;
+use db::{BatchCommit, LruBlockStore, SqliteBlockStore};
 use lang::Result;
-use models::{Block, IcxTxSet, TxType};
-use std::collections::HashMap;
-use std::collections::HashSet;
-use std::rc::Rc;
+use models::Block;
 use tracing::debug;
 use tracing::info;
 
@@ -37,12 +31,50 @@ pub struct SqliteIndexArgs {
     pub log_icx_calc_matcher: String,
     #[arg(long, default_value = "SwapResult:")]
     pub log_swap_matcher: String,
-    #[arg(short = 's', long, default_value_t = 0)]
-    pub start_height: i64,
+    /// Defaults to 0
+    #[arg(short = 's', long)]
+    pub start_height: Option<u32>,
     #[arg(short = 'e', long, default_value_t = 2_000_000)]
-    pub end_height: i64,
+    pub end_height: u32,
     #[arg(long, default_value_t = true)]
     pub enable_graph_table: bool,
+    /// Start right after the last checkpoint in the index, for re-running
+    /// after a crash or to catch up with the chain
+    #[arg(long, default_value_t = false)]
+    pub resume: bool,
+    /// Number of deserialized txs kept in memory for resolving tx ins.
+    /// 0 disables the cache.
+    #[arg(long, default_value_t = 1000)]
+    pub tx_cache_size: usize,
+    #[command(flatten)]
+    pub sqlite_pragmas: db::SqlitePragmaArgs,
+    /// Token id to symbol map, json or csv, for the swap tokens that
+    /// aren't in the built-in table
+    #[arg(long)]
+    pub token_map_path: Option<String>,
+    /// Read and resolve the range as usual, but roll back all writes at
+    /// the end and print what would have been indexed
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+    /// Commit the write transaction every this many blocks
+    #[arg(long, default_value_t = 10000, value_parser = clap::value_parser!(u32).range(1..))]
+    pub batch_size: u32,
+    /// Also commit once the db has grown by this many bytes since the
+    /// last commit, for large blocks on limited memory
+    #[arg(long)]
+    pub batch_size_bytes: Option<u64>,
+    /// Skip txs that fail to index, like ones with an input that can't
+    /// be resolved, instead of stopping. The skipped txs are logged and
+    /// written out to the error log path.
+    #[arg(long, default_value_t = false)]
+    pub error_recovery: bool,
+    #[arg(long, default_value = "data/errors.jsonl")]
+    pub error_log_path: String,
+    /// Check the height range against the height of the source index,
+    /// and the dest db for write access and disk space, before indexing.
+    /// Pass false to skip.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub height_range_validate: bool,
 }
 
 pub fn run(args: &SqliteIndexArgs) -> Result<()> {
@@ -58,15 +90,30 @@ pub fn run(args: &SqliteIndexArgs) -> Result<()> {
         true => None,
         false => Some(args.defid_log_path.as_str()),
     };
-    let enable_addr_graph = args.enable_graph_table;
-    let start_height = args.start_height;
+    if args.resume && args.start_height.is_some() {
+        return Err("cannot specify both --resume and --start-height".into());
+    }
     let end_height = args.end_height;
 
     info!("{:?}", args);
 
+    db::validate_sqlite_path(
+        db_path_src.unwrap_or(db::DEFAULT_SQLITE_PATH),
+        db::PathMode::Read,
+    )?;
+    db::validate_sqlite_path(
+        db_path_dest.unwrap_or(db::DEFAULT_SQLITE_PATH),
+        db::PathMode::Create,
+    )?;
+
     let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
 
+    let tokens = TokenResolver::from_path(args.token_map_path.as_deref())?;
+    if let Some(path) = &args.token_map_path {
+        info!("token map: {} ({} tokens)", path, tokens.len());
+    }
+
     let mut log_entry_map = LogEntryMap::new();
 
     if let Some(defid_log_path) = defid_log_path {
@@ -77,6 +124,8 @@ pub fn run(args: &SqliteIndexArgs) -> Result<()> {
             args.log_icx_matcher.as_str(),
             args.log_icx_calc_matcher.as_str(),
             args.log_swap_matcher.as_str(),
+            None,
+            None,
             &mut log_entry_map,
         )?;
 
@@ -85,216 +134,146 @@ pub fn run(args: &SqliteIndexArgs) -> Result<()> {
             \tTotal transactions:     {}\n\
             \tTotal ICX entries:      {}\n\
             \tTotal ICX calc entries: {}\n\
-            \tTotal Swap entries:     {}",
+            \tTotal Swap entries:     {}\n\
+            \tTotal lines:            {}\n\
+            \tSkipped lines:          {}\n\
+            \tUpdated entries:        {}",
             log_entry_map.data.len(),
             log_entry_map.icx_count,
             log_entry_map.icx_calc_count,
             log_entry_map.swap_count,
+            log_entry_map.total_lines,
+            log_entry_map.skipped_lines,
+            log_entry_map.update_count,
         );
     }
 
-    let sql_store = SqliteBlockStore::new_v2(db_path_src)?;
-    let sql_store_dest = SqliteBlockStore::new_v2(db_path_dest)?;
+    let sql_store = SqliteBlockStore::open(db_path_src)?;
+    let sql_store_dest = if args.dry_run {
+        SqliteBlockStore::open_dry_run(
+            db_path_dest.unwrap_or(db::DEFAULT_SQLITE_PATH),
+            &args.sqlite_pragmas,
+        )?
+    } else {
+        SqliteBlockStore::open_or_create_with(
+            db_path_dest.unwrap_or(db::DEFAULT_SQLITE_PATH),
+            &args.sqlite_pragmas,
+        )?
+    };
+    info!(
+        "db: {} ({} bytes)",
+        sql_store_dest,
+        sql_store_dest.db_size_bytes()?
+    );
+    debug!("{:?}", sql_store_dest);
+
+    // The dest keeps the filter it was built with, if any
+    let tx_filter = TxTypeFilter::load(&sql_store_dest)?;
+    let opts = InsertOpts {
+        enable_addr_graph: args.enable_graph_table,
+        error_recovery: args.error_recovery,
+        tx_filter: Some(&tx_filter),
+    };
 
-    let sconn = &sql_store_dest.conn;
-    for (name, _) in sqlite_create_index_factory_v2(sconn) {
-        if quit.load(std::sync::atomic::Ordering::Relaxed) {
-            info!("int: early exit indexes");
-            break;
-        }
-        info!("drop index: {}..", name);
-        let q = format!("DROP INDEX IF EXISTS {}", name);
-        sconn.execute(&q, [])?;
-    }
+    let start_height = if args.resume {
+        let h = sql_store_dest.resume_height()?;
+        info!("resuming from height {}", h);
+        h
+    } else {
+        args.start_height.unwrap_or(0)
+    };
 
-    let mut stmts = sqlite_get_stmts_v2(sconn)?;
-    sqlite_begin_tx(sconn)?;
+    let end_height = if args.height_range_validate {
+        let src_height = sql_store
+            .max_block_height()?
+            .ok_or_context("source index has no blocks")?;
+        let end = cliindexer::validate_height_range(
+            start_height,
+            end_height,
+            u32::try_from(src_height)?,
+        )?;
+        if !args.dry_run {
+            let path = db_path_dest.unwrap_or(db::DEFAULT_SQLITE_PATH);
+            db::validate_sqlite_path(path, db::PathMode::Write)?;
+            cliindexer::check_range_disk_space(path, &sql_store, start_height, end)?;
+        }
+        end
+    } else {
+        end_height
+    };
 
-    let res = sql_store.iter_blocks_raw(
-        Some(&format!(
-            "where height between {} and {} order by height",
-            start_height, end_height
-        )),
-        |r| {
+    if args.dry_run {
+        info!("dry run, all writes are rolled back");
+    } else {
+        for (name, _) in sql_store_dest.index_factory() {
             if quit.load(std::sync::atomic::Ordering::Relaxed) {
-                info!("int: early exit");
-                return Err("interrupted".into());
-            }
-            let row = r?;
-            let height = row.get_ref(0)?.as_i64().context("height str")?;
-            let hash = row.get_ref(1)?.as_str().context("hash str")?;
-            let block_json = row.get_ref(2)?.as_str().context("block str")?;
-            let block = serde_json::from_str::<Block>(block_json)?;
-
-            debug!("[{}] hash: {}", height, &hash);
-            {
-                stmts[0].execute(rusqlite::params![height, &hash, block_json])?;
+                info!("int: early exit indexes");
+                break;
             }
+            info!("drop index: {}..", name);
+            sql_store_dest.drop_index(name)?;
+        }
+    }
 
-            for tx in block.tx {
-                let tx_in_addrs = dfiutils::get_txin_addr_val_list(&tx.vin, &sql_store)?;
-                let tx_out_addrs = dfiutils::get_txout_addr_val_list(&tx, &tx.vout);
-
-                let tx_in_addrs = dfiutils::fold_addr_val_map(&tx_in_addrs);
-                let tx_out = dfiutils::fold_addr_val_map(&tx_out_addrs)
-                    .into_iter()
-                    .filter(|x| *x.0 != *"x") // strip coinbase out
-                    .collect::<HashMap<_, _>>();
-
-                let mut tx_type = tx.vm.as_ref().map(|x| TxType::from(&*x.txtype));
-                let mut dvm_addrs = HashSet::new();
-
-                if tx_in_addrs.is_empty() {
-                    tx_type = Some(TxType::Coinbase);
+    let tx_cache = LruBlockStore::new(&sql_store, args.tx_cache_size);
+    let mut batch = BatchCommit::new(&sql_store_dest, args.batch_size, args.batch_size_bytes)?;
+    sql_store_dest.begin_tx()?;
+
+    let mut counts = IndexCounts::default();
+
+    let res = sql_store_dest.with_stmts(|stmts| {
+        sql_store.iter_blocks_raw(
+            Some(&format!(
+                "where height between {} and {} order by height",
+                start_height, end_height
+            )),
+            |r| {
+                if quit.load(std::sync::atomic::Ordering::Relaxed) {
+                    info!("int: early exit");
+                    return Err("interrupted".into());
                 }
-
-                if !matches!(
-                    &tx_type,
-                    Some(TxType::Coinbase) | Some(TxType::Unknown) | Some(TxType::Utxo) | None
-                ) {
-                    let dvm_data = tx.vm.as_ref().map(|x| x.msg.to_string()).unwrap();
-                    dvm_addrs = extract_all_dfi_addresses(&dvm_data);
+                let row = r?;
+                let height = row.get_ref(0)?.as_i64().context("height str")?;
+                let block_json = row.get_ref(2)?.as_str().context("block str")?;
+                let block = serde_json::from_str::<Block>(block_json)?;
+
+                counts += cliindexer::insert_block(
+                    stmts,
+                    &tx_cache,
+                    &log_entry_map,
+                    &tokens,
+                    block_json,
+                    block,
+                    opts,
+                )?;
+
+                if !args.dry_run && batch.is_due(&sql_store_dest, height)? {
+                    sql_store_dest.commit_and_begin_tx()?;
                 }
-                let mut icx_claim_data: Option<IcxTxSet> = None;
-                let mut icx_addr = empty();
-                let mut icx_amt = empty();
-                let mut swap_from = empty();
-                let mut swap_to = empty();
-                let mut swap_amt = empty();
-
-                match tx_type {
-                    Some(TxType::PoolSwap) | Some(TxType::CompositeSwap) => {
-                        let swap_data = &tx.vm.as_ref().ok_or_err()?.msg;
-                        let swap_data: models::PoolSwapMsg =
-                            serde_json::from_value(swap_data.clone())?;
-                        swap_from = token_id_to_symbol_maybe(&swap_data.from_token).to_string();
-                        swap_to = token_id_to_symbol_maybe(&swap_data.to_token).to_string();
-                        swap_amt = format!("{:.9}", &swap_data.from_amount);
-                    }
-                    Some(TxType::ICXClaimDFCHTLC) => {
-                        if let Some(log_entry) = &log_entry_map.data.get(&tx.txid) {
-                            if let Some(icx_data) = &log_entry.icx_data {
-                                icx_claim_data = Some(IcxTxSet {
-                                    order_tx: icx_data.order_tx.clone(),
-                                    claim_tx: icx_data.claim_tx.clone(),
-                                    offer_tx: icx_data.offer_tx.clone(),
-                                    dfchtlc_tx: icx_data.dfchtlc_tx.clone(),
-                                });
-                                icx_addr = icx_data.address.to_string();
-                                icx_amt = icx_data.amount.to_string();
-                            }
-                        }
-                    }
-                    _ => {}
+                if height % 10000 == 0 {
+                    info!("processed: [{}] / [{}]", height, end_height);
                 }
 
-                let (dvm_in_addrs, _): (Vec<_>, Vec<_>) = dvm_addrs
-                    .iter()
-                    .cloned()
-                    .partition(|addr| tx_in_addrs.iter().any(|(in_addr, _)| *in_addr == *addr));
-
-                if enable_addr_graph {
-                    // DVM addresses are parsed for all matching addresses inside the
-                    // DVM data. There is no clean in and out: this requires specific
-                    // knowledge of each message and there's no clear convention of this.
-                    // So instead, we workaround this as we know that if tx in and dvm addr
-                    // is the same, they were _likely_ source.
-                    // We partition these out first. For out, we take the whole list
-                    // to err on the side of caution to add more edges.
-
-                    let mut changeset = HashMap::<[Rc<str>; 2], i64>::new();
-
-                    for (out_addr, _) in tx_out.iter() {
-                        for (in_addr, _) in tx_in_addrs.iter() {
-                            let k = [in_addr.clone(), (*out_addr).clone()];
-                            changeset.insert(k, 0);
-                        }
-                    }
-
-                    for out_addr in dvm_addrs.iter() {
-                        for in_addr in dvm_in_addrs.iter() {
-                            let k = [in_addr.clone(), out_addr.clone()];
-                            let v = changeset.get_mut(&k);
-                            if let Some(v) = v {
-                                // we set to DVM + UTXO
-                                if *v == 0 {
-                                    *v = 2;
-                                }
-                            } else {
-                                // we set this with DVM only
-                                changeset.insert(k, 1);
-                            }
-                        }
-                    }
-
-                    for ([edge_in, edge_out], c_flags) in &changeset {
-                        stmts[2]
-                            .execute(rusqlite::params![&tx.txid, &edge_in, &edge_out, c_flags])?;
-                    }
-                }
+                Ok(())
+            },
+        )
+    });
 
-                // Transform to final strings. Mostly empty strings for non relevant fields
+    let (hits, misses) = tx_cache.stats();
+    info!("tx cache: {} hits, {} misses", hits, misses);
+    counts.write_tx_errors(&args.error_log_path)?;
 
-                let tx_type_str = tx_type.clone().unwrap_or(TxType::Unknown).to_string();
-                let dvm_in_addrs_json = if dvm_in_addrs.is_empty() {
-                    empty()
-                } else {
-                    serde_json::to_string(&dvm_in_addrs)?
-                };
-                let dvm_addrs_json = if dvm_addrs.is_empty() {
-                    empty()
-                } else {
-                    serde_json::to_string(&dvm_addrs)?
-                };
-                let tx_in_json = if tx_in_addrs.is_empty() {
-                    empty()
-                } else {
-                    serde_json::to_string(&tx_in_addrs)?
-                };
-                let tx_out_json = if tx_out.is_empty() {
-                    empty()
-                } else {
-                    serde_json::to_string(&tx_out)?
-                };
-                let tx_json = serde_json::to_string(&tx)?;
-                let icx_claim_data = if icx_claim_data.is_none() {
-                    empty()
-                } else {
-                    serde_json::to_string(&icx_claim_data.unwrap())?
-                };
-
-                stmts[1].execute(rusqlite::params![
-                    &tx.txid,
-                    height,
-                    &tx_type_str,
-                    &tx_in_json,
-                    &tx_out_json,
-                    &dvm_in_addrs_json,
-                    &dvm_addrs_json,
-                    &tx_json,
-                    &icx_claim_data,
-                    &icx_addr,
-                    &icx_amt,
-                    &swap_from,
-                    &swap_to,
-                    &swap_amt,
-                ])?;
-            }
-
-            if height % 10000 == 0 {
-                sqlite_commit_and_begin_tx(sconn)?;
-                info!("processed: [{}] / [{}]", height, end_height);
-            }
-
-            Ok(())
-        },
-    );
+    if args.dry_run {
+        sql_store_dest.rollback_tx()?;
+        counts.print_dry_run_summary();
+        return res;
+    }
 
     info!("flushing db");
-    sqlite_commit_tx(sconn)?;
+    sql_store_dest.commit_tx()?;
 
     if res.is_ok() {
-        for (name, indexer) in sqlite_create_index_factory_v2(sconn) {
+        for (name, indexer) in sql_store_dest.index_factory() {
             if quit.load(std::sync::atomic::Ordering::Relaxed) {
                 info!("int: early exit indexes");
                 break;
@@ -307,8 +286,3 @@ pub fn run(args: &SqliteIndexArgs) -> Result<()> {
 
     res
 }
-
-// Just a short convenience alias for internal use.
-fn empty() -> String {
-    String::new()
-}
@@ -0,0 +1,137 @@
+use crate::args::OutputFormat;
+use crate::db::{self, PageStats, SqliteBlockStore};
+use crate::lang::Result;
+use clap::Parser;
+use tracing::{debug, info, warn};
+
+#[derive(Parser, Debug)]
+pub struct StatsArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+    /// Max number of height gaps to report
+    #[arg(long, default_value_t = 20)]
+    pub max_gaps: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct DbStats {
+    pub size_bytes: u64,
+    pub block_count: i64,
+    pub tx_count: i64,
+    /// `(tx_type, count)`, most common first
+    pub tx_types: Vec<(String, i64)>,
+    pub graph_addr_count: i64,
+    pub min_height: Option<i64>,
+    pub max_height: Option<i64>,
+    /// Inclusive `(start, end)` of the missing height runs, up to
+    /// `max_gaps` of them
+    pub gaps: Vec<(i64, i64)>,
+    pub pages: PageStats,
+}
+
+pub fn get_stats(sql_store: &SqliteBlockStore, max_gaps: usize) -> Result<DbStats> {
+    Ok(DbStats {
+        size_bytes: sql_store.db_size_bytes()?,
+        block_count: sql_store.count_blocks()?,
+        tx_count: sql_store.count_txs()?,
+        tx_types: sql_store.count_txs_by_type()?,
+        graph_addr_count: sql_store.count_graph_addresses()?,
+        min_height: sql_store.min_block_height()?,
+        max_height: sql_store.max_block_height()?,
+        gaps: sql_store.get_height_gaps(max_gaps)?,
+        pages: sql_store.get_page_stats()?,
+    })
+}
+
+pub fn run(args: &StatsArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    let sql_store = SqliteBlockStore::open(Some(&args.sqlite_path))?;
+
+    info!("get stats..");
+    let stats = get_stats(&sql_store, args.max_gaps)?;
+    if !stats.gaps.is_empty() {
+        warn!("index has height gaps, first at {}", stats.gaps[0].0);
+    }
+
+    match args.format {
+        OutputFormat::Text => {
+            let p = &stats.pages;
+            println!("size: {} bytes", stats.size_bytes);
+            println!("blocks: {}", stats.block_count);
+            match (stats.min_height, stats.max_height) {
+                (Some(min), Some(max)) => println!("heights: {} - {}", min, max),
+                _ => println!("heights: -"),
+            }
+            println!("txs: {}", stats.tx_count);
+            for (tx_type, n) in stats.tx_types.iter() {
+                println!("    {:<16} {:>12}", tx_type, n);
+            }
+            println!("graph addrs: {}", stats.graph_addr_count);
+            println!("gaps: {}", stats.gaps.len());
+            for (start, end) in stats.gaps.iter() {
+                println!("    {} - {} ({} blocks)", start, end, end - start + 1);
+            }
+            println!(
+                "pages: {} of {} bytes, free: {}, cache size: {}",
+                p.page_count, p.page_size, p.freelist_count, p.cache_size
+            );
+        }
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        }
+    }
+
+    info!("complete");
+    Ok(())
+}
+
+#[test]
+fn test_get_stats() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            for height in [3, 4, 5, 8, 10, 11] {
+                stmts[0].execute(rusqlite::params![
+                    height,
+                    format!("h{}", height),
+                    "",
+                    rusqlite::types::Null,
+                    rusqlite::types::Null
+                ])?;
+            }
+            for (txid, tx_type) in [("t1", "utxo"), ("t2", "ps"), ("t3", "utxo")] {
+                crate::db::insert_test_tx(
+                    stmts,
+                    &crate::db::TestTx {
+                        txid,
+                        height: 3,
+                        tx_type,
+                        ..Default::default()
+                    },
+                )?;
+            }
+            for (txid, in_addr, out_addr) in [("t1", "a", "b"), ("t2", "b", "c"), ("t3", "a", "c")]
+            {
+                stmts[2].execute(rusqlite::params![txid, in_addr, out_addr, 0])?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let stats = get_stats(&store, 20).unwrap();
+    assert_eq!(stats.block_count, 6);
+    assert_eq!(stats.tx_count, 3);
+    assert_eq!(
+        stats.tx_types,
+        [("utxo".to_string(), 2), ("ps".to_string(), 1)]
+    );
+    assert_eq!(stats.graph_addr_count, 3);
+    assert_eq!((stats.min_height, stats.max_height), (Some(3), Some(11)));
+    assert_eq!(stats.gaps, [(6, 7), (9, 9)]);
+    assert_eq!(get_stats(&store, 1).unwrap().gaps, [(6, 7)]);
+    assert!(stats.pages.page_count > 0);
+}
@@ -0,0 +1,57 @@
+use crate::args::OutputFormat;
+use crate::db::{self, SqliteBlockStore};
+use crate::lang::Result;
+use clap::Parser;
+use tracing::{debug, info};
+
+#[derive(Parser, Debug)]
+pub struct SwapStatsArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    #[arg(short = 's', long)]
+    pub start_height: Option<u32>,
+    #[arg(short = 'e', long)]
+    pub end_height: Option<u32>,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+}
+
+pub fn run(args: &SwapStatsArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    let sql_store = SqliteBlockStore::open(Some(&args.sqlite_path))?;
+
+    info!("get swap stats..");
+    let stats = sql_store.get_pool_swap_stats(
+        args.start_height.map(i64::from),
+        args.end_height.map(i64::from),
+    )?;
+    // Volumes are in the from token, so this is only a rough total.
+    let total_amount = stats.iter().map(|x| x.total_amount).sum::<f64>();
+    let total_count = stats.iter().map(|x| x.tx_count).sum::<i64>();
+
+    match args.output_format {
+        OutputFormat::Text => {
+            println!("{:<12} {:<12} {:>10} {:>24}", "from", "to", "txs", "volume");
+            for s in stats.iter() {
+                println!(
+                    "{:<12} {:<12} {:>10} {:>24.8}",
+                    s.from_token, s.to_token, s.tx_count, s.total_amount
+                );
+            }
+            println!("total: {} txs, volume: {:.8}", total_count, total_amount);
+        }
+        OutputFormat::Json => {
+            let out = serde_json::json!({
+                "pairs": stats,
+                "total_tx_count": total_count,
+                "total_amount": total_amount,
+            });
+            println!("{}", serde_json::to_string_pretty(&out)?);
+        }
+    }
+
+    info!("complete");
+    Ok(())
+}
@@ -0,0 +1,217 @@
+use crate::args::OutputFormat;
+use crate::db::{self, SqliteBlockStore, TxRow};
+use crate::lang::Result;
+use clap::Parser;
+use std::collections::BTreeMap;
+use tracing::{debug, info};
+
+/// Density of a bucket relative to the busiest one, lowest to highest
+const DENSITY_CHARS: &[u8] = b" .:-=+*#%@";
+
+#[derive(Parser, Debug)]
+pub struct TimelineArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    #[arg(long, short = 'a')]
+    pub address: String,
+    /// Blocks per timeline bucket
+    #[arg(long, default_value_t = 10000, value_parser = clap::value_parser!(u32).range(1..))]
+    pub bucket_size: u32,
+    /// Scan the tx_in and tx_out json of every tx instead of looking the
+    /// address up through the graph table. Slow, but also finds txs
+    /// without ins, like coinbases.
+    #[arg(long, default_value_t = false)]
+    pub full_scan: bool,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct AddrTimeline {
+    pub address: String,
+    pub bucket_size: u32,
+    pub first_seen: Option<i64>,
+    pub last_seen: Option<i64>,
+    pub tx_count: u64,
+    pub tx_types: BTreeMap<String, u64>,
+    /// Sum of the tx outs to the address
+    pub dfi_received: f64,
+    /// Sum of the tx ins from the address
+    pub dfi_sent: f64,
+    /// Tx count per bucket, keyed by the bucket's start height
+    pub buckets: BTreeMap<i64, u64>,
+}
+
+impl AddrTimeline {
+    pub fn new(address: &str, bucket_size: u32) -> Self {
+        Self {
+            address: address.to_string(),
+            bucket_size,
+            ..Default::default()
+        }
+    }
+
+    pub fn add_tx(&mut self, tx: &TxRow) {
+        let addr = self.address.as_str();
+        self.first_seen = Some(self.first_seen.map_or(tx.height, |x| x.min(tx.height)));
+        self.last_seen = Some(self.last_seen.map_or(tx.height, |x| x.max(tx.height)));
+        self.tx_count += 1;
+        *self.tx_types.entry(tx.tx_type.clone()).or_default() += 1;
+        self.dfi_received += tx.tx_out.get(addr).copied().unwrap_or_default();
+        self.dfi_sent += tx.tx_in.get(addr).copied().unwrap_or_default();
+        let bucket = tx.height - tx.height % i64::from(self.bucket_size);
+        *self.buckets.entry(bucket).or_default() += 1;
+    }
+
+    /// One char per bucket from the first to the last seen, empty buckets
+    /// included, scaled to the busiest bucket
+    pub fn density_line(&self) -> String {
+        let (Some(first), Some(last)) =
+            (self.buckets.keys().next(), self.buckets.keys().next_back())
+        else {
+            return String::new();
+        };
+        let max = self.buckets.values().max().copied().unwrap_or(1);
+        let top = DENSITY_CHARS.len() - 1;
+        (*first..=*last)
+            .step_by(self.bucket_size as usize)
+            .map(|x| match self.buckets.get(&x) {
+                None => DENSITY_CHARS[0] as char,
+                // Any activity shows, so only empty buckets are blank
+                Some(n) => DENSITY_CHARS[((*n * top as u64).div_ceil(max) as usize).max(1)] as char,
+            })
+            .collect()
+    }
+}
+
+pub fn build_timeline(
+    sql_store: &SqliteBlockStore,
+    addr: &str,
+    bucket_size: u32,
+    full_scan: bool,
+) -> Result<AddrTimeline> {
+    let mut t = AddrTimeline::new(addr, bucket_size);
+    let f = |tx: Result<TxRow>| {
+        t.add_tx(&tx?);
+        Ok(())
+    };
+    if full_scan {
+        sql_store.iter_txs_for_addr(addr, i64::MAX, f)?;
+    } else {
+        sql_store.iter_txs_for_addr_graph(addr, 0, i64::MAX, f)?;
+    }
+    Ok(t)
+}
+
+pub fn run(args: &TimelineArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    let sql_store = SqliteBlockStore::open(Some(&args.sqlite_path))?;
+
+    info!("timeline: {}..", args.address);
+    let t = build_timeline(&sql_store, &args.address, args.bucket_size, args.full_scan)?;
+
+    match args.format {
+        OutputFormat::Text => {
+            let (Some(first), Some(last)) = (t.first_seen, t.last_seen) else {
+                println!("{}: no txs found", t.address);
+                info!("complete");
+                return Ok(());
+            };
+            println!("address: {}", t.address);
+            println!("first seen: {}", first);
+            println!("last seen: {}", last);
+            println!("txs: {}", t.tx_count);
+            for (tx_type, n) in t.tx_types.iter() {
+                println!("    {}: {}", tx_type, n);
+            }
+            println!("dfi in (received): {:.8}", t.dfi_received);
+            println!("dfi out (sent): {:.8}", t.dfi_sent);
+            println!("timeline ({} blocks per char):", t.bucket_size);
+            let (bucket_first, bucket_last) = (
+                first - first % i64::from(t.bucket_size),
+                last - last % i64::from(t.bucket_size),
+            );
+            println!(
+                "    {} |{}| {}",
+                bucket_first,
+                t.density_line(),
+                bucket_last + i64::from(t.bucket_size) - 1
+            );
+        }
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&t)?);
+        }
+    }
+
+    info!("complete");
+    Ok(())
+}
+
+#[test]
+fn test_build_timeline() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    store
+        .with_stmts(|stmts| {
+            let txs = [
+                ("t1", 5, "cb", "", r#"{"a":50.0}"#),
+                ("t2", 12, "utxo", r#"{"a":50.0}"#, r#"{"b":49.5}"#),
+                ("t3", 15, "ps", r#"{"b":10.0}"#, r#"{"a":9.5}"#),
+                ("t4", 18, "ps", r#"{"b":1.0}"#, r#"{"a":0.5}"#),
+                ("t5", 41, "utxo", r#"{"a":10.0}"#, r#"{"a":9.0}"#),
+                ("t6", 42, "utxo", r#"{"b":1.0}"#, r#"{"c":1.0}"#),
+            ];
+            for (txid, height, tx_type, tx_in, tx_out) in txs {
+                crate::db::insert_test_tx(
+                    stmts,
+                    &crate::db::TestTx {
+                        txid,
+                        height,
+                        tx_type,
+                        tx_in,
+                        tx_out,
+                        ..Default::default()
+                    },
+                )?;
+                let tx_in = match tx_in {
+                    "" => Default::default(),
+                    x => serde_json::from_str::<std::collections::HashMap<String, f64>>(x).unwrap(),
+                };
+                let tx_out =
+                    serde_json::from_str::<std::collections::HashMap<String, f64>>(tx_out).unwrap();
+                for i in tx_in.keys() {
+                    for o in tx_out.keys() {
+                        stmts[2].execute(rusqlite::params![txid, i, o, 0])?;
+                    }
+                }
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let t = build_timeline(&store, "a", 10, true).unwrap();
+    assert_eq!((t.first_seen, t.last_seen), (Some(5), Some(41)));
+    assert_eq!(t.tx_count, 5);
+    assert_eq!(
+        t.tx_types,
+        BTreeMap::from([
+            ("cb".to_string(), 1),
+            ("ps".to_string(), 2),
+            ("utxo".to_string(), 2)
+        ])
+    );
+    assert_eq!(t.dfi_received, 69.0);
+    assert_eq!(t.dfi_sent, 60.0);
+    assert_eq!(t.buckets, BTreeMap::from([(0, 1), (10, 3), (40, 1)]));
+    assert_eq!(t.density_line(), "-@  -");
+
+    // The coinbase has no graph edges
+    let t = build_timeline(&store, "a", 10, false).unwrap();
+    assert_eq!(t.tx_count, 4);
+    assert_eq!(t.first_seen, Some(12));
+    assert!(build_timeline(&store, "z", 10, false)
+        .unwrap()
+        .density_line()
+        .is_empty());
+}
@@ -0,0 +1,232 @@
+use crate::db::{self, SqliteBlockStore};
+use crate::lang::Result;
+use clap::{Parser, ValueEnum};
+use std::collections::BTreeMap;
+use tracing::{debug, info};
+
+const SECS_PER_DAY: i64 = 86400;
+
+#[derive(Parser, Debug)]
+pub struct TokenVolumeArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    /// Token symbol as stored in the index (eg. dfi, btc, dusd)
+    #[arg(long, short = 't')]
+    pub token: String,
+    #[arg(long, value_enum, default_value_t = VolumePeriod::Daily)]
+    pub period: VolumePeriod,
+    /// Unix timestamp (block time) to start from, inclusive
+    #[arg(long)]
+    pub start_ts: Option<i64>,
+    /// Unix timestamp (block time) to end at, inclusive
+    #[arg(long)]
+    pub end_ts: Option<i64>,
+    #[arg(long, value_enum, default_value_t = VolumeOutputFormat::Csv)]
+    pub output_format: VolumeOutputFormat,
+}
+
+/// Periods are in UTC. Weeks start on Monday, months on the 1st.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VolumePeriod {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VolumeOutputFormat {
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct PeriodVolume {
+    pub period_start: i64,
+    pub token_pair: String,
+    /// In the from token of the pair
+    pub volume: f64,
+    pub tx_count: u64,
+}
+
+impl VolumePeriod {
+    /// Start of the period `ts` falls in, as unix timestamp
+    pub fn period_start(&self, ts: i64) -> i64 {
+        let days = ts.div_euclid(SECS_PER_DAY);
+        let start_days = match self {
+            VolumePeriod::Daily => days,
+            // 1970-01-01 is a Thursday
+            VolumePeriod::Weekly => days - (days + 3).rem_euclid(7),
+            VolumePeriod::Monthly => {
+                let (y, m, _) = civil_from_days(days);
+                days_from_civil(y, m, 1)
+            }
+        };
+        start_days * SECS_PER_DAY
+    }
+}
+
+// Conversions between days since epoch and (year, month, day), from
+// http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    (y, m, d)
+}
+
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = if m > 2 { m - 3 } else { m + 9 };
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+pub fn run(args: &TokenVolumeArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    let sql_store = SqliteBlockStore::open(Some(&args.sqlite_path))?;
+
+    info!("get {} swap volume..", args.token);
+    let volumes = get_period_volumes(
+        &sql_store,
+        &args.token.to_lowercase(),
+        args.period,
+        args.start_ts,
+        args.end_ts,
+    )?;
+
+    match args.output_format {
+        VolumeOutputFormat::Csv => {
+            println!("period_start,token_pair,volume,tx_count");
+            for v in volumes.iter() {
+                println!(
+                    "{},{},{:.8},{}",
+                    v.period_start, v.token_pair, v.volume, v.tx_count
+                );
+            }
+        }
+        VolumeOutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&volumes)?);
+        }
+    }
+
+    info!("complete");
+    Ok(())
+}
+
+/// Swap volume and count of `token` per period and pair, ordered by
+/// period and then pair.
+pub fn get_period_volumes(
+    sql_store: &SqliteBlockStore,
+    token: &str,
+    period: VolumePeriod,
+    start_ts: Option<i64>,
+    end_ts: Option<i64>,
+) -> Result<Vec<PeriodVolume>> {
+    let mut buckets = BTreeMap::<(i64, String), PeriodVolume>::new();
+    sql_store.iter_token_swaps(token, start_ts, end_ts, |block_time, from, to, amt| {
+        let period_start = period.period_start(block_time);
+        let token_pair = format!("{}-{}", from, to);
+        let v = buckets
+            .entry((period_start, token_pair.clone()))
+            .or_insert_with(|| PeriodVolume {
+                period_start,
+                token_pair,
+                ..Default::default()
+            });
+        v.volume += amt.parse::<f64>()?;
+        v.tx_count += 1;
+        Ok(())
+    })?;
+    Ok(buckets.into_values().collect())
+}
+
+#[test]
+fn test_period_start() {
+    // 2024-03-14T15:09:26Z, a Thursday
+    let ts = 1710428966;
+    assert_eq!(VolumePeriod::Daily.period_start(ts), 1710374400);
+    assert_eq!(VolumePeriod::Weekly.period_start(ts), 1710115200);
+    assert_eq!(VolumePeriod::Monthly.period_start(ts), 1709251200);
+    assert_eq!(VolumePeriod::Monthly.period_start(1709251200), 1709251200);
+}
+
+#[test]
+fn test_get_period_volumes() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    let day = |d: i64| 1710374400 + d * SECS_PER_DAY;
+    store
+        .with_stmts(|stmts| {
+            let blocks = [
+                (1, day(0) + 10),
+                (2, day(0) + 500),
+                (3, day(1)),
+                (4, day(2) + 1),
+            ];
+            for (height, time) in blocks {
+                let data = serde_json::json!({ "time": time }).to_string();
+                stmts[0].execute(rusqlite::params![
+                    height,
+                    format!("h{}", height),
+                    data,
+                    rusqlite::types::Null,
+                    rusqlite::types::Null
+                ])?;
+            }
+            let swaps = [
+                ("s1", 1, "ps", "btc", "dfi", "0.5"),
+                ("s2", 2, "ps", "btc", "dfi", "0.25"),
+                ("s3", 2, "cs", "dfi", "dusd", "100.0"),
+                ("s4", 3, "ps", "btc", "dfi", "1.0"),
+                ("s5", 4, "ps", "dusd", "dfi", "30.0"),
+                ("s6", 4, "ps", "btc", "eth", "9.0"),
+                ("x1", 4, "aa", "", "", ""),
+            ];
+            for (txid, height, tx_type, from, to, amt) in swaps {
+                crate::db::insert_test_tx(
+                    stmts,
+                    &crate::db::TestTx {
+                        txid,
+                        height,
+                        tx_type,
+                        swap_from: from,
+                        swap_to: to,
+                        swap_amt: amt,
+                        ..Default::default()
+                    },
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let v = get_period_volumes(&store, "dfi", VolumePeriod::Daily, None, None).unwrap();
+    let rows = v
+        .iter()
+        .map(|x| (x.period_start, x.token_pair.as_str(), x.volume, x.tx_count))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rows,
+        [
+            (day(0), "btc-dfi", 0.75, 2),
+            (day(0), "dfi-dusd", 100.0, 1),
+            (day(1), "btc-dfi", 1.0, 1),
+            (day(2), "dusd-dfi", 30.0, 1),
+        ]
+    );
+
+    let v = get_period_volumes(&store, "dfi", VolumePeriod::Daily, Some(day(1)), None).unwrap();
+    assert_eq!(v.len(), 2);
+    let v = get_period_volumes(&store, "dfi", VolumePeriod::Monthly, None, None).unwrap();
+    assert_eq!(v.iter().map(|x| x.tx_count).sum::<u64>(), 5);
+}
@@ -0,0 +1,247 @@
+use crate::args::OutputFormat;
+use crate::db::{self, SqliteBlockStore};
+use crate::lang::{Error, Result};
+use crate::models::TxType;
+use bigdecimal::BigDecimal;
+use clap::Parser;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use tracing::{debug, error, info};
+
+#[derive(Parser, Debug)]
+pub struct VaultHistoryArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    /// Vault id, which is the txid of the tx that created the vault
+    #[arg(long)]
+    pub vault_id: String,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub output_format: OutputFormat,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct VaultEvent {
+    pub height: i64,
+    pub tx_type: TxType,
+    pub txid: String,
+    pub amount: Option<String>,
+    pub token: Option<String>,
+}
+
+// Net amounts per token
+#[derive(Debug, Default, serde::Serialize)]
+struct VaultPosition {
+    collateral: BTreeMap<String, String>,
+    loans: BTreeMap<String, String>,
+}
+
+// Not a TxType yet, so it's stored as is.
+const CLOSE_VAULT_TX_TYPE: &str = "CloseVault";
+
+pub fn run(args: &VaultHistoryArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+
+    let vault_id = args.vault_id.as_str();
+    // The id goes into the query below, so only allow what a txid can be.
+    if vault_id.is_empty() || !vault_id.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(Error::from(format!("invalid vault id: {}", vault_id)));
+    }
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    let sql_store = SqliteBlockStore::open(Some(&args.sqlite_path))?;
+
+    let tx_types = [
+        TxType::Vault,
+        TxType::DepositToVault,
+        TxType::TakeLoan,
+        TxType::PaybackLoan,
+        TxType::WithdrawFromVault,
+    ]
+    .iter()
+    .map(|x| format!("'{}'", x))
+    .chain(std::iter::once(format!("'{}'", CLOSE_VAULT_TX_TYPE)))
+    .collect::<Vec<_>>()
+    .join(", ");
+
+    // There's no vault id column, so the data is matched loosely here and
+    // the vault id in the msg is checked below.
+    let modifier = format!(
+        "where tx_type in ({}) and (txid = '{}' or data like '%{}%') order by height",
+        tx_types, vault_id, vault_id
+    );
+
+    let mut events = Vec::new();
+    info!("get vault txs..");
+    let r = sql_store.iter_txs(Some(&modifier), |tx| {
+        if quit.load(std::sync::atomic::Ordering::Relaxed) {
+            info!("int: early exit");
+            return Err("interrupted".into());
+        }
+        let tx = tx?;
+        let tx_type = TxType::from_display(&tx.tx_type);
+        let Some(vm) = tx.data.vm.as_ref() else {
+            return Ok(());
+        };
+
+        if matches!(tx_type, TxType::Vault) {
+            if tx.txid == vault_id {
+                events.push(VaultEvent {
+                    height: tx.height,
+                    tx_type,
+                    txid: tx.txid,
+                    amount: None,
+                    token: None,
+                });
+            }
+            return Ok(());
+        }
+
+        if vm.msg["vaultId"].as_str() != Some(vault_id) {
+            return Ok(());
+        }
+        let amounts = vault_msg_amounts(&vm.msg);
+        if amounts.is_empty() {
+            events.push(VaultEvent {
+                height: tx.height,
+                tx_type,
+                txid: tx.txid,
+                amount: None,
+                token: None,
+            });
+            return Ok(());
+        }
+        for (amount, token) in amounts {
+            events.push(VaultEvent {
+                height: tx.height,
+                tx_type: tx_type.clone(),
+                txid: tx.txid.clone(),
+                amount: Some(amount),
+                token: Some(token),
+            });
+        }
+        Ok(())
+    });
+    if let Err(e) = r {
+        error!("{:?}", e);
+        return Err(e);
+    }
+    events.sort_by_key(|x| x.height);
+
+    let position = vault_position(&events)?;
+
+    match args.output_format {
+        OutputFormat::Text => {
+            println!(
+                "{:<10} {:<12} {:<64} {:>20} {:<8}",
+                "height", "type", "txid", "amount", "token"
+            );
+            for e in events.iter() {
+                println!(
+                    "{:<10} {:<12} {:<64} {:>20} {:<8}",
+                    e.height,
+                    e.tx_type.to_string(),
+                    e.txid,
+                    e.amount.as_deref().unwrap_or("-"),
+                    e.token.as_deref().unwrap_or("-"),
+                );
+            }
+            for (token, amount) in position.collateral.iter() {
+                println!("net collateral: {} {}", amount, token);
+            }
+            for (token, amount) in position.loans.iter() {
+                println!("net loan: {} {}", amount, token);
+            }
+        }
+        OutputFormat::Json => {
+            let out = serde_json::json!({
+                "vault_id": vault_id,
+                "events": events,
+                "position": position,
+            });
+            println!("{}", serde_json::to_string_pretty(&out)?);
+        }
+    }
+
+    info!("complete");
+    Ok(())
+}
+
+// Deposits and withdrawals have a single `amount`, loans have `amounts`
+// which is either a single balance or a list of them. Balances are in
+// the `amount@token` form.
+fn vault_msg_amounts(msg: &serde_json::Value) -> Vec<(String, String)> {
+    let v = if msg["amounts"].is_null() {
+        &msg["amount"]
+    } else {
+        &msg["amounts"]
+    };
+    let balances = match v {
+        serde_json::Value::String(s) => vec![s.as_str()],
+        serde_json::Value::Array(arr) => arr.iter().filter_map(|x| x.as_str()).collect(),
+        _ => vec![],
+    };
+    balances
+        .into_iter()
+        .filter_map(|x| x.split_once('@'))
+        .map(|(amount, token)| (amount.to_owned(), token.to_owned()))
+        .collect()
+}
+
+fn vault_position(events: &[VaultEvent]) -> Result<VaultPosition> {
+    let mut collateral = BTreeMap::<String, BigDecimal>::new();
+    let mut loans = BTreeMap::<String, BigDecimal>::new();
+    for e in events {
+        let (Some(amount), Some(token)) = (&e.amount, &e.token) else {
+            continue;
+        };
+        let amount = BigDecimal::from_str(amount)?;
+        let (m, amount) = match e.tx_type {
+            TxType::DepositToVault => (&mut collateral, amount),
+            TxType::WithdrawFromVault => (&mut collateral, -amount),
+            TxType::TakeLoan => (&mut loans, amount),
+            TxType::PaybackLoan => (&mut loans, -amount),
+            _ => continue,
+        };
+        *m.entry(token.clone()).or_default() += amount;
+    }
+    let to_str = |m: BTreeMap<String, BigDecimal>| {
+        m.into_iter()
+            .map(|(k, v)| (k, v.to_string()))
+            .collect::<BTreeMap<_, _>>()
+    };
+    Ok(VaultPosition {
+        collateral: to_str(collateral),
+        loans: to_str(loans),
+    })
+}
+
+#[test]
+fn test_vault_msg_amounts() {
+    let msg = serde_json::json!({"vaultId": "v1", "from": "a", "amount": "10.00000000@DFI"});
+    assert_eq!(
+        vault_msg_amounts(&msg),
+        [("10.00000000".to_owned(), "DFI".to_owned())]
+    );
+    let msg = serde_json::json!({"vaultId": "v1", "to": "a", "amounts": ["1.5@DUSD", "0.1@TSLA"]});
+    assert_eq!(vault_msg_amounts(&msg).len(), 2);
+
+    let ev = |tx_type, amount: &str, token: &str| VaultEvent {
+        height: 0,
+        tx_type,
+        txid: String::new(),
+        amount: Some(amount.to_owned()),
+        token: Some(token.to_owned()),
+    };
+    let position = vault_position(&[
+        ev(TxType::DepositToVault, "10", "DFI"),
+        ev(TxType::WithdrawFromVault, "2.5", "DFI"),
+        ev(TxType::TakeLoan, "100", "DUSD"),
+        ev(TxType::PaybackLoan, "40", "DUSD"),
+    ])
+    .unwrap();
+    assert_eq!(position.collateral["DFI"], "7.5");
+    assert_eq!(position.loans["DUSD"], "60");
+}
@@ -0,0 +1,455 @@
+use crate::args::OutputFormat;
+use crate::cliindexer;
+use crate::clipool::CliDriverPool;
+use crate::db::{self, SqliteBlockStore};
+use crate::dfiutils::TokenResolver;
+use crate::lang::{Error, Result};
+use crate::logparse::process_log_file;
+use crate::models::{Block, LogEntryMap};
+use clap::Parser;
+use rusqlite::types::ValueRef;
+use std::collections::{BTreeSet, HashMap};
+use tracing::{debug, info, warn};
+
+#[derive(Parser, Debug)]
+pub struct VerifyArgs {
+    #[arg(long, default_value = "data/index.sqlite")]
+    pub sqlite_path: String,
+    /// Defaults to the lowest height in the index
+    #[arg(short = 's', long)]
+    pub start_height: Option<u32>,
+    /// Defaults to the highest height in the index
+    #[arg(short = 'e', long)]
+    pub end_height: Option<u32>,
+    /// Re-fetch the broken and missing blocks from the node and re-index
+    /// them in place
+    #[arg(long, default_value_t = false)]
+    pub fix: bool,
+    #[arg(long, default_value = "defi-cli")]
+    pub defi_cli_path: String,
+    /// Log to take the ICX claim data of re-indexed blocks from. Left
+    /// empty on those blocks if not set.
+    #[arg(long)]
+    pub defid_log_path: Option<String>,
+    #[arg(long, default_value = "ICX:")]
+    pub log_icx_matcher: String,
+    #[arg(long, default_value = "ICXCalc:")]
+    pub log_icx_calc_matcher: String,
+    #[arg(long, default_value = "SwapResult:")]
+    pub log_swap_matcher: String,
+    #[arg(long, default_value_t = true)]
+    pub enable_graph_table: bool,
+    /// Token id to symbol map, json or csv, for the swap tokens of
+    /// re-indexed blocks that aren't in the built-in table
+    #[arg(long)]
+    pub token_map_path: Option<String>,
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum VerifyIssue {
+    /// Inclusive run of heights with no block
+    Gap { start: i64, end: i64 },
+    /// Block data that isn't text or doesn't parse
+    BadBlock { height: i64, error: String },
+    /// Tx of the block that's not in `txs`, or stored at another height
+    MissingTx {
+        height: i64,
+        txid: String,
+        found_at: Option<i64>,
+    },
+    /// `tx_in` or `tx_out` that isn't valid utf8 or doesn't parse
+    BadTxField {
+        height: i64,
+        txid: String,
+        field: String,
+        error: String,
+    },
+}
+
+impl VerifyIssue {
+    fn heights(&self) -> std::ops::RangeInclusive<i64> {
+        match self {
+            VerifyIssue::Gap { start, end } => *start..=*end,
+            VerifyIssue::BadBlock { height, .. }
+            | VerifyIssue::MissingTx { height, .. }
+            | VerifyIssue::BadTxField { height, .. } => *height..=*height,
+        }
+    }
+}
+
+impl std::fmt::Display for VerifyIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyIssue::Gap { start, end } => write!(f, "gap: {} - {}", start, end),
+            VerifyIssue::BadBlock { height, error } => {
+                write!(f, "bad block: [{}] {}", height, error)
+            }
+            VerifyIssue::MissingTx {
+                height,
+                txid,
+                found_at: Some(h),
+            } => write!(f, "missing tx: [{}] {} (found at {})", height, txid, h),
+            VerifyIssue::MissingTx { height, txid, .. } => {
+                write!(f, "missing tx: [{}] {}", height, txid)
+            }
+            VerifyIssue::BadTxField {
+                height,
+                txid,
+                field,
+                error,
+            } => write!(f, "bad {}: [{}] {} {}", field, height, txid, error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct VerifyReport {
+    pub start_height: i64,
+    pub end_height: i64,
+    pub blocks_checked: u64,
+    pub txs_checked: u64,
+    pub issues: Vec<VerifyIssue>,
+    /// Heights re-indexed with `--fix`. The issues are the ones left after.
+    pub fixed_heights: Vec<i64>,
+}
+
+impl VerifyReport {
+    /// Heights to re-index to clear the issues
+    pub fn heights_to_fix(&self) -> BTreeSet<i64> {
+        self.issues.iter().flat_map(|x| x.heights()).collect()
+    }
+}
+
+pub fn run(args: &VerifyArgs) -> Result<()> {
+    debug!("args: {:?}", args);
+
+    let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+    let interrupted = || quit.load(std::sync::atomic::Ordering::Relaxed);
+
+    db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Read)?;
+    let sql_store = if args.fix {
+        db::validate_sqlite_path(&args.sqlite_path, db::PathMode::Write)?;
+        SqliteBlockStore::open_or_create(&args.sqlite_path)?
+    } else {
+        SqliteBlockStore::open(Some(&args.sqlite_path))?
+    };
+
+    let start_height = match args.start_height {
+        Some(h) => h.into(),
+        None => sql_store.min_block_height()?.unwrap_or(0),
+    };
+    let end_height = match args.end_height {
+        Some(h) => h.into(),
+        None => sql_store.max_block_height()?.unwrap_or(-1),
+    };
+    info!("verify: {} - {}..", start_height, end_height);
+    let mut report = verify_index(&sql_store, start_height, end_height, interrupted)?;
+    info!("issues: {}", report.issues.len());
+
+    let heights = report.heights_to_fix();
+    if args.fix && !heights.is_empty() {
+        let tokens = TokenResolver::from_path(args.token_map_path.as_deref())?;
+        let log_entry_map = load_log(args)?;
+        let cli = CliDriverPool::new(&args.defi_cli_path, 1, None);
+        let mut fixed = Vec::new();
+        for height in heights {
+            if interrupted() {
+                info!("int: early exit");
+                break;
+            }
+            fix_height(
+                &sql_store,
+                &cli,
+                &log_entry_map,
+                &tokens,
+                height,
+                args.enable_graph_table,
+            )?;
+            info!("fixed: {}", height);
+            fixed.push(height);
+        }
+
+        info!("re-verify..");
+        report = verify_index(&sql_store, start_height, end_height, interrupted)?;
+        report.fixed_heights = fixed;
+    }
+
+    match args.format {
+        OutputFormat::Text => {
+            println!("heights: {} - {}", report.start_height, report.end_height);
+            println!("blocks checked: {}", report.blocks_checked);
+            println!("txs checked: {}", report.txs_checked);
+            if !report.fixed_heights.is_empty() {
+                println!("fixed: {} blocks", report.fixed_heights.len());
+            }
+            println!("issues: {}", report.issues.len());
+            for x in report.issues.iter() {
+                println!("    {}", x);
+            }
+        }
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+    }
+
+    if !report.issues.is_empty() {
+        return Err(format!("index has {} issues", report.issues.len()).into());
+    }
+    info!("complete");
+    Ok(())
+}
+
+/// Check `start..=end` for height gaps, block data that doesn't parse,
+/// block txs missing from `txs`, and `tx_in` / `tx_out` that don't parse.
+pub fn verify_index<F>(
+    sql_store: &SqliteBlockStore,
+    start: i64,
+    end: i64,
+    interrupted: F,
+) -> Result<VerifyReport>
+where
+    F: Fn() -> bool,
+{
+    let mut report = VerifyReport {
+        start_height: start,
+        end_height: end,
+        ..Default::default()
+    };
+    let range = format!("where height between {} and {}", start, end);
+
+    info!("check blocks..");
+    let mut next = start;
+    sql_store.iter_blocks_raw(Some(&format!("{} order by height", range)), |r| {
+        if interrupted() {
+            info!("int: early exit");
+            return Err("interrupted".into());
+        }
+        let row = r?;
+        let height: i64 = row.get(0)?;
+        if height > next {
+            report.issues.push(VerifyIssue::Gap {
+                start: next,
+                end: height - 1,
+            });
+        }
+        next = height + 1;
+        report.blocks_checked += 1;
+
+        let block = match parse_block(row.get_ref(2)?) {
+            Ok(x) => x,
+            Err(e) => {
+                report.issues.push(VerifyIssue::BadBlock {
+                    height,
+                    error: e.to_string(),
+                });
+                return Ok(());
+            }
+        };
+        for tx in block.tx.iter() {
+            let found_at = sql_store.get_tx_height(&tx.txid)?;
+            if found_at != Some(height) {
+                report.issues.push(VerifyIssue::MissingTx {
+                    height,
+                    txid: tx.txid.to_string(),
+                    found_at,
+                });
+            }
+        }
+        Ok(())
+    })?;
+    if next <= end {
+        report.issues.push(VerifyIssue::Gap { start: next, end });
+    }
+
+    info!("check txs..");
+    sql_store.iter_txs_raw(Some(&range), |r| {
+        if interrupted() {
+            info!("int: early exit");
+            return Err("interrupted".into());
+        }
+        let row = r?;
+        report.txs_checked += 1;
+        for (i, field) in [(3, "tx_in"), (4, "tx_out")] {
+            if let Err(e) = parse_addr_val_map(row.get_ref(i)?) {
+                report.issues.push(VerifyIssue::BadTxField {
+                    height: row.get(1)?,
+                    txid: row.get(0)?,
+                    field: field.to_string(),
+                    error: e.to_string(),
+                });
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(report)
+}
+
+fn parse_block(v: ValueRef) -> Result<Block> {
+    let s = match v {
+        ValueRef::Text(b) => std::str::from_utf8(b)?,
+        _ => return Err(Error::from(format!("block data is {}", v.data_type()))),
+    };
+    Ok(serde_json::from_str(s)?)
+}
+
+fn parse_addr_val_map(v: ValueRef) -> Result<()> {
+    let s = match v {
+        ValueRef::Text(b) => std::str::from_utf8(b)?,
+        _ => return Err(Error::from(format!("value is {}", v.data_type()))),
+    };
+    if !s.is_empty() {
+        serde_json::from_str::<HashMap<&str, f64>>(s)?;
+    }
+    Ok(())
+}
+
+fn load_log(args: &VerifyArgs) -> Result<LogEntryMap> {
+    let mut log_entry_map = LogEntryMap::new();
+    match &args.defid_log_path {
+        Some(path) => {
+            info!("ingesting log file: {}", path);
+            process_log_file(
+                path,
+                &args.log_icx_matcher,
+                &args.log_icx_calc_matcher,
+                &args.log_swap_matcher,
+                None,
+                None,
+                &mut log_entry_map,
+            )?;
+        }
+        None => warn!("no log file, icx claim data of re-indexed blocks is left empty"),
+    }
+    Ok(log_entry_map)
+}
+
+/// Re-fetch the block at `height` and replace everything indexed at it.
+fn fix_height(
+    sql_store: &SqliteBlockStore,
+    cli: &CliDriverPool,
+    log_entry_map: &LogEntryMap,
+    tokens: &TokenResolver,
+    height: i64,
+    enable_addr_graph: bool,
+) -> Result<()> {
+    let tx_filter = cliindexer::TxTypeFilter::load(sql_store)?;
+    let block_out = cliindexer::fetch_block(cli, height.try_into()?)?;
+    let block: Block = block_out.json()?;
+    let block_json_str = block_out.str()?;
+    if i64::from(block.height) != height {
+        return Err(format!("fetched block {} for height {}", block.height, height).into());
+    }
+    sql_store.atomic_replace_range(height, height, |store| {
+        store.with_stmts(|stmts| {
+            cliindexer::insert_block(
+                stmts,
+                store,
+                log_entry_map,
+                tokens,
+                block_json_str,
+                block,
+                cliindexer::InsertOpts {
+                    enable_addr_graph,
+                    tx_filter: Some(&tx_filter),
+                    ..Default::default()
+                },
+            )?;
+            Ok(())
+        })
+    })
+}
+
+#[test]
+fn test_verify_index() {
+    let store = SqliteBlockStore::open_or_create(":memory:").unwrap();
+    let block = |height: i64, txids: &[&str]| {
+        let tx = txids
+            .iter()
+            .map(|x| {
+                serde_json::json!({
+                    "txid": x, "hash": "", "version": 4, "size": 0, "vsize": 0,
+                    "weight": 0, "locktime": 0, "vin": [], "vout": [], "hex": "",
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({
+            "hash": format!("h{}", height), "height": height, "confirmations": 1,
+            "strippedsize": 1, "size": 1, "weight": 1,
+            "minter": {"id": "m", "stakeModifier": "s"},
+            "version": 1, "versionHex": "1", "merkleroot": "r",
+            "time": 0, "mediantime": 0, "bits": "b", "difficulty": 1.0,
+            "chainwork": "c", "tx": tx, "nTx": txids.len(),
+        })
+        .to_string()
+    };
+    store
+        .with_stmts(|stmts| {
+            let blocks = [
+                (1, block(1, &["t1"])),
+                (2, block(2, &["t2", "t3", "t4"])),
+                (5, "{".to_string()),
+                (6, block(6, &[])),
+            ];
+            for (height, data) in blocks {
+                stmts[0].execute(rusqlite::params![
+                    height,
+                    format!("h{}", height),
+                    data,
+                    rusqlite::types::Null,
+                    rusqlite::types::Null
+                ])?;
+            }
+            let txs = [
+                ("t1", 1, r#"{"a":1.0}"#),
+                ("t2", 2, r#"{"a":1.0"#),
+                ("t4", 1, ""),
+            ];
+            for (txid, height, tx_out) in txs {
+                crate::db::insert_test_tx(
+                    stmts,
+                    &crate::db::TestTx {
+                        txid,
+                        height,
+                        tx_type: "utxo",
+                        tx_out,
+                        ..Default::default()
+                    },
+                )?;
+            }
+            Ok(())
+        })
+        .unwrap();
+
+    let report = verify_index(&store, 1, 7, || false).unwrap();
+    assert_eq!((report.blocks_checked, report.txs_checked), (4, 3));
+    let issues = report
+        .issues
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        issues[..4],
+        [
+            "missing tx: [2] t3",
+            "missing tx: [2] t4 (found at 1)",
+            "gap: 3 - 4",
+            "bad block: [5] serde json: EOF while parsing an object at line 1 column 1",
+        ]
+    );
+    assert_eq!(issues[4], "gap: 7 - 7");
+    assert!(issues[5].starts_with("bad tx_out: [2] t2 serde json: EOF"));
+    assert_eq!(issues.len(), 6);
+    assert_eq!(
+        report.heights_to_fix().into_iter().collect::<Vec<_>>(),
+        [2, 3, 4, 5, 7]
+    );
+    assert!(verify_index(&store, 6, 6, || false)
+        .unwrap()
+        .issues
+        .is_empty());
+}